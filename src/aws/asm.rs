@@ -2,10 +2,11 @@ use std::{collections::HashMap, io::Read, sync::Arc};
 
 use anyhow::{anyhow, Result};
 use minaws::{
-    imds::{Credentials, Imds},
+    imds::Credentials,
     secretsmanager::{self, GetSecretValueInput, GetSecretValueOutput},
 };
 
+use crate::aws::imds::CachedImds;
 use crate::writable::Writable;
 
 #[derive(Debug, Clone)]
@@ -19,13 +20,24 @@ impl AsmClient {
         Ok(Self { api: api.into() })
     }
 
-    pub fn from_imds(imds: &Imds, region: &str) -> Result<Self> {
+    pub fn from_imds(imds: &CachedImds, region: &str) -> Result<Self> {
         let credentials = imds.get_credentials()?;
         let api = secretsmanager::Api::new(region, credentials);
         Ok(Self { api: api.into() })
     }
 
-    pub fn get_secret_list(&self, secret_id: &str) -> Result<Vec<AsmSecretValue>> {
+    // Get the secret as a single file, or with split_json as one file per key of a
+    // secret that is a JSON object.
+    pub fn get_secret_list(
+        &self,
+        secret_id: &str,
+        split_json: bool,
+    ) -> Result<Vec<AsmSecretValue>> {
+        if split_json {
+            let secret = self.get_secret_value(secret_id)?;
+            return split_json_secret(&secret)
+                .map_err(|e| anyhow!("unable to split secret with ID {}: {}", secret_id, e));
+        }
         let secret = self.get_secret(secret_id)?;
         if let Some(secret_string) = secret.secret_string {
             return Ok(vec![AsmSecretValue {
@@ -68,9 +80,31 @@ impl AsmClient {
     }
 }
 
+fn split_json_secret(secret: &[u8]) -> Result<Vec<AsmSecretValue>> {
+    let map: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(secret)?;
+    map.into_iter()
+        .map(|(key, value)| {
+            if key.is_empty() || key.contains('/') || key == "." || key == ".." {
+                return Err(anyhow!("key {:?} is not a valid file name", key));
+            }
+            // Strings are written as is, and other values as JSON.
+            let string = match value {
+                serde_json::Value::String(string) => string,
+                value => value.to_string(),
+            };
+            Ok(AsmSecretValue {
+                name: key,
+                string: Some(string),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
 #[derive(Debug, Default)]
 pub struct AsmSecretValue {
     pub binary: Option<Vec<u8>>,
+    pub name: String,
     pub string: Option<String>,
 }
 
@@ -96,6 +130,35 @@ impl Writable for AsmSecretValue {
     }
 
     fn name(&self) -> &str {
-        ""
+        &self.name
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_split_json_secret() {
+        let secret = br#"{"username": "app", "password": "s3cret", "port": 5432}"#;
+        let mut values = split_json_secret(secret)
+            .unwrap()
+            .into_iter()
+            .map(|v| (v.name, v.string.unwrap()))
+            .collect::<Vec<_>>();
+        values.sort();
+        assert_eq!(
+            values,
+            vec![
+                ("password".to_string(), "s3cret".to_string()),
+                ("port".to_string(), "5432".to_string()),
+                ("username".to_string(), "app".to_string()),
+            ]
+        );
+
+        assert!(split_json_secret(br#"{"../escape": "x"}"#).is_err());
+        assert!(split_json_secret(b"not json").is_err());
     }
 }
@@ -0,0 +1,159 @@
+use std::{
+    collections::BTreeMap, io::Read, path::Path, sync::Mutex, thread::sleep, time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use log::debug;
+use minaws::imds::{Credentials, Imds};
+
+// Metadata that does not change while the instance runs, which is fetched only once.
+const IMMUTABLE_PATHS: [&str; 7] = [
+    "identity-credentials/ec2/info",
+    "instance-id",
+    "instance-life-cycle",
+    "instance-type",
+    "mac",
+    "placement/availability-zone",
+    "placement/region",
+];
+
+// The region is cached under the path it is found at in instance metadata.
+const KEY_REGION: &str = "placement/region";
+
+const IMDS_ENDPOINT: &str = "http://169.254.169.254";
+const PATH_TOKEN: &str = "/latest/api/token";
+const PATH_USER_DATA: &str = "/latest/user-data";
+
+// User data is limited to 16 KiB by EC2, so anything larger is not user data.
+const MAX_USER_DATA_BYTES: u64 = 16 * 1024;
+
+// IMDS may not answer right away early in boot, so requests are retried.
+const RETRIES: u32 = 5;
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// Values of immutable metadata, shared by all clients so that threads and reloads
+// that create their own do not fetch them again.
+static CACHE: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+// A client for IMDS that remembers metadata which does not change, such as the
+// instance ID and region. Other metadata, such as tags, spot events and credentials,
+// is fetched each time it is asked for.
+#[derive(Default)]
+pub struct CachedImds {
+    imds: Imds,
+}
+
+impl CachedImds {
+    pub fn get_credentials(&self) -> Result<Credentials> {
+        Ok(self.imds.get_credentials()?)
+    }
+
+    pub fn get_metadata(&self, path: &Path) -> Result<String> {
+        let key = path.to_string_lossy();
+        let key = key.trim_matches('/');
+        if !IMMUTABLE_PATHS.contains(&key) {
+            return Ok(self.imds.get_metadata(path)?);
+        }
+        cached(key, || Ok(self.imds.get_metadata(path)?))
+    }
+
+    pub fn get_region(&self) -> Result<String> {
+        cached(KEY_REGION, || Ok(self.imds.get_region()?))
+    }
+}
+
+// Get a value from the cache, or fetch and cache it. The cache is not locked while
+// fetching, so a slow request does not hold up others.
+fn cached<F>(key: &str, fetch: F) -> Result<String>
+where
+    F: FnOnce() -> Result<String>,
+{
+    if let Some(value) = CACHE.lock().unwrap().get(key) {
+        return Ok(value.clone());
+    }
+    let value = fetch()?;
+    CACHE.lock().unwrap().insert(key.into(), value.clone());
+    Ok(value)
+}
+
+// Get the user data as it was given to the instance. It is read as bytes rather
+// than a string because it may be gzip compressed. Instances without user data
+// get an empty result.
+pub fn get_user_data() -> Result<Vec<u8>> {
+    let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+    let mut attempt = 1;
+    loop {
+        match fetch_user_data(&agent) {
+            Ok(user_data) => return Ok(user_data),
+            Err(e) if attempt < RETRIES => {
+                debug!("Retrying request for user data after error: {}", e);
+                attempt += 1;
+                sleep(RETRY_INTERVAL);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn fetch_user_data(agent: &ureq::Agent) -> Result<Vec<u8>> {
+    let token = agent
+        .put(&format!("{}{}", IMDS_ENDPOINT, PATH_TOKEN))
+        .set("X-aws-ec2-metadata-token-ttl-seconds", "60")
+        .call()
+        .map_err(|e| anyhow!("unable to get IMDS token: {}", e))?
+        .into_string()
+        .map_err(|e| anyhow!("unable to read IMDS token: {}", e))?;
+    let response = match agent
+        .get(&format!("{}{}", IMDS_ENDPOINT, PATH_USER_DATA))
+        .set("X-aws-ec2-metadata-token", &token)
+        .call()
+    {
+        Ok(response) => response,
+        Err(ureq::Error::Status(404, _)) => return Ok(Vec::new()),
+        Err(e) => return Err(anyhow!("unable to get user data: {}", e)),
+    };
+    // One byte past the limit is read, so larger user data fails rather than being
+    // cut off.
+    let mut buf = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_USER_DATA_BYTES + 1)
+        .read_to_end(&mut buf)
+        .map_err(|e| anyhow!("unable to read user data: {}", e))?;
+    if buf.len() as u64 > MAX_USER_DATA_BYTES {
+        return Err(anyhow!(
+            "user data is larger than {} bytes",
+            MAX_USER_DATA_BYTES
+        ));
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_cached() {
+        let fetches = Cell::new(0);
+        let fetch = || {
+            fetches.set(fetches.get() + 1);
+            Ok("i-0123456789abcdef0".to_string())
+        };
+        assert_eq!(cached("test/cached", fetch).unwrap(), "i-0123456789abcdef0");
+        assert_eq!(cached("test/cached", fetch).unwrap(), "i-0123456789abcdef0");
+        assert_eq!(fetches.get(), 1);
+
+        // Errors are not cached, so the value is fetched again.
+        assert!(cached("test/cached-error", || Err(anyhow!("unreachable"))).is_err());
+        assert_eq!(
+            cached("test/cached-error", || Ok("us-east-1".to_string())).unwrap(),
+            "us-east-1"
+        );
+    }
+}
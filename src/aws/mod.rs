@@ -1,3 +1,47 @@
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use minaws::imds::Credentials;
+
+use imds::CachedImds;
+
 pub mod asm;
+pub mod imds;
 pub mod s3;
 pub mod ssm;
+
+// Credentials are only fetched from IMDS the first time a source that needs them
+// is used, so instances without an instance profile can use other features.
+pub struct LazyCredentials<'a> {
+    imds: &'a CachedImds,
+    credentials: Mutex<Option<Credentials>>,
+}
+
+impl<'a> LazyCredentials<'a> {
+    pub fn new(imds: &'a CachedImds) -> Self {
+        Self {
+            imds,
+            credentials: Mutex::new(None),
+        }
+    }
+
+    // Get the credentials, naming the source that requires them in case of error.
+    pub fn get(&self, source: &str) -> Result<Credentials> {
+        let mut credentials = self
+            .credentials
+            .lock()
+            .map_err(|e| anyhow!("unable to lock credentials: {}", e))?;
+        if let Some(credentials) = credentials.as_ref() {
+            return Ok(credentials.clone());
+        }
+        let fetched = self.imds.get_credentials().map_err(|e| {
+            anyhow!(
+                "unable to get AWS credentials from IMDS for {}: {}",
+                source,
+                e
+            )
+        })?;
+        *credentials = Some(fetched.clone());
+        Ok(fetched)
+    }
+}
@@ -7,10 +7,11 @@ use std::{
 use anyhow::{anyhow, Result};
 use log::debug;
 use minaws::{
-    imds::{Credentials, Imds},
+    imds::Credentials,
     s3::{self, GetObjectInput, GetObjectOutput, Object},
 };
 
+use crate::aws::imds::CachedImds;
 use crate::writable::Writable;
 
 pub struct S3Client {
@@ -23,7 +24,7 @@ impl S3Client {
         Ok(Self { api: api.into() })
     }
 
-    pub fn from_imds(imds: &Imds, region: &str) -> Result<Self> {
+    pub fn from_imds(imds: &CachedImds, region: &str) -> Result<Self> {
         let credentials = imds.get_credentials()?;
         let api = s3::Api::new(region, credentials);
         Ok(Self { api: api.into() })
@@ -43,10 +44,25 @@ impl S3Client {
     pub fn get_object_bytes(&self, bucket: &str, key: &str) -> Result<Vec<u8>> {
         let mut object = self.get_object(bucket, key)?;
         let mut buf = Vec::new();
-        let _ = object.body.read(&mut buf)?;
+        object.body.read_to_end(&mut buf)?;
         Ok(buf)
     }
 
+    pub fn put_object_bytes(&self, bucket: &str, key: &str, body: Vec<u8>) -> Result<()> {
+        self.api
+            .put_object(
+                s3::PutObjectInput::default()
+                    .bucket(bucket)
+                    .key(key)
+                    .body(body),
+            )
+            .map_err(|e| {
+                let s3_url = format!("s3://{}/{}", bucket, key);
+                anyhow!("unable to put object at {}: {}", s3_url, e)
+            })?;
+        Ok(())
+    }
+
     fn get_object(&self, bucket: &str, key: &str) -> Result<GetObjectOutput> {
         self.api
             .get_object(s3::GetObjectInput::default().bucket(bucket).key(key))
@@ -83,6 +99,7 @@ impl S3Client {
                     key: key.into(),
                     object: None,
                     path_suffix,
+                    size: object.size.and_then(|size| u64::try_from(size).ok()),
                 };
                 list.push(s3_object);
             }
@@ -118,6 +135,15 @@ impl S3Client {
     }
 }
 
+// Split an S3 URL such as s3://bucket/key into its bucket and key.
+pub fn parse_s3_url(url: &str) -> Option<(&str, &str)> {
+    let (bucket, key) = url.strip_prefix("s3://")?.split_once('/')?;
+    if bucket.is_empty() || key.is_empty() {
+        return None;
+    }
+    Some((bucket, key))
+}
+
 #[derive(Debug)]
 pub struct S3Object {
     api: Arc<s3::Api>,
@@ -125,12 +151,17 @@ pub struct S3Object {
     key: String,
     object: Option<GetObjectOutput>,
     path_suffix: String,
+    size: Option<u64>,
 }
 
 impl S3Object {
+    pub fn url(&self) -> String {
+        format!("s3://{}/{}", self.bucket, self.key)
+    }
+
     fn download(&mut self) -> Result<()> {
         if self.object.is_none() {
-            debug!("downloading s3://{}/{}", self.bucket, self.key);
+            debug!("downloading {} of size {:?}", self.url(), self.size);
             let object = self.api.get_object(
                 GetObjectInput::default()
                     .bucket(&self.bucket)
@@ -145,13 +176,11 @@ impl S3Object {
 impl Read for S3Object {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         self.download().map_err(|e| {
-            let s3_url = format!("s3://{}/{}", self.bucket, self.key);
             io::Error::new(
                 io::ErrorKind::Other,
-                format!("unable to download S3 object {}: {}", s3_url, e),
+                format!("unable to download S3 object {}: {}", self.url(), e),
             )
         })?;
-        debug!("reading from S3 object s3://{}/{}", self.bucket, self.key);
         self.object.as_mut().unwrap().body.read(buf)
     }
 }
@@ -164,4 +193,48 @@ impl Writable for S3Object {
     fn name(&self) -> &str {
         &self.path_suffix
     }
+
+    fn size(&self) -> Option<u64> {
+        self.size
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_s3_url() {
+        struct Case<'a> {
+            url: &'a str,
+            expected: Option<(&'a str, &'a str)>,
+        }
+        let cases = [
+            Case {
+                url: "s3://bucket/key",
+                expected: Some(("bucket", "key")),
+            },
+            Case {
+                url: "s3://bucket/a/b/c.yaml",
+                expected: Some(("bucket", "a/b/c.yaml")),
+            },
+            Case {
+                url: "s3://bucket/",
+                expected: None,
+            },
+            Case {
+                url: "s3://bucket",
+                expected: None,
+            },
+            Case {
+                url: "https://bucket/key",
+                expected: None,
+            },
+        ];
+        for case in cases {
+            assert_eq!(case.expected, parse_s3_url(case.url));
+        }
+    }
 }
@@ -3,10 +3,11 @@ use std::{collections::HashMap, io::Read, sync::Arc};
 use anyhow::{anyhow, Result};
 use log::debug;
 use minaws::{
-    imds::{Credentials, Imds},
+    imds::Credentials,
     ssm::{self, GetParametersByPathInput, Parameter},
 };
 
+use crate::aws::imds::CachedImds;
 use crate::writable::Writable;
 
 pub struct SsmClient {
@@ -19,19 +20,21 @@ impl SsmClient {
         Ok(Self { api: api.into() })
     }
 
-    pub fn from_imds(imds: &Imds, region: &str) -> Result<Self> {
+    pub fn from_imds(imds: &CachedImds, region: &str) -> Result<Self> {
         let credentials = imds.get_credentials()?;
         let api = ssm::Api::new(region, credentials);
         Ok(Self { api: api.into() })
     }
 
     pub fn get_parameter_list(&self, ssm_path: &str) -> Result<Vec<SsmParameterValue>> {
+        // Names of parameters do not include the version selector.
+        let (base_path, _) = split_version_selector(ssm_path);
         self.get_parameters(ssm_path).map(|parameters| {
             parameters
                 .into_iter()
                 .map(|p| {
                     let mut name = p.name.clone().unwrap();
-                    name = name[ssm_path.len()..].to_string();
+                    name = name[base_path.len()..].to_string();
                     SsmParameterValue {
                         name,
                         value: p.value.clone().unwrap(),
@@ -60,7 +63,9 @@ impl SsmClient {
 
     fn get_parameters(&self, ssm_path: &str) -> Result<Vec<Parameter>> {
         let mut parameters = Vec::new();
-        if ssm_path.starts_with("/") {
+        // A version selector refers to a single parameter, not a path.
+        let (_, version) = split_version_selector(ssm_path);
+        if ssm_path.starts_with("/") && version.is_none() {
             parameters = self.get_parameters_by_path(ssm_path)?;
         }
         if parameters.is_empty() {
@@ -110,6 +115,18 @@ impl SsmClient {
     }
 }
 
+// Split a parameter name with a version selector, such as /app/config:3, into the
+// name and the version.
+pub fn split_version_selector(ssm_path: &str) -> (&str, Option<u64>) {
+    match ssm_path.rsplit_once(':') {
+        Some((name, version)) => match version.parse::<u64>() {
+            Ok(version) => (name, Some(version)),
+            Err(_) => (ssm_path, None),
+        },
+        None => (ssm_path, None),
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct SsmParameterValue {
     pub name: String,
@@ -134,3 +151,39 @@ impl Writable for SsmParameterValue {
         &self.name
     }
 }
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_split_version_selector() {
+        struct Case<'a> {
+            ssm_path: &'a str,
+            expected: (&'a str, Option<u64>),
+        }
+        let cases = [
+            Case {
+                ssm_path: "/app/config",
+                expected: ("/app/config", None),
+            },
+            Case {
+                ssm_path: "/app/config:3",
+                expected: ("/app/config", Some(3)),
+            },
+            Case {
+                ssm_path: "config:12",
+                expected: ("config", Some(12)),
+            },
+            Case {
+                ssm_path: "/app/config:latest",
+                expected: ("/app/config:latest", None),
+            },
+        ];
+        for case in cases {
+            assert_eq!(split_version_selector(case.ssm_path), case.expected);
+        }
+    }
+}
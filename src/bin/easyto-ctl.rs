@@ -0,0 +1,73 @@
+use std::{env, process::exit};
+
+use anyhow::{anyhow, Result};
+use easyto_init::{
+    constants,
+    control::{self, Request, ServiceState},
+};
+
+const USAGE: &str = "Usage: easyto-ctl <command>
+
+Commands:
+  list               List services and their states
+  status <service>   Show the state of a service
+  start <service>    Start a stopped service
+  stop <service>     Stop a service so it is not restarted
+  restart <service>  Restart a service
+  reboot             Stop all processes and reboot
+  shutdown           Stop all processes and power off";
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let request = parse_request(args)?;
+    let response = control::request(constants::FILE_CONTROL_SOCKET, &request)?;
+    if let Some(error) = response.error {
+        return Err(anyhow!("{}", error));
+    }
+    match request {
+        Request::Reboot => {
+            println!("Rebooting");
+            return Ok(());
+        }
+        Request::Shutdown => {
+            println!("Shutting down");
+            return Ok(());
+        }
+        _ => (),
+    }
+    println!("{:<24} {:<10} {:<10} RESTARTS", "NAME", "STATE", "PID");
+    for status in response.services {
+        let state = match status.state {
+            ServiceState::Running => "running",
+            ServiceState::Starting => "starting",
+            ServiceState::Stopped => "stopped",
+        };
+        let pid = status.pid.map(|pid| pid.to_string()).unwrap_or("-".into());
+        println!(
+            "{:<24} {:<10} {:<10} {}",
+            status.name, state, pid, status.restarts
+        );
+    }
+    Ok(())
+}
+
+fn parse_request(args: &[String]) -> Result<Request> {
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    match args.as_slice() {
+        ["list"] => Ok(Request::List),
+        ["reboot"] => Ok(Request::Reboot),
+        ["shutdown"] => Ok(Request::Shutdown),
+        ["restart", service] => Ok(Request::Restart(service.to_string())),
+        ["start", service] => Ok(Request::Start(service.to_string())),
+        ["status", service] => Ok(Request::Status(service.to_string())),
+        ["stop", service] => Ok(Request::Stop(service.to_string())),
+        _ => Err(anyhow!("{}", USAGE)),
+    }
+}
@@ -1,14 +1,27 @@
 use std::{thread, time::Duration};
 
-use easyto_init::init;
+use easyto_init::{
+    constants, init,
+    service::PowerAction,
+    status::{self, Phase, Status},
+};
 use rustix::system::{reboot, RebootCommand};
 
 fn main() {
-    if let Err(e) = init::initialize() {
-        // Use eprintln! here in case logger does not initialize.
-        eprintln!("Failed to initialize: {}", e);
-    }
+    let command = match init::initialize() {
+        Ok(PowerAction::Kexec) => RebootCommand::Kexec,
+        Ok(PowerAction::Reboot) => RebootCommand::Restart,
+        Ok(PowerAction::Poweroff) => RebootCommand::PowerOff,
+        Err(e) => {
+            // Use eprintln! here in case logger does not initialize.
+            eprintln!("Failed to initialize: {}", e);
+            status::record_error(format!("unable to initialize: {}", e));
+            // The status directory may not be mounted if init failed early.
+            let _ = Status::new(Phase::Failed).write(constants::FILE_STATUS);
+            RebootCommand::PowerOff
+        }
+    };
     // Sleep to let console output catch up.
     thread::sleep(Duration::from_secs(1));
-    let _ = reboot(RebootCommand::PowerOff);
+    let _ = reboot(command);
 }
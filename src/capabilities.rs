@@ -0,0 +1,202 @@
+use anyhow::{anyhow, Result};
+use rustix::io::{self, Errno};
+use rustix::thread::{
+    capabilities, configure_capability_in_ambient_set, remove_capability_from_bounding_set,
+    set_capabilities, set_keep_capabilities, Capability, CapabilityFlags, CapabilitySets,
+};
+
+const CAPABILITIES: [(&str, Capability); 41] = [
+    ("AUDIT_CONTROL", Capability::AuditControl),
+    ("AUDIT_READ", Capability::AuditRead),
+    ("AUDIT_WRITE", Capability::AuditWrite),
+    ("BLOCK_SUSPEND", Capability::BlockSuspend),
+    ("BPF", Capability::BerkeleyPacketFilters),
+    ("CHECKPOINT_RESTORE", Capability::CheckpointRestore),
+    ("CHOWN", Capability::ChangeOwnership),
+    ("DAC_OVERRIDE", Capability::DACOverride),
+    ("DAC_READ_SEARCH", Capability::DACReadSearch),
+    ("FOWNER", Capability::FileOwner),
+    ("FSETID", Capability::FileSetID),
+    ("IPC_LOCK", Capability::IPCLock),
+    ("IPC_OWNER", Capability::IPCOwner),
+    ("KILL", Capability::Kill),
+    ("LEASE", Capability::Lease),
+    ("LINUX_IMMUTABLE", Capability::LinuxImmutable),
+    ("MAC_ADMIN", Capability::MACAdmin),
+    ("MAC_OVERRIDE", Capability::MACOverride),
+    ("MKNOD", Capability::MakeNode),
+    ("NET_ADMIN", Capability::NetAdmin),
+    ("NET_BIND_SERVICE", Capability::NetBindService),
+    ("NET_BROADCAST", Capability::NetBroadcast),
+    ("NET_RAW", Capability::NetRaw),
+    ("PERFMON", Capability::PerformanceMonitoring),
+    ("SETFCAP", Capability::SetFileCapabilities),
+    ("SETGID", Capability::SetGroupID),
+    ("SETPCAP", Capability::SetPermittedCapabilities),
+    ("SETUID", Capability::SetUserID),
+    ("SYSLOG", Capability::SystemLog),
+    ("SYS_ADMIN", Capability::SystemAdmin),
+    ("SYS_BOOT", Capability::SystemBoot),
+    ("SYS_CHROOT", Capability::SystemChangeRoot),
+    ("SYS_MODULE", Capability::SystemModule),
+    ("SYS_NICE", Capability::SystemNice),
+    ("SYS_PACCT", Capability::SystemProcessAccounting),
+    ("SYS_PTRACE", Capability::SystemProcessTrace),
+    ("SYS_RAWIO", Capability::SystemRawIO),
+    ("SYS_RESOURCE", Capability::SystemResource),
+    ("SYS_TIME", Capability::SystemTime),
+    ("SYS_TTY_CONFIG", Capability::SystemTTYConfig),
+    ("WAKE_ALARM", Capability::WakeAlarm),
+];
+
+// Parse a capability name, with or without the CAP_ prefix, in any case.
+pub fn parse_capability(name: &str) -> Result<Capability> {
+    let upper = name.to_uppercase();
+    let short = upper.strip_prefix("CAP_").unwrap_or(&upper);
+    CAPABILITIES
+        .iter()
+        .find(|(cap_name, _)| *cap_name == short)
+        .map(|(_, cap)| *cap)
+        .ok_or_else(|| anyhow!("unknown capability {}", name))
+}
+
+fn capability_flag(cap: Capability) -> CapabilityFlags {
+    CapabilityFlags::from_bits_retain(1 << cap as u32)
+}
+
+// The changes to make to a process's capabilities before it executes its command.
+// Capabilities not retained are removed from the bounding set, so they cannot be
+// regained. A root process keeps all retained capabilities, while a non-root process
+// only keeps the ones explicitly added, which are raised in its ambient set so they
+// survive execve.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapabilityPlan {
+    ambient: Vec<Capability>,
+    bounding_drop: Vec<Capability>,
+    sets: CapabilityFlags,
+}
+
+impl CapabilityPlan {
+    pub fn new(add: &[String], drop: &[String], uid: u32) -> Result<Self> {
+        let drop_all = drop.iter().any(|name| name.eq_ignore_ascii_case("ALL"));
+        let dropped = drop
+            .iter()
+            .filter(|name| !name.eq_ignore_ascii_case("ALL"))
+            .map(|name| parse_capability(name))
+            .collect::<Result<Vec<Capability>>>()?;
+        let added = add
+            .iter()
+            .map(|name| parse_capability(name))
+            .collect::<Result<Vec<Capability>>>()?;
+
+        let mut retained = Vec::new();
+        let mut bounding_drop = Vec::new();
+        for (_, cap) in CAPABILITIES {
+            if added.contains(&cap) || !(drop_all || dropped.contains(&cap)) {
+                retained.push(cap);
+            } else {
+                bounding_drop.push(cap);
+            }
+        }
+
+        let ambient = if uid == 0 { Vec::new() } else { added };
+        let kept = if uid == 0 { &retained } else { &ambient };
+        let sets = kept.iter().fold(CapabilityFlags::empty(), |flags, cap| {
+            flags | capability_flag(*cap)
+        });
+
+        Ok(Self {
+            ambient,
+            bounding_drop,
+            sets,
+        })
+    }
+
+    // Call while still privileged, before changing the user. This is safe to call
+    // between fork and exec, as it only makes system calls.
+    pub fn apply_before_setuid(&self) -> io::Result<()> {
+        for cap in &self.bounding_drop {
+            match remove_capability_from_bounding_set(*cap) {
+                // The capability is not known to the running kernel.
+                Ok(_) | Err(Errno::INVAL) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !self.ambient.is_empty() {
+            set_keep_capabilities(true)?;
+        }
+        Ok(())
+    }
+
+    // Call after changing the user. Capabilities the kernel does not know about are
+    // not in the permitted set, and cannot be added to it.
+    pub fn apply_after_setuid(&self) -> io::Result<()> {
+        let sets = capabilities(None)?.permitted & self.sets;
+        set_capabilities(
+            None,
+            CapabilitySets {
+                effective: sets,
+                permitted: sets,
+                inheritable: sets,
+            },
+        )?;
+        for cap in &self.ambient {
+            configure_capability_in_ambient_set(*cap, true)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_capability() {
+        struct Case {
+            input: &'static str,
+            expected: Option<Capability>,
+        }
+        let cases = [
+            Case {
+                input: "CAP_NET_BIND_SERVICE",
+                expected: Some(Capability::NetBindService),
+            },
+            Case {
+                input: "net_admin",
+                expected: Some(Capability::NetAdmin),
+            },
+            Case {
+                input: "SYS_ADMIN",
+                expected: Some(Capability::SystemAdmin),
+            },
+            Case {
+                input: "CAP_NOT_REAL",
+                expected: None,
+            },
+        ];
+        for case in cases {
+            assert_eq!(parse_capability(case.input).ok(), case.expected);
+        }
+    }
+
+    #[test]
+    fn test_capability_plan() {
+        let plan =
+            CapabilityPlan::new(&["CAP_NET_BIND_SERVICE".into()], &["ALL".into()], 1000).unwrap();
+        assert_eq!(plan.ambient, vec![Capability::NetBindService]);
+        assert_eq!(plan.bounding_drop.len(), CAPABILITIES.len() - 1);
+        assert_eq!(plan.sets, CapabilityFlags::NET_BIND_SERVICE);
+
+        let plan = CapabilityPlan::new(&[], &["SYS_ADMIN".into(), "NET_RAW".into()], 0).unwrap();
+        assert_eq!(plan.ambient, Vec::new());
+        assert_eq!(
+            plan.bounding_drop,
+            vec![Capability::NetRaw, Capability::SystemAdmin]
+        );
+        assert!(!plan.sets.contains(CapabilityFlags::SYS_ADMIN));
+        assert!(plan.sets.contains(CapabilityFlags::NET_BIND_SERVICE));
+    }
+}
@@ -0,0 +1,211 @@
+use std::fs::File;
+use std::io::BufReader;
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+use crate::constants;
+use crate::login::user_group_id;
+use crate::vmspec::{InitScript, UserData, UserGroupNames, WriteFile};
+
+const CLOUD_CONFIG_HEADER: &str = "#cloud-config";
+
+// A minimal subset of a cloud-init cloud-config document, translated into the
+// equivalent easyto user data to ease migration from images using cloud-init.
+#[derive(Debug, Default, Deserialize)]
+pub struct CloudConfig {
+    bootcmd: Option<Vec<CloudConfigCommand>>,
+    runcmd: Option<Vec<CloudConfigCommand>>,
+    write_files: Option<Vec<CloudConfigWriteFile>>,
+}
+
+// Commands are either a string run by the shell or a list of arguments.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum CloudConfigCommand {
+    Shell(String),
+    Exec(Vec<String>),
+}
+
+impl CloudConfigCommand {
+    fn to_shell(&self) -> String {
+        match self {
+            Self::Shell(command) => command.clone(),
+            Self::Exec(args) => args
+                .iter()
+                .map(|arg| shell_quote(arg))
+                .collect::<Vec<String>>()
+                .join(" "),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CloudConfigWriteFile {
+    content: Option<String>,
+    encoding: Option<String>,
+    owner: Option<String>,
+    path: String,
+    permissions: Option<String>,
+}
+
+impl CloudConfigWriteFile {
+    fn to_write_file(&self) -> Result<WriteFile> {
+        let base64_encoded = match self.encoding.as_deref() {
+            None | Some("text/plain") => false,
+            Some("b64") | Some("base64") => true,
+            Some(encoding) => {
+                return Err(anyhow!(
+                    "unsupported encoding {} for file {}",
+                    encoding,
+                    self.path
+                ))
+            }
+        };
+        let (user_id, group_id) = match &self.owner {
+            Some(owner) => {
+                let names: UserGroupNames = owner.clone().try_into()?;
+                let fp = File::open(constants::FILE_ETC_PASSWD)?;
+                let uid = user_group_id(BufReader::new(fp), &names.user)?;
+                let gid = match names.group {
+                    Some(group) => {
+                        let fg = File::open(constants::FILE_ETC_GROUP)?;
+                        Some(user_group_id(BufReader::new(fg), &group)?)
+                    }
+                    None => None,
+                };
+                (Some(uid), gid)
+            }
+            None => (None, None),
+        };
+        Ok(WriteFile {
+            base64_encoded: Some(base64_encoded),
+            content: self.content.clone().unwrap_or_default(),
+            group_id,
+            mode: self.permissions.clone(),
+            path: self.path.clone(),
+            user_id,
+        })
+    }
+}
+
+impl CloudConfig {
+    pub fn from_string(user_data: &str) -> Result<Self> {
+        serde_yml::from_str::<CloudConfig>(user_data)
+            .map_err(|e| anyhow!("unable to parse cloud-config user data: {}", e))
+    }
+
+    // Convert to user data, where bootcmd and runcmd become init scripts that
+    // run in that order, and write_files become write-files.
+    pub fn to_user_data(&self) -> Result<UserData> {
+        let mut init_scripts = Vec::new();
+        if let Some(bootcmd) = &self.bootcmd {
+            init_scripts.push(InitScript::from(commands_to_script(bootcmd)));
+        }
+        if let Some(runcmd) = &self.runcmd {
+            init_scripts.push(InitScript::from(commands_to_script(runcmd)));
+        }
+
+        let write_files = match &self.write_files {
+            Some(write_files) => Some(
+                write_files
+                    .iter()
+                    .map(|wf| wf.to_write_file())
+                    .collect::<Result<Vec<WriteFile>>>()?,
+            ),
+            None => None,
+        };
+
+        Ok(UserData {
+            init_scripts: if init_scripts.is_empty() {
+                None
+            } else {
+                Some(init_scripts)
+            },
+            write_files,
+            ..Default::default()
+        })
+    }
+}
+
+pub fn is_cloud_config(user_data: &str) -> bool {
+    user_data.starts_with(CLOUD_CONFIG_HEADER)
+}
+
+fn commands_to_script(commands: &[CloudConfigCommand]) -> String {
+    let mut script = format!("#!{}/sh\n", constants::DIR_ET_BIN);
+    for command in commands {
+        script.push_str(&command.to_shell());
+        script.push('\n');
+    }
+    script
+}
+
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_is_cloud_config() {
+        assert_eq!(is_cloud_config("#cloud-config\nruncmd: []\n"), true);
+        assert_eq!(is_cloud_config("command: [/bin/true]\n"), false);
+        assert_eq!(is_cloud_config(""), false);
+    }
+
+    #[test]
+    fn test_cloud_config_to_user_data() {
+        let cloud_config = CloudConfig::from_string(
+            r#"#cloud-config
+bootcmd:
+  - echo boot
+runcmd:
+  - [echo, "it's", two words]
+  - echo run
+write_files:
+  - path: /etc/app.conf
+    content: a=b
+    permissions: "0600"
+  - path: /etc/app.bin
+    content: YWJj
+    encoding: b64
+"#,
+        )
+        .unwrap();
+        let user_data = cloud_config.to_user_data().unwrap();
+        assert_eq!(
+            Some(vec![
+                InitScript::from("#!/.easyto/bin/sh\necho boot\n".to_string()),
+                InitScript::from(
+                    "#!/.easyto/bin/sh\n'echo' 'it'\\''s' 'two words'\necho run\n".to_string()
+                ),
+            ]),
+            user_data.init_scripts
+        );
+        let write_files = user_data.write_files.unwrap();
+        assert_eq!(2, write_files.len());
+        assert_eq!("/etc/app.conf", write_files[0].path);
+        assert_eq!(Some(false), write_files[0].base64_encoded);
+        assert_eq!(Some("0600".to_string()), write_files[0].mode);
+        assert_eq!(Some(true), write_files[1].base64_encoded);
+    }
+
+    #[test]
+    fn test_cloud_config_unsupported_encoding() {
+        let cloud_config = CloudConfig::from_string(
+            r#"#cloud-config
+write_files:
+  - path: /etc/app.conf
+    content: abc
+    encoding: gzip
+"#,
+        )
+        .unwrap();
+        assert!(cloud_config.to_user_data().is_err());
+    }
+}
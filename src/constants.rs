@@ -1,5 +1,6 @@
 pub const DIR_DEV: &str = "/dev";
 pub const DIR_DEV_HUGEPAGES: &str = "/dev/hugepages";
+pub const DIR_DEV_INPUT: &str = "/dev/input";
 pub const DIR_DEV_MQUEUE: &str = "/dev/mqueue";
 pub const DIR_DEV_PTS: &str = "/dev/pts";
 pub const DIR_DEV_SHM: &str = "/dev/shm";
@@ -10,16 +11,38 @@ pub const DIR_ET_HOME: &str = "/.easyto/home";
 pub const DIR_ET_RUN: &str = "/.easyto/run";
 pub const DIR_ET_SBIN: &str = "/.easyto/sbin";
 pub const DIR_ET_SERVICES: &str = "/.easyto/services";
+pub const DIR_ET_VAR: &str = "/.easyto/var";
+pub const DIR_INSTANCE_STORE: &str = "/mnt/instance-store";
+pub const DIR_LIB_MODULES: &str = "/lib/modules";
 pub const DIR_PROC: &str = "/proc";
 pub const DIR_ROOT: &str = "/";
 pub const DIR_ROOT_HOME: &str = "/root";
+pub const DIR_SSM_AGENT_LOG: &str = "/var/log/amazon/ssm";
+pub const DIR_SSM_AGENT_STATE: &str = "/var/lib/amazon/ssm";
 pub const DIR_SYS: &str = "/sys";
+pub const DIR_SYS_CLASS_INPUT: &str = "/sys/class/input";
+pub const DIR_SYS_CLASS_NET: &str = "/sys/class/net";
+pub const DIR_SYS_CLASS_PTP: &str = "/sys/class/ptp";
 pub const DIR_SYS_FS_CGROUP: &str = "/sys/fs/cgroup";
 pub const DIR_SYS_KERNEL_DEBUG: &str = "/sys/kernel/debug";
+pub const DIR_SYS_KERNEL_MM_HUGEPAGES: &str = "/sys/kernel/mm/hugepages";
 
+pub const FILE_BOOT_KERNEL: &str = "/boot/vmlinuz";
+pub const FILE_CONTROL_SOCKET: &str = "/.easyto/run/control.sock";
+pub const FILE_CRASH_MARKER: &str = "crash-marker";
+pub const FILE_DEV_KMSG: &str = "/dev/kmsg";
+pub const FILE_DEV_LOG: &str = "/dev/log";
+pub const FILE_DEV_WATCHDOG: &str = "/dev/watchdog";
 pub const FILE_ETC_GROUP: &str = "/etc/group";
+pub const FILE_ETC_MACHINE_ID: &str = "/etc/machine-id";
 pub const FILE_ETC_PASSWD: &str = "/etc/passwd";
+pub const FILE_ETC_SHADOW: &str = "/etc/shadow";
+pub const FILE_FAILED_BOOTS: &str = "failed-boots";
+pub const FILE_MACHINE_ID: &str = "machine-id";
 pub const FILE_METADATA: &str = "metadata.json";
+pub const FILE_PROC_RANDOM_UUID: &str = "/proc/sys/kernel/random/uuid";
+pub const FILE_PROC_SELF_OOM_SCORE_ADJ: &str = "/proc/self/oom_score_adj";
+pub const FILE_STATUS: &str = "/.easyto/run/status.json";
 
 pub const GROUP_NAME_WHEEL: &str = "wheel";
 
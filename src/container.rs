@@ -13,8 +13,26 @@ pub struct Config {
     pub entrypoint: Option<Vec<String>>,
     #[serde(rename = "Env")]
     pub env: Option<Vec<String>>,
+    #[serde(rename = "Healthcheck")]
+    pub healthcheck: Option<HealthConfig>,
     #[serde(rename = "User")]
     pub user: Option<String>,
     #[serde(rename = "WorkingDir")]
     pub working_dir: Option<String>,
 }
+
+// The HEALTHCHECK of an image. Durations are in nanoseconds, with zero meaning the
+// default, and a test of ["NONE"] disables a health check from a base image.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct HealthConfig {
+    #[serde(rename = "Test")]
+    pub test: Option<Vec<String>>,
+    #[serde(rename = "Interval")]
+    pub interval: Option<u64>,
+    #[serde(rename = "Timeout")]
+    pub timeout: Option<u64>,
+    #[serde(rename = "StartPeriod")]
+    pub start_period: Option<u64>,
+    #[serde(rename = "Retries")]
+    pub retries: Option<u32>,
+}
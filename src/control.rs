@@ -0,0 +1,195 @@
+use std::{
+    fs::remove_file,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    net::Shutdown,
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    sync::Arc,
+    thread,
+};
+
+use anyhow::{anyhow, Result};
+use log::error;
+use rustix::fs::{chmod, Mode};
+use serde::{Deserialize, Serialize};
+
+use crate::resources::ResourceUsage;
+
+// A command sent to the supervisor, as a single line of JSON such as
+// {"command":"restart","service":"chrony"}.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "command", content = "service")]
+pub enum Request {
+    List,
+    Reboot,
+    Restart(String),
+    Shutdown,
+    Start(String),
+    Status(String),
+    Stop(String),
+}
+
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Response {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<ServiceStatus>,
+}
+
+impl Response {
+    pub fn error(message: String) -> Self {
+        Self {
+            error: Some(message),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ServiceStatus {
+    // The number of times the process was detected in a crash loop.
+    pub crash_loops: u32,
+    // The number of times the process failed to start or exited with an error.
+    pub failures: u32,
+    pub name: String,
+    pub pid: Option<u32>,
+    // Whether the process is running and its readiness probe, if any, succeeded.
+    pub ready: bool,
+    // The most recent sample of the resources used by the process while it runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resources: Option<ResourceUsage>,
+    pub restarts: u32,
+    pub state: ServiceState,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServiceState {
+    // The process is running.
+    Running,
+    // The process is supervised, but is waiting to be started or restarted.
+    Starting,
+    // The process has exited and will not be restarted.
+    Stopped,
+}
+
+// A listener for commands sent by easyto-ctl.
+pub struct ControlSocket {
+    listener: UnixListener,
+}
+
+impl ControlSocket {
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        match remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(anyhow!("unable to remove {:?}: {}", path, e));
+            }
+            _ => (),
+        }
+        let listener =
+            UnixListener::bind(path).map_err(|e| anyhow!("unable to bind {:?}: {}", path, e))?;
+        // Only root may control the supervisor.
+        chmod(path, Mode::from(0o600))
+            .map_err(|e| anyhow!("unable to change permissions on {:?}: {}", path, e))?;
+        Ok(Self { listener })
+    }
+
+    // Accept connections for the life of the system, answering a single request on
+    // each. Requests are handled in their own threads, as some wait for processes.
+    pub fn serve<F>(&self, handler: F)
+    where
+        F: Fn(Request) -> Response + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Unable to accept control connection: {}", e);
+                    continue;
+                }
+            };
+            let handler = handler.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle_connection(stream, handler.as_ref()) {
+                    error!("Unable to handle control request: {}", e);
+                }
+            });
+        }
+    }
+}
+
+fn handle_connection<F>(mut stream: UnixStream, handler: &F) -> Result<()>
+where
+    F: Fn(Request) -> Response,
+{
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let response = match serde_json::from_str(&line) {
+        Ok(request) => handler(request),
+        Err(e) => Response::error(format!("invalid request: {}", e)),
+    };
+    write_message(&mut stream, &response)
+}
+
+// Send a request to the control socket and wait for its response.
+pub fn request<P: AsRef<Path>>(path: P, request: &Request) -> Result<Response> {
+    let path = path.as_ref();
+    let mut stream =
+        UnixStream::connect(path).map_err(|e| anyhow!("unable to connect to {:?}: {}", path, e))?;
+    write_message(&mut stream, request)?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    serde_json::from_str(&line).map_err(|e| anyhow!("invalid response: {}", e))
+}
+
+fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> Result<()> {
+    let mut buf = serde_json::to_vec(message)?;
+    buf.push(b'\n');
+    writer.write_all(&buf)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_request_serialize() {
+        struct Case {
+            request: Request,
+            expected: &'static str,
+        }
+        let cases = [
+            Case {
+                request: Request::List,
+                expected: r#"{"command":"list"}"#,
+            },
+            Case {
+                request: Request::Restart("chrony".into()),
+                expected: r#"{"command":"restart","service":"chrony"}"#,
+            },
+            Case {
+                request: Request::Reboot,
+                expected: r#"{"command":"reboot"}"#,
+            },
+            Case {
+                request: Request::Shutdown,
+                expected: r#"{"command":"shutdown"}"#,
+            },
+        ];
+        for case in cases {
+            let serialized = serde_json::to_string(&case.request).unwrap();
+            assert_eq!(serialized, case.expected);
+            let deserialized: Request = serde_json::from_str(&serialized).unwrap();
+            assert_eq!(deserialized, case.request);
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    ffi::c_char,
+    fs::{read_dir, read_to_string},
+    io::Write,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use flate2::{write::GzEncoder, Compression};
+
+use crate::constants;
+
+// Actions of syslog(2) to read the kernel log buffer without clearing it.
+const SYSLOG_ACTION_READ_ALL: libc::c_int = 3;
+const SYSLOG_ACTION_SIZE_BUFFER: libc::c_int = 10;
+
+// A file in a diagnostics bundle.
+pub struct Entry {
+    pub name: String,
+    pub contents: Vec<u8>,
+}
+
+impl Entry {
+    pub fn new<S: Into<String>, B: Into<Vec<u8>>>(name: S, contents: B) -> Self {
+        Self {
+            name: name.into(),
+            contents: contents.into(),
+        }
+    }
+
+    // An entry from the result of gathering its contents, holding the error instead
+    // if it could not be gathered, so one missing source does not lose the others.
+    fn gathered<S: Into<String>>(name: S, contents: Result<String>) -> Self {
+        let contents = contents.unwrap_or_else(|e| format!("{}\n", e));
+        Self::new(name, contents)
+    }
+}
+
+// Gather the state of the system that is useful for debugging a failed boot: the
+// kernel log, the mounted filesystems, and the state of the network interfaces.
+pub fn system_entries() -> Vec<Entry> {
+    let mounts = Path::new(constants::DIR_PROC).join("mounts");
+    let routes = Path::new(constants::DIR_PROC).join("net/route");
+    vec![
+        Entry::gathered("dmesg.log", read_kernel_log()),
+        Entry::gathered("mounts", read_file(&mounts)),
+        Entry::gathered(
+            "interfaces",
+            interface_state(Path::new(constants::DIR_SYS_CLASS_NET)),
+        ),
+        Entry::gathered("routes", read_file(&routes)),
+    ]
+}
+
+// Write the entries to a gzipped tarball.
+pub fn bundle(entries: &[Entry]) -> Result<Vec<u8>> {
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    for entry in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(entry.contents.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(mtime);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, &entry.name, entry.contents.as_slice())
+            .map_err(|e| anyhow!("unable to add {} to diagnostics bundle: {}", entry.name, e))?;
+    }
+    let mut encoder = builder
+        .into_inner()
+        .map_err(|e| anyhow!("unable to write diagnostics bundle: {}", e))?;
+    encoder.flush()?;
+    encoder
+        .finish()
+        .map_err(|e| anyhow!("unable to compress diagnostics bundle: {}", e))
+}
+
+// Get at most the last n lines of text.
+pub fn last_lines(text: &str, n: usize) -> &str {
+    let skip = text.lines().count().saturating_sub(n);
+    if skip == 0 {
+        return text;
+    }
+    text.match_indices('\n')
+        .nth(skip - 1)
+        .map_or("", |(i, _)| &text[i + 1..])
+}
+
+fn read_file(path: &Path) -> Result<String> {
+    read_to_string(path).map_err(|e| anyhow!("unable to read {:?}: {}", path, e))
+}
+
+// Read the kernel log buffer, as shown by dmesg.
+fn read_kernel_log() -> Result<String> {
+    let size = unsafe { libc::klogctl(SYSLOG_ACTION_SIZE_BUFFER, std::ptr::null_mut(), 0) };
+    if size < 0 {
+        return Err(anyhow!(
+            "unable to get size of kernel log: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    let mut buf = vec![0u8; size as usize];
+    let n = unsafe {
+        libc::klogctl(
+            SYSLOG_ACTION_READ_ALL,
+            buf.as_mut_ptr() as *mut c_char,
+            size,
+        )
+    };
+    if n < 0 {
+        return Err(anyhow!(
+            "unable to read kernel log: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    buf.truncate(n as usize);
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+// Describe each network interface on a line, from its attributes in sys_class_net.
+fn interface_state(sys_class_net: &Path) -> Result<String> {
+    let mut names = read_dir(sys_class_net)
+        .map_err(|e| anyhow!("unable to read {:?}: {}", sys_class_net, e))?
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    names.sort();
+    let mut state = String::new();
+    for name in names {
+        let dir = sys_class_net.join(&name);
+        let attribute = |attribute: &str| {
+            read_to_string(dir.join(attribute))
+                .map(|value| value.trim().to_string())
+                .unwrap_or_else(|_| "unknown".into())
+        };
+        state.push_str(&format!(
+            "{} state {} carrier {} mtu {} address {}\n",
+            name,
+            attribute("operstate"),
+            attribute("carrier"),
+            attribute("mtu"),
+            attribute("address")
+        ));
+    }
+    Ok(state)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io::Read};
+
+    use flate2::read::GzDecoder;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_last_lines() {
+        struct Case {
+            text: &'static str,
+            n: usize,
+            expected: &'static str,
+        }
+        let cases = [
+            Case {
+                text: "one\ntwo\nthree\n",
+                n: 2,
+                expected: "two\nthree\n",
+            },
+            Case {
+                text: "one\ntwo\nthree\n",
+                n: 3,
+                expected: "one\ntwo\nthree\n",
+            },
+            Case {
+                text: "one\ntwo\nthree\n",
+                n: 10,
+                expected: "one\ntwo\nthree\n",
+            },
+            Case {
+                text: "one\ntwo\nthree",
+                n: 1,
+                expected: "three",
+            },
+            Case {
+                text: "one\ntwo\n",
+                n: 0,
+                expected: "",
+            },
+            Case {
+                text: "",
+                n: 5,
+                expected: "",
+            },
+        ];
+        for case in cases {
+            assert_eq!(
+                last_lines(case.text, case.n),
+                case.expected,
+                "{:?}",
+                case.text
+            );
+        }
+    }
+
+    #[test]
+    fn test_interface_state() {
+        let dir = std::env::temp_dir().join(format!("sys-class-net-{}", std::process::id()));
+        for (name, operstate, mtu) in [("lo", "unknown", "65536"), ("ens5", "up", "9001")] {
+            let iface_dir = dir.join(name);
+            fs::create_dir_all(&iface_dir).unwrap();
+            fs::write(iface_dir.join("operstate"), format!("{}\n", operstate)).unwrap();
+            fs::write(iface_dir.join("mtu"), format!("{}\n", mtu)).unwrap();
+            fs::write(iface_dir.join("carrier"), "1\n").unwrap();
+        }
+        let state = interface_state(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            state.unwrap(),
+            "ens5 state up carrier 1 mtu 9001 address unknown\n\
+             lo state unknown carrier 1 mtu 65536 address unknown\n"
+        );
+    }
+
+    #[test]
+    fn test_bundle() {
+        let entries = [
+            Entry::new("boot.log", "Failed to initialize\n"),
+            Entry::gathered("mounts", Err(anyhow!("unable to read mounts"))),
+        ];
+        let bundle = bundle(&entries).unwrap();
+        let mut archive = tar::Archive::new(GzDecoder::new(bundle.as_slice()));
+        let files = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents).unwrap();
+                (entry.path().unwrap().display().to_string(), contents)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            vec![
+                ("boot.log".to_string(), "Failed to initialize\n".to_string()),
+                ("mounts".to_string(), "unable to read mounts\n".to_string()),
+            ]
+        );
+    }
+}
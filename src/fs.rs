@@ -1,15 +1,18 @@
 use std::{
-    fs::create_dir,
+    fs::{create_dir, File},
     path::{Path, PathBuf, MAIN_SEPARATOR_STR},
 };
 
 use anyhow::{anyhow, Result};
-use log::debug;
+use log::{debug, error};
 use rustix::{
-    fs::{chmod, chown, Gid, Mode, MountFlags, Uid},
+    fs::{chmod, chown, remount, unmount, Gid, Mode, MountFlags, Uid, UnmountFlags},
+    ioctl::{ioctl, ReadWriteOpcode, Updater},
     mount::mount,
 };
 
+use crate::constants;
+
 #[derive(Debug)]
 pub struct Link<'a> {
     pub path: &'a str,
@@ -42,6 +45,61 @@ impl<'a> Mount<'a> {
     }
 }
 
+// Split mount options as given to mount(8) into flags and a data string for the
+// filesystem. Options that only restate the defaults are dropped.
+pub fn parse_mount_options(options: &[String]) -> (MountFlags, String) {
+    let mut flags = MountFlags::empty();
+    let mut data = Vec::new();
+    for option in options {
+        match option.as_str() {
+            "async" | "atime" | "defaults" | "dev" | "exec" | "rw" | "suid" => (),
+            "dirsync" => flags |= MountFlags::DIRSYNC,
+            "lazytime" => flags |= MountFlags::LAZYTIME,
+            "noatime" => flags |= MountFlags::NOATIME,
+            "nodev" => flags |= MountFlags::NODEV,
+            "nodiratime" => flags |= MountFlags::NODIRATIME,
+            "noexec" => flags |= MountFlags::NOEXEC,
+            "nosuid" => flags |= MountFlags::NOSUID,
+            "relatime" => flags |= MountFlags::RELATIME,
+            "ro" => flags |= MountFlags::RDONLY,
+            "strictatime" => flags |= MountFlags::STRICTATIME,
+            "sync" => flags |= MountFlags::SYNCHRONOUS,
+            _ => data.push(option.as_str()),
+        }
+    }
+    (flags, data.join(","))
+}
+
+// Argument of the FITRIM ioctl, from include/uapi/linux/fs.h in kernel source.
+#[repr(C)]
+struct FstrimRange {
+    start: u64,
+    len: u64,
+    minlen: u64,
+}
+
+type FitrimOpcode = ReadWriteOpcode<b'X', 121, FstrimRange>;
+
+// Discard unused blocks of the filesystem mounted at path, returning the number of
+// bytes trimmed.
+pub fn fstrim<P: AsRef<Path>>(path: P) -> Result<u64> {
+    let dir =
+        File::open(&path).map_err(|e| anyhow!("unable to open {:?}: {}", path.as_ref(), e))?;
+    let mut range = FstrimRange {
+        start: 0,
+        len: u64::MAX,
+        minlen: 0,
+    };
+    unsafe { ioctl(&dir, Updater::<FitrimOpcode, FstrimRange>::new(&mut range)) }
+        .map_err(|e| anyhow!("unable to trim {:?}: {}", path.as_ref(), e))?;
+    Ok(range.len)
+}
+
+pub fn parse_mode(mode: &str) -> Result<Mode> {
+    let m = u32::from_str_radix(mode, 8)?;
+    Ok(Mode::from(m))
+}
+
 pub fn mkdir_p<P: AsRef<Path>>(path: P, mode: Mode) -> Result<()> {
     mkdir_p_own(path, mode, None, None)
 }
@@ -68,6 +126,34 @@ pub fn mkdir_p_own<P: AsRef<Path>>(
     Ok(())
 }
 
+pub fn unmount_all(mount_points: &[String]) -> Result<()> {
+    let mut error_count = 0;
+
+    if let Err(e) = remount(constants::DIR_ROOT, MountFlags::RDONLY, "") {
+        error_count += 1;
+        error!(
+            "unable to remount {} as read-only: {}",
+            constants::DIR_ROOT,
+            e
+        );
+    }
+
+    for mount_point in mount_points {
+        if let Err(e) = unmount(mount_point, UnmountFlags::empty()) {
+            error_count += 1;
+            error!("unable to unmount {}: {}", mount_point, e);
+        }
+    }
+
+    if error_count == mount_points.len() + 1 {
+        // Only return an error if all unmounts failed so we can wait
+        // for those that did not fail.
+        return Err(anyhow!("unable to unmount filesystems"));
+    }
+
+    Ok(())
+}
+
 // Given a path, return a list of it and its parents in descending order.
 // For example, "/a/b/c", returns the Vector ["/a", "/a/b", "/a/b/c"].
 fn descending_dirs(path: &str) -> Vec<String> {
@@ -103,6 +189,40 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_parse_mount_options() {
+        struct Case {
+            options: Vec<String>,
+            expected: (MountFlags, String),
+        }
+        let cases = [
+            Case {
+                options: vec![],
+                expected: (MountFlags::empty(), "".into()),
+            },
+            Case {
+                options: vec!["defaults".into(), "rw".into()],
+                expected: (MountFlags::empty(), "".into()),
+            },
+            Case {
+                options: vec![
+                    "ro".into(),
+                    "noatime".into(),
+                    "discard".into(),
+                    "nodev".into(),
+                    "data=ordered".into(),
+                ],
+                expected: (
+                    MountFlags::RDONLY | MountFlags::NOATIME | MountFlags::NODEV,
+                    "discard,data=ordered".into(),
+                ),
+            },
+        ];
+        for case in cases {
+            assert_eq!(parse_mount_options(&case.options), case.expected);
+        }
+    }
+
     #[test]
     fn test_descending_dirs() {
         struct Case<'a> {
@@ -147,6 +267,45 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_mode() {
+        struct Case<'a> {
+            err: bool,
+            mode: &'a str,
+            expected: Mode,
+        }
+        let cases = [
+            Case {
+                err: true,
+                mode: "",
+                expected: Mode::from(0),
+            },
+            Case {
+                err: true,
+                mode: "abc",
+                expected: Mode::from(0),
+            },
+            Case {
+                err: false,
+                mode: "0",
+                expected: Mode::from(0),
+            },
+            Case {
+                err: false,
+                mode: "0755",
+                expected: Mode::from(0o755),
+            },
+        ];
+        for case in cases {
+            let mode = parse_mode(case.mode);
+            if case.err {
+                assert_eq!(mode.is_err(), case.err);
+            } else {
+                assert_eq!(case.expected, mode.unwrap());
+            }
+        }
+    }
+
     #[test]
     fn test_path_ext_join_relative() {
         struct Case<'a> {
@@ -1,119 +1,246 @@
 use std::collections::HashMap;
 use std::ffi::{c_char, CStr, CString};
-use std::fs::File;
-use std::io::{BufRead, BufReader, Read};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Result};
 use base64::prelude::*;
-use crossbeam::channel::{bounded, Select};
+use chrono::DateTime;
+use crossbeam::channel::{bounded, unbounded, Select};
 use crossbeam::sync::WaitGroup;
 use k8s_expand::{expand, mapping_func_for};
-use log::{debug, error, info, Level};
-use minaws::imds::{Credentials, Imds};
-use rustix::fs::{chown, remount, stat, symlink, unmount, Gid, Mode, Uid, UnmountFlags};
+use log::{debug, error, info, warn, LevelFilter};
+use minaws::imds::Credentials;
+use rustix::fs::{chmod, chown, remount, stat, symlink, Gid, Mode, OpenOptionsExt, Uid};
 use rustix::io::Errno;
 use rustix::mount::{mount, MountFlags};
-use rustix::process::{chdir, umask};
+use rustix::process::{chdir, setrlimit, umask};
 use rustix::runtime::execve;
-use rustix::thread::{set_thread_gid, set_thread_uid};
+use rustix::thread::{set_no_new_privs, set_thread_gid, set_thread_groups, set_thread_uid};
 
 use crate::aws::asm::AsmClient;
-use crate::aws::s3::S3Client;
+use crate::aws::imds::CachedImds;
+use crate::aws::s3::{parse_s3_url, S3Client, S3Object};
 use crate::aws::ssm::SsmClient;
-use crate::fs::{mkdir_p, Link, Mount};
-use crate::service::Supervisor;
-use crate::system::{device_has_fs, link_nvme_devices, resize_root_volume};
+use crate::aws::LazyCredentials;
+use crate::fs::{
+    fstrim, mkdir_p, parse_mode, parse_mount_options, unmount_all, JoinRelative, Link, Mount,
+};
+use crate::logrotate::RotatingFile;
+use crate::service::{PowerAction, Supervisor};
+use crate::status::{Phase, Status};
+use crate::system::{
+    activate_volume_group, assemble_raid0, check_oom_score_adj, device_has_fs,
+    ensure_logical_volume, find_device_by_label, find_executable_in_path,
+    find_instance_store_devices, link_nvme_devices, partition_device, resize_root_volume,
+    set_oom_score_adj, wait_for_device, wait_for_volume_id, write_machine_id, OOM_SCORE_ADJ_MIN,
+};
 use crate::vmspec::{
-    EbsVolumeSource, EnvFromSources, ImdsEnvSource, NameValue, NameValues, NameValuesExt,
-    S3EnvSource, S3VolumeSource, SecretsManagerEnvSource, SecretsManagerVolumeSource, SsmEnvSource,
-    SsmVolumeSource, UserData, VmSpec,
+    run_scripts, EbsVolumeSource, EnvFileFormat, EnvFromSource, EnvFromSources, FailureReport,
+    FileEnvSource, Fsck, HttpEnvSource, ImdsEnvSource, InstanceStoreVolumeSource,
+    InstanceTagsEnvSource, Kexec, LogLevel, LoginPassword, LvmVolumeSource, NameValue, NameValues,
+    NameValuesExt, OnFailure, Overlay, PrivilegeEscalation, S3EnvSource, S3VolumeSource,
+    SecretsManagerEnvSource, SecretsManagerVolumeSource, SsmEnvSource, SsmVolumeSource, UserData,
+    VmSpec,
 };
 use crate::writable::Writable;
-use crate::{constants, container};
+use crate::{constants, container, diagnostics, kexec, logger, login, state};
+
+// How long to wait for the device of an EBS volume to appear.
+const EBS_DEVICE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How long a request to an HTTPS URL may take, so an unresponsive server does not
+// stop the system from booting.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The device of the array that instance store devices are striped into.
+const INSTANCE_STORE_RAID_DEVICE: &str = "/dev/md0";
+
+// Fields of the configuration that are applied when it is reloaded.
+const RELOADABLE_FIELDS: [&str; 5] = ["debug", "disable-services", "env", "env-from", "log-levels"];
+
+// The largest response accepted from an HTTP environment source.
+const MAX_HTTP_ENV_BYTES: u64 = 1024 * 1024;
 
-pub fn initialize() -> Result<()> {
+// The largest document accepted from an include directive of user data.
+const MAX_INCLUDED_USER_DATA_BYTES: u64 = 1024 * 1024;
+
+// The number of objects downloaded at the same time for an S3 volume.
+const S3_DOWNLOAD_CONCURRENCY: usize = 8;
+
+pub fn initialize() -> Result<PowerAction> {
     let base_dir = "/";
 
-    let imds_client = Imds::default();
-    let user_data =
-        UserData::from_imds(&imds_client).map_err(|e| anyhow!("unable to get user data: {}", e))?;
+    // Count this boot as failed until initialization succeeds.
+    let failed_boots = state::start_boot();
 
-    simple_logger::init_with_level(if user_data.debug.unwrap_or_default() {
-        Level::Trace
-    } else {
-        Level::Info
-    })
-    .map_err(|e| anyhow!("unable to initialize logger: {}", e))?;
+    // The kernel command line can only be read once /proc is mounted.
+    base_mounts()?;
+
+    // Configure logging from the kernel command line until user data is fetched, so
+    // that failures to reach IMDS can be debugged.
+    let cmdline = kernel_cmdline();
+    logger::init(logger::parse_cmdline_log_format(&cmdline).unwrap_or_default())?;
+    set_log_level(false, &HashMap::new());
     debug!("Initialized logger");
 
-    base_mounts()?;
+    let imds_client = CachedImds::default();
+    let user_data = UserData::from_imds().map_err(|e| anyhow!("unable to get user data: {}", e))?;
+
+    if let Some(log_format) = user_data.log_format {
+        logger::set_format(log_format);
+    }
+    set_log_level(
+        user_data.debug.unwrap_or_default(),
+        &user_data.log_levels.clone().unwrap_or_default(),
+    );
+
+    match state::take_crash_marker() {
+        Ok(Some(marker)) => error!("Previous boot ended abnormally: {}", marker),
+        Ok(None) => (),
+        Err(e) => error!("Unable to check for a crash marker: {}", e),
+    }
+
+    if let Err(e) = Status::new(Phase::Initializing).write(constants::FILE_STATUS) {
+        error!("Unable to write status: {}", e);
+    }
     base_links()?;
     link_nvme_devices()?;
 
-    let config_file_path = Path::new(constants::DIR_ET).join(constants::FILE_METADATA);
-    let config_file = read_config_file(&config_file_path).map_err(|e| {
-        anyhow!(
-            "unable to read image config file {:?}: {}",
-            config_file_path,
-            e
-        )
-    })?;
-    let mut vmspec = VmSpec::from_config_file(&config_file)
-        .map_err(|e| anyhow!("unable to configure instance: {}", e))?;
-    vmspec.merge_user_data(user_data);
-    debug!("VM spec: {:?}", vmspec);
+    // Keep the OOM killer away from init, so the supervisor outlives its processes.
+    if let Err(e) = set_oom_score_adj(OOM_SCORE_ADJ_MIN.to_string().as_bytes()) {
+        error!("Unable to set OOM score adjustment of init: {}", e);
+    }
 
-    vmspec.set_sysctls(base_dir)?;
     let aws_region = imds_client
         .get_region()
         .map_err(|e| anyhow!("unable to get AWS region from IMDS: {}", e))?;
     debug!("AWS region: {}", aws_region);
 
+    let credentials = LazyCredentials::new(&imds_client);
+
+    let mut vmspec = load_vmspec(user_data, &credentials, &aws_region)?;
+
+    let mut degraded = false;
+    match failed_boots {
+        Ok(n) if vmspec.failed_boot_threshold > 0 && n >= vmspec.failed_boot_threshold => {
+            error!(
+                "EASYTO-BOOT-FAILURE-THRESHOLD: {} consecutive failed boots, threshold is {}",
+                n, vmspec.failed_boot_threshold
+            );
+            if vmspec.degraded_boot {
+                info!("Skipping optional volumes and environment sources for a degraded boot");
+                vmspec.degrade();
+                degraded = true;
+            }
+        }
+        Ok(n) if n > 0 => info!("{} consecutive failed boots", n),
+        Ok(_) => (),
+        Err(e) => error!("Unable to update failed boot counter: {}", e),
+    }
+    debug!("VM spec: {:?}", vmspec);
+
+    set_boot_log(&vmspec);
+
+    let instance_id = imds_client
+        .get_metadata(Path::new("instance-id"))
+        .map_err(|e| error!("Unable to get instance ID from IMDS: {}", e))
+        .ok();
+
+    let failure_report = vmspec.failure_report.clone();
+    let redacted_vmspec = vmspec.redacted();
+    // Only failures to initialize are reported, not errors of the supervisor, so a
+    // report is not labeled as a failure to initialize when it is not one.
+    let report_failure = |e: &anyhow::Error| {
+        let Some(failure_report) = &failure_report else {
+            return;
+        };
+        let instance_id = instance_id.as_deref().unwrap_or("unknown");
+        if let Err(e) = save_failure_report(
+            failure_report,
+            e,
+            &redacted_vmspec,
+            instance_id,
+            &credentials,
+            &aws_region,
+        ) {
+            error!("Unable to save failure report: {}", e);
+        }
+    };
+    let (vmspec, command, resolved_env) = configure_instance(
+        base_dir,
+        vmspec,
+        instance_id.as_deref(),
+        &imds_client,
+        &credentials,
+        &aws_region,
+    )
+    .inspect_err(report_failure)?;
+
+    if vmspec.replace_init {
+        replace_init(vmspec, command, resolved_env).inspect_err(report_failure)?;
+        Ok(PowerAction::Poweroff)
+    } else {
+        let reloader = reloader(vmspec.clone(), aws_region.clone(), degraded);
+        supervise(vmspec, command, resolved_env, &aws_region, reloader)
+    }
+}
+
+// Prepare the instance as configured, returning the command to run and its
+// environment.
+fn configure_instance(
+    base_dir: &str,
+    vmspec: VmSpec,
+    instance_id: Option<&str>,
+    imds_client: &CachedImds,
+    credentials: &LazyCredentials,
+    aws_region: &str,
+) -> Result<(VmSpec, Vec<String>, NameValues)> {
+    write_machine_id(base_dir, instance_id)
+        .map_err(|e| anyhow!("unable to write machine ID: {}", e))?;
+
+    vmspec.load_kernel_modules()?;
+    vmspec.set_sysctls(base_dir)?;
+    vmspec.reserve_hugepages(base_dir)?;
     resize_root_volume().map_err(|e| anyhow!("unable to resize root volume: {}", e))?;
 
-    let credentials = imds_client
-        .get_credentials()
-        .map_err(|e| anyhow!("unable to get AWS credentials from IMDS: {}", e))?;
     for volume in &vmspec.volumes {
         debug!("Processing volume {:?}", volume);
         if let Some(source) = &volume.ebs {
             handle_volume_ebs(source)?;
         }
+        if let Some(source) = &volume.instance_store {
+            handle_volume_instance_store(source)?;
+        }
+        if let Some(source) = &volume.lvm {
+            handle_volume_lvm(source)?;
+        }
         if let Some(source) = &volume.s3 {
-            handle_volume_s3(
-                Path::new(base_dir),
-                source,
-                credentials.clone(),
-                &aws_region,
-            )?;
+            handle_volume_s3(Path::new(base_dir), source, credentials, aws_region)?;
         }
         if let Some(source) = &volume.secrets_manager {
-            handle_volume_secretsmanager(
-                Path::new(base_dir),
-                source,
-                credentials.clone(),
-                &aws_region,
-            )?;
+            handle_volume_secretsmanager(Path::new(base_dir), source, credentials, aws_region)?;
         }
         if let Some(source) = &volume.ssm {
-            handle_volume_ssm(
-                Path::new(base_dir),
-                source,
-                credentials.clone(),
-                &aws_region,
-            )?;
+            handle_volume_ssm(Path::new(base_dir), source, credentials, aws_region)?;
         }
     }
 
+    for (i, overlay) in vmspec.overlays().iter().enumerate() {
+        mount_overlay(i, overlay)
+            .map_err(|e| anyhow!("unable to mount overlay on {}: {}", &overlay.path, e))?;
+    }
+
     let resolved_env = resolve_all_envs(
-        &imds_client,
+        imds_client,
         credentials,
-        &aws_region,
+        aws_region,
         &vmspec.env,
         &vmspec.env_from,
     )
@@ -128,17 +255,251 @@ pub fn initialize() -> Result<()> {
     let command = vmspec.full_command(&resolved_env)?;
     debug!("Full command: {:?}", command);
 
+    vmspec.create_users_groups(base_dir)?;
+
+    if let Some(login_password) = &vmspec.login_password {
+        set_login_password(
+            Path::new(base_dir),
+            login_password,
+            imds_client,
+            credentials,
+        )?;
+    }
+
+    if let Some(privilege_escalation) = &vmspec.privilege_escalation {
+        configure_privilege_escalation(Path::new(base_dir), privilege_escalation)?;
+    }
+
+    vmspec.write_files(base_dir)?;
+
     vmspec.run_init_scripts(base_dir, &resolved_env)?;
 
-    if vmspec.replace_init {
-        replace_init(vmspec, command, resolved_env)?;
-    } else {
-        supervise(vmspec, command, resolved_env)?;
+    // Reset the counter now, before the root filesystem may be remounted read-only.
+    if let Err(e) = state::reset_failed_boots() {
+        error!("Unable to reset failed boot counter: {}", e);
     }
 
+    Ok((vmspec, command, resolved_env))
+}
+
+// Save a tarball of the recent log messages along with the error, the redacted
+// configuration, and the state of the system, so the failure can be debugged after
+// the instance is gone.
+fn save_failure_report(
+    failure_report: &FailureReport,
+    error: &anyhow::Error,
+    redacted_vmspec: &serde_json::Value,
+    instance_id: &str,
+    credentials: &LazyCredentials,
+    region: &str,
+) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    let time = DateTime::from_timestamp(now, 0)
+        .map(|t| t.format("%Y%m%dT%H%M%SZ").to_string())
+        .unwrap_or_default();
+
+    let recent_messages = logger::recent_messages();
+    let mut boot_log =
+        diagnostics::last_lines(&recent_messages, failure_report.log_lines()).to_string();
+    boot_log.push_str(&format!("Failed to initialize: {}\n", error));
+    let mut vmspec = serde_json::to_vec_pretty(redacted_vmspec)?;
+    vmspec.push(b'\n');
+    let mut entries = vec![
+        diagnostics::Entry::new("boot.log", boot_log),
+        diagnostics::Entry::new("vmspec.json", vmspec),
+    ];
+    entries.extend(diagnostics::system_entries());
+    let bundle = diagnostics::bundle(&entries)?;
+
+    // Keep the report on the root volume even if it is also uploaded, in case the
+    // network is what failed.
+    let dir = failure_report.path();
+    let path = dir.join(format!("{}-{}.tar.gz", instance_id, time));
+    info!("Writing failure report to {:?}", path);
+    let written = mkdir_p(&dir, Mode::from(0o700)).and_then(|_| {
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&bundle))
+            .map_err(|e| anyhow!("unable to write {:?}: {}", path, e))
+    });
+    if let Err(e) = written {
+        error!("Unable to write failure report: {}", e);
+    }
+
+    if let Some(destination) = &failure_report.s3 {
+        let key = format!(
+            "{}{}/{}/diagnostics.tar.gz",
+            destination.key_prefix.as_deref().unwrap_or_default(),
+            instance_id,
+            time
+        );
+        info!(
+            "Uploading failure report to s3://{}/{}",
+            destination.bucket, key
+        );
+        let client = S3Client::new(credentials.get("failure report")?, region)
+            .map_err(|e| anyhow!("unable to create S3 client: {}", e))?;
+        client.put_object_bytes(&destination.bucket, &key, bundle)?;
+    }
     Ok(())
 }
 
+// Load the image configuration and merge user data into it, along with any user
+// data it includes or is overlaid with.
+fn load_vmspec(
+    user_data: UserData,
+    credentials: &LazyCredentials,
+    aws_region: &str,
+) -> Result<VmSpec> {
+    let included_user_data = match &user_data.include {
+        Some(url) => Some(
+            fetch_included_user_data(url, credentials, aws_region)
+                .map_err(|e| anyhow!("unable to include user data from {}: {}", url, e))?,
+        ),
+        None => None,
+    };
+
+    let overlay_user_data = match &user_data.overlay_from {
+        Some(overlay_from) => {
+            match fetch_overlay_user_data(&overlay_from.ssm_path, credentials, aws_region) {
+                Ok(overlay) => Some(overlay),
+                Err(e) if overlay_from.optional.unwrap_or_default() => {
+                    debug!(
+                        "overlay {} is optional, skipping: {}",
+                        overlay_from.ssm_path, e
+                    );
+                    None
+                }
+                Err(e) => {
+                    return Err(anyhow!(
+                        "unable to get user data overlay from SSM parameter {}: {}",
+                        overlay_from.ssm_path,
+                        e
+                    ))
+                }
+            }
+        }
+        None => None,
+    };
+
+    let config_file_path = Path::new(constants::DIR_ET).join(constants::FILE_METADATA);
+    let config_file = read_config_file(&config_file_path).map_err(|e| {
+        anyhow!(
+            "unable to read image config file {:?}: {}",
+            config_file_path,
+            e
+        )
+    })?;
+    let mut vmspec = VmSpec::from_config_file(&config_file)
+        .map_err(|e| anyhow!("unable to configure instance: {}", e))?;
+    // Inline user data takes precedence over included user data.
+    if let Some(included) = included_user_data {
+        vmspec.merge_user_data(included);
+    }
+    vmspec.merge_user_data(user_data);
+    // An overlay takes precedence over inline user data.
+    if let Some(overlay) = overlay_user_data {
+        vmspec.merge_user_data(overlay);
+    }
+    Ok(vmspec)
+}
+
+// Return a function that loads the configuration again as at boot, for when init
+// receives SIGHUP. The log level is applied right away, and changes that are not
+// applied while running are logged.
+fn reloader(
+    vmspec: VmSpec,
+    aws_region: String,
+    degraded: bool,
+) -> impl Fn() -> Result<(VmSpec, NameValues)> + Send + Sync + 'static {
+    let last = Mutex::new(vmspec);
+    move || {
+        let imds_client = CachedImds::default();
+        let credentials = LazyCredentials::new(&imds_client);
+        let user_data =
+            UserData::from_imds().map_err(|e| anyhow!("unable to get user data: {}", e))?;
+        let mut vmspec = load_vmspec(user_data, &credentials, &aws_region)?;
+        if degraded {
+            vmspec.degrade();
+        }
+        let env = resolve_all_envs(
+            &imds_client,
+            &credentials,
+            &aws_region,
+            &vmspec.env,
+            &vmspec.env_from,
+        )
+        .map_err(|e| {
+            anyhow!(
+                "unable to resolve environment variables from external sources: {}",
+                e
+            )
+        })?;
+
+        set_log_level(vmspec.debug, &vmspec.log_levels);
+        let mut last = last.lock().unwrap();
+        let changed = last
+            .changed_fields(&vmspec)
+            .into_iter()
+            .filter(|field| !RELOADABLE_FIELDS.contains(&field.as_str()))
+            .collect::<Vec<_>>();
+        if !changed.is_empty() {
+            warn!("Changes to {} require a reboot", changed.join(", "));
+        }
+        *last = vmspec.clone();
+        Ok((vmspec, env))
+    }
+}
+
+// Write log messages to the boot log file unless it is disabled.
+fn set_boot_log(vmspec: &VmSpec) {
+    if vmspec.boot_log.disable.unwrap_or_default() {
+        return;
+    }
+    let readonly_root_fs = vmspec.security.readonly_root_fs.unwrap_or_default();
+    let path = vmspec.boot_log.path(&vmspec.service_logs, readonly_root_fs);
+    if let Some(dir) = path.parent() {
+        if let Err(e) = mkdir_p(dir, Mode::from(0o755)) {
+            error!("Unable to create boot log directory {:?}: {}", dir, e);
+            return;
+        }
+    }
+    info!("Writing boot log to {:?}", path);
+    logger::set_boot_log(RotatingFile::new(
+        &path,
+        vmspec.service_logs.max_size(),
+        vmspec.service_logs.max_files(),
+    ));
+}
+
+// Set the level of messages that are logged, and of messages of any modules with
+// their own levels. Without debug, the level given on the kernel command line is
+// used if there is one.
+fn set_log_level(debug: bool, log_levels: &HashMap<String, LogLevel>) {
+    let default = if debug {
+        LevelFilter::Trace
+    } else {
+        logger::parse_cmdline_log_level(&kernel_cmdline()).unwrap_or(LevelFilter::Info)
+    };
+    let modules = log_levels
+        .iter()
+        .map(|(module, level)| (module.clone(), LevelFilter::from(*level)))
+        .collect();
+    logger::set_levels(default, modules);
+}
+
+// Read the kernel command line, which is empty if it cannot be read.
+fn kernel_cmdline() -> String {
+    fs::read_to_string(Path::new(constants::DIR_PROC).join("cmdline")).unwrap_or_default()
+}
+
 fn base_links() -> Result<()> {
     let ls = vec![
         Link {
@@ -268,21 +629,107 @@ fn base_mounts() -> Result<()> {
     Ok(())
 }
 
+// Fetch user data referenced by an include directive from S3 or an HTTPS URL.
+fn fetch_included_user_data(
+    url: &str,
+    credentials: &LazyCredentials,
+    region: &str,
+) -> Result<UserData> {
+    let document = if url.starts_with("s3://") {
+        let (bucket, key) = parse_s3_url(url).ok_or_else(|| anyhow!("invalid S3 URL"))?;
+        S3Client::new(credentials.get("included user data")?, region)?
+            .get_object_bytes(bucket, key)?
+    } else if url.starts_with("https://") {
+        http_get_bytes(url, None, MAX_INCLUDED_USER_DATA_BYTES)?
+    } else {
+        return Err(anyhow!("URL must begin with s3:// or https://"));
+    };
+    let user_data = UserData::from_bytes(&document)?;
+    if user_data.include.is_some() {
+        return Err(anyhow!("nested includes are not supported"));
+    }
+    Ok(user_data)
+}
+
+// Fetch user data from an SSM parameter to merge on top of the inline user data.
+fn fetch_overlay_user_data(
+    ssm_path: &str,
+    credentials: &LazyCredentials,
+    region: &str,
+) -> Result<UserData> {
+    let document = SsmClient::new(credentials.get("user data overlay")?, region)?
+        .get_parameter_value(ssm_path)?;
+    let user_data = UserData::from_bytes(&document)?;
+    if user_data.include.is_some() || user_data.overlay_from.is_some() {
+        return Err(anyhow!(
+            "include and overlay-from are not supported in an overlay"
+        ));
+    }
+    Ok(user_data)
+}
+
 fn read_config_file(path: &Path) -> Result<container::ConfigFile> {
     let config = File::open(path).and_then(|f| serde_json::from_reader(f).map_err(Into::into))?;
     Ok(config)
 }
 
-fn parse_mode(mode: &str) -> Result<Mode> {
-    let m = u32::from_str_radix(mode, 8)?;
-    Ok(Mode::from(m))
+// Mount a writable overlay on a directory of the root filesystem, with the upper and
+// work directories on a dedicated tmpfs, or under the overlay's upper-dir if it is set.
+fn mount_overlay(index: usize, overlay: &Overlay) -> Result<()> {
+    info!("Mounting writable overlay on {}", &overlay.path);
+
+    if overlay.path.is_empty() || Path::new(&overlay.path) == Path::new(constants::DIR_ROOT) {
+        return Err(anyhow!(
+            "overlay path must be a directory other than {}",
+            constants::DIR_ROOT
+        ));
+    }
+
+    let base = match &overlay.upper_dir {
+        Some(upper_dir) => PathBuf::from(upper_dir),
+        None => {
+            let base = Path::new(constants::DIR_ET_RUN)
+                .join("overlays")
+                .join(index.to_string());
+            let options = overlay.size.as_ref().map(|size| format!("size={}", size));
+            Mount {
+                source: "tmpfs",
+                flags: MountFlags::NODEV | MountFlags::NOSUID,
+                fs_type: "tmpfs",
+                mode: Mode::from(0o755),
+                options: options.as_deref(),
+                target: base.clone(),
+            }
+            .execute()?;
+            base
+        }
+    };
+    let upper = base.join("upper");
+    let work = base.join("work");
+    mkdir_p(&upper, Mode::from(0o755))?;
+    mkdir_p(&work, Mode::from(0o755))?;
+
+    let options = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        &overlay.path,
+        upper.display(),
+        work.display()
+    );
+    mount(
+        "overlay",
+        &overlay.path,
+        "overlay",
+        MountFlags::empty(),
+        options,
+    )?;
+    Ok(())
 }
 
 fn handle_volume_ebs(volume: &EbsVolumeSource) -> Result<()> {
     info!("Handling volume {:?}", volume);
 
-    if volume.device.is_empty() {
-        return Err(anyhow!("volume must have a device"));
+    if volume.device.is_empty() && volume.volume_id.is_none() {
+        return Err(anyhow!("volume must have a device or volume ID"));
     }
 
     if volume.fs_type.is_none() {
@@ -293,6 +740,34 @@ fn handle_volume_ebs(volume: &EbsVolumeSource) -> Result<()> {
         return Err(anyhow!("volume must have a mount point"));
     }
 
+    // Once a volume has been labeled, find it by label, as its device may change.
+    let labeled = volume.mount.label.as_ref().and_then(|label| {
+        let labeled = find_device_by_label(label)?;
+        info!("Found volume labeled {} on {}", label, labeled);
+        Some(labeled)
+    });
+    let device = match labeled {
+        Some(labeled) => labeled,
+        None => {
+            let disk = match &volume.volume_id {
+                Some(volume_id) => {
+                    let disk = wait_for_volume_id(volume_id, EBS_DEVICE_TIMEOUT)?;
+                    info!("Found volume {} on {}", volume_id, disk);
+                    disk
+                }
+                None => volume.device.clone(),
+            };
+            match volume.partition {
+                Some(partition) => {
+                    let device = partition_device(&disk, partition);
+                    wait_for_device(&device, EBS_DEVICE_TIMEOUT)?;
+                    device
+                }
+                None => disk,
+            }
+        }
+    };
+
     let mode = parse_mode(volume.mount.mode.as_ref().unwrap())?;
     debug!("Parsed mode, before: {:?}, after: {:?}", volume, mode);
 
@@ -317,32 +792,147 @@ fn handle_volume_ebs(volume: &EbsVolumeSource) -> Result<()> {
         volume.mount.destination
     );
 
-    try_mkfs(&volume.device, volume.fs_type.as_ref().unwrap())?;
+    let fs_type = volume.fs_type.as_ref().unwrap();
+    try_mkfs(
+        &device,
+        fs_type,
+        volume.mount.label.as_deref(),
+        volume.mount.mkfs_options.as_deref().unwrap_or_default(),
+    )?;
+
+    let fsck = volume.fsck.unwrap_or_default();
+    if fsck != Fsck::Disabled {
+        match (try_fsck(&device, fs_type, fsck), volume.fsck_on_failure) {
+            (Ok(_), _) => (),
+            (Err(e), None | Some(OnFailure::Fail)) => return Err(e),
+            (Err(e), Some(OnFailure::Warn)) => warn!("{}", e),
+            (Err(e), Some(OnFailure::Ignore)) => debug!("{}", e),
+        }
+    }
 
-    mount(
-        &volume.device,
-        &volume.mount.destination,
-        volume.fs_type.as_ref().unwrap(),
-        MountFlags::empty(),
-        "",
-    )
-    .map_err(|e| {
+    let (flags, data) = parse_mount_options(volume.mount.options.as_deref().unwrap_or_default());
+    mount(&device, &volume.mount.destination, fs_type, flags, data).map_err(|e| {
         anyhow!(
             "unable to mount {} on {}: {}",
-            &volume.device,
+            &device,
             &volume.mount.destination,
             e
         )
     })?;
     info!(
         "Mounted volume {} on {}",
-        &volume.device, &volume.mount.destination
+        &device, &volume.mount.destination
     );
 
+    if volume.trim.unwrap_or_default() {
+        match fstrim(&volume.mount.destination) {
+            Ok(trimmed) => info!("Trimmed {} bytes on {}", trimmed, &volume.mount.destination),
+            Err(e) => warn!("Unable to trim {}: {}", &volume.mount.destination, e),
+        }
+    }
+
     Ok(())
 }
 
-fn try_mkfs(device: &str, fs_type: &str) -> Result<()> {
+fn handle_volume_instance_store(volume: &InstanceStoreVolumeSource) -> Result<()> {
+    let index = volume.device_index.unwrap_or_default();
+    let devices = find_instance_store_devices()?;
+    let device = match devices.len() {
+        n if n > 1 && volume.raid0.unwrap_or_default() => {
+            assemble_raid0(INSTANCE_STORE_RAID_DEVICE, &devices)?;
+            Some(INSTANCE_STORE_RAID_DEVICE.to_string())
+        }
+        _ => devices.get(index).cloned(),
+    };
+    let device = match device {
+        Some(device) => device,
+        None if volume.optional.unwrap_or_default() => {
+            info!("Skipping optional instance store volume {}", index);
+            return Ok(());
+        }
+        None => {
+            return Err(anyhow!(
+                "instance store device {} not found, {} available",
+                index,
+                devices.len()
+            ))
+        }
+    };
+    handle_volume_ebs(&EbsVolumeSource {
+        device,
+        fs_type: volume.fs_type.clone(),
+        make_fs: Some(true),
+        mount: volume.mount.clone(),
+        ..Default::default()
+    })
+}
+
+fn handle_volume_lvm(volume: &LvmVolumeSource) -> Result<()> {
+    info!("Handling volume group {}", &volume.volume_group);
+
+    if volume.volume_group.is_empty() {
+        return Err(anyhow!("volume group must have a name"));
+    }
+
+    if volume.devices.is_empty() {
+        return Err(anyhow!("volume group must have at least one device"));
+    }
+
+    activate_volume_group(&volume.volume_group, &volume.devices).map_err(|e| {
+        anyhow!(
+            "unable to activate volume group {}: {}",
+            &volume.volume_group,
+            e
+        )
+    })?;
+
+    for lv in &volume.logical_volumes {
+        let device = ensure_logical_volume(&volume.volume_group, &lv.name, lv.size.as_deref())
+            .map_err(|e| anyhow!("unable to create logical volume {}: {}", &lv.name, e))?;
+        handle_volume_ebs(&EbsVolumeSource {
+            device: device.to_string_lossy().to_string(),
+            fs_type: lv.fs_type.clone(),
+            make_fs: Some(true),
+            mount: lv.mount.clone(),
+            ..Default::default()
+        })?;
+    }
+
+    Ok(())
+}
+
+// Check a filesystem with fsck in preen mode, which fixes problems that are safe to
+// fix without intervention. Exit codes above 1 mean errors remain or fsck failed.
+fn try_fsck(device: &str, fs_type: &str, fsck: Fsck) -> Result<()> {
+    let fsck_path = Path::new(constants::DIR_ET_SBIN).join(format!("fsck.{}", fs_type));
+    if let Err(Errno::NOENT) = stat(&fsck_path) {
+        warn!("Skipping check of {}, {:?} not found", device, fsck_path);
+        return Ok(());
+    }
+    let mut cmd = Command::new(&fsck_path);
+    cmd.arg("-p");
+    if fsck == Fsck::Force {
+        cmd.arg("-f");
+    }
+    let status = cmd
+        .arg(device)
+        .status()
+        .map_err(|e| anyhow!("unable to check filesystem on {}: {}", device, e))?;
+    match status.code() {
+        Some(0) => Ok(()),
+        Some(1) => {
+            info!("Corrected errors in filesystem on {}", device);
+            Ok(())
+        }
+        _ => Err(anyhow!(
+            "filesystem check on {} exited with {}",
+            device,
+            status
+        )),
+    }
+}
+
+fn try_mkfs(device: &str, fs_type: &str, label: Option<&str>, options: &[String]) -> Result<()> {
     let has_fs = device_has_fs(Path::new(device))
         .map_err(|e| anyhow!("unable to check if {} has a filesystem: {}", device, e))?;
     if !has_fs {
@@ -355,10 +945,23 @@ fn try_mkfs(device: &str, fs_type: &str) -> Result<()> {
                 return Err(anyhow!("unable to stat {:?}: {}", mkfs_path, e));
             }
             Ok(_) => {
-                Command::new(&mkfs_path)
-                    .arg(device)
+                let mut cmd = Command::new(&mkfs_path);
+                if let Some(label) = label {
+                    cmd.args(["-L", label]);
+                }
+                cmd.args(options).arg(device);
+                let output = cmd
                     .output()
                     .map_err(|e| anyhow!("unable to create a filesystem on {}: {}", device, e))?;
+                if !output.status.success() {
+                    return Err(anyhow!(
+                        "unable to create a filesystem on {}: {:?} exited with {}: {}",
+                        device,
+                        cmd,
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ));
+                }
             }
         }
         info!("Created filesystem on device {:?}", device);
@@ -366,14 +969,105 @@ fn try_mkfs(device: &str, fs_type: &str) -> Result<()> {
     Ok(())
 }
 
+// Write rules for sudo and doas if they are on the image. Rules for doas go in
+// /etc/doas.d if it exists, and otherwise replace /etc/doas.conf.
+fn configure_privilege_escalation(
+    base_dir: &Path,
+    privilege_escalation: &PrivilegeEscalation,
+) -> Result<()> {
+    let users = match &privilege_escalation.users {
+        Some(users) => users.clone(),
+        None => {
+            let home_base = base_dir.join_relative(constants::DIR_ET_HOME);
+            let login_user = login::login_user(&home_base)
+                .map_err(|e| anyhow!("unable to find login user: {}", e))?
+                .ok_or_else(|| anyhow!("login user not found"))?;
+            vec![login_user]
+        }
+    };
+    let require_password = privilege_escalation.require_password.unwrap_or_default();
+
+    let mut configs = Vec::new();
+    if find_executable_in_path("sudo", constants::ENV_PATH).is_some() {
+        let rules = login::sudoers_rules(&users, require_password)?;
+        configs.push((base_dir.join_relative("/etc/sudoers.d/easyto"), rules));
+    }
+    if find_executable_in_path("doas", constants::ENV_PATH).is_some() {
+        let rules = login::doas_rules(&users, require_password)?;
+        let doas_dir = base_dir.join_relative("/etc/doas.d");
+        let path = match stat(&doas_dir) {
+            Ok(_) => doas_dir.join("easyto.conf"),
+            Err(_) => base_dir.join_relative("/etc/doas.conf"),
+        };
+        configs.push((path, rules));
+    }
+    if configs.is_empty() {
+        warn!("Neither sudo nor doas was found, skipping privilege escalation rules");
+        return Ok(());
+    }
+
+    for (path, rules) in configs {
+        info!("Writing privilege escalation rules to {:?}", path);
+        if let Some(dir) = path.parent() {
+            mkdir_p(dir, Mode::from(0o750))?;
+        }
+        let mut f = File::options()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(0o440)
+            .open(&path)
+            .map_err(|e| anyhow!("unable to open {:?}: {}", path, e))?;
+        // Set the mode explicitly, as sudo ignores rules in a file writable by others.
+        chmod(&path, Mode::from(0o440))
+            .map_err(|e| anyhow!("unable to change permissions on {:?}: {}", path, e))?;
+        f.write_all(rules.as_bytes())
+            .map_err(|e| anyhow!("unable to write {:?}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+fn set_login_password(
+    base_dir: &Path,
+    login_password: &LoginPassword,
+    imds: &CachedImds,
+    credentials: &LazyCredentials,
+) -> Result<()> {
+    let home_base = base_dir.join_relative(constants::DIR_ET_HOME);
+    let login_user = login::login_user(&home_base)
+        .map_err(|e| anyhow!("unable to find login user: {}", e))?
+        .ok_or_else(|| anyhow!("login user not found"))?;
+    let password = match login_password.from.fetch(imds, credentials) {
+        Ok(password) => {
+            String::from_utf8(password).map_err(|e| anyhow!("invalid login password: {}", e))?
+        }
+        Err(e) if login_password.from.is_optional() => {
+            warn!("Login password is optional, skipping: {}", e);
+            return Ok(());
+        }
+        Err(e) => return Err(anyhow!("unable to get login password: {}", e)),
+    };
+    let password = password.trim_end_matches(['\r', '\n']);
+    let password_hash = if login_password.hashed.unwrap_or_default() {
+        password.to_string()
+    } else {
+        login::hash_password(password).map_err(|e| anyhow!("unable to hash password: {}", e))?
+    };
+    info!("Setting password of login user {}", login_user);
+    let shadow_path = base_dir.join_relative(constants::FILE_ETC_SHADOW);
+    login::set_password(&shadow_path, &login_user, &password_hash)
+        .map_err(|e| anyhow!("unable to write {:?}: {}", shadow_path, e))
+}
+
 fn handle_volume_ssm(
     base_dir: &Path,
     volume: &SsmVolumeSource,
-    credentials: Credentials,
+    credentials: &LazyCredentials,
     region: &str,
 ) -> Result<()> {
+    let credentials = credentials.get(&format!("SSM volume {}", volume.path))?;
     let client = SsmClient::new(credentials, region)?;
-    match client.get_parameter_list(&volume.path) {
+    match client.get_parameter_list(&volume.selector()) {
         Ok(mut parameters) => {
             debug!("SSM parameters: {:?}", parameters);
             for parameter in parameters.iter_mut() {
@@ -397,11 +1091,12 @@ fn handle_volume_ssm(
 fn handle_volume_secretsmanager(
     base_dir: &Path,
     volume: &SecretsManagerVolumeSource,
-    credentials: Credentials,
+    credentials: &LazyCredentials,
     region: &str,
 ) -> Result<()> {
+    let credentials = credentials.get(&format!("Secrets Manager volume {}", volume.secret_id))?;
     let client = AsmClient::new(credentials, region)?;
-    match client.get_secret_list(&volume.secret_id) {
+    match client.get_secret_list(&volume.secret_id, volume.split_json.unwrap_or_default()) {
         Ok(mut secrets) => {
             debug!("Secrets Manager secrets: {:?}", secrets);
             for secret in secrets.iter_mut() {
@@ -425,29 +1120,26 @@ fn handle_volume_secretsmanager(
 fn handle_volume_s3(
     base_dir: &Path,
     volume: &S3VolumeSource,
-    credentials: Credentials,
+    credentials: &LazyCredentials,
     region: &str,
 ) -> Result<()> {
+    let s3_url = format!("s3://{}/{}", volume.bucket, volume.key_prefix);
+    let credentials = credentials.get(&format!("S3 volume {}", s3_url))?;
     let client = S3Client::new(credentials, region)
         .map_err(|e| anyhow!("unable to create S3 client: {}", e))?;
-    let s3_url = format!("s3://{}/{}", volume.bucket, volume.key_prefix);
     match client.get_object_list(&volume.bucket, &volume.key_prefix) {
-        Ok(mut objects) => {
+        Ok(objects) => {
             debug!("S3 objects: {:?}", objects);
-            for object in objects.iter_mut() {
-                let dest = Path::new(base_dir).join(&volume.mount.destination);
-                debug!("S3 object dest: {:?}", &dest);
-                object
-                    .write(
-                        dest.as_path(),
-                        volume.mount.user_id.unwrap(),
-                        volume.mount.group_id.unwrap(),
-                    )
-                    .map_err(|e| {
-                        anyhow!("unable to write S3 object {} to {:?}: {}", s3_url, dest, e)
-                    })?;
-            }
-            Ok(())
+            let dest = Path::new(base_dir).join(&volume.mount.destination);
+            debug!("S3 object dest: {:?}", &dest);
+            write_s3_objects(
+                objects,
+                &dest,
+                volume.extract.unwrap_or_default(),
+                volume.mount.user_id.unwrap(),
+                volume.mount.group_id.unwrap(),
+            )
+            .map_err(|e| anyhow!("unable to write S3 object {} to {:?}: {}", s3_url, dest, e))
         }
         Err(e) if volume.optional.unwrap_or_default() => {
             debug!("volume {} is optional, skipping: {}", s3_url, e);
@@ -461,6 +1153,56 @@ fn handle_volume_s3(
     }
 }
 
+// Download objects with a bounded number of workers, as volumes may have many files.
+fn write_s3_objects(
+    objects: Vec<S3Object>,
+    dest: &Path,
+    extract: bool,
+    user_id: u32,
+    group_id: u32,
+) -> Result<()> {
+    let (object_tx, object_rx) = unbounded::<S3Object>();
+    for object in objects {
+        object_tx.send(object)?;
+    }
+    drop(object_tx);
+
+    // Set by the first worker to fail, so the others stop taking objects rather
+    // than downloading the rest of a volume that will not be used.
+    let stop = AtomicBool::new(false);
+    thread::scope(|scope| {
+        let workers = (0..S3_DOWNLOAD_CONCURRENCY)
+            .map(|_| {
+                let object_rx = object_rx.clone();
+                let stop = &stop;
+                scope.spawn(move || -> Result<()> {
+                    for mut object in object_rx.iter() {
+                        if stop.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        let result = if extract {
+                            object.extract_or_write(dest, user_id, group_id)
+                        } else {
+                            object.write(dest, user_id, group_id)
+                        };
+                        if let Err(e) = result {
+                            stop.store(true, Ordering::Relaxed);
+                            return Err(anyhow!("unable to write {}: {}", object.url(), e));
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        for worker in workers {
+            worker
+                .join()
+                .unwrap_or_else(|_| Err(anyhow!("S3 download worker panicked")))?;
+        }
+        Ok(())
+    })
+}
+
 fn resolve_env_from<GetBytes, GetMap>(
     name: &str,
     b64_encode: bool,
@@ -497,7 +1239,72 @@ where
     }
 }
 
-fn resolve_env_from_imds(source: &ImdsEnvSource, imds: &Imds) -> Result<NameValues> {
+fn resolve_env_from_instance_identity(imds: &CachedImds) -> Result<NameValues> {
+    let info = imds.get_metadata(Path::new("identity-credentials/ec2/info"))?;
+    let identity = [
+        ("AWS_ACCOUNT_ID", parse_account_id(&info)?),
+        (
+            "AWS_AVAILABILITY_ZONE",
+            imds.get_metadata(Path::new("placement/availability-zone"))?,
+        ),
+        ("AWS_REGION", imds.get_region()?),
+        (
+            "EC2_INSTANCE_ID",
+            imds.get_metadata(Path::new("instance-id"))?,
+        ),
+        (
+            "EC2_INSTANCE_TYPE",
+            imds.get_metadata(Path::new("instance-type"))?,
+        ),
+    ];
+    Ok(identity
+        .into_iter()
+        .map(|(name, value)| NameValue {
+            name: name.into(),
+            value,
+        })
+        .collect())
+}
+
+// Get the account ID from the document at identity-credentials/ec2/info, which is
+// available whether or not the instance has an instance profile.
+fn parse_account_id(info: &str) -> Result<String> {
+    let info: serde_json::Value = serde_json::from_str(info)?;
+    info.get("AccountId")
+        .and_then(|id| id.as_str())
+        .map(String::from)
+        .ok_or_else(|| anyhow!("no account ID in instance identity"))
+}
+
+fn resolve_env_from_instance_tags(
+    source: &InstanceTagsEnvSource,
+    imds: &CachedImds,
+) -> Result<NameValues> {
+    let keys = imds.get_metadata(Path::new("tags/instance"))?;
+    let prefix = source.prefix.as_deref().unwrap_or_default();
+    keys.lines()
+        .filter(|key| !key.is_empty())
+        .map(|key| {
+            let value = imds.get_metadata(&Path::new("tags/instance").join(key))?;
+            Ok(NameValue {
+                name: tag_env_name(prefix, key),
+                value,
+            })
+        })
+        .collect()
+}
+
+// Tag keys may contain characters such as - and : that are not valid in shell
+// variable names, so those are replaced with underscores.
+fn tag_env_name(prefix: &str, key: &str) -> String {
+    let key = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    format!("{}{}", prefix, key)
+}
+
+fn resolve_env_from_imds(source: &ImdsEnvSource, imds: &CachedImds) -> Result<NameValues> {
     let value = imds.get_metadata(Path::new(&source.path))?;
     let nv = NameValue {
         name: source.name.clone(),
@@ -506,6 +1313,125 @@ fn resolve_env_from_imds(source: &ImdsEnvSource, imds: &Imds) -> Result<NameValu
     Ok(vec![nv])
 }
 
+fn resolve_env_from_file(base_dir: &Path, source: &FileEnvSource) -> Result<NameValues> {
+    let path = base_dir.join_relative(&source.path);
+    let get_bytes = || fs::read(&path).map_err(|e| anyhow!("unable to read {:?}: {}", path, e));
+    let get_map = || {
+        let buf = get_bytes()?;
+        match source.format() {
+            EnvFileFormat::Dotenv => parse_dotenv(&String::from_utf8(buf)?),
+            EnvFileFormat::Json => {
+                let map: HashMap<String, String> = serde_json::from_slice(&buf)?;
+                Ok(map)
+            }
+        }
+    };
+    resolve_env_from(
+        source.name.as_ref().unwrap_or(&"".into()),
+        source.base64_encode.unwrap_or_default(),
+        get_bytes,
+        get_map,
+    )
+}
+
+// Parse lines of NAME=value, ignoring blank lines and comments. Lines may begin with
+// export, and values may be enclosed in single or double quotes.
+fn parse_dotenv(contents: &str) -> Result<HashMap<String, String>> {
+    let mut map = HashMap::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid line {}: expected NAME=value", i + 1))?;
+        let value = value.trim();
+        let value = [('"', '"'), ('\'', '\'')]
+            .iter()
+            .find_map(|(open, close)| {
+                value
+                    .strip_prefix(*open)
+                    .and_then(|v| v.strip_suffix(*close))
+            })
+            .unwrap_or(value);
+        map.insert(name.trim().to_string(), value.to_string());
+    }
+    Ok(map)
+}
+
+fn resolve_env_from_http(source: &HttpEnvSource) -> Result<NameValues> {
+    if !source.url.starts_with("https://") {
+        return Err(anyhow!("URL {} must begin with https://", source.url));
+    }
+    let body = http_get_bytes(&source.url, source.headers.as_ref(), MAX_HTTP_ENV_BYTES)?;
+    env_from_http_body(source, &body)
+}
+
+// Convert the body of a response from an HTTP environment source to environment
+// variables, either a single one with the source's name or one per key of a JSON
+// object.
+fn env_from_http_body(source: &HttpEnvSource, body: &[u8]) -> Result<NameValues> {
+    resolve_env_from(
+        source.name.as_ref().unwrap_or(&"".into()),
+        source.base64_encode.unwrap_or_default(),
+        || Ok(body.to_vec()),
+        || {
+            serde_json::from_slice(body).map_err(|e| {
+                anyhow!(
+                    "unable to parse response from {} as a JSON object: {}",
+                    source.url,
+                    e
+                )
+            })
+        },
+    )
+}
+
+// Get the body of an HTTPS URL, failing if it is larger than max_bytes.
+fn http_get_bytes(
+    url: &str,
+    headers: Option<&HashMap<String, String>>,
+    max_bytes: u64,
+) -> Result<Vec<u8>> {
+    let agent = ureq::AgentBuilder::new().timeout(HTTP_TIMEOUT).build();
+    let mut request = agent.get(url);
+    for (name, value) in headers.into_iter().flatten() {
+        request = request.set(name, value);
+    }
+    let response = request
+        .call()
+        .map_err(|e| anyhow!("unable to get {}: {}", url, e))?;
+    read_limited(response.into_reader(), max_bytes)
+        .map_err(|e| anyhow!("unable to read response from {}: {}", url, e))
+}
+
+// Read all of a reader, failing rather than truncating if it has more than
+// max_bytes.
+fn read_limited<R: Read>(reader: R, max_bytes: u64) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.take(max_bytes + 1).read_to_end(&mut buf)?;
+    if buf.len() as u64 > max_bytes {
+        return Err(anyhow!("it is larger than {} bytes", max_bytes));
+    }
+    Ok(buf)
+}
+
+// Select the keys of a map from an environment source, and add a prefix to them.
+fn filter_keys(
+    map: HashMap<String, String>,
+    include_keys: Option<&[String]>,
+    exclude_keys: Option<&[String]>,
+    prefix: Option<&str>,
+) -> HashMap<String, String> {
+    map.into_iter()
+        .filter(|(key, _)| include_keys.map_or(true, |keys| keys.contains(key)))
+        .filter(|(key, _)| !exclude_keys.is_some_and(|keys| keys.contains(key)))
+        .map(|(key, value)| (format!("{}{}", prefix.unwrap_or_default(), key), value))
+        .collect()
+}
+
 fn resolve_env_from_s3(
     source: &S3EnvSource,
     credentials: Credentials,
@@ -517,7 +1443,13 @@ fn resolve_env_from_s3(
     };
     let get_map = || {
         let client = S3Client::new(credentials.clone(), region)?;
-        client.get_object_map(&source.bucket, &source.key)
+        let map = client.get_object_map(&source.bucket, &source.key)?;
+        Ok(filter_keys(
+            map,
+            source.include_keys.as_deref(),
+            source.exclude_keys.as_deref(),
+            source.prefix.as_deref(),
+        ))
     };
     resolve_env_from(
         source.name.as_ref().unwrap_or(&"".into()),
@@ -534,7 +1466,15 @@ fn resolve_env_from_secretsmanager(
 ) -> Result<NameValues> {
     let client = &AsmClient::new(credentials, region)?;
     let get_bytes = || client.get_secret_value(&source.secret_id);
-    let get_map = || client.get_secret_map(&source.secret_id);
+    let get_map = || {
+        let map = client.get_secret_map(&source.secret_id)?;
+        Ok(filter_keys(
+            map,
+            source.include_keys.as_deref(),
+            source.exclude_keys.as_deref(),
+            source.prefix.as_deref(),
+        ))
+    };
     resolve_env_from(
         source.name.as_ref().unwrap_or(&"".into()),
         source.base64_encode.unwrap_or_default(),
@@ -549,8 +1489,17 @@ fn resolve_env_from_ssm(
     region: &str,
 ) -> Result<NameValues> {
     let client = &SsmClient::new(credentials, region)?;
-    let get_bytes = || client.get_parameter_value(&source.path);
-    let get_map = || client.get_parameter_map(&source.path);
+    let selector = source.selector();
+    let get_bytes = || client.get_parameter_value(&selector);
+    let get_map = || {
+        let map = client.get_parameter_map(&selector)?;
+        Ok(filter_keys(
+            map,
+            source.include_keys.as_deref(),
+            source.exclude_keys.as_deref(),
+            source.prefix.as_deref(),
+        ))
+    };
     resolve_env_from(
         source.name.as_ref().unwrap_or(&"".into()),
         source.base64_encode.unwrap_or_default(),
@@ -559,45 +1508,109 @@ fn resolve_env_from_ssm(
     )
 }
 
-fn resolve_all_envs(
-    imds: &Imds,
-    credentials: Credentials,
+fn resolve_env_from_source(
+    source: &EnvFromSource,
+    imds: &CachedImds,
+    credentials: &LazyCredentials,
     region: &str,
-    env: &NameValues,
-    env_from: &EnvFromSources,
 ) -> Result<NameValues> {
-    let mut resolved_env = Vec::with_capacity(env_from.len());
-
-    for source in env_from.iter() {
-        if let Some(imds_source) = &source.imds {
-            match resolve_env_from_imds(imds_source, imds) {
-                Ok(imds_env) => resolved_env.extend(imds_env),
-                Err(_) if imds_source.optional.unwrap_or_default() => (),
-                Err(e) => return Err(e),
-            }
+    let mut resolved_env = Vec::new();
+    if let Some(file_source) = &source.file {
+        match resolve_env_from_file(Path::new("/"), file_source) {
+            Ok(file_env) => resolved_env.extend(file_env),
+            Err(_) if file_source.optional.unwrap_or_default() => (),
+            Err(e) => return Err(e),
         }
-        if let Some(s3_source) = &source.s3 {
-            match resolve_env_from_s3(s3_source, credentials.clone(), region) {
-                Ok(s3_env) => resolved_env.extend(s3_env),
-                Err(_) if s3_source.optional.unwrap_or_default() => (),
-                Err(e) => return Err(e),
-            }
+    }
+    if let Some(http_source) = &source.http {
+        match resolve_env_from_http(http_source) {
+            Ok(http_env) => resolved_env.extend(http_env),
+            Err(_) if http_source.optional.unwrap_or_default() => (),
+            Err(e) => return Err(e),
         }
-        if let Some(asm_source) = &source.secrets_manager {
-            match resolve_env_from_secretsmanager(asm_source, credentials.clone(), region) {
-                Ok(asm_env) => resolved_env.extend(asm_env),
-                Err(_) if asm_source.optional.unwrap_or_default() => (),
-                Err(e) => return Err(e),
-            }
+    }
+    if let Some(imds_source) = &source.imds {
+        match resolve_env_from_imds(imds_source, imds) {
+            Ok(imds_env) => resolved_env.extend(imds_env),
+            Err(_) if imds_source.optional.unwrap_or_default() => (),
+            Err(e) => return Err(e),
         }
-        if let Some(ssm_source) = &source.ssm {
-            match resolve_env_from_ssm(ssm_source, credentials.clone(), region) {
-                Ok(ssm_env) => resolved_env.extend(ssm_env),
-                Err(_) if ssm_source.optional.unwrap_or_default() => (),
-                Err(e) => return Err(e),
-            }
+    }
+    if let Some(identity_source) = &source.instance_identity {
+        match resolve_env_from_instance_identity(imds) {
+            Ok(identity_env) => resolved_env.extend(identity_env),
+            Err(_) if identity_source.optional.unwrap_or_default() => (),
+            Err(e) => return Err(anyhow!("unable to get instance identity from IMDS: {}", e)),
         }
     }
+    if let Some(tags_source) = &source.instance_tags {
+        match resolve_env_from_instance_tags(tags_source, imds) {
+            Ok(tags_env) => resolved_env.extend(tags_env),
+            Err(_) if tags_source.optional.unwrap_or_default() => (),
+            Err(e) => return Err(anyhow!("unable to get instance tags from IMDS: {}", e)),
+        }
+    }
+    if let Some(s3_source) = &source.s3 {
+        let s3_url = format!("s3://{}/{}", s3_source.bucket, s3_source.key);
+        let credentials = credentials.get(&format!("S3 environment source {}", s3_url));
+        match credentials.and_then(|c| resolve_env_from_s3(s3_source, c, region)) {
+            Ok(s3_env) => resolved_env.extend(s3_env),
+            Err(_) if s3_source.optional.unwrap_or_default() => (),
+            Err(e) => return Err(e),
+        }
+    }
+    if let Some(asm_source) = &source.secrets_manager {
+        let credentials = credentials.get(&format!(
+            "Secrets Manager environment source {}",
+            asm_source.secret_id
+        ));
+        match credentials.and_then(|c| resolve_env_from_secretsmanager(asm_source, c, region)) {
+            Ok(asm_env) => resolved_env.extend(asm_env),
+            Err(_) if asm_source.optional.unwrap_or_default() => (),
+            Err(e) => return Err(e),
+        }
+    }
+    if let Some(ssm_source) = &source.ssm {
+        let credentials = credentials.get(&format!("SSM environment source {}", ssm_source.path));
+        match credentials.and_then(|c| resolve_env_from_ssm(ssm_source, c, region)) {
+            Ok(ssm_env) => resolved_env.extend(ssm_env),
+            Err(_) if ssm_source.optional.unwrap_or_default() => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(resolved_env)
+}
+
+fn resolve_all_envs(
+    imds: &CachedImds,
+    credentials: &LazyCredentials,
+    region: &str,
+    env: &NameValues,
+    env_from: &EnvFromSources,
+) -> Result<NameValues> {
+    // Sources are resolved concurrently, but the results are joined in the order the
+    // sources are declared so later sources still take precedence.
+    let results = thread::scope(|scope| {
+        let handles = env_from
+            .iter()
+            .map(|source| {
+                scope.spawn(move || resolve_env_from_source(source, imds, credentials, region))
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow!("environment source resolution panicked")))
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let mut resolved_env = Vec::with_capacity(env_from.len());
+    for result in results {
+        resolved_env.extend(result?);
+    }
 
     let mut all_env: NameValues = expand_env(env, &resolved_env);
     debug!("Expanded environment: {:?}", &all_env);
@@ -643,14 +1656,37 @@ fn replace_init(vmspec: VmSpec, command: Vec<String>, env: NameValues) -> Result
     chdir(&vmspec.working_dir)
         .map_err(|e| anyhow!("unable to chdir to {}: {}", &vmspec.working_dir, e))?;
 
+    check_oom_score_adj(vmspec.oom_score_adj)?;
+    set_oom_score_adj(vmspec.oom_score_adj.to_string().as_bytes())
+        .map_err(|e| anyhow!("unable to set OOM score adjustment: {}", e))?;
+
+    for (resource, rlimit) in vmspec.limits.to_rlimits() {
+        setrlimit(resource, rlimit)
+            .map_err(|e| anyhow!("unable to set limit {:?}: {}", resource, e))?;
+    }
+
     let (uid, gid) = unsafe {
         (
             Uid::from_raw(vmspec.security.run_as_user_id.unwrap()),
             Gid::from_raw(vmspec.security.run_as_group_id.unwrap()),
         )
     };
-    // This calls setgid and setuid only for the current thread, but since this thread
-    // is calling execve(), the new process will inherit the new user and group.
+    let capability_plan = vmspec.security.capability_plan()?;
+    if let Some(plan) = &capability_plan {
+        plan.apply_before_setuid()
+            .map_err(|e| anyhow!("unable to drop capabilities: {}", e))?;
+    }
+
+    // This calls setgroups, setgid, and setuid only for the current thread, but since
+    // this thread is calling execve(), the new process will inherit the new user and groups.
+    if let Some(group_ids) = &vmspec.security.supplementary_group_ids {
+        let groups: Vec<Gid> = group_ids
+            .iter()
+            .map(|gid| unsafe { Gid::from_raw(*gid) })
+            .collect();
+        set_thread_groups(&groups)
+            .map_err(|e| anyhow!("unable to set supplementary groups {:?}: {}", group_ids, e))?;
+    }
     set_thread_gid(gid).map_err(|e| {
         anyhow!(
             "unable to setgid to {}: {}",
@@ -666,6 +1702,15 @@ fn replace_init(vmspec: VmSpec, command: Vec<String>, env: NameValues) -> Result
         )
     })?;
 
+    if let Some(plan) = &capability_plan {
+        plan.apply_after_setuid()
+            .map_err(|e| anyhow!("unable to set capabilities: {}", e))?;
+    }
+
+    if let Some(true) = vmspec.security.no_new_privileges {
+        set_no_new_privs(true).map_err(|e| anyhow!("unable to set no_new_privs: {}", e))?;
+    }
+
     exec(command, env)
 }
 
@@ -698,53 +1743,69 @@ fn exec(command: Vec<String>, env: Vec<NameValue>) -> Result<(), anyhow::Error>
     Ok(())
 }
 
-fn supervise(vmspec: VmSpec, command: Vec<String>, env: NameValues) -> Result<()> {
-    // Collect the EBS mount points for later, before the supervisor drops the VmSpec.
-    let mount_points: Vec<String> = vmspec
-        .volumes
-        .iter()
-        .filter(|v| v.ebs.is_some())
-        .map(|v| v.ebs.as_ref().unwrap().mount.destination.clone())
-        .collect();
-
-    let mut supervisor = Supervisor::new(vmspec, command, env)?;
+fn supervise<F>(
+    vmspec: VmSpec,
+    command: Vec<String>,
+    env: NameValues,
+    region: &str,
+    reloader: F,
+) -> Result<PowerAction>
+where
+    F: Fn() -> Result<(VmSpec, NameValues)> + Send + Sync + 'static,
+{
+    // Collect the mount points for later, before the supervisor drops the VmSpec.
+    let mount_points = vmspec.mount_points();
+    let shutdown_scripts = vmspec.shutdown_scripts.clone();
+    let shutdown_env = env.clone();
+    let kexec = Some(vmspec.kexec.clone()).filter(|kexec| kexec.enable.unwrap_or_default());
+
+    let mut supervisor = Supervisor::new(vmspec, command, env, region)?;
+    supervisor.set_reloader(reloader);
     supervisor.start()?;
-    supervisor.wait();
+    let mut power_action = supervisor.wait();
+    if let (PowerAction::Reboot, Some(kexec)) = (power_action, kexec) {
+        match load_kexec_kernel(&kexec) {
+            Ok(_) => power_action = PowerAction::Kexec,
+            Err(e) => error!("Unable to load kernel for kexec, rebooting instead: {}", e),
+        }
+    }
 
-    unmount_all(&mount_points)?;
-    wait_for_unmounts(
+    if let Err(e) = run_scripts(
+        &shutdown_scripts,
+        "shutdown",
+        constants::DIR_ROOT,
+        &shutdown_env,
+    ) {
+        error!("Unable to run shutdown scripts: {}", e);
+    }
+
+    // The supervisor has already exited cleanly, so a busy mount must not turn the
+    // power action into a failure.
+    if let Err(e) = unmount_all(&mount_points) {
+        error!("Unable to unmount filesystems: {}", e);
+    }
+    if let Err(e) = wait_for_unmounts(
         &Path::new(constants::DIR_PROC).join("mounts"),
         &mount_points,
         Duration::from_secs(10),
-    )
-}
-
-fn unmount_all(mount_points: &[String]) -> Result<()> {
-    let mut error_count = 0;
-
-    if let Err(e) = remount(constants::DIR_ROOT, MountFlags::RDONLY, "") {
-        error_count += 1;
-        error!(
-            "unable to remount {} as read-only: {}",
-            constants::DIR_ROOT,
-            e
-        );
+    ) {
+        error!("Unable to wait for filesystems to be unmounted: {}", e);
     }
+    Ok(power_action)
+}
 
-    for mount_point in mount_points {
-        if let Err(e) = unmount(mount_point, UnmountFlags::empty()) {
-            error_count += 1;
-            error!("unable to unmount {}: {}", mount_point, e);
+// Load the kernel for a kexec reboot, with the command line of the running kernel
+// unless another is configured.
+fn load_kexec_kernel(kexec: &Kexec) -> Result<()> {
+    let command_line = match &kexec.command_line {
+        Some(command_line) => command_line.clone(),
+        None => {
+            let path = Path::new(constants::DIR_PROC).join("cmdline");
+            fs::read_to_string(&path).map_err(|e| anyhow!("unable to read {:?}: {}", path, e))?
         }
-    }
-
-    if error_count == mount_points.len() + 1 {
-        // Only return an error if all unmounts failed so we can wait
-        // for those that did not fail.
-        return Err(anyhow!("unable to unmount filesystems"));
-    }
-
-    Ok(())
+    };
+    info!("Loading kernel {} for kexec", kexec.kernel());
+    kexec::load_kernel(kexec.kernel(), kexec.initramfs.as_deref(), &command_line)
 }
 
 fn wait_for_unmounts(mtab: &Path, mount_points: &[String], timeout: Duration) -> Result<()> {
@@ -827,44 +1888,258 @@ mod test {
     use super::*;
 
     #[test]
-    fn test_parse_mode() {
-        struct Case<'a> {
-            err: bool,
-            mode: &'a str,
-            expected: Mode,
+    fn test_filter_keys() {
+        struct Case {
+            include_keys: Option<Vec<String>>,
+            exclude_keys: Option<Vec<String>>,
+            prefix: Option<&'static str>,
+            expected: Vec<(&'static str, &'static str)>,
         }
+        let map = HashMap::from([
+            ("DB_HOST".to_string(), "db".to_string()),
+            ("DB_PASSWORD".to_string(), "secret".to_string()),
+            ("API_KEY".to_string(), "key".to_string()),
+        ]);
         let cases = [
             Case {
-                err: true,
-                mode: "",
-                expected: Mode::from(0),
+                include_keys: None,
+                exclude_keys: None,
+                prefix: None,
+                expected: vec![
+                    ("API_KEY", "key"),
+                    ("DB_HOST", "db"),
+                    ("DB_PASSWORD", "secret"),
+                ],
             },
             Case {
-                err: true,
-                mode: "abc",
-                expected: Mode::from(0),
+                include_keys: Some(vec!["DB_HOST".into(), "DB_PASSWORD".into()]),
+                exclude_keys: None,
+                prefix: Some("APP_"),
+                expected: vec![("APP_DB_HOST", "db"), ("APP_DB_PASSWORD", "secret")],
             },
             Case {
-                err: false,
-                mode: "0",
-                expected: Mode::from(0),
+                include_keys: None,
+                exclude_keys: Some(vec!["DB_PASSWORD".into()]),
+                prefix: None,
+                expected: vec![("API_KEY", "key"), ("DB_HOST", "db")],
             },
             Case {
-                err: false,
-                mode: "0755",
-                expected: Mode::from(0o755),
+                include_keys: Some(vec!["DB_HOST".into(), "DB_PASSWORD".into()]),
+                exclude_keys: Some(vec!["DB_PASSWORD".into()]),
+                prefix: None,
+                expected: vec![("DB_HOST", "db")],
             },
         ];
         for case in cases {
-            let mode = parse_mode(case.mode);
-            if case.err {
-                assert_eq!(mode.is_err(), case.err);
-            } else {
-                assert_eq!(case.expected, mode.unwrap());
+            let filtered = filter_keys(
+                map.clone(),
+                case.include_keys.as_deref(),
+                case.exclude_keys.as_deref(),
+                case.prefix,
+            );
+            let mut filtered = filtered.into_iter().collect::<Vec<_>>();
+            filtered.sort();
+            let expected = case
+                .expected
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Vec<_>>();
+            assert_eq!(filtered, expected);
+        }
+    }
+
+    #[test]
+    fn test_env_from_http_body() {
+        struct Case {
+            name: Option<&'static str>,
+            base64_encode: Option<bool>,
+            body: &'static [u8],
+            expected: Option<Vec<(&'static str, &'static str)>>,
+        }
+        let cases = [
+            Case {
+                name: Some("TOKEN"),
+                base64_encode: None,
+                body: b"abc123",
+                expected: Some(vec![("TOKEN", "abc123")]),
+            },
+            Case {
+                name: Some("CERT"),
+                base64_encode: Some(true),
+                body: b"\x00\xff",
+                expected: Some(vec![("CERT", "AP8=")]),
+            },
+            Case {
+                name: Some("TOKEN"),
+                base64_encode: Some(false),
+                body: b"\xff\xfe",
+                expected: None,
+            },
+            Case {
+                name: None,
+                base64_encode: None,
+                body: br#"{"DB_HOST": "db.internal", "DB_PORT": "5432"}"#,
+                expected: Some(vec![("DB_HOST", "db.internal"), ("DB_PORT", "5432")]),
+            },
+            Case {
+                name: None,
+                base64_encode: None,
+                body: br#"{"DB_PORT": 5432}"#,
+                expected: None,
+            },
+            Case {
+                name: None,
+                base64_encode: None,
+                body: b"<html>Service Unavailable</html>",
+                expected: None,
+            },
+            Case {
+                name: None,
+                base64_encode: None,
+                body: b"",
+                expected: None,
+            },
+        ];
+        for case in cases {
+            let source = HttpEnvSource {
+                base64_encode: case.base64_encode,
+                name: case.name.map(Into::into),
+                url: "https://config.example.com/env".into(),
+                ..Default::default()
+            };
+            let result = env_from_http_body(&source, case.body);
+            match case.expected {
+                Some(expected) => {
+                    let mut nvs = result.unwrap();
+                    nvs.sort_by(|a, b| a.name.cmp(&b.name));
+                    let expected = expected
+                        .into_iter()
+                        .map(|(name, value)| NameValue {
+                            name: name.into(),
+                            value: value.into(),
+                        })
+                        .collect::<Vec<_>>();
+                    assert_eq!(nvs, expected, "{:?}", case.body);
+                }
+                None => assert!(result.is_err(), "{:?}", case.body),
             }
         }
     }
 
+    #[test]
+    fn test_read_limited() {
+        struct Case {
+            data: &'static [u8],
+            max_bytes: u64,
+            expected: Option<&'static [u8]>,
+        }
+        let cases = [
+            Case {
+                data: b"0123456789",
+                max_bytes: 10,
+                expected: Some(b"0123456789"),
+            },
+            Case {
+                data: b"0123456789",
+                max_bytes: 100,
+                expected: Some(b"0123456789"),
+            },
+            Case {
+                data: b"0123456789",
+                max_bytes: 9,
+                expected: None,
+            },
+            Case {
+                data: b"",
+                max_bytes: 0,
+                expected: Some(b""),
+            },
+        ];
+        for case in cases {
+            let result = read_limited(case.data, case.max_bytes);
+            match case.expected {
+                Some(expected) => assert_eq!(result.unwrap(), expected, "{:?}", case.data),
+                None => assert!(result.is_err(), "{:?}", case.data),
+            }
+        }
+    }
+
+    #[test]
+    fn test_resolve_env_from_http_scheme() {
+        let source = HttpEnvSource {
+            url: "http://config.example.com/env".into(),
+            ..Default::default()
+        };
+        let err = resolve_env_from_http(&source).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "URL http://config.example.com/env must begin with https://"
+        );
+    }
+
+    #[test]
+    fn test_parse_dotenv() {
+        let contents = [
+            "# Database settings",
+            "DB_HOST=db.internal",
+            "",
+            "export DB_PORT=5432",
+            "DB_NAME=\"app db\"",
+            "DB_PASSWORD='p=ss'",
+            "  EMPTY=  ",
+        ]
+        .join("\n");
+        let expected = HashMap::from([
+            ("DB_HOST".to_string(), "db.internal".to_string()),
+            ("DB_PORT".to_string(), "5432".to_string()),
+            ("DB_NAME".to_string(), "app db".to_string()),
+            ("DB_PASSWORD".to_string(), "p=ss".to_string()),
+            ("EMPTY".to_string(), "".to_string()),
+        ]);
+        assert_eq!(parse_dotenv(&contents).unwrap(), expected);
+        assert!(parse_dotenv("NOT_A_VARIABLE").is_err());
+    }
+
+    #[test]
+    fn test_parse_account_id() {
+        let info = r#"{
+  "Code" : "Success",
+  "LastUpdated" : "2024-09-01T12:00:00Z",
+  "AccountId" : "123456789012"
+}"#;
+        assert_eq!(parse_account_id(info).unwrap(), "123456789012");
+        assert!(parse_account_id(r#"{"Code": "Success"}"#).is_err());
+    }
+
+    #[test]
+    fn test_tag_env_name() {
+        struct Case {
+            prefix: &'static str,
+            key: &'static str,
+            expected: &'static str,
+        }
+        let cases = [
+            Case {
+                prefix: "",
+                key: "ENVIRONMENT",
+                expected: "ENVIRONMENT",
+            },
+            Case {
+                prefix: "TAG_",
+                key: "service-name",
+                expected: "TAG_service_name",
+            },
+            Case {
+                prefix: "",
+                key: "team:owner.email",
+                expected: "team_owner_email",
+            },
+        ];
+        for case in cases {
+            assert_eq!(tag_env_name(case.prefix, case.key), case.expected);
+        }
+    }
+
     #[test]
     fn test_is_mounted() {
         struct Case<'a> {
@@ -0,0 +1,47 @@
+use std::{ffi::CString, fs::File, os::fd::AsRawFd, path::Path};
+
+use anyhow::{anyhow, Result};
+
+// Load a kernel to be run by a reboot with RebootCommand::Kexec, which starts it
+// directly instead of going through the firmware and bootloader.
+pub fn load_kernel<P: AsRef<Path>, Q: AsRef<Path>>(
+    kernel: P,
+    initramfs: Option<Q>,
+    command_line: &str,
+) -> Result<()> {
+    let kernel = kernel.as_ref();
+    let kernel_file =
+        File::open(kernel).map_err(|e| anyhow!("unable to open {:?}: {}", kernel, e))?;
+    let initramfs_file = initramfs
+        .map(|initramfs| {
+            let initramfs = initramfs.as_ref();
+            File::open(initramfs).map_err(|e| anyhow!("unable to open {:?}: {}", initramfs, e))
+        })
+        .transpose()?;
+    let (initramfs_fd, flags) = match &initramfs_file {
+        Some(file) => (file.as_raw_fd(), 0),
+        None => (-1, libc::KEXEC_FILE_NO_INITRAMFS),
+    };
+    let command_line = CString::new(command_line.trim())
+        .map_err(|e| anyhow!("invalid kernel command line: {}", e))?;
+    // The length of the command line includes its terminating nul.
+    let command_line = command_line.as_bytes_with_nul();
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_kexec_file_load,
+            kernel_file.as_raw_fd(),
+            initramfs_fd,
+            command_line.len(),
+            command_line.as_ptr(),
+            flags,
+        )
+    };
+    if ret != 0 {
+        return Err(anyhow!(
+            "unable to load kernel {:?}: {}",
+            kernel,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use log::{debug, info};
+use rustix::{
+    io::Errno,
+    system::{finit_module, uname},
+};
+
+use crate::constants;
+
+// Flag for finit_module to let the kernel decompress the module, from
+// include/uapi/linux/module.h in kernel source. The kernel only accepts it from
+// Linux 5.17, when built with CONFIG_MODULE_DECOMPRESS, and rejects it with EINVAL
+// otherwise. Modules are not decompressed here, so compressed modules cannot be
+// loaded on older kernels.
+const MODULE_INIT_COMPRESSED_FILE: i32 = 4;
+
+// Modules are named with underscores internally, though files may use hyphens.
+fn normalize_name(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+// The module name of a path relative to the modules directory, such as
+// kernel/net/netfilter/ipvs/ip_vs.ko.xz.
+fn module_name(path: &str) -> String {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let name = file_name.split(".ko").next().unwrap_or(file_name);
+    normalize_name(name)
+}
+
+// Parse modules.dep, mapping each module name to its path and to the paths of the
+// modules it depends on, in the order they must be loaded.
+fn parse_modules_dep(contents: &str) -> HashMap<String, (String, Vec<String>)> {
+    let mut modules = HashMap::new();
+    for line in contents.lines() {
+        let Some((path, deps)) = line.split_once(':') else {
+            continue;
+        };
+        // Dependencies are listed with the last one to be loaded first.
+        let deps = deps.split_whitespace().rev().map(String::from).collect();
+        modules.insert(module_name(path), (path.to_string(), deps));
+    }
+    modules
+}
+
+pub struct ModuleLoader {
+    dir: PathBuf,
+    modules: HashMap<String, (String, Vec<String>)>,
+}
+
+impl ModuleLoader {
+    pub fn new() -> Result<Self> {
+        let release = uname().release().to_string_lossy().to_string();
+        let dir = Path::new(constants::DIR_LIB_MODULES).join(release);
+        let dep_path = dir.join("modules.dep");
+        let contents = fs::read_to_string(&dep_path)
+            .map_err(|e| anyhow!("unable to read {:?}: {}", dep_path, e))?;
+        Ok(Self {
+            dir,
+            modules: parse_modules_dep(&contents),
+        })
+    }
+
+    // Load a module after the modules it depends on, passing it the parameters.
+    pub fn load(&self, name: &str, parameters: &[String]) -> Result<()> {
+        let (path, deps) = self
+            .modules
+            .get(&normalize_name(name))
+            .ok_or_else(|| anyhow!("module {} not found", name))?;
+        for dep in deps {
+            self.load_path(dep, "")?;
+        }
+        self.load_path(path, &parameters.join(" "))?;
+        info!("Loaded kernel module {}", name);
+        Ok(())
+    }
+
+    fn load_path(&self, path: &str, parameters: &str) -> Result<()> {
+        let full_path = self.dir.join(path);
+        let file =
+            File::open(&full_path).map_err(|e| anyhow!("unable to open {:?}: {}", full_path, e))?;
+        let parameters = CString::new(parameters)
+            .map_err(|e| anyhow!("invalid parameters for {:?}: {}", full_path, e))?;
+        let flags = if path.ends_with(".ko") {
+            0
+        } else {
+            MODULE_INIT_COMPRESSED_FILE
+        };
+        match finit_module(&file, &parameters, flags) {
+            Ok(_) => Ok(()),
+            Err(Errno::EXIST) => {
+                debug!("Kernel module {:?} is already loaded", full_path);
+                Ok(())
+            }
+            Err(Errno::INVAL) if flags == MODULE_INIT_COMPRESSED_FILE => Err(anyhow!(
+                "unable to load compressed module {:?}, which requires Linux 5.17 or later with CONFIG_MODULE_DECOMPRESS",
+                full_path
+            )),
+            Err(e) => Err(anyhow!("unable to load {:?}: {}", full_path, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_module_name() {
+        assert_eq!(module_name("kernel/drivers/block/nbd.ko"), "nbd");
+        assert_eq!(
+            module_name("kernel/net/bridge/br_netfilter.ko.xz"),
+            "br_netfilter"
+        );
+        assert_eq!(module_name("kernel/fs/fuse/cuse.ko.zst"), "cuse");
+        assert_eq!(module_name("extra/some-module.ko"), "some_module");
+    }
+
+    #[test]
+    fn test_parse_modules_dep() {
+        let contents = [
+            "kernel/net/bridge/br_netfilter.ko.xz: kernel/net/bridge/bridge.ko.xz kernel/net/802/stp.ko.xz",
+            "kernel/net/bridge/bridge.ko.xz: kernel/net/802/stp.ko.xz",
+            "kernel/drivers/block/nbd.ko.xz:",
+        ]
+        .join("\n");
+        let modules = parse_modules_dep(&contents);
+        assert_eq!(
+            modules.get("br_netfilter"),
+            Some(&(
+                "kernel/net/bridge/br_netfilter.ko.xz".to_string(),
+                vec![
+                    "kernel/net/802/stp.ko.xz".to_string(),
+                    "kernel/net/bridge/bridge.ko.xz".to_string(),
+                ]
+            ))
+        );
+        assert_eq!(
+            modules.get("nbd"),
+            Some(&("kernel/drivers/block/nbd.ko.xz".to_string(), vec![]))
+        );
+    }
+}
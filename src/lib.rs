@@ -1,11 +1,28 @@
 pub mod aws;
+pub mod capabilities;
+pub mod cloudconfig;
 pub mod constants;
 pub mod container;
+pub mod control;
+pub mod diagnostics;
 pub mod fs;
 pub mod init;
+pub mod kexec;
+pub mod kmod;
+pub mod logger;
 pub mod login;
+pub mod logrotate;
+pub mod mime;
+pub mod powerbutton;
 pub mod rdev;
+pub mod resources;
 pub mod service;
+pub mod spot;
+pub mod state;
+pub mod status;
+pub mod statusserver;
+pub mod syslog;
 pub mod system;
 pub mod vmspec;
+pub mod watchdog;
 pub mod writable;
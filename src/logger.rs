@@ -0,0 +1,561 @@
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{stdout, Write},
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, SecondsFormat};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::Serialize;
+use simple_logger::SimpleLogger;
+
+use crate::constants;
+use crate::logrotate::RotatingFile;
+use crate::status::Phase;
+use crate::vmspec::LogFormat;
+
+// Kernel command line parameters that configure logging before user data is
+// available, such as easyto.log-format=json or easyto.log-level=trace.
+const CMDLINE_DEBUG: &str = "easyto.debug";
+const CMDLINE_LOG_FORMAT: &str = "easyto.log-format";
+const CMDLINE_LOG_LEVEL: &str = "easyto.log-level";
+
+// The number of recent messages kept in memory, beyond which the oldest are dropped.
+const MAX_RECENT_MESSAGES: usize = 1000;
+
+// The kernel rejects records written to /dev/kmsg that are longer than about 1 KiB,
+// so messages are truncated to leave room for the prefix.
+const MAX_KMSG_MESSAGE_SIZE: usize = 960;
+
+// Messages from processes that use syslog(3), which are not mirrored to the kernel
+// log or the boot log as they could crowd out the messages of init.
+const TARGET_SYSLOG: &str = "syslog";
+
+// The phase of the boot, which is included in each JSON message.
+static PHASE: Mutex<Phase> = Mutex::new(Phase::Initializing);
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+// A logger that writes each message to the console in the configured format, and
+// mirrors it to the kernel log so it appears in dmesg alongside kernel messages, and
+// to the boot log file so it is kept after the console scrollback is gone.
+struct Logger {
+    boot_log: Mutex<BootLog>,
+    format: Mutex<LogFormat>,
+    kmsg: Option<Mutex<File>>,
+    levels: Mutex<Levels>,
+    text: SimpleLogger,
+}
+
+// The level of messages that are logged, which may be overridden for the messages of
+// particular modules. Modules are named without the crate, such as service, and the
+// most specific module that matches a message is used.
+#[derive(Debug)]
+struct Levels {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Default for Levels {
+    fn default() -> Self {
+        Self {
+            default: LevelFilter::Trace,
+            modules: Vec::new(),
+        }
+    }
+}
+
+impl Levels {
+    fn level(&self, target: &str) -> LevelFilter {
+        let target = target
+            .strip_prefix(concat!(env!("CARGO_CRATE_NAME"), "::"))
+            .unwrap_or(target);
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target
+                    .strip_prefix(module.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.default)
+    }
+
+    // The highest level of any module, below which messages need not be checked.
+    fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+// The most recent messages are kept in memory, as the boot log file is only known
+// once the configuration is loaded, and so they can be included in a failure report.
+#[derive(Default)]
+struct BootLog {
+    file: Option<RotatingFile>,
+    recent: VecDeque<String>,
+}
+
+impl BootLog {
+    fn write_line(&mut self, line: String) {
+        if let Some(file) = &mut self.file {
+            if let Err(e) = file.write_line(line.as_bytes()) {
+                eprintln!("Unable to write boot log: {}", e);
+            }
+        }
+        if self.recent.len() == MAX_RECENT_MESSAGES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(line);
+    }
+
+    fn set_file(&mut self, mut file: RotatingFile) {
+        for line in &self.recent {
+            if let Err(e) = file.write_line(line.as_bytes()) {
+                eprintln!("Unable to write boot log: {}", e);
+            }
+        }
+        self.file = Some(file);
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.levels.lock().unwrap().level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let time = timestamp();
+        let phase = *PHASE.lock().unwrap();
+        let format = *self.format.lock().unwrap();
+        match format {
+            LogFormat::Json => {
+                // Each message is written as a single line of JSON, at once so messages
+                // from different threads are not mixed.
+                let line = format_json(record, phase, &time);
+                let _ = stdout().lock().write_all(line.as_bytes());
+            }
+            LogFormat::Text => self.text.log(record),
+        }
+        if record.target() == TARGET_SYSLOG {
+            return;
+        }
+        if let Some(kmsg) = &self.kmsg {
+            // Each write to /dev/kmsg is a separate record.
+            let line = format_kmsg(record);
+            let _ = kmsg.lock().unwrap().write_all(line.as_bytes());
+        }
+        let line = match format {
+            LogFormat::Json => format_json(record, phase, &time),
+            LogFormat::Text => format_text(record, &time),
+        };
+        self.boot_log.lock().unwrap().write_line(line);
+    }
+
+    fn flush(&self) {
+        let _ = stdout().flush();
+    }
+}
+
+#[derive(Serialize)]
+struct JsonMessage<'a> {
+    time: &'a str,
+    level: &'a str,
+    module: &'a str,
+    phase: Phase,
+    message: String,
+}
+
+// Initialize the logger at every level, so the level can be raised when the
+// configuration is reloaded.
+pub fn init(format: LogFormat) -> Result<()> {
+    // Logging to the console is enough if the kernel log is unavailable.
+    let kmsg = OpenOptions::new()
+        .write(true)
+        .open(constants::FILE_DEV_KMSG)
+        .map_err(|e| eprintln!("Unable to open {}: {}", constants::FILE_DEV_KMSG, e))
+        .ok()
+        .map(Mutex::new);
+    let logger = LOGGER.get_or_init(|| Logger {
+        boot_log: Mutex::new(BootLog::default()),
+        format: Mutex::new(format),
+        kmsg,
+        levels: Mutex::new(Levels::default()),
+        text: SimpleLogger::new().with_level(LevelFilter::Trace),
+    });
+    log::set_logger(logger).map_err(|e| anyhow!("unable to initialize logger: {}", e))?;
+    log::set_max_level(LevelFilter::Trace);
+    Ok(())
+}
+
+// Write messages to the boot log file from now on, starting with the recent messages
+// that were logged before it was set.
+pub fn set_boot_log(file: RotatingFile) {
+    if let Some(logger) = LOGGER.get() {
+        logger.boot_log.lock().unwrap().set_file(file);
+    }
+}
+
+// Get the most recent messages, in the format of the boot log.
+pub fn recent_messages() -> String {
+    LOGGER
+        .get()
+        .map(|logger| {
+            logger
+                .boot_log
+                .lock()
+                .unwrap()
+                .recent
+                .iter()
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Set the format of later messages, such as when user data configures another
+// format than the kernel command line.
+pub fn set_format(format: LogFormat) {
+    if let Some(logger) = LOGGER.get() {
+        *logger.format.lock().unwrap() = format;
+    }
+}
+
+// Set the level of messages that are logged, and of messages of modules that have
+// their own levels.
+pub fn set_levels(default: LevelFilter, modules: Vec<(String, LevelFilter)>) {
+    let levels = Levels { default, modules };
+    log::set_max_level(levels.max());
+    if let Some(logger) = LOGGER.get() {
+        *logger.levels.lock().unwrap() = levels;
+    }
+}
+
+// Set the phase of the boot that is included in later messages.
+pub fn set_phase(phase: Phase) {
+    *PHASE.lock().unwrap() = phase;
+}
+
+// Get the log format from the contents of /proc/cmdline, such as
+// easyto.log-format=json.
+pub fn parse_cmdline_log_format(cmdline: &str) -> Option<LogFormat> {
+    match cmdline_param(cmdline, CMDLINE_LOG_FORMAT)? {
+        "json" => Some(LogFormat::Json),
+        "text" => Some(LogFormat::Text),
+        _ => None,
+    }
+}
+
+// Get the log level from the contents of /proc/cmdline, from easyto.log-level, or
+// trace if easyto.debug is given.
+pub fn parse_cmdline_log_level(cmdline: &str) -> Option<LevelFilter> {
+    if let Some(level) = cmdline_param(cmdline, CMDLINE_LOG_LEVEL) {
+        return level.parse().ok();
+    }
+    match cmdline_param(cmdline, CMDLINE_DEBUG)? {
+        "" | "1" | "true" => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+// Get the value of a kernel command line parameter, which is empty if the parameter
+// is given without one. The last occurrence of the parameter is used.
+fn cmdline_param<'a>(cmdline: &'a str, name: &str) -> Option<&'a str> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|param| match param.strip_prefix(name)? {
+            "" => Some(""),
+            rest => rest.strip_prefix('='),
+        })
+        .next_back()
+}
+
+fn format_json(record: &Record, phase: Phase, time: &str) -> String {
+    let message = JsonMessage {
+        time,
+        level: record.level().as_str(),
+        module: record.module_path().unwrap_or(record.target()),
+        phase,
+        message: record.args().to_string(),
+    };
+    let mut line = serde_json::to_string(&message).unwrap_or_default();
+    line.push('\n');
+    line
+}
+
+fn format_text(record: &Record, time: &str) -> String {
+    format!(
+        "{} {:<5} [{}] {}\n",
+        time,
+        record.level(),
+        record.module_path().unwrap_or(record.target()),
+        record.args()
+    )
+}
+
+// Format a record for /dev/kmsg, with a priority prefix of the user facility and the
+// syslog severity of its level. The kernel adds its own timestamp.
+fn format_kmsg(record: &Record) -> String {
+    const FACILITY_USER: u8 = 1;
+    let severity = match record.level() {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+    let mut message = record.args().to_string();
+    if message.len() > MAX_KMSG_MESSAGE_SIZE {
+        let mut end = MAX_KMSG_MESSAGE_SIZE;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+    format!(
+        "<{}>easyto-init: {}\n",
+        (FACILITY_USER << 3) | severity,
+        message.trim_end()
+    )
+}
+
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default();
+    DateTime::from_timestamp_millis(now)
+        .map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, true))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_format_json() {
+        let record = Record::builder()
+            .args(format_args!("Mounted \"/data\""))
+            .level(Level::Info)
+            .module_path(Some("easyto_init::init"))
+            .build();
+        assert_eq!(
+            format_json(&record, Phase::Initializing, "2024-09-12T08:22:00.123Z"),
+            concat!(
+                r#"{"time":"2024-09-12T08:22:00.123Z","level":"INFO","module":"easyto_init::init","#,
+                r#""phase":"initializing","message":"Mounted \"/data\""}"#,
+                "\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_boot_log() {
+        let dir = std::env::temp_dir().join(format!("boot-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("init.log");
+
+        let mut boot_log = BootLog::default();
+        for n in 0..MAX_RECENT_MESSAGES + 2 {
+            boot_log.write_line(format!("{}\n", n));
+        }
+        assert_eq!(boot_log.recent.len(), MAX_RECENT_MESSAGES);
+        assert_eq!(boot_log.recent[0], "2\n");
+
+        let mut boot_log = BootLog::default();
+        boot_log.write_line("Loading\n".into());
+        boot_log.set_file(RotatingFile::new(&path, 1024, 1));
+        boot_log.write_line("Starting\n".into());
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "Loading\nStarting\n"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_format_kmsg() {
+        struct Case {
+            level: Level,
+            message: String,
+            expected: String,
+        }
+        let cases = [
+            Case {
+                level: Level::Error,
+                message: "Unable to mount /data".into(),
+                expected: "<11>easyto-init: Unable to mount /data\n".into(),
+            },
+            Case {
+                level: Level::Info,
+                message: "Starting main process\n".into(),
+                expected: "<14>easyto-init: Starting main process\n".into(),
+            },
+            Case {
+                level: Level::Trace,
+                message: "é".repeat(MAX_KMSG_MESSAGE_SIZE),
+                expected: format!(
+                    "<15>easyto-init: {}\n",
+                    "é".repeat(MAX_KMSG_MESSAGE_SIZE / 2)
+                ),
+            },
+        ];
+        for case in cases {
+            let kmsg = format_kmsg(
+                &Record::builder()
+                    .args(format_args!("{}", case.message))
+                    .level(case.level)
+                    .build(),
+            );
+            assert_eq!(kmsg, case.expected);
+        }
+    }
+
+    #[test]
+    fn test_format_text() {
+        let record = Record::builder()
+            .args(format_args!("Mounted /data"))
+            .level(Level::Warn)
+            .module_path(Some("easyto_init::init"))
+            .build();
+        assert_eq!(
+            format_text(&record, "2024-09-12T08:22:00.123Z"),
+            "2024-09-12T08:22:00.123Z WARN  [easyto_init::init] Mounted /data\n"
+        );
+    }
+
+    #[test]
+    fn test_levels() {
+        let levels = Levels {
+            default: LevelFilter::Info,
+            modules: vec![
+                ("service".into(), LevelFilter::Debug),
+                ("syslog".into(), LevelFilter::Warn),
+                ("aws::s3".into(), LevelFilter::Trace),
+                ("aws".into(), LevelFilter::Error),
+            ],
+        };
+        struct Case {
+            target: &'static str,
+            expected: LevelFilter,
+        }
+        let cases = [
+            Case {
+                target: "easyto_init::service",
+                expected: LevelFilter::Debug,
+            },
+            Case {
+                target: "syslog",
+                expected: LevelFilter::Warn,
+            },
+            Case {
+                target: "easyto_init::aws::s3",
+                expected: LevelFilter::Trace,
+            },
+            Case {
+                target: "easyto_init::aws::ssm",
+                expected: LevelFilter::Error,
+            },
+            Case {
+                target: "easyto_init::services",
+                expected: LevelFilter::Info,
+            },
+            Case {
+                target: "easyto_init::init",
+                expected: LevelFilter::Info,
+            },
+        ];
+        for case in cases {
+            assert_eq!(levels.level(case.target), case.expected, "{}", case.target);
+        }
+        assert_eq!(levels.max(), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn test_parse_cmdline_log_level() {
+        struct Case {
+            cmdline: &'static str,
+            expected: Option<LevelFilter>,
+        }
+        let cases = [
+            Case {
+                cmdline: "console=ttyS0 easyto.log-level=debug\n",
+                expected: Some(LevelFilter::Debug),
+            },
+            Case {
+                cmdline: "console=ttyS0 easyto.debug",
+                expected: Some(LevelFilter::Trace),
+            },
+            Case {
+                cmdline: "easyto.debug=0",
+                expected: None,
+            },
+            Case {
+                cmdline: "easyto.debug easyto.log-level=warn",
+                expected: Some(LevelFilter::Warn),
+            },
+            Case {
+                cmdline: "easyto.debugger easyto.log-level=loud",
+                expected: None,
+            },
+            Case {
+                cmdline: "console=ttyS0",
+                expected: None,
+            },
+        ];
+        for case in cases {
+            assert_eq!(
+                parse_cmdline_log_level(case.cmdline),
+                case.expected,
+                "{}",
+                case.cmdline
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_cmdline_log_format() {
+        struct Case {
+            cmdline: &'static str,
+            expected: Option<LogFormat>,
+        }
+        let cases = [
+            Case {
+                cmdline: "console=ttyS0 easyto.log-format=json nvme_core.io_timeout=4294967295\n",
+                expected: Some(LogFormat::Json),
+            },
+            Case {
+                cmdline: "easyto.log-format=json easyto.log-format=text",
+                expected: Some(LogFormat::Text),
+            },
+            Case {
+                cmdline: "console=ttyS0 easyto.log-format-extra=json",
+                expected: None,
+            },
+            Case {
+                cmdline: "easyto.log-format=xml",
+                expected: None,
+            },
+        ];
+        for case in cases {
+            assert_eq!(
+                parse_cmdline_log_format(case.cmdline),
+                case.expected,
+                "{}",
+                case.cmdline
+            );
+        }
+    }
+}
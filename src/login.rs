@@ -1,16 +1,25 @@
 use std::fmt;
-use std::io::{BufRead, BufReader, Read};
+use std::fs::{read_to_string, File};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
 use std::path::Path;
 
-use rustix::fs::{chown, mkdir, Gid, Mode, Uid};
+use rustix::fs::{chmod, chown, mkdir, Dir, Gid, Mode, OpenOptionsExt, Uid};
 use rustix::io::Errno;
 use rustix::process::umask;
+use sha2::{Digest, Sha512};
+
+// Characters of the base64 encoding used by crypt(3).
+const CRYPT_ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+// The default number of rounds of SHA-512 crypt, which is left out of the hash.
+const SHA512_CRYPT_ROUNDS: usize = 5000;
 
 type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
     Errno(Errno),
+    Io(io::Error),
     ParseError(String),
 }
 
@@ -20,12 +29,19 @@ impl From<Errno> for Error {
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
 impl std::error::Error for Error {}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::Errno(e) => write!(f, "Errno: {}", e),
+            Self::Io(e) => write!(f, "I/O error: {}", e),
             Self::ParseError(details) => write!(f, "Invalid format: {}", details),
         }
     }
@@ -61,6 +77,40 @@ impl fmt::Display for PasswdEntry {
     }
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupEntry {
+    pub group_name: String,
+    pub password: String,
+    pub gid: GroupId,
+    pub members: Vec<String>,
+}
+
+impl fmt::Display for GroupEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}:{}",
+            self.group_name,
+            self.password,
+            self.gid,
+            self.members.join(",")
+        )
+    }
+}
+
+// Only the name and password are set, leaving password aging disabled.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShadowEntry {
+    pub user_name: String,
+    pub password: String,
+}
+
+impl fmt::Display for ShadowEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:::::::", self.user_name, self.password)
+    }
+}
+
 pub trait Find<T> {
     fn find(&self, name: &str) -> Option<T>;
 }
@@ -76,6 +126,17 @@ impl Find<PasswdEntry> for Vec<PasswdEntry> {
     }
 }
 
+impl Find<GroupEntry> for Vec<GroupEntry> {
+    fn find(&self, name: &str) -> Option<GroupEntry> {
+        for entry in self.iter() {
+            if entry.group_name == name {
+                return Some(entry.clone());
+            }
+        }
+        None
+    }
+}
+
 fn parse_passwd_line(line: &str, line_number: usize) -> Result<PasswdEntry> {
     let fields: Vec<&str> = line.split(":").collect();
     if fields.len() != 7 {
@@ -124,6 +185,73 @@ pub fn parse_passwd_lines<R: Read>(reader: R) -> Result<Vec<PasswdEntry>> {
     Ok(entry_list)
 }
 
+fn parse_group_line(line: &str, line_number: usize) -> Result<GroupEntry> {
+    let fields: Vec<&str> = line.split(":").collect();
+    if fields.len() != 4 {
+        return Err(Error::ParseError(format!(
+            "expected 4 fields on group line {}, got {}",
+            line_number + 1,
+            fields.len()
+        )));
+    }
+    let gid = fields[2].parse::<GroupId>().map_err(|e| {
+        Error::ParseError(format!(
+            "expected an integer in GID field on group line {}, got {}: {}",
+            line_number + 1,
+            fields[2],
+            e
+        ))
+    })?;
+    let members = fields[3]
+        .split(",")
+        .filter(|member| !member.is_empty())
+        .map(String::from)
+        .collect();
+    Ok(GroupEntry {
+        group_name: fields[0].into(),
+        password: fields[1].into(),
+        gid,
+        members,
+    })
+}
+
+pub fn parse_group_lines<R: Read>(reader: R) -> Result<Vec<GroupEntry>> {
+    let mut entry_list = Vec::new();
+    let buf_reader = BufReader::new(reader);
+
+    let lines = buf_reader.lines();
+    for (i, line) in lines.map_while(|l| l.ok()).enumerate() {
+        let entry = parse_group_line(&line, i + 1)?;
+        entry_list.push(entry);
+    }
+    Ok(entry_list)
+}
+
+// Append entries to a passwd, group, or shadow file, creating it if it does not exist.
+pub fn append_entries<T: fmt::Display>(path: &Path, entries: &[T]) -> Result<()> {
+    let mut f = File::options().create(true).append(true).open(path)?;
+    for entry in entries {
+        writeln!(f, "{}", entry)?;
+    }
+    Ok(())
+}
+
+// Replace the contents of a passwd, group, or shadow file with the given entries.
+pub fn write_entries<T: fmt::Display>(path: &Path, entries: &[T]) -> Result<()> {
+    let mut f = File::create(path)?;
+    for entry in entries {
+        writeln!(f, "{}", entry)?;
+    }
+    Ok(())
+}
+
+fn mkdir_exist_ok(path: &Path, mode: Mode) -> Result<()> {
+    match mkdir(path, mode) {
+        Ok(_) | Err(Errno::EXIST) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
 pub fn create_home_dir(home_dir: &Path, uid: u32, gid: u32) -> Result<()> {
     let old_mask = umask(Mode::empty());
     let parent = home_dir.parent().ok_or_else(|| {
@@ -133,9 +261,9 @@ pub fn create_home_dir(home_dir: &Path, uid: u32, gid: u32) -> Result<()> {
         ))
     })?;
     let ssh_dir = &home_dir.join(".ssh");
-    mkdir(parent, Mode::from_bits(0o755).unwrap())?;
-    mkdir(home_dir, Mode::from_bits(0o700).unwrap())?;
-    mkdir(ssh_dir, Mode::from_bits(0o700).unwrap())?;
+    mkdir_exist_ok(parent, Mode::from_bits(0o755).unwrap())?;
+    mkdir_exist_ok(home_dir, Mode::from_bits(0o700).unwrap())?;
+    mkdir_exist_ok(ssh_dir, Mode::from_bits(0o700).unwrap())?;
     let (uid, gid) = unsafe { (Uid::from_raw(uid), Gid::from_raw(gid)) };
     chown(home_dir, Some(uid), Some(gid))?;
     chown(ssh_dir, Some(uid), Some(gid))?;
@@ -143,6 +271,243 @@ pub fn create_home_dir(home_dir: &Path, uid: u32, gid: u32) -> Result<()> {
     Ok(())
 }
 
+// Replace the authorized_keys file in a user's .ssh directory with the given keys,
+// creating the directory if it does not exist.
+pub fn write_authorized_keys(ssh_dir: &Path, keys: &[String], uid: u32, gid: u32) -> Result<()> {
+    let (uid, gid) = unsafe { (Uid::from_raw(uid), Gid::from_raw(gid)) };
+    mkdir_exist_ok(ssh_dir, Mode::from_bits(0o700).unwrap())?;
+    chown(ssh_dir, Some(uid), Some(gid))?;
+    let key_path = ssh_dir.join("authorized_keys");
+    let mut f = File::options()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&key_path)?;
+    chown(&key_path, Some(uid), Some(gid))?;
+    chmod(&key_path, Mode::from_bits(0o640).unwrap())?;
+    for key in keys {
+        writeln!(f, "{}", key)?;
+    }
+    Ok(())
+}
+
+// Return the name of the login user, which is the single directory under home_base
+// if the image was built with ssh enabled.
+pub fn login_user(home_base: &Path) -> Result<Option<String>> {
+    for entry_res in Dir::read_from(File::open(home_base)?)? {
+        let entry = entry_res?;
+        let entry_name = entry.file_name().to_string_lossy().to_string();
+        if entry_name == "." || entry_name == ".." {
+            continue;
+        }
+        return Ok(Some(entry_name));
+    }
+    Ok(None)
+}
+
+// Set the password hash of a user in a shadow file, keeping the other fields of its
+// entry, or adding an entry if the user has none.
+pub fn set_password(shadow_path: &Path, user_name: &str, password_hash: &str) -> Result<()> {
+    let contents = match read_to_string(shadow_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let mut found = false;
+    let mut lines = Vec::new();
+    for line in contents.lines() {
+        let mut fields: Vec<&str> = line.split(":").collect();
+        if fields.len() > 1 && fields[0] == user_name {
+            fields[1] = password_hash;
+            found = true;
+            lines.push(fields.join(":"));
+        } else {
+            lines.push(line.to_string());
+        }
+    }
+    if !found {
+        lines.push(
+            ShadowEntry {
+                user_name: user_name.into(),
+                password: password_hash.into(),
+            }
+            .to_string(),
+        );
+    }
+    // The mode only applies if the file is created.
+    let mut f = File::options()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(shadow_path)?;
+    for line in lines {
+        writeln!(f, "{}", line)?;
+    }
+    Ok(())
+}
+
+// Hash a password with SHA-512 crypt and a random salt, for a shadow file.
+pub fn hash_password(password: &str) -> Result<String> {
+    let mut random = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut random)?;
+    let salt = random
+        .iter()
+        .map(|b| CRYPT_ALPHABET[(b % 64) as usize])
+        .collect::<Vec<_>>();
+    Ok(sha512_crypt(password.as_bytes(), &salt))
+}
+
+// The SHA-512 crypt algorithm as specified at https://www.akkadia.org/drepper/SHA-crypt.txt,
+// with the default number of rounds and a salt of at most 16 bytes.
+fn sha512_crypt(password: &[u8], salt: &[u8]) -> String {
+    let salt = &salt[..salt.len().min(16)];
+
+    let digest_b = Sha512::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize();
+
+    let mut hasher = Sha512::new().chain_update(password).chain_update(salt);
+    for chunk in password.chunks(64) {
+        hasher.update(&digest_b[..chunk.len()]);
+    }
+    let mut n = password.len();
+    while n > 0 {
+        if n & 1 == 1 {
+            hasher.update(digest_b);
+        } else {
+            hasher.update(password);
+        }
+        n >>= 1;
+    }
+    let digest_a = hasher.finalize();
+
+    let mut hasher = Sha512::new();
+    for _ in 0..password.len() {
+        hasher.update(password);
+    }
+    let digest_p = hasher.finalize();
+    let p_bytes = digest_p
+        .iter()
+        .cycle()
+        .take(password.len())
+        .copied()
+        .collect::<Vec<_>>();
+
+    let mut hasher = Sha512::new();
+    for _ in 0..16 + digest_a[0] as usize {
+        hasher.update(salt);
+    }
+    let digest_s = hasher.finalize();
+    let s_bytes = &digest_s[..salt.len()];
+
+    let mut digest_c = digest_a;
+    for i in 0..SHA512_CRYPT_ROUNDS {
+        let mut hasher = Sha512::new();
+        if i % 2 == 1 {
+            hasher.update(&p_bytes);
+        } else {
+            hasher.update(digest_c);
+        }
+        if i % 3 != 0 {
+            hasher.update(s_bytes);
+        }
+        if i % 7 != 0 {
+            hasher.update(&p_bytes);
+        }
+        if i % 2 == 1 {
+            hasher.update(digest_c);
+        } else {
+            hasher.update(&p_bytes);
+        }
+        digest_c = hasher.finalize();
+    }
+
+    // The bytes of the final digest are encoded in groups of three, in this order.
+    const ORDER: [(usize, usize, usize); 21] = [
+        (0, 21, 42),
+        (22, 43, 1),
+        (44, 2, 23),
+        (3, 24, 45),
+        (25, 46, 4),
+        (47, 5, 26),
+        (6, 27, 48),
+        (28, 49, 7),
+        (50, 8, 29),
+        (9, 30, 51),
+        (31, 52, 10),
+        (53, 11, 32),
+        (12, 33, 54),
+        (34, 55, 13),
+        (56, 14, 35),
+        (15, 36, 57),
+        (37, 58, 16),
+        (59, 17, 38),
+        (18, 39, 60),
+        (40, 61, 19),
+        (62, 20, 41),
+    ];
+    let mut encoded = String::with_capacity(86);
+    let mut encode = |word: u32, chars: usize| {
+        for i in 0..chars {
+            encoded.push(CRYPT_ALPHABET[((word >> (6 * i)) & 0x3f) as usize] as char);
+        }
+    };
+    for (b2, b1, b0) in ORDER {
+        let word = (digest_c[b2] as u32) << 16 | (digest_c[b1] as u32) << 8 | digest_c[b0] as u32;
+        encode(word, 4);
+    }
+    encode(digest_c[63] as u32, 2);
+
+    format!("$6${}${}", String::from_utf8_lossy(salt), encoded)
+}
+
+// Rules for /etc/sudoers.d allowing each user to run any command as any user.
+pub fn sudoers_rules(users: &[String], require_password: bool) -> Result<String> {
+    let tag = if require_password { "" } else { "NOPASSWD: " };
+    let mut rules = String::new();
+    for user in users {
+        check_user_name(user)?;
+        rules.push_str(&format!("{} ALL=(ALL:ALL) {}ALL\n", user, tag));
+    }
+    Ok(rules)
+}
+
+// Rules for doas.conf allowing each user to run any command as any user.
+pub fn doas_rules(users: &[String], require_password: bool) -> Result<String> {
+    let option = if require_password { "" } else { " nopass" };
+    let mut rules = String::new();
+    for user in users {
+        check_user_name(user)?;
+        rules.push_str(&format!("permit{} {}\n", option, user));
+    }
+    Ok(rules)
+}
+
+// Reject names that could change the meaning of a rule in a configuration file, or
+// add entries to /etc/passwd or /etc/group.
+pub fn check_user_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'));
+    if !valid {
+        return Err(Error::ParseError(format!("invalid user name {:?}", name)));
+    }
+    Ok(())
+}
+
+// Reject values that would add fields or lines to an entry of /etc/passwd.
+pub fn check_entry_field(value: &str) -> Result<()> {
+    if value.contains([':', '\n']) {
+        return Err(Error::ParseError(format!("invalid value {:?}", value)));
+    }
+    Ok(())
+}
+
 pub fn user_group_id<T: Read>(rdr: BufReader<T>, name: &str) -> Result<u32> {
     fn is_numeric(s: &str) -> bool {
         s.chars().all(|c| c.is_ascii_digit())
@@ -276,4 +641,174 @@ mod test {
         let reader = contents.as_bytes();
         assert_eq!(true, parse_passwd_lines(reader).is_err());
     }
+
+    #[test]
+    fn test_parse_group_lines() {
+        let contents = vec!["root:x:0:", "wheel:x:10:root,cloudboss"].join("\n");
+        let reader = contents.as_bytes();
+        match parse_group_lines(reader) {
+            Ok(entries) => {
+                assert_eq!(
+                    entries,
+                    vec![
+                        GroupEntry {
+                            group_name: "root".into(),
+                            password: "x".into(),
+                            gid: 0,
+                            members: Vec::new(),
+                        },
+                        GroupEntry {
+                            group_name: "wheel".into(),
+                            password: "x".into(),
+                            gid: 10,
+                            members: vec!["root".into(), "cloudboss".into()],
+                        },
+                    ]
+                );
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_parse_group_lines_bad_gid() {
+        let contents = "wheel:x:bad_gid:root";
+        let reader = contents.as_bytes();
+        assert_eq!(true, parse_group_lines(reader).is_err());
+    }
+
+    #[test]
+    fn test_write_authorized_keys() {
+        use std::os::unix::fs::PermissionsExt;
+
+        use rustix::process::{getgid, getuid};
+
+        let dir = std::env::temp_dir().join(format!("login-{}", std::process::id()));
+        let ssh_dir = dir.join(".ssh");
+        std::fs::create_dir_all(&dir).unwrap();
+        let keys = vec![
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG4f alice".to_string(),
+            "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQC7 bob".to_string(),
+        ];
+        let (uid, gid) = (getuid().as_raw(), getgid().as_raw());
+
+        write_authorized_keys(&ssh_dir, &keys, uid, gid).unwrap();
+        write_authorized_keys(&ssh_dir, &keys[1..], uid, gid).unwrap();
+
+        let key_path = ssh_dir.join("authorized_keys");
+        assert_eq!(
+            std::fs::read_to_string(&key_path).unwrap(),
+            "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQC7 bob\n"
+        );
+        let mode = std::fs::metadata(&key_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sha512_crypt() {
+        struct Case {
+            password: &'static str,
+            salt: &'static str,
+            expected: &'static str,
+        }
+        let cases = [
+            Case {
+                password: "Hello world!",
+                salt: "saltstring",
+                expected: "$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1",
+            },
+            Case {
+                password: "we have a short salt string but not a short password",
+                salt: "short",
+                expected: "$6$short$qmfj2meTBr5G2EAGIJ4vjX7RpefsD4JzpEyTAeEUJdzdxlBS6pe8gdMHm5zFftaFSj/2p2bjBwyVS9ZhWpLZt.",
+            },
+        ];
+        for case in cases {
+            assert_eq!(
+                sha512_crypt(case.password.as_bytes(), case.salt.as_bytes()),
+                case.expected
+            );
+        }
+        assert!(hash_password("secret").unwrap().starts_with("$6$"));
+    }
+
+    #[test]
+    fn test_set_password() {
+        let dir = std::env::temp_dir().join(format!("shadow-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let shadow_path = dir.join("shadow");
+        std::fs::write(
+            &shadow_path,
+            "root:*:19000:0:99999:7:::\ncloudboss:!:::::::\n",
+        )
+        .unwrap();
+
+        set_password(&shadow_path, "cloudboss", "$6$salt$hash").unwrap();
+        set_password(&shadow_path, "operator", "$6$salt$other").unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(&shadow_path).unwrap(),
+            "root:*:19000:0:99999:7:::\ncloudboss:$6$salt$hash:::::::\noperator:$6$salt$other:::::::\n"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_privilege_rules() {
+        struct Case {
+            users: Vec<String>,
+            require_password: bool,
+            sudoers: Option<&'static str>,
+            doas: Option<&'static str>,
+        }
+        let cases = [
+            Case {
+                users: vec!["cb-ssh".into()],
+                require_password: false,
+                sudoers: Some("cb-ssh ALL=(ALL:ALL) NOPASSWD: ALL\n"),
+                doas: Some("permit nopass cb-ssh\n"),
+            },
+            Case {
+                users: vec!["alice".into(), "bob.smith".into()],
+                require_password: true,
+                sudoers: Some("alice ALL=(ALL:ALL) ALL\nbob.smith ALL=(ALL:ALL) ALL\n"),
+                doas: Some("permit alice\npermit bob.smith\n"),
+            },
+            Case {
+                users: vec!["alice ALL=(ALL) NOPASSWD: ALL\nmallory".into()],
+                require_password: true,
+                sudoers: None,
+                doas: None,
+            },
+        ];
+        for case in cases {
+            assert_eq!(
+                sudoers_rules(&case.users, case.require_password).ok(),
+                case.sudoers.map(String::from)
+            );
+            assert_eq!(
+                doas_rules(&case.users, case.require_password).ok(),
+                case.doas.map(String::from)
+            );
+        }
+    }
+
+    #[test]
+    fn test_entries_display() {
+        let group = GroupEntry {
+            group_name: "wheel".into(),
+            password: "x".into(),
+            gid: 10,
+            members: vec!["root".into(), "cloudboss".into()],
+        };
+        assert_eq!(group.to_string(), "wheel:x:10:root,cloudboss");
+        let shadow = ShadowEntry {
+            user_name: "cloudboss".into(),
+            password: "!".into(),
+        };
+        assert_eq!(shadow.to_string(), "cloudboss:!:::::::");
+    }
 }
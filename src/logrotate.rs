@@ -0,0 +1,114 @@
+use std::{
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+// A log file that is rotated when it reaches a maximum size. The current file is
+// renamed with a .1 suffix, any existing .1 becomes .2, and so on, keeping at most
+// max_files rotated files.
+#[derive(Debug)]
+pub struct RotatingFile {
+    file: Option<File>,
+    max_files: u32,
+    max_size: u64,
+    path: PathBuf,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub fn new<P: AsRef<Path>>(path: P, max_size: u64, max_files: u32) -> Self {
+        Self {
+            file: None,
+            max_files,
+            max_size,
+            path: path.as_ref().to_path_buf(),
+            size: 0,
+        }
+    }
+
+    // Write a whole line, so output of different streams is not interleaved within
+    // a line. A line is never split across files.
+    pub fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.file.is_some() && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        if self.file.is_none() {
+            let file = File::options().create(true).append(true).open(&self.path)?;
+            self.size = file.metadata()?.len();
+            self.file = Some(file);
+        }
+        let file = self.file.as_mut().unwrap();
+        file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        if self.max_files == 0 {
+            return match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            };
+        }
+        for n in (1..self.max_files).rev() {
+            match fs::rename(self.rotated_path(n), self.rotated_path(n + 1)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))
+    }
+
+    fn rotated_path(&self, n: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir().join(format!("rotating-file-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("chrony.log");
+
+        let mut file = RotatingFile::new(&path, 10, 2);
+        for line in ["one\n", "two\n", "three\n", "four\n", "five\n"] {
+            file.write_line(line.as_bytes()).unwrap();
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "four\nfive\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("chrony.log.1")).unwrap(),
+            "three\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("chrony.log.2")).unwrap(),
+            "one\ntwo\n"
+        );
+
+        // The oldest file is dropped once there are max_files rotated files.
+        file.write_line(b"six\n").unwrap();
+        file.write_line(b"seven\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "six\nseven\n");
+        assert_eq!(
+            fs::read_to_string(dir.join("chrony.log.1")).unwrap(),
+            "four\nfive\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("chrony.log.2")).unwrap(),
+            "three\n"
+        );
+        assert!(!dir.join("chrony.log.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
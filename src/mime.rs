@@ -0,0 +1,192 @@
+use anyhow::{anyhow, Result};
+use base64::prelude::*;
+
+// A minimal parser for multipart/mixed documents, such as the user data
+// generated by cloud-init's make-mime or the EC2 launch wizard.
+
+#[derive(Debug, PartialEq)]
+pub struct Part {
+    pub body: Vec<u8>,
+    pub content_type: String,
+    pub filename: Option<String>,
+}
+
+pub fn is_multipart(document: &str) -> bool {
+    let (headers, _) = split_headers(document);
+    headers
+        .iter()
+        .any(|(name, value)| name == "content-type" && media_type(value).starts_with("multipart/"))
+}
+
+pub fn parse_multipart(document: &str) -> Result<Vec<Part>> {
+    let (headers, body) = split_headers(document);
+    let content_type = find_header(&headers, "content-type")
+        .ok_or_else(|| anyhow!("multipart document has no Content-Type header"))?;
+    let boundary = header_param(content_type, "boundary")
+        .ok_or_else(|| anyhow!("multipart document has no boundary"))?;
+    let delimiter = format!("--{}", boundary);
+    let terminator = format!("--{}--", boundary);
+
+    let mut parts = Vec::new();
+    let mut current: Option<Vec<&str>> = None;
+    for line in body.split('\n') {
+        let line_trimmed = line.trim_end_matches('\r');
+        if line_trimmed == delimiter || line_trimmed == terminator {
+            if let Some(lines) = current.take() {
+                parts.push(parse_part(&lines.join("\n"))?);
+            }
+            if line_trimmed == terminator {
+                break;
+            }
+            current = Some(Vec::new());
+        } else if let Some(lines) = current.as_mut() {
+            lines.push(line_trimmed);
+        }
+    }
+    Ok(parts)
+}
+
+fn parse_part(part: &str) -> Result<Part> {
+    let (headers, body) = split_headers(part);
+    let content_type = find_header(&headers, "content-type").unwrap_or("text/plain");
+    let filename = find_header(&headers, "content-disposition")
+        .and_then(|value| header_param(value, "filename"));
+    let body = match find_header(&headers, "content-transfer-encoding") {
+        Some(encoding) if encoding.eq_ignore_ascii_case("base64") => {
+            let encoded: String = body.split_whitespace().collect();
+            BASE64_STANDARD
+                .decode(encoded)
+                .map_err(|e| anyhow!("unable to decode base64 part: {}", e))?
+        }
+        _ => body.as_bytes().to_vec(),
+    };
+    Ok(Part {
+        body,
+        content_type: media_type(content_type),
+        filename,
+    })
+}
+
+// Split a document into its headers, with lowercased names, and its body.
+// Folded header lines are joined to the header they continue.
+fn split_headers(document: &str) -> (Vec<(String, String)>, &str) {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    let mut rest = document;
+    while !rest.is_empty() {
+        let (line, remaining) = match rest.find('\n') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, ""),
+        };
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() {
+            return (headers, remaining);
+        }
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_lowercase(), value.trim().to_string()));
+        } else {
+            // Not a header, so there are no more headers.
+            return (headers, rest);
+        }
+        rest = remaining;
+    }
+    (headers, rest)
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, value)| value.as_str())
+}
+
+fn media_type(value: &str) -> String {
+    value
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase()
+}
+
+fn header_param(value: &str, param: &str) -> Option<String> {
+    value.split(';').skip(1).find_map(|p| {
+        let (name, value) = p.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case(param) {
+            Some(value.trim().trim_matches('"').to_string())
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    const DOCUMENT: &str = "Content-Type: multipart/mixed;\r
+ boundary=\"==BOUNDARY==\"\r
+MIME-Version: 1.0\r
+\r
+--==BOUNDARY==\r
+Content-Type: text/yaml; charset=\"us-ascii\"\r
+\r
+debug: true\r
+--==BOUNDARY==\r
+Content-Type: text/x-shellscript\r
+Content-Disposition: attachment; filename=\"init.sh\"\r
+\r
+#!/bin/sh\r
+echo hello\r
+--==BOUNDARY==\r
+Content-Type: application/octet-stream\r
+Content-Disposition: attachment; filename=\"/etc/app.bin\"\r
+Content-Transfer-Encoding: base64\r
+\r
+YWJj\r
+--==BOUNDARY==--\r
+";
+
+    #[test]
+    fn test_is_multipart() {
+        assert_eq!(is_multipart(DOCUMENT), true);
+        assert_eq!(is_multipart("debug: true\n"), false);
+        assert_eq!(is_multipart("#cloud-config\nruncmd: []\n"), false);
+    }
+
+    #[test]
+    fn test_parse_multipart() {
+        let parts = parse_multipart(DOCUMENT).unwrap();
+        assert_eq!(
+            vec![
+                Part {
+                    body: b"debug: true".to_vec(),
+                    content_type: "text/yaml".into(),
+                    filename: None,
+                },
+                Part {
+                    body: b"#!/bin/sh\necho hello".to_vec(),
+                    content_type: "text/x-shellscript".into(),
+                    filename: Some("init.sh".into()),
+                },
+                Part {
+                    body: b"abc".to_vec(),
+                    content_type: "application/octet-stream".into(),
+                    filename: Some("/etc/app.bin".into()),
+                },
+            ],
+            parts
+        );
+    }
+
+    #[test]
+    fn test_parse_multipart_no_boundary() {
+        assert!(parse_multipart("Content-Type: multipart/mixed\n\n").is_err());
+    }
+}
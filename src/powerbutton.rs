@@ -0,0 +1,143 @@
+use std::{
+    fs::{read_dir, read_to_string, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+// The name the kernel gives the input device of the ACPI power button.
+const POWER_BUTTON_NAME: &str = "Power Button";
+
+// The size of struct input_event from include/uapi/linux/input.h on 64-bit
+// systems: a struct timeval followed by the type, code, and value.
+const INPUT_EVENT_SIZE: usize = 24;
+
+// Event type and key code from include/uapi/linux/input-event-codes.h.
+const EV_KEY: u16 = 0x01;
+const KEY_POWER: u16 = 116;
+
+// Find the event devices of ACPI power buttons, by the names of the input devices
+// under sys_input_dir. Their device files are in dev_input_dir.
+pub fn find_power_buttons<P: AsRef<Path>, Q: AsRef<Path>>(
+    sys_input_dir: P,
+    dev_input_dir: Q,
+) -> Result<Vec<PathBuf>> {
+    let sys_input_dir = sys_input_dir.as_ref();
+    let entries = read_dir(sys_input_dir)
+        .map_err(|e| anyhow!("unable to read {:?}: {}", sys_input_dir, e))?;
+    let mut devices = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if !file_name.starts_with("event") {
+            continue;
+        }
+        let name = match read_to_string(entry.path().join("device").join("name")) {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        if name.trim() == POWER_BUTTON_NAME {
+            devices.push(dev_input_dir.as_ref().join(file_name));
+        }
+    }
+    devices.sort();
+    Ok(devices)
+}
+
+// Wait for the power button to be pressed, returning an error if the device can
+// no longer be read.
+pub fn wait_pressed<P: AsRef<Path>>(device: P) -> Result<()> {
+    let device = device.as_ref();
+    let mut file = File::open(device).map_err(|e| anyhow!("unable to open {:?}: {}", device, e))?;
+    let mut buf = [0; INPUT_EVENT_SIZE];
+    loop {
+        file.read_exact(&mut buf)
+            .map_err(|e| anyhow!("unable to read {:?}: {}", device, e))?;
+        if is_power_pressed(&buf) {
+            return Ok(());
+        }
+    }
+}
+
+// Check whether an input event is a press of the power key. Releases have a value
+// of 0 and repeats a value of 2.
+fn is_power_pressed(event: &[u8; INPUT_EVENT_SIZE]) -> bool {
+    let event_type = u16::from_ne_bytes([event[16], event[17]]);
+    let code = u16::from_ne_bytes([event[18], event[19]]);
+    let value = i32::from_ne_bytes([event[20], event[21], event[22], event[23]]);
+    event_type == EV_KEY && code == KEY_POWER && value == 1
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn event(event_type: u16, code: u16, value: i32) -> [u8; INPUT_EVENT_SIZE] {
+        let mut buf = [0; INPUT_EVENT_SIZE];
+        buf[16..18].copy_from_slice(&event_type.to_ne_bytes());
+        buf[18..20].copy_from_slice(&code.to_ne_bytes());
+        buf[20..24].copy_from_slice(&value.to_ne_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_is_power_pressed() {
+        struct Case {
+            event: [u8; INPUT_EVENT_SIZE],
+            expected: bool,
+        }
+        let cases = [
+            Case {
+                event: event(EV_KEY, KEY_POWER, 1),
+                expected: true,
+            },
+            Case {
+                event: event(EV_KEY, KEY_POWER, 0),
+                expected: false,
+            },
+            Case {
+                event: event(EV_KEY, 1, 1),
+                expected: false,
+            },
+            Case {
+                // A synchronization event.
+                event: event(0, 0, 0),
+                expected: false,
+            },
+        ];
+        for case in cases {
+            assert_eq!(is_power_pressed(&case.event), case.expected);
+        }
+    }
+
+    #[test]
+    fn test_find_power_buttons() {
+        let dir = std::env::temp_dir().join(format!("power-buttons-{}", std::process::id()));
+        for (entry, name) in [
+            ("event0", "Power Button\n"),
+            ("event1", "AT Translated Set 2 keyboard\n"),
+            ("event2", "Power Button\n"),
+            ("input0", "Power Button\n"),
+        ] {
+            let device_dir = dir.join(entry).join("device");
+            fs::create_dir_all(&device_dir).unwrap();
+            fs::write(device_dir.join("name"), name).unwrap();
+        }
+
+        let devices = find_power_buttons(&dir, "/dev/input").unwrap();
+        assert_eq!(
+            devices,
+            vec![
+                PathBuf::from("/dev/input/event0"),
+                PathBuf::from("/dev/input/event2"),
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+use std::{
+    fmt,
+    fs::{read_dir, read_to_string},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use rustix::param::clock_ticks_per_second;
+use serde::{Deserialize, Serialize};
+
+// Resources used by a process, as written to the status document.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResourceUsage {
+    // The share of a CPU used since the previous sample, which is unknown for the
+    // first sample of a process.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_percent: Option<f64>,
+    // CPU time used in user and system mode since the process started.
+    pub cpu_seconds: f64,
+    pub fds: u32,
+    // The resident set size of the process.
+    pub memory_bytes: u64,
+}
+
+impl fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(cpu_percent) = self.cpu_percent {
+            write!(f, "cpu {:.1}%, ", cpu_percent)?;
+        }
+        write!(
+            f,
+            "cpu time {:.1}s, memory {} KiB, {} open files",
+            self.cpu_seconds,
+            self.memory_bytes / 1024,
+            self.fds
+        )
+    }
+}
+
+// A sample of the resources of a process, read from proc_dir.
+#[derive(Clone, Debug)]
+pub struct ProcessSample {
+    cpu_time: Duration,
+    fds: u32,
+    memory_bytes: u64,
+    time: Instant,
+}
+
+impl ProcessSample {
+    pub fn read<P: AsRef<Path>>(proc_dir: P, pid: u32) -> Result<Self> {
+        let pid_dir = proc_dir.as_ref().join(pid.to_string());
+        let stat_path = pid_dir.join("stat");
+        let stat = read_to_string(&stat_path)
+            .map_err(|e| anyhow!("unable to read {:?}: {}", stat_path, e))?;
+        let status_path = pid_dir.join("status");
+        let status = read_to_string(&status_path)
+            .map_err(|e| anyhow!("unable to read {:?}: {}", status_path, e))?;
+        let fd_path = pid_dir.join("fd");
+        let fds = read_dir(&fd_path)
+            .map_err(|e| anyhow!("unable to read {:?}: {}", fd_path, e))?
+            .count() as u32;
+        let ticks = parse_cpu_ticks(&stat)?;
+        Ok(Self {
+            cpu_time: Duration::from_secs_f64(ticks as f64 / clock_ticks_per_second() as f64),
+            fds,
+            memory_bytes: parse_rss_bytes(&status)?,
+            time: Instant::now(),
+        })
+    }
+
+    // The usage of the process, with the CPU used since a previous sample of it.
+    pub fn usage(&self, previous: Option<&ProcessSample>) -> ResourceUsage {
+        let cpu_percent = previous.and_then(|previous| {
+            let elapsed = self.time.duration_since(previous.time).as_secs_f64();
+            let used = self
+                .cpu_time
+                .saturating_sub(previous.cpu_time)
+                .as_secs_f64();
+            (elapsed > 0.0).then(|| (used / elapsed * 1000.0).round() / 10.0)
+        });
+        ResourceUsage {
+            cpu_percent,
+            cpu_seconds: (self.cpu_time.as_secs_f64() * 10.0).round() / 10.0,
+            fds: self.fds,
+            memory_bytes: self.memory_bytes,
+        }
+    }
+}
+
+// Get the user and system CPU time in clock ticks from the contents of
+// /proc/<pid>/stat. The command name may contain spaces and parentheses, so
+// fields are counted from the last closing parenthesis.
+fn parse_cpu_ticks(stat: &str) -> Result<u64> {
+    let fields = stat
+        .rfind(')')
+        .map(|i| stat[i + 1..].split_whitespace().collect::<Vec<_>>())
+        .unwrap_or_default();
+    // After the command name, utime and stime are the 12th and 13th fields.
+    let ticks = |i: usize| {
+        fields
+            .get(i)
+            .and_then(|field| field.parse::<u64>().ok())
+            .ok_or_else(|| anyhow!("invalid stat: {:?}", stat))
+    };
+    Ok(ticks(11)? + ticks(12)?)
+}
+
+// Get the resident set size from the contents of /proc/<pid>/status. Kernel
+// threads have no VmRSS.
+fn parse_rss_bytes(status: &str) -> Result<u64> {
+    let Some(line) = status.lines().find(|line| line.starts_with("VmRSS:")) else {
+        return Ok(0);
+    };
+    line.split_whitespace()
+        .nth(1)
+        .and_then(|kib| kib.parse::<u64>().ok())
+        .map(|kib| kib * 1024)
+        .ok_or_else(|| anyhow!("invalid status line: {:?}", line))
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_ticks() {
+        struct Case {
+            stat: &'static str,
+            expected: Option<u64>,
+        }
+        let cases = [
+            Case {
+                stat: "42 (sleep) S 1 42 42 0 -1 4194560 97 0 0 0 3 5 0 0 20 0 1 0 1234 2207744 131 18446744073709551615",
+                expected: Some(8),
+            },
+            Case {
+                stat: "43 (a (b) c) R 1 43 43 0 -1 4194560 97 0 0 0 10 20 0 0 20 0 1 0 1234 2207744 131 18446744073709551615",
+                expected: Some(30),
+            },
+            Case {
+                stat: "44 (short) S 1",
+                expected: None,
+            },
+        ];
+        for case in cases {
+            assert_eq!(
+                parse_cpu_ticks(case.stat).ok(),
+                case.expected,
+                "{}",
+                case.stat
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_rss_bytes() {
+        let status = "Name:\tsleep\nVmPeak:\t    2156 kB\nVmRSS:\t     524 kB\nThreads:\t1\n";
+        assert_eq!(parse_rss_bytes(status).unwrap(), 524 * 1024);
+        assert_eq!(parse_rss_bytes("Name:\tkthreadd\n").unwrap(), 0);
+    }
+}
@@ -1,98 +1,374 @@
 use std::{
+    collections::{HashMap, VecDeque},
     ffi::c_int,
     fs::File,
-    io::{self, ErrorKind, Read, Write},
-    os::unix::process::CommandExt,
-    path::Path,
-    process::{Command, ExitStatus},
-    sync::{Arc, Mutex, Once},
-    thread::{self, sleep},
-    time::Duration,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    mem,
+    net::{TcpStream, ToSocketAddrs},
+    ops::RangeInclusive,
+    os::unix::process::{CommandExt, ExitStatusExt},
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    slice,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, TryLockError,
+    },
+    thread::{self, sleep, JoinHandle},
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Result};
-use crossbeam::channel::{bounded, Receiver, Select, Sender};
-use log::{debug, error, info};
-use minaws::imds::Imds;
+use crossbeam::channel::{bounded, Receiver, RecvTimeoutError, Select, Sender};
+use log::{debug, error, info, warn};
 use rustix::{
-    fs::{chmod, chown, remount, stat, Dir, FileType, Gid, Mode, MountFlags, Uid},
+    fs::{
+        chmod, chown, remount, stat, sync, Dir, FileType, Gid, Mode, MountFlags, OpenOptionsExt,
+        Uid,
+    },
     io::Errno,
-    process::{kill_process, wait, Signal, WaitOptions},
-    thread::Pid,
+    process::{
+        getpid, kill_process, set_child_subreaper, setrlimit, wait, Resource, Rlimit, Signal,
+        WaitOptions, WaitStatus,
+    },
+    system::{reboot, RebootCommand},
+    thread::{set_no_new_privs, set_thread_gid, set_thread_groups, set_thread_uid, Pid},
 };
-use signal_hook::iterator::Signals;
+use signal_hook::{consts::SIGHUP, iterator::Signals};
 
 use crate::{
+    aws::{imds::CachedImds, LazyCredentials},
+    capabilities::CapabilityPlan,
     constants,
-    fs::mkdir_p,
+    control::{ControlSocket, Request, Response, ServiceState, ServiceStatus},
+    fs::{fstrim, mkdir_p, unmount_all},
+    logger,
     login::{self, Find},
-    vmspec::{NameValues, VmSpec},
+    logrotate::RotatingFile,
+    powerbutton::{find_power_buttons, wait_pressed},
+    resources::{ProcessSample, ResourceUsage},
+    spot::{self, InstanceEvent},
+    state,
+    status::{self, Phase, Status},
+    statusserver::StatusListener,
+    syslog::SyslogSink,
+    system::{check_oom_score_adj, find_executable_in_path, set_oom_score_adj},
+    vmspec::{
+        self, CrashLoop, CrashLoopAction, EventAction, ExitAction, HealthCheck, HostCertificate,
+        NameValue, NameValues, NameValuesExt, ReadinessProbe, RestartCondition, RestartPolicy,
+        SecretSource, ServiceDependencies, ServiceOverride, SidecarService, UnhealthyAction,
+        VmSpec, VolumeRefresh, Watchdog,
+    },
+    watchdog::WatchdogDevice,
 };
 
 // Signal sent by the "ACPI tiny power button" kernel driver, which causes the
 // kernel to send a signal to init. The kernel must be compiled to use this.
 const SIGPOWEROFF: c_int = 38;
 
+// Processes whose exit statuses are needed by the thread that started them. Any
+// child may be collected by the reaper first, so it records their statuses here.
+static WATCHED_PIDS: Mutex<Vec<(u32, Option<WaitStatus>)>> = Mutex::new(Vec::new());
+
+// Set to stop the reaper after processes are killed, so it does not collect the
+// exit statuses of shutdown scripts that are run after the supervisor.
+static STOP_REAPING: AtomicBool = AtomicBool::new(false);
+
+// Loads the configuration again, returning it with the environment resolved from it.
+type Reloader = Arc<dyn Fn() -> Result<(VmSpec, NameValues)> + Send + Sync>;
+
+// How often a watched process is checked for an exit status.
+const WATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+// How long to wait for the reaper to stop after processes are killed.
+const REAPER_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often the status document is checked for changes.
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+// How often to sample the resources used by supervised processes.
+const RESOURCES_INTERVAL: Duration = Duration::from_secs(60);
+
+// How long a process waits for those it depends on to start.
+const DEPENDENCY_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How often the watchdog checks the supervisor, and how long the supervisor
+// may hold its lock before the watchdog considers it stalled.
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(5);
+const WATCHDOG_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+// The link-local address of the Amazon Time Sync Service.
+const NTP_SERVER_AMAZON_TIME_SYNC: &str = "169.254.169.123";
+
 // Process flag for kernel threads, from include/linux/sched.h in kernel source.
 const PF_KTHREAD: u32 = 0x00200000;
 
+// A function run before a service is first started, which may be given the
+// configuration of its service.
+type InitFn = Box<dyn FnOnce() -> Result<()> + Send>;
+
 #[derive(Debug)]
 struct ServiceBase {
+    active: bool,
+    after: Vec<String>,
     args: Vec<String>,
+    capabilities: Option<CapabilityPlan>,
     env: NameValues,
+    exit_actions: Vec<(RangeInclusive<i32>, ExitAction)>,
     gid: Gid,
+    groups: Option<Vec<Gid>>,
     init: Option<fn() -> Result<()>>,
     init_rx: Receiver<()>,
     init_tx: Sender<()>,
+    limits: Vec<(Resource, Rlimit)>,
+    log: Option<Arc<Mutex<RotatingFile>>>,
+    no_new_privs: bool,
+    oom_score_adj: i32,
     optional: bool,
     pid: Option<u32>,
-    start_rx: Receiver<()>,
-    start_tx: Sender<()>,
+    readiness_probe: Option<ReadinessProbe>,
+    ready: bool,
+    reboot: bool,
+    requires: Vec<String>,
+    resources: Option<ResourceUsage>,
+    restart: bool,
+    restart_history: RestartHistory,
+    restart_policy: RestartPolicy,
+    stop_timeout: Option<Duration>,
     stop_rx: Receiver<io::Result<ExitStatus>>,
     stop_tx: Sender<io::Result<ExitStatus>>,
     shutdown: bool,
+    // Set when the process is left stopped after a crash loop.
+    stay_down: bool,
     uid: Uid,
+    unhealthy: bool,
     working_dir: String,
 }
 
 impl ServiceBase {
     fn command(&self) -> Command {
-        let args = &self.args;
+        self.command_with_args(&self.args)
+    }
+
+    // A command run with the user, environment, and limits of the service.
+    fn command_with_args(&self, args: &[String]) -> Command {
         let mut cmd = Command::new(&args[0]);
         cmd.args(&args[1..]);
         cmd.current_dir(&self.working_dir);
         for nv in &self.env {
             cmd.env(nv.name.clone(), nv.value.clone());
         }
-        cmd.gid(self.gid.as_raw());
-        cmd.uid(self.uid.as_raw());
+        // Command changes the user before running pre_exec, after which limits
+        // could no longer be raised, and it clears supplementary groups, so the
+        // whole change is done here instead.
+        let groups = self.groups.clone().unwrap_or_default();
+        let limits = self.limits.clone();
+        let capabilities = self.capabilities.clone();
+        let (gid, no_new_privs, uid) = (self.gid, self.no_new_privs, self.uid);
+        // Init's own score is inherited, so every process sets its own.
+        let oom_score_adj = self.oom_score_adj.to_string();
+        unsafe {
+            cmd.pre_exec(move || {
+                set_oom_score_adj(oom_score_adj.as_bytes())?;
+                for (resource, rlimit) in &limits {
+                    let rlimit = Rlimit {
+                        current: rlimit.current,
+                        maximum: rlimit.maximum,
+                    };
+                    setrlimit(*resource, rlimit)?;
+                }
+                if let Some(plan) = &capabilities {
+                    plan.apply_before_setuid()?;
+                }
+                set_thread_groups(&groups)?;
+                set_thread_gid(gid)?;
+                set_thread_uid(uid)?;
+                if let Some(plan) = &capabilities {
+                    plan.apply_after_setuid()?;
+                }
+                if no_new_privs {
+                    set_no_new_privs(true)?;
+                }
+                Ok(())
+            });
+        }
         cmd
     }
+
+    // Build the arguments of a built-in service, with its default flags unless the
+    // override replaces them, and the extra arguments of the override.
+    fn override_args(
+        exe: &Path,
+        flags: &[&str],
+        config_args: Vec<String>,
+        service_override: &ServiceOverride,
+    ) -> Vec<String> {
+        let mut args = vec![exe.to_string_lossy().to_string()];
+        if !service_override.replace_args.unwrap_or_default() {
+            args.extend(flags.iter().map(|flag| flag.to_string()));
+        }
+        args.extend(config_args);
+        args.extend(service_override.args.clone().unwrap_or_default());
+        args
+    }
+
+    // Apply the environment and user of an override of a built-in service.
+    fn apply_override(&mut self, service_override: &ServiceOverride) {
+        if let Some(env) = &service_override.env {
+            self.env = env.clone();
+        }
+        if let Some(uid) = service_override.run_as_user_id {
+            self.uid = unsafe { Uid::from_raw(uid) };
+        }
+        if let Some(gid) = service_override.run_as_group_id {
+            self.gid = unsafe { Gid::from_raw(gid) };
+        }
+        if let Some(oom_score_adj) = service_override.oom_score_adj {
+            self.oom_score_adj = oom_score_adj;
+        }
+        self.stop_timeout = service_override.stop_timeout.map(Duration::from_secs);
+    }
 }
 
 impl Default for ServiceBase {
     fn default() -> Self {
         let (err_send, err_recv) = bounded(1);
         let (init_send, init_recv) = bounded(1);
-        let (start_send, start_recv) = bounded(1);
         Self {
+            active: false,
+            after: Vec::new(),
             args: Vec::new(),
+            capabilities: None,
             working_dir: "/".into(),
             env: Vec::new(),
+            exit_actions: Vec::new(),
             gid: unsafe { Gid::from_raw(0) },
+            groups: None,
             uid: unsafe { Uid::from_raw(0) },
+            unhealthy: false,
             init: None,
             stop_rx: err_recv,
             stop_tx: err_send,
             init_rx: init_recv,
             init_tx: init_send,
+            limits: Vec::new(),
+            log: None,
+            no_new_privs: false,
+            oom_score_adj: 0,
             pid: None,
-            start_rx: start_recv,
-            start_tx: start_send,
+            readiness_probe: None,
+            ready: false,
+            reboot: false,
+            requires: Vec::new(),
+            resources: None,
+            restart: false,
+            restart_history: RestartHistory::default(),
+            restart_policy: RestartPolicy {
+                condition: Some(RestartCondition::Always),
+                ..Default::default()
+            },
+            stop_timeout: None,
             optional: false,
             shutdown: false,
+            stay_down: false,
+        }
+    }
+}
+
+// Restarts of a process since it last recovered, used to apply its restart policy.
+#[derive(Debug, Default)]
+struct RestartHistory {
+    // Restarts since a crash loop was detected, if the process is in one.
+    crash_loop_restarts: Option<u32>,
+    crash_loops: u32,
+    // Times of recent failed exits, used to detect a crash loop.
+    failure_times: VecDeque<Instant>,
+    failures: u32,
+    restarts: u32,
+    total_restarts: u32,
+}
+
+impl RestartHistory {
+    // Decide whether to restart a process that exited after running for the given
+    // time, returning the delay before starting it again.
+    fn next(
+        &mut self,
+        policy: &RestartPolicy,
+        success: bool,
+        ran_for: Duration,
+    ) -> Option<Duration> {
+        if !success {
+            self.failures += 1;
+        }
+        match policy.condition.unwrap_or(RestartCondition::Never) {
+            RestartCondition::Never => return None,
+            RestartCondition::OnFailure if success => return None,
+            _ => (),
+        }
+        if ran_for >= policy.max_backoff() {
+            self.restarts = 0;
+            self.crash_loop_restarts = None;
+            self.failure_times.clear();
+        }
+        if policy.max_restarts.is_some_and(|max| self.restarts >= max) {
+            return None;
+        }
+        // With crash loop detection, the backoff only grows once in a crash loop.
+        let doublings = match &policy.crash_loop {
+            Some(crash_loop) => {
+                if !success {
+                    self.record_failure(crash_loop);
+                }
+                match self.crash_loop_restarts {
+                    None => 0,
+                    Some(n) if n >= crash_loop.max_restarts() => return None,
+                    Some(n) => {
+                        self.crash_loop_restarts = Some(n + 1);
+                        n
+                    }
+                }
+            }
+            None => self.restarts,
+        };
+        let delay = policy
+            .backoff()
+            .saturating_mul(2u32.saturating_pow(doublings))
+            .min(policy.max_backoff());
+        self.restarts += 1;
+        self.total_restarts += 1;
+        debug!("Restart {} in total", self.total_restarts);
+        Some(delay)
+    }
+
+    // Record a failed exit, detecting a crash loop once enough failures are within
+    // the period.
+    fn record_failure(&mut self, crash_loop: &CrashLoop) {
+        let now = Instant::now();
+        self.failure_times.push_back(now);
+        while self.failure_times.len() > crash_loop.failures() as usize
+            || self
+                .failure_times
+                .front()
+                .is_some_and(|time| now.duration_since(*time) > crash_loop.period())
+        {
+            self.failure_times.pop_front();
+        }
+        if self.crash_loop_restarts.is_none()
+            && self.failure_times.len() == crash_loop.failures() as usize
+        {
+            self.crash_loop_restarts = Some(0);
+            self.crash_loops += 1;
         }
     }
+
+    // The action to take on a process that is not restarted because it used all the
+    // restarts of a crash loop.
+    fn crash_loop_action(&self, policy: &RestartPolicy) -> Option<CrashLoopAction> {
+        let crash_loop = policy.crash_loop.as_ref()?;
+        self.crash_loop_restarts
+            .filter(|n| *n >= crash_loop.max_restarts())
+            .map(|_| crash_loop.action())
+    }
 }
 
 fn wait_stop(rx: Receiver<io::Result<ExitStatus>>) -> io::Result<ExitStatus> {
@@ -112,8 +388,8 @@ trait Service: Send + Sync {
         self.base().command()
     }
 
-    fn init_fn(&self) -> Option<fn() -> Result<()>> {
-        self.base().init
+    fn init_fn(&self) -> Option<InitFn> {
+        self.base().init.map(|init| Box::new(init) as InitFn)
     }
 
     fn init_rx(&self) -> Receiver<()> {
@@ -130,14 +406,6 @@ trait Service: Send + Sync {
 
     fn name(&self) -> String;
 
-    fn start_rx(&self) -> Receiver<()> {
-        self.base().start_rx.clone()
-    }
-
-    fn start_tx(&self) -> Sender<()> {
-        self.base().start_tx.clone()
-    }
-
     fn stop_rx(&self) -> Receiver<io::Result<ExitStatus>> {
         self.base().stop_rx.clone()
     }
@@ -157,6 +425,11 @@ trait Service: Send + Sync {
     fn pid(&self) -> Option<u32> {
         self.base().pid
     }
+
+    // Sidecars are stopped before the services built into the image.
+    fn is_sidecar(&self) -> bool {
+        false
+    }
 }
 
 #[derive(Debug)]
@@ -185,12 +458,14 @@ impl Main {
         working_dir: String,
         env: NameValues,
         gid: Gid,
+        groups: Option<Vec<Gid>>,
         uid: Uid,
     ) -> Self {
         Self(ServiceBase {
             args,
             env,
             gid,
+            groups,
             uid,
             working_dir,
             ..Default::default()
@@ -199,18 +474,29 @@ impl Main {
 }
 
 #[derive(Debug, Default)]
-struct Chrony(ServiceBase);
+struct Chrony {
+    base: ServiceBase,
+    // The generated configuration, unless the override gives one.
+    config: Option<String>,
+    drift_path: PathBuf,
+}
 
 unsafe impl Send for Chrony {}
 unsafe impl Sync for Chrony {}
 
 impl Service for Chrony {
     fn base(&self) -> &ServiceBase {
-        &self.0
+        &self.base
     }
 
     fn base_mut(&mut self) -> &mut ServiceBase {
-        &mut self.0
+        &mut self.base
+    }
+
+    fn init_fn(&self) -> Option<InitFn> {
+        let config = self.config.clone();
+        let drift_path = self.drift_path.clone();
+        Some(Box::new(move || Self::init(config.as_deref(), &drift_path)))
     }
 
     fn name(&self) -> String {
@@ -219,7 +505,7 @@ impl Service for Chrony {
 }
 
 impl Chrony {
-    fn init() -> Result<()> {
+    fn init(config: Option<&str>, drift_path: &Path) -> Result<()> {
         info!("Initializing chrony");
 
         let passwd_file = File::open(constants::FILE_ETC_PASSWD)?;
@@ -233,36 +519,216 @@ impl Chrony {
         let (uid, gid) = unsafe { (Uid::from_raw(user.uid), (Gid::from_raw(user.gid))) };
         chown(&chrony_run_path, Some(uid), Some(gid))?;
 
+        if let Some(drift_dir) = drift_path.parent() {
+            mkdir_p(drift_dir, Mode::from(0o750))?;
+            chown(drift_dir, Some(uid), Some(gid))?;
+        }
+        if drift_path.exists() {
+            info!("Restoring chrony drift file {:?}", drift_path);
+            chown(drift_path, Some(uid), Some(gid))
+                .map_err(|e| anyhow!("unable to change owner of {:?}: {}", drift_path, e))?;
+        }
+
+        if let Some(config) = config {
+            let config_path = Self::generated_config_path();
+            std::fs::write(&config_path, config)
+                .map_err(|e| anyhow!("unable to write {:?}: {}", config_path, e))?;
+        }
+
         Ok(())
     }
 
-    pub fn new() -> Self {
-        let path = Path::new(constants::DIR_ET_SBIN)
-            .join("chronyd")
-            .to_string_lossy()
-            .to_string();
-        let args = vec![path, "-d".into()];
-        Self(ServiceBase {
+    pub fn new(
+        service_override: &ServiceOverride,
+        time: &vmspec::Time,
+        readonly_root_fs: bool,
+    ) -> Self {
+        let path = Path::new(constants::DIR_ET_SBIN).join("chronyd");
+        let drift_path = Self::drift_path(readonly_root_fs);
+        let (config_args, config) = match &service_override.config {
+            Some(config) => (vec!["-f".into(), config.clone()], None),
+            None => {
+                let phc = find_ena_phc(Path::new(constants::DIR_SYS_CLASS_PTP));
+                if let Some(phc) = &phc {
+                    info!("Using PTP hardware clock {:?} as a time source", phc);
+                }
+                let config_path = Self::generated_config_path();
+                (
+                    vec!["-f".into(), config_path.to_string_lossy().to_string()],
+                    Some(chrony_config(
+                        phc.as_deref(),
+                        time.ntp_servers(),
+                        &drift_path,
+                    )),
+                )
+            }
+        };
+        let args = ServiceBase::override_args(&path, &["-d"], config_args, service_override);
+        let mut base = ServiceBase {
+            args,
+            ..Default::default()
+        };
+        base.apply_override(service_override);
+        Self {
+            base,
+            config,
+            drift_path,
+        }
+    }
+
+    // The drift file is kept on the root volume so the clock does not have to be
+    // disciplined from scratch on every boot. A read-only root volume is remounted
+    // before chronyd starts, so then it is kept in the run directory instead.
+    fn drift_path(readonly_root_fs: bool) -> PathBuf {
+        let dir = if readonly_root_fs {
+            constants::DIR_ET_RUN
+        } else {
+            constants::DIR_ET_VAR
+        };
+        Path::new(dir).join("chrony").join("drift")
+    }
+
+    fn generated_config_path() -> PathBuf {
+        Path::new(constants::DIR_ET_RUN)
+            .join("chrony")
+            .join("chrony.conf")
+    }
+}
+
+// Find the PTP hardware clock of an ENA device, which Nitro instances that support
+// it expose as a PTP clock named ena-ptp-<n>.
+fn find_ena_phc(sys_class_ptp: &Path) -> Option<PathBuf> {
+    let mut names = std::fs::read_dir(sys_class_ptp)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    names.sort();
+    names.into_iter().find_map(|name| {
+        let clock_name =
+            std::fs::read_to_string(sys_class_ptp.join(&name).join("clock_name")).ok()?;
+        clock_name
+            .starts_with("ena")
+            .then(|| Path::new(constants::DIR_DEV).join(name))
+    })
+}
+
+// Render a chrony configuration that uses the given NTP servers, or the Amazon Time
+// Sync Service if there are none, preferring a PTP hardware clock if one is given,
+// which is much more accurate than NTP.
+fn chrony_config(phc: Option<&Path>, ntp_servers: &[String], drift_path: &Path) -> String {
+    let mut lines = vec![
+        format!("user {}", constants::USER_NAME_CHRONY),
+        format!("pidfile {}/chrony/chronyd.pid", constants::DIR_ET_RUN),
+        format!("driftfile {}", drift_path.to_string_lossy()),
+        "makestep 1.0 3".into(),
+        "rtcsync".into(),
+    ];
+    let server_prefer = match phc {
+        Some(phc) => {
+            lines.push(format!(
+                "refclock PHC {} poll 0 delay 0.000010 prefer",
+                phc.to_string_lossy()
+            ));
+            ""
+        }
+        None => " prefer",
+    };
+    if ntp_servers.is_empty() {
+        lines.push(format!(
+            "server {}{} iburst minpoll 4 maxpoll 4",
+            NTP_SERVER_AMAZON_TIME_SYNC, server_prefer
+        ));
+    } else {
+        lines.extend(
+            ntp_servers
+                .iter()
+                .map(|server| format!("server {} iburst", server)),
+        );
+    }
+    let mut config = lines.join("\n");
+    config.push('\n');
+    config
+}
+
+#[derive(Debug, Default)]
+struct SsmAgent(ServiceBase);
+
+unsafe impl Send for SsmAgent {}
+unsafe impl Sync for SsmAgent {}
+
+impl Service for SsmAgent {
+    fn base(&self) -> &ServiceBase {
+        &self.0
+    }
+
+    fn base_mut(&mut self) -> &mut ServiceBase {
+        &mut self.0
+    }
+
+    fn name(&self) -> String {
+        "ssm-agent".into()
+    }
+}
+
+impl SsmAgent {
+    // The agent is not shipped with easyto, so it is only run if the image has it.
+    fn find_executable() -> Option<PathBuf> {
+        find_executable_in_path("amazon-ssm-agent", constants::ENV_PATH)
+    }
+
+    fn init() -> Result<()> {
+        info!("Initializing amazon-ssm-agent");
+
+        // The agent keeps its registration and documents in its state directory,
+        // and creates ssm-user itself when a session is first started.
+        for dir in [constants::DIR_SSM_AGENT_LOG, constants::DIR_SSM_AGENT_STATE] {
+            mkdir_p(dir, Mode::from(0o750))
+                .map_err(|e| anyhow!("unable to create directory {}: {}", dir, e))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn new(path: &Path, service_override: &ServiceOverride) -> Self {
+        let args = ServiceBase::override_args(path, &[], Vec::new(), service_override);
+        let mut base = ServiceBase {
             args,
+            // Sessions run shell commands, which need a PATH.
+            env: vec![NameValue {
+                name: "PATH".into(),
+                value: constants::ENV_PATH.into(),
+            }],
             init: Some(Self::init),
+            optional: true,
             ..Default::default()
-        })
+        };
+        base.apply_override(service_override);
+        Self(base)
     }
 }
 
 #[derive(Debug, Default)]
-struct Ssh(ServiceBase);
+struct Ssh {
+    base: ServiceBase,
+    config: vmspec::Ssh,
+}
 
 unsafe impl Send for Ssh {}
 unsafe impl Sync for Ssh {}
 
 impl Service for Ssh {
     fn base(&self) -> &ServiceBase {
-        &self.0
+        &self.base
     }
 
     fn base_mut(&mut self) -> &mut ServiceBase {
-        &mut self.0
+        &mut self.base
+    }
+
+    fn init_fn(&self) -> Option<InitFn> {
+        let config = self.config.clone();
+        Some(Box::new(move || Self::init(&config)))
     }
 
     fn name(&self) -> String {
@@ -271,29 +737,46 @@ impl Service for Ssh {
 }
 
 impl Ssh {
-    pub fn new() -> Self {
+    pub fn new(service_override: &ServiceOverride, config: &vmspec::Ssh) -> Self {
         let path = Path::new(constants::DIR_ET_SBIN).join("sshd");
-        let sshd_config = Path::new(constants::DIR_ET_ETC)
-            .join("ssh")
-            .join("sshd_config");
-        let args = vec![
-            path.to_string_lossy().to_string(),
-            "-D".to_string(),
-            "-f".to_string(),
-            sshd_config.to_string_lossy().to_string(),
-            "-e".to_string(),
-        ];
-        Self(ServiceBase {
+        let sshd_config = service_override.config.clone().unwrap_or_else(|| {
+            Path::new(constants::DIR_ET_ETC)
+                .join("ssh")
+                .join("sshd_config")
+                .to_string_lossy()
+                .to_string()
+        });
+        let mut config_args = vec!["-f".into(), sshd_config];
+        if config.host_certificate.is_some() {
+            config_args.extend([
+                "-o".into(),
+                format!("HostKey={}", Self::host_key_path().to_string_lossy()),
+                "-o".into(),
+                format!(
+                    "HostCertificate={}",
+                    Self::host_certificate_path().to_string_lossy()
+                ),
+            ]);
+        }
+        let args = ServiceBase::override_args(&path, &["-D", "-e"], config_args, service_override);
+        let mut base = ServiceBase {
             args,
-            init: Some(Self::init),
             optional: true,
             ..Default::default()
-        })
+        };
+        base.apply_override(service_override);
+        Self {
+            base,
+            config: config.clone(),
+        }
     }
 
-    fn init() -> Result<()> {
+    fn init(config: &vmspec::Ssh) -> Result<()> {
         info!("Initializing sshd");
 
+        let imds = CachedImds::default();
+        let credentials = LazyCredentials::new(&imds);
+
         let login_user = Self::get_login_user()?;
         let passwd_file = File::open(constants::FILE_ETC_PASSWD)?;
         let user = login::parse_passwd_lines(passwd_file)?
@@ -301,8 +784,26 @@ impl Ssh {
             .ok_or_else(|| anyhow!("user {} not found", login_user))?;
 
         let ssh_dir = Path::new(&user.home_dir).join(".ssh");
-        let (uid, gid) = unsafe { (Uid::from_raw(user.uid), (Gid::from_raw(user.gid))) };
-        Self::ssh_write_pub_key(&ssh_dir, uid, gid)?;
+        let authorized_keys_from = config.authorized_keys_from();
+        let mut pub_keys = match Self::get_ssh_keys(&imds) {
+            Ok(pub_keys) => pub_keys,
+            // An instance launched without a key pair may get keys from other sources.
+            Err(e) if !authorized_keys_from.is_empty() => {
+                debug!("Unable to get instance public keys: {}", e);
+                Vec::new()
+            }
+            Err(e) => return Err(e),
+        };
+        for pub_key in Self::get_authorized_keys_from(authorized_keys_from, &imds, &credentials)? {
+            if !pub_keys.contains(&pub_key) {
+                pub_keys.push(pub_key);
+            }
+        }
+        if pub_keys.is_empty() {
+            return Err(anyhow!("no public keys found"));
+        }
+        login::write_authorized_keys(&ssh_dir, &pub_keys, user.uid, user.gid)
+            .map_err(|e| anyhow!("unable to write authorized keys to {:?}: {}", ssh_dir, e))?;
 
         let rsa_key_path = Path::new(constants::DIR_ET_ETC)
             .join("ssh")
@@ -318,6 +819,16 @@ impl Ssh {
             Self::ssh_keygen("ed25519", &ed25519_key_path)?;
         }
 
+        if let Some(host_certificate) = &config.host_certificate {
+            match Self::write_host_certificate(host_certificate, &imds, &credentials) {
+                Ok(()) => (),
+                Err(e) if host_certificate.is_optional() => {
+                    warn!("Host certificate is optional, skipping: {}", e)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
         Ok(())
     }
 
@@ -335,47 +846,177 @@ impl Ssh {
         Ok(())
     }
 
-    fn ssh_write_pub_key(dir: &Path, uid: Uid, gid: Gid) -> Result<()> {
-        let pub_key = Self::get_ssh_key()?;
-        let key_path = Path::new(dir).join("authorized_keys");
-        let mut file = File::options()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(&key_path)
-            .map_err(|e| anyhow!("unable to open {:?}: {}", key_path, e))?;
-        chown(&key_path, Some(uid), Some(gid))
-            .map_err(|e| anyhow!("unable to change ownership of {:?}: {}", key_path, e))?;
-        chmod(&key_path, Mode::from(0o640))
-            .map_err(|e| anyhow!("unable to change permissions on {:?}: {}", key_path, e))?;
-        file.write_all(pub_key.as_bytes())
-            .map_err(|e| anyhow!("unable to write {:?}: {}", key_path, e))?;
+    // The host key and certificate are kept apart from the generated host keys, as
+    // they are replaced from their sources on every boot.
+    fn host_key_path() -> PathBuf {
+        Path::new(constants::DIR_ET_ETC)
+            .join("ssh")
+            .join("ssh_host_cert_key")
+    }
+
+    fn host_certificate_path() -> PathBuf {
+        Path::new(constants::DIR_ET_ETC)
+            .join("ssh")
+            .join("ssh_host_cert_key-cert.pub")
+    }
+
+    fn write_host_certificate(
+        host_certificate: &HostCertificate,
+        imds: &CachedImds,
+        credentials: &LazyCredentials,
+    ) -> Result<()> {
+        for (source, path, mode) in [
+            (&host_certificate.key, Self::host_key_path(), 0o600),
+            (
+                &host_certificate.certificate,
+                Self::host_certificate_path(),
+                0o644,
+            ),
+        ] {
+            let contents = source.fetch(imds, credentials)?;
+            let mut file = File::options()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .mode(mode)
+                .open(&path)
+                .map_err(|e| anyhow!("unable to open {:?}: {}", path, e))?;
+            // Set the mode explicitly as the file may have existed with another mode.
+            chmod(&path, Mode::from(mode))
+                .map_err(|e| anyhow!("unable to change permissions on {:?}: {}", path, e))?;
+            file.write_all(&contents)
+                .map_err(|e| anyhow!("unable to write {:?}: {}", path, e))?;
+        }
         Ok(())
     }
 
-    // Return the login username for the system. If the image was built with ssh
-    // enabled, this will be the name of the single directory under /.easyto/home.
     fn get_login_user() -> Result<String> {
-        let dir_fd = File::open(constants::DIR_ET_HOME)?;
-        for entry_res in Dir::read_from(dir_fd)? {
-            let entry = entry_res?;
-            let entry_name = entry.file_name().to_string_lossy().to_string();
-            if entry_name == "." || entry_name == ".." {
-                continue;
+        login::login_user(Path::new(constants::DIR_ET_HOME))?
+            .ok_or_else(|| anyhow!("login user not found"))
+    }
+
+    // Get the public keys of all the key pairs the instance was launched with,
+    // skipping any that cannot be fetched.
+    fn get_ssh_keys(imds: &CachedImds) -> Result<Vec<String>> {
+        let index = imds
+            .get_metadata(Path::new("public-keys"))
+            .map_err(|e| anyhow!("unable to list public keys: {}", e))?;
+        let mut keys = Vec::new();
+        for key_index in parse_public_key_indices(&index) {
+            let path = Path::new("public-keys").join(key_index).join("openssh-key");
+            match imds.get_metadata(&path) {
+                Ok(key) => keys.push(key.trim().to_string()),
+                Err(e) => error!("Unable to get public key {}: {}", key_index, e),
+            }
+        }
+        Ok(keys)
+    }
+
+    // Get the public keys from each source, skipping optional sources that cannot
+    // be fetched.
+    fn get_authorized_keys_from(
+        sources: &[SecretSource],
+        imds: &CachedImds,
+        credentials: &LazyCredentials,
+    ) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for source in sources {
+            let document = source.fetch(imds, credentials).and_then(|document| {
+                String::from_utf8(document).map_err(|e| anyhow!("invalid authorized keys: {}", e))
+            });
+            match document {
+                Ok(document) => keys.extend(parse_authorized_keys(&document).map(String::from)),
+                Err(e) if source.is_optional() => {
+                    debug!("Authorized keys source is optional, skipping: {}", e)
+                }
+                Err(e) => return Err(e),
             }
-            return Ok(entry_name);
         }
-        Err(anyhow!("login user not found"))
+        Ok(keys)
+    }
+}
+
+// Get the keys from a document in authorized_keys format, without blank lines or
+// comments.
+fn parse_authorized_keys(document: &str) -> impl Iterator<Item = &str> {
+    document
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+// A service defined in user data rather than built into the image.
+#[derive(Debug)]
+struct Sidecar {
+    base: ServiceBase,
+    name: String,
+}
+
+unsafe impl Send for Sidecar {}
+unsafe impl Sync for Sidecar {}
+
+impl Service for Sidecar {
+    fn base(&self) -> &ServiceBase {
+        &self.base
+    }
+
+    fn base_mut(&mut self) -> &mut ServiceBase {
+        &mut self.base
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
     }
 
-    fn get_ssh_key() -> Result<String> {
-        Imds::default()
-            .get_metadata(Path::new("public-keys/0/openssh-key"))
-            .map_err(Into::into)
+    fn is_sidecar(&self) -> bool {
+        true
+    }
+}
+
+impl Sidecar {
+    fn new(sidecar: &SidecarService, main_env: &NameValues, uid: Uid, gid: Gid) -> Result<Self> {
+        let env = main_env.merge(&sidecar.env.clone().unwrap_or_default());
+        let args = sidecar.full_command(&env)?;
+        let policy = sidecar.restart_policy.clone().unwrap_or_default();
+        let (uid, gid) = unsafe {
+            (
+                sidecar.run_as_user_id.map_or(uid, |id| Uid::from_raw(id)),
+                sidecar.run_as_group_id.map_or(gid, |id| Gid::from_raw(id)),
+            )
+        };
+        Ok(Self {
+            base: ServiceBase {
+                args,
+                env,
+                gid,
+                oom_score_adj: sidecar.oom_score_adj.unwrap_or_default(),
+                restart_policy: RestartPolicy {
+                    condition: policy.condition.or(Some(RestartCondition::Always)),
+                    ..policy
+                },
+                stop_timeout: sidecar.stop_timeout.map(Duration::from_secs),
+                uid,
+                working_dir: sidecar.working_dir.clone().unwrap_or_else(|| "/".into()),
+                ..Default::default()
+            },
+            name: sidecar.name.clone(),
+        })
     }
 }
 
+// What to do with the instance once the supervisor has stopped all processes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PowerAction {
+    // Reboot into a kernel loaded with kexec.
+    Kexec,
+    Poweroff,
+    Reboot,
+}
+
 pub struct SupervisorBase {
+    disabled_services: Vec<String>,
+    drain_command: Vec<String>,
+    drain_timeout: Duration,
     main_ref: Arc<Mutex<dyn Service>>,
     readonly_root_fs: bool,
     service_refs: Vec<Arc<Mutex<dyn Service>>>,
@@ -401,12 +1042,32 @@ impl SupervisorBase {
         Ok(flags & PF_KTHREAD != 0)
     }
 
+    // Return the services followed by the main process.
+    fn all_refs(&self) -> Vec<Arc<Mutex<dyn Service>>> {
+        let mut all_refs = self.service_refs.clone();
+        all_refs.push(self.main_ref.clone());
+        all_refs
+    }
+
+    fn status(&self) -> Status {
+        let phase = if self.shutdown {
+            Phase::ShuttingDown
+        } else {
+            Phase::Running
+        };
+        Status {
+            main: Some(service_status(&self.main_ref)),
+            services: self.service_refs.iter().map(service_status).collect(),
+            ..Status::new(phase)
+        }
+    }
+
     fn kill(&self) -> Result<()> {
         self.signal(Signal::Kill)
     }
 
     // Return the PIDs of all current non-kernel processes excluding init.
-    fn pids(&self) -> Result<Vec<u32>> {
+    fn pids() -> Result<Vec<u32>> {
         let mut pids = Vec::with_capacity(100);
         let dir_fd = File::open(constants::DIR_PROC)?;
         for dir_entry_res in Dir::read_from(dir_fd)? {
@@ -437,9 +1098,13 @@ impl SupervisorBase {
         Ok(pids)
     }
 
-    fn start(&mut self) -> Result<()> {
+    // Initialize the services and work out the order processes are started in. This
+    // is done while the supervisor is locked, but starting them is not, as it waits
+    // for dependencies.
+    fn prepare_start(&mut self) -> Result<StartPlan> {
+        let mut failed = Vec::new();
         for service_ref in &self.service_refs {
-            match start_service(service_ref.clone()) {
+            match init_service(service_ref.clone()) {
                 Ok(_) => (),
                 Err(e) => {
                     let service = service_ref.lock().unwrap();
@@ -450,7 +1115,13 @@ impl SupervisorBase {
                             "Optional service {} failed to start: {}",
                             &service.name(),
                             e
-                        )
+                        );
+                        status::record_error(format!(
+                            "optional service {} failed to start: {}",
+                            service.name(),
+                            e
+                        ));
+                        failed.push(service.name());
                     }
                 }
             }
@@ -465,16 +1136,129 @@ impl SupervisorBase {
             remount(constants::DIR_ROOT, MountFlags::RDONLY, "")?;
         }
 
-        start_main(self.main_ref.clone())
+        // The main process is last, so it starts after the services unless the
+        // services are configured to start after it.
+        let all_refs = self.all_refs();
+        let names = all_refs
+            .iter()
+            .map(|service_ref| service_ref.lock().unwrap().name())
+            .collect::<Vec<_>>();
+        let dependencies = all_refs
+            .iter()
+            .map(|service_ref| {
+                let service = service_ref.lock().unwrap();
+                let base = service.base();
+                base.after.iter().chain(&base.requires).cloned().collect()
+            })
+            .collect::<Vec<Vec<String>>>();
+
+        let order = start_order(&names, &dependencies)?;
+        Ok(StartPlan {
+            all_refs,
+            failed,
+            names,
+            order,
+        })
+    }
+
+    // Start processes in order, each once those it depends on are ready. The
+    // supervisor is only locked briefly, so it can be controlled or shut down while
+    // processes wait for their dependencies.
+    fn start(base_ref: &Arc<Mutex<SupervisorBase>>, plan: StartPlan) -> Result<()> {
+        let StartPlan {
+            all_refs,
+            mut failed,
+            names,
+            order,
+        } = plan;
+        // The main process is last in all_refs.
+        let main_index = all_refs.len() - 1;
+        for i in order {
+            if base_ref.lock().unwrap().shutdown {
+                debug!("Not starting remaining processes during shutdown");
+                return Ok(());
+            }
+            if failed.contains(&names[i]) {
+                continue;
+            }
+            if let Err(e) = Self::wait_dependencies(&all_refs[i], &all_refs, &names, &failed) {
+                let service = all_refs[i].lock().unwrap();
+                if i == main_index || !service.optional() {
+                    return Err(anyhow!("unable to start {}: {}", names[i], e));
+                }
+                info!("Optional service {} not started: {}", names[i], e);
+                failed.push(names[i].clone());
+                continue;
+            }
+            if i == main_index {
+                // The main process also waits for services with a readiness probe.
+                for (service_ref, name) in all_refs.iter().zip(&names) {
+                    let has_probe = service_ref.lock().unwrap().base().readiness_probe.is_some();
+                    if has_probe
+                        && !failed.contains(name)
+                        && !wait_ready(service_ref, DEPENDENCY_TIMEOUT)
+                    {
+                        info!("Timed out waiting for {} to be ready", name);
+                    }
+                }
+                start_main(all_refs[i].clone())?;
+            } else {
+                start_service(all_refs[i].clone());
+            }
+        }
+        Ok(())
+    }
+
+    // Wait for the processes a process depends on to start, failing if one it
+    // requires is not running.
+    fn wait_dependencies(
+        service_ref: &Arc<Mutex<dyn Service>>,
+        all_refs: &[Arc<Mutex<dyn Service>>],
+        names: &[String],
+        failed: &[String],
+    ) -> Result<()> {
+        let (after, requires) = {
+            let service = service_ref.lock().unwrap();
+            (
+                service.base().after.clone(),
+                service.base().requires.clone(),
+            )
+        };
+        let dependencies = requires
+            .iter()
+            .map(|name| (name, true))
+            .chain(after.iter().map(|name| (name, false)));
+        for (dependency, required) in dependencies {
+            let index = names
+                .iter()
+                .position(|name| name == dependency)
+                .filter(|_| !failed.contains(dependency));
+            match index {
+                None if required => {
+                    return Err(anyhow!("required service {} is not running", dependency));
+                }
+                None => debug!("Not waiting for {}, as it is not running", dependency),
+                Some(i) => {
+                    if !wait_ready(&all_refs[i], DEPENDENCY_TIMEOUT) {
+                        if required {
+                            return Err(anyhow!("required service {} did not start", dependency));
+                        }
+                        info!("Timed out waiting for {} to start", dependency);
+                    }
+                }
+            }
+        }
+        Ok(())
     }
 
     fn signal(&self, signal: Signal) -> Result<()> {
+        self.main_ref.lock().unwrap().stop();
         for service_ref in &self.service_refs {
             service_ref.lock().unwrap().stop();
         }
         // Attempt to get all PIDs, but on error fall back to getting
         // just the tracked PIDs so a best-effort shutdown can be done.
-        let pids = self.pids().unwrap_or_else(|_| self.tracked_pids());
+        let pids = Self::pids().unwrap_or_else(|_| self.tracked_pids());
         for pid in pids {
             if let Some(p) = Pid::from_raw(pid as i32) {
                 match kill_process(p, signal) {
@@ -498,20 +1282,45 @@ impl SupervisorBase {
                 self.shutdown = true;
             }
         }
+        logger::set_phase(Phase::ShuttingDown);
 
         info!("Shutting down all processes");
-        if let Err(e) = self.signal(Signal::Term) {
-            error!("Error sending TERM signal: {}", e);
-        }
 
-        // Start the shutdown grace period countdown.
-        let shutdown_grace_period = self.shutdown_grace_period;
+        // Stop the main process first so it can finish using the processes it depends
+        // on, then sidecars, then the services built into the image. This is done in
+        // another thread, as the supervisor must not be locked while waiting.
+        let (sidecars, services): (Vec<_>, Vec<_>) = self
+            .service_refs
+            .iter()
+            .cloned()
+            .partition(|service_ref| service_ref.lock().unwrap().is_sidecar());
+        let main_ref = self.main_ref.clone();
+        let groups = [vec![self.main_ref.clone()], sidecars, services];
+        let shutdown_grace_period = Duration::from_secs(self.shutdown_grace_period);
+        let drain_command = self.drain_command.clone();
+        let drain_timeout = self.drain_timeout;
         thread::spawn(move || {
+            // Let the workload finish its work before anything is signaled.
+            run_drain(&main_ref, &drain_command, drain_timeout);
+            for group in groups {
+                stop_group(&group, shutdown_grace_period);
+            }
+
+            // Any processes not started by the supervisor are stopped last.
+            for pid in Self::pids().unwrap_or_default() {
+                if let Some(p) = Pid::from_raw(pid as i32) {
+                    match kill_process(p, Signal::Term) {
+                        Ok(_) | Err(Errno::SRCH) => (),
+                        Err(e) => error!("Error sending TERM signal: {}", e),
+                    }
+                }
+            }
+
             debug!(
-                "Starting {} second shutdown grace period countdown",
+                "Starting {:?} shutdown grace period countdown",
                 shutdown_grace_period
             );
-            sleep(Duration::from_secs(shutdown_grace_period));
+            sleep(shutdown_grace_period);
             let _ = timeout_tx.send(());
         });
     }
@@ -532,33 +1341,220 @@ impl SupervisorBase {
     }
 }
 
+// The processes to start, in the order given by the indexes in order, and the names
+// of services that failed to initialize.
+struct StartPlan {
+    all_refs: Vec<Arc<Mutex<dyn Service>>>,
+    failed: Vec<String>,
+    names: Vec<String>,
+    order: Vec<usize>,
+}
+
 pub struct Supervisor {
     base_ref: Arc<Mutex<SupervisorBase>>,
+    control: Option<ControlSocket>,
+    health_check: Option<HealthCheck>,
+    // Events to watch for, with the action, how often to check, and signal of each.
+    instance_events: Vec<(InstanceEvent, EventAction, Duration, Option<Signal>)>,
+    mount_points: Vec<String>,
+    reloader: Option<Reloader>,
+    spot_monitor: Option<bool>,
+    // When the main process must be ready by, from the startup-timeout option.
+    startup_deadline: Option<Instant>,
+    status_server: Option<StatusListener>,
+    syslog: Option<Arc<SyslogSink>>,
+    trim_intervals: Vec<(String, Duration)>,
+    volume_refreshes: Vec<(VolumeRefresh, Option<Signal>)>,
+    watchdog: Option<Watchdog>,
 }
 
 impl Supervisor {
-    pub fn new(vmspec: VmSpec, command: Vec<String>, env: NameValues) -> Result<Self> {
+    pub fn new(
+        vmspec: VmSpec,
+        command: Vec<String>,
+        env: NameValues,
+        region: &str,
+    ) -> Result<Self> {
         let (uid, gid) = unsafe {
             (
                 Uid::from_raw(vmspec.security.run_as_user_id.unwrap()),
                 Gid::from_raw(vmspec.security.run_as_group_id.unwrap()),
             )
         };
+        let groups = vmspec
+            .security
+            .supplementary_group_ids
+            .as_ref()
+            .map(|ids| ids.iter().map(|id| unsafe { Gid::from_raw(*id) }).collect());
         let working_dir = vmspec.working_dir.clone();
-        let main = Main::new(command, working_dir, env, gid, uid);
+        let readonly_root_fs = vmspec.security.readonly_root_fs.unwrap_or_default();
 
-        let service_refs = find_enabled_services(
+        let mut service_refs = find_enabled_services(
             Path::new(constants::DIR_ET_SERVICES),
             &vmspec.disable_services,
+            &vmspec.service_overrides,
+            &vmspec.ssh,
+            &vmspec.time,
+            readonly_root_fs,
         )?;
+        // No new privileges applies to the main process and services from user data,
+        // but not to built-in services such as sshd, whose login sessions may need
+        // setuid programs such as sudo.
+        let no_new_privs = vmspec.security.no_new_privileges.unwrap_or_default();
+        for sidecar in &vmspec.services {
+            let taken = sidecar.name == "main"
+                || service_refs
+                    .iter()
+                    .any(|service_ref| service_ref.lock().unwrap().name() == sidecar.name);
+            if taken {
+                return Err(anyhow!("service name {} is already in use", sidecar.name));
+            }
+            let mut service = Sidecar::new(sidecar, &env, uid, gid)
+                .map_err(|e| anyhow!("unable to configure service {}: {}", sidecar.name, e))?;
+            service.base_mut().no_new_privs = no_new_privs;
+            service_refs.push(Arc::new(Mutex::new(service)));
+        }
 
-        let readonly_root_fs = vmspec.security.readonly_root_fs.unwrap_or_default();
-        let shutdown_grace_period = vmspec.shutdown_grace_period;
-
-        drop(vmspec);
+        let mut main = Main::new(command, working_dir, env, gid, groups, uid);
+
+        let limits = vmspec.limits.to_rlimits();
+        main.base_mut().capabilities = vmspec.security.capability_plan()?;
+        main.base_mut().limits = limits.clone();
+        main.base_mut().no_new_privs = no_new_privs;
+        main.base_mut().oom_score_adj = vmspec.oom_score_adj;
+        main.base_mut().readiness_probe = vmspec.readiness_probe.clone();
+        main.base_mut().exit_actions = vmspec
+            .exit_actions()
+            .map_err(|e| anyhow!("invalid on-exit: {}", e))?;
+        let dependencies = |name: &str| {
+            let deps = vmspec.service_dependencies.get(name).cloned();
+            let ServiceDependencies { after, requires } = deps.unwrap_or_default();
+            (after.unwrap_or_default(), requires.unwrap_or_default())
+        };
+        (main.base_mut().after, main.base_mut().requires) = dependencies("main");
+        main.base_mut().restart_policy = RestartPolicy {
+            condition: vmspec
+                .restart_policy
+                .condition
+                .or(Some(RestartCondition::Never)),
+            ..vmspec.restart_policy.clone()
+        };
+        for service_ref in &service_refs {
+            let mut service = service_ref.lock().unwrap();
+            service.base_mut().limits = limits.clone();
+            (service.base_mut().after, service.base_mut().requires) = dependencies(&service.name());
+            service.base_mut().readiness_probe = vmspec
+                .service_readiness_probes
+                .get(&service.name())
+                .cloned();
+            if let Some(policy) = vmspec.service_restart_policies.get(&service.name()) {
+                service.base_mut().restart_policy = RestartPolicy {
+                    condition: policy.condition.or(Some(RestartCondition::Always)),
+                    ..policy.clone()
+                };
+            }
+            let crash_loop = service.base().restart_policy.crash_loop.as_ref();
+            if crash_loop.is_some_and(|c| c.action() == CrashLoopAction::Poweroff) {
+                return Err(anyhow!(
+                    "invalid service {}: crash-loop action poweroff is only for the main process",
+                    service.name()
+                ));
+            }
+            check_oom_score_adj(service.base().oom_score_adj)
+                .map_err(|e| anyhow!("invalid service {}: {}", service.name(), e))?;
+        }
+        check_oom_score_adj(main.base().oom_score_adj)?;
+
+        // Services write their output to files, while the main process keeps the console.
+        let log_dir = vmspec.service_logs.directory(readonly_root_fs);
+        mkdir_p(&log_dir, Mode::from(0o755))
+            .map_err(|e| anyhow!("unable to create log directory {:?}: {}", log_dir, e))?;
+        for service_ref in &service_refs {
+            let mut service = service_ref.lock().unwrap();
+            let log = RotatingFile::new(
+                log_dir.join(format!("{}.log", service.name())),
+                vmspec.service_logs.max_size(),
+                vmspec.service_logs.max_files(),
+            );
+            service.base_mut().log = Some(Arc::new(Mutex::new(log)));
+        }
+
+        // Bind /dev/log now, so it is ready before any process starts.
+        let syslog = if vmspec.syslog.enable.unwrap_or_default() {
+            let file = vmspec.syslog.write_file.unwrap_or_default().then(|| {
+                RotatingFile::new(
+                    log_dir.join("syslog.log"),
+                    vmspec.service_logs.max_size(),
+                    vmspec.service_logs.max_files(),
+                )
+            });
+            SyslogSink::bind(constants::FILE_DEV_LOG, file)
+                .map_err(|e| error!("Unable to start syslog listener: {}", e))
+                .ok()
+                .map(Arc::new)
+        } else {
+            None
+        };
+        let control = ControlSocket::bind(constants::FILE_CONTROL_SOCKET)
+            .map_err(|e| error!("Unable to start control socket: {}", e))
+            .ok();
+        let status_server = if vmspec.status_server.enable.unwrap_or_default() {
+            StatusListener::bind(vmspec.status_server.address())
+                .map_err(|e| error!("Unable to start status server: {}", e))
+                .ok()
+        } else {
+            None
+        };
+        let shutdown_grace_period = vmspec.shutdown_grace_period;
+        let disabled_services = vmspec.disable_services.clone();
+        let drain_command = vmspec.drain_command.clone();
+        let drain_timeout = Duration::from_secs(vmspec.drain_timeout);
+
+        // Keep the mount points so the watchdog can unmount them if it has to reboot.
+        let mount_points = vmspec.mount_points();
+        let trim_intervals = vmspec.trim_intervals();
+        let health_check = vmspec.health_check.clone().filter(|hc| {
+            hc.command
+                .as_ref()
+                .is_some_and(|command| !command.is_empty())
+        });
+        let volume_refreshes = vmspec
+            .volume_refreshes(Path::new(constants::DIR_ROOT), region)
+            .into_iter()
+            .map(|refresh| {
+                let signal = refresh.signal.as_deref().map(parse_signal).transpose()?;
+                Ok((refresh, signal))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let watchdog =
+            Some(vmspec.watchdog.clone()).filter(|watchdog| watchdog.enable.unwrap_or_default());
+        let spot_monitor = vmspec.spot_monitor;
+        let instance_events = vmspec
+            .instance_events
+            .handlers()
+            .into_iter()
+            .filter(|(_, handler)| handler.action != Some(EventAction::Ignore))
+            .map(|(event, handler)| {
+                let action = handler.action.unwrap_or(EventAction::Ignore);
+                let signal = handler.signal.as_deref().map(parse_signal).transpose()?;
+                if action == EventAction::Signal && signal.is_none() {
+                    return Err(anyhow!("instance event {} needs a signal", event));
+                }
+                Ok((event, action, handler.interval(), signal))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let startup_deadline = vmspec
+            .startup_timeout
+            .map(|timeout| Instant::now() + Duration::from_secs(timeout));
+
+        drop(vmspec);
 
         Ok(Self {
             base_ref: Arc::new(Mutex::new(SupervisorBase {
+                disabled_services,
+                drain_command,
+                drain_timeout,
                 main_ref: Arc::new(Mutex::new(main)),
                 readonly_root_fs,
                 service_refs,
@@ -566,35 +1562,376 @@ impl Supervisor {
                 shutdown_grace_period,
                 shutdown_mutex: Mutex::new(()),
             })),
+            control,
+            health_check,
+            instance_events,
+            mount_points,
+            reloader: None,
+            spot_monitor,
+            startup_deadline,
+            status_server,
+            syslog,
+            trim_intervals,
+            volume_refreshes,
+            watchdog,
         })
     }
 
+    // Set the function that loads the configuration again when SIGHUP is received,
+    // returning it with the environment resolved from it.
+    pub fn set_reloader<F>(&mut self, reloader: F)
+    where
+        F: Fn() -> Result<(VmSpec, NameValues)> + Send + Sync + 'static,
+    {
+        self.reloader = Some(Arc::new(reloader));
+    }
+
     pub fn start(&self) -> Result<()> {
-        self.base_ref.lock().unwrap().start()
+        // Orphaned processes are reparented to PID 1, but if running as any other
+        // PID, they must be reparented to the supervisor for it to reap them.
+        let pid = getpid();
+        if !pid.is_init() {
+            set_child_subreaper(Some(pid))
+                .map_err(|e| anyhow!("unable to become a child subreaper: {}", e))?;
+        }
+        if let Some(syslog) = self.syslog.clone() {
+            thread::spawn(move || syslog.serve());
+        }
+        let plan = self.base_ref.lock().unwrap().prepare_start()?;
+        SupervisorBase::start(&self.base_ref, plan)?;
+        logger::set_phase(Phase::Running);
+        for (mount_point, interval) in self.trim_intervals.clone() {
+            thread::spawn(move || Self::trim(mount_point, interval));
+        }
+        let main_ref = self.base_ref.lock().unwrap().main_ref.clone();
+        for (refresh, signal) in self.volume_refreshes.clone() {
+            let main_ref = main_ref.clone();
+            thread::spawn(move || Self::refresh(main_ref, refresh, signal));
+        }
+        if let Some(reloader) = self.reloader.clone() {
+            let base_ref = self.base_ref.clone();
+            let volume_refreshes = self.volume_refreshes.clone();
+            thread::spawn(move || Self::wait_reload(base_ref, reloader, volume_refreshes));
+        }
+        if let Some(health_check) = self.health_check.clone() {
+            let base_ref = self.base_ref.clone();
+            thread::spawn(move || Self::health_check(base_ref, main_ref, health_check));
+        }
+        Ok(())
     }
 
-    pub fn wait(&mut self) {
+    // Periodically run the health check, taking its action on the main process after
+    // too many consecutive failures. Failures during the start period are not counted.
+    fn health_check(
+        base_ref: Arc<Mutex<SupervisorBase>>,
+        main_ref: Arc<Mutex<dyn Service>>,
+        health_check: HealthCheck,
+    ) {
+        let command = health_check.command.clone().unwrap_or_default();
+        let action = health_check.action.unwrap_or_default();
+        let retries = health_check.retries();
+        let mut started = Instant::now();
+        let mut failures = 0;
+        loop {
+            sleep(health_check.interval());
+            // A main process left stopped after a crash loop is not checked.
+            if main_ref.lock().unwrap().base().stay_down {
+                return;
+            }
+            if run_check(&main_ref, &command, health_check.timeout()) {
+                failures = 0;
+                main_ref.lock().unwrap().base_mut().unhealthy = false;
+                continue;
+            }
+            if started.elapsed() < health_check.start_period() {
+                debug!("Health check failed during start period");
+                continue;
+            }
+            failures += 1;
+            error!("Health check failed, {} of {} retries", failures, retries);
+            if failures < retries {
+                continue;
+            }
+            main_ref.lock().unwrap().base_mut().unhealthy = true;
+            failures = 0;
+
+            if base_ref.lock().unwrap().shutdown {
+                return;
+            }
+            status::record_error(format!("health check failed {} times", retries));
+            let mut main = main_ref.lock().unwrap();
+            let Some(pid) = main.pid().and_then(|pid| Pid::from_raw(pid as i32)) else {
+                continue;
+            };
+            match action {
+                UnhealthyAction::Restart => {
+                    info!("Main process is unhealthy, restarting it");
+                    main.base_mut().restart = true;
+                    started = Instant::now();
+                }
+                UnhealthyAction::Poweroff => {
+                    info!("Main process is unhealthy, powering off");
+                    main.stop();
+                }
+            }
+            if let Err(e) = kill_process(pid, Signal::Term) {
+                error!("Unable to signal unhealthy main process: {}", e);
+            }
+        }
+    }
+
+    // Periodically fetch a volume again, signaling the main process if it changed.
+    fn refresh(main_ref: Arc<Mutex<dyn Service>>, refresh: VolumeRefresh, signal: Option<Signal>) {
+        let imds = CachedImds::default();
+        loop {
+            sleep(refresh.interval);
+            Self::refresh_volume(&main_ref, &imds, &refresh, signal);
+        }
+    }
+
+    // Fetch a volume again, signaling the main process if it changed.
+    fn refresh_volume(
+        main_ref: &Arc<Mutex<dyn Service>>,
+        imds: &CachedImds,
+        refresh: &VolumeRefresh,
+        signal: Option<Signal>,
+    ) {
+        let destination = refresh.destination.display();
+        match refresh.refresh(imds) {
+            Ok(true) => {
+                info!("Refreshed volume {}", destination);
+                let pid = main_ref.lock().unwrap().pid();
+                if let (Some(signal), Some(pid)) = (signal, pid) {
+                    if let Err(e) = kill_process(Pid::from_raw(pid as i32).unwrap(), signal) {
+                        error!("Unable to signal main process after refresh: {}", e);
+                    }
+                }
+            }
+            Ok(false) => debug!("Volume {} is unchanged", destination),
+            Err(e) => error!("Unable to refresh volume {}: {}", destination, e),
+        }
+    }
+
+    // Load the configuration again each time SIGHUP is received, applying the
+    // changes that are safe while processes are running. Volumes that are
+    // refreshed periodically are also fetched again right away.
+    fn wait_reload(
+        base_ref: Arc<Mutex<SupervisorBase>>,
+        reloader: Reloader,
+        volume_refreshes: Vec<(VolumeRefresh, Option<Signal>)>,
+    ) {
+        let mut signals = Signals::new([SIGHUP]).unwrap();
+        for _ in signals.forever() {
+            if base_ref.lock().unwrap().shutdown {
+                return;
+            }
+            info!("Reloading configuration");
+            let (vmspec, env) = match reloader() {
+                Ok(reloaded) => reloaded,
+                Err(e) => {
+                    error!("Unable to reload configuration: {}", e);
+                    status::record_error(format!("unable to reload configuration: {}", e));
+                    continue;
+                }
+            };
+            Self::apply_disabled_services(&base_ref, &vmspec.disable_services);
+
+            let main_ref = base_ref.lock().unwrap().main_ref.clone();
+            {
+                let mut main = main_ref.lock().unwrap();
+                if main.base().env != env {
+                    info!("Environment of the main process changed, it applies when it restarts");
+                    main.base_mut().env = env;
+                }
+            }
+
+            let imds = CachedImds::default();
+            for (refresh, signal) in &volume_refreshes {
+                Self::refresh_volume(&main_ref, &imds, refresh, *signal);
+            }
+        }
+    }
+
+    // Stop services that were disabled and start those that were enabled again.
+    // Services that were disabled at boot were never set up, so enabling them
+    // requires a reboot.
+    fn apply_disabled_services(base_ref: &Arc<Mutex<SupervisorBase>>, disabled: &[String]) {
+        let (service_refs, previous, shutdown_grace_period) = {
+            let mut base = base_ref.lock().unwrap();
+            let previous = mem::replace(&mut base.disabled_services, disabled.to_vec());
+            let service_refs = base
+                .service_refs
+                .iter()
+                .filter(|service_ref| !service_ref.lock().unwrap().is_sidecar())
+                .cloned()
+                .collect::<Vec<_>>();
+            let shutdown_grace_period = Duration::from_secs(base.shutdown_grace_period);
+            (service_refs, previous, shutdown_grace_period)
+        };
+        let names = service_refs
+            .iter()
+            .map(|service_ref| service_ref.lock().unwrap().name())
+            .collect::<Vec<_>>();
+
+        for name in previous.iter().filter(|name| !disabled.contains(name)) {
+            if !names.contains(name) {
+                warn!("Enabling service {} requires a reboot", name);
+            }
+        }
+        for (service_ref, name) in service_refs.iter().zip(&names) {
+            let active = service_ref.lock().unwrap().base().active;
+            let result = if disabled.contains(name) && !previous.contains(name) && active {
+                stop_running(service_ref, shutdown_grace_period)
+            } else if previous.contains(name) && !disabled.contains(name) && !active {
+                start_stopped(service_ref)
+            } else {
+                continue;
+            };
+            if let Err(e) = result {
+                error!("Unable to apply changes to service {}: {}", name, e);
+            }
+        }
+    }
+
+    // Periodically discard unused blocks of a filesystem, for the life of the system.
+    fn trim(mount_point: String, interval: Duration) {
+        loop {
+            sleep(interval);
+            match fstrim(&mount_point) {
+                Ok(trimmed) => debug!("Trimmed {} bytes on {}", trimmed, &mount_point),
+                Err(e) => error!("Unable to trim {}: {}", &mount_point, e),
+            }
+        }
+    }
+
+    pub fn wait(&mut self) -> PowerAction {
         let (done_tx, done_rx) = bounded(1);
         let (timeout_tx, timeout_rx) = bounded(1);
+        let mut handles = Vec::with_capacity(3);
 
         let wait_poweroff_base_ref = self.base_ref.clone();
         let wait_poweroff_timeout_tx = timeout_tx.clone();
-        thread::spawn(move || {
+        handles.push(thread::spawn(move || {
             debug!("Starting thread to wait for a poweroff signal");
             Self::wait_poweroff(wait_poweroff_base_ref, wait_poweroff_timeout_tx);
-        });
+        }));
+
+        // Without the tiny power button driver, the ACPI power button is an input device.
+        match find_power_buttons(constants::DIR_SYS_CLASS_INPUT, constants::DIR_DEV_INPUT) {
+            Ok(devices) => {
+                for device in devices {
+                    let power_button_base_ref = self.base_ref.clone();
+                    let power_button_timeout_tx = timeout_tx.clone();
+                    handles.push(thread::spawn(move || {
+                        debug!("Starting thread to wait for power button {:?}", device);
+                        Self::wait_power_button(
+                            power_button_base_ref,
+                            power_button_timeout_tx,
+                            device,
+                        );
+                    }));
+                }
+            }
+            Err(e) => debug!("Unable to find power button input devices: {}", e),
+        }
+
+        if let Some(deadline) = self.startup_deadline {
+            let startup_base_ref = self.base_ref.clone();
+            let startup_timeout_tx = timeout_tx.clone();
+            thread::spawn(move || {
+                debug!("Starting thread to wait for the main process to be ready");
+                Self::wait_startup(startup_base_ref, startup_timeout_tx, deadline);
+            });
+        }
+
+        if !self.instance_events.is_empty() {
+            let events_base_ref = self.base_ref.clone();
+            let events_timeout_tx = timeout_tx.clone();
+            let instance_events = self.instance_events.clone();
+            let spot_monitor = self.spot_monitor;
+            thread::spawn(move || {
+                Self::watch_instance_events(
+                    events_base_ref,
+                    events_timeout_tx,
+                    instance_events,
+                    spot_monitor,
+                );
+            });
+        }
 
         let wait_main_base_ref = self.base_ref.clone();
         let wait_main_timeout_tx = timeout_tx.clone();
-        thread::spawn(move || {
+        handles.push(thread::spawn(move || {
             debug!("Starting thread to wait for the main process");
             Self::wait_main(wait_main_base_ref, wait_main_timeout_tx);
+        }));
+
+        if let Some(control) = self.control.take() {
+            let control_base_ref = self.base_ref.clone();
+            let control_timeout_tx = timeout_tx.clone();
+            thread::spawn(move || {
+                debug!("Starting thread to serve the control socket");
+                control.serve(move |request| {
+                    Self::control(&control_base_ref, request, &control_timeout_tx)
+                });
+            });
+        }
+
+        if let Some(status_server) = self.status_server.take() {
+            let status_server_base_ref = self.base_ref.clone();
+            thread::spawn(move || {
+                debug!("Starting thread to serve the status server");
+                status_server.serve(move || status_server_base_ref.lock().unwrap().status());
+            });
+        }
+
+        let status_base_ref = self.base_ref.clone();
+        thread::spawn(move || {
+            debug!("Starting thread to write the status document");
+            Self::write_status(status_base_ref);
         });
 
-        let main_start_rx = self.main_start_rx();
+        let resources_base_ref = self.base_ref.clone();
         thread::spawn(move || {
+            debug!("Starting thread to sample resource usage");
+            Self::sample_resources(resources_base_ref);
+        });
+
+        let wait_children_base_ref = self.base_ref.clone();
+        handles.push(thread::spawn(move || {
             debug!("Starting thread to reap child processes");
-            Self::wait_children(main_start_rx, done_tx);
+            Self::wait_children(wait_children_base_ref, done_tx);
+        }));
+
+        // The watchdog device is disarmed when _feed_done_tx is dropped at the end of this
+        // method, so it does not reboot an instance that is powering off.
+        let (_feed_done_tx, feed_done_rx) = bounded::<()>(1);
+        // The device is opened only now, as its timer starts when it is opened.
+        let watchdog_device = self.watchdog.as_ref().and_then(|watchdog| {
+            WatchdogDevice::open(watchdog.device(), watchdog.timeout())
+                .map_err(|e| error!("Unable to start watchdog device: {}", e))
+                .ok()
+        });
+        if let Some(device) = watchdog_device {
+            let feed_base_ref = self.base_ref.clone();
+            thread::spawn(move || {
+                debug!("Starting thread to feed the watchdog device");
+                Self::feed_watchdog(feed_base_ref, device, feed_done_rx);
+            });
+        }
+
+        // The watchdog exits when _watchdog_done_tx is dropped at the end of this method.
+        let (_watchdog_done_tx, watchdog_done_rx) = bounded::<()>(1);
+        let watchdog_base_ref = self.base_ref.clone();
+        let watchdog_mount_points = self.mount_points.clone();
+        thread::spawn(move || {
+            debug!("Starting supervisor watchdog thread");
+            Self::watchdog(
+                watchdog_base_ref,
+                handles,
+                watchdog_mount_points,
+                watchdog_done_rx,
+            );
         });
 
         let mut stopped = false;
@@ -611,22 +1948,235 @@ impl Supervisor {
                 1 => {
                     info!("Timeout waiting for a graceful shutdown");
                     let _ = self.base_ref.lock().unwrap().kill();
+                    STOP_REAPING.store(true, Ordering::SeqCst);
+                    if done_rx.recv_timeout(REAPER_STOP_TIMEOUT).is_err() {
+                        error!("Timeout waiting for the reaper to stop");
+                    }
                     stopped = true;
                 }
                 _ => unreachable!(),
             }
         }
+
+        let base = self.base_ref.lock().unwrap();
+        if let Err(e) = base.status().write(constants::FILE_STATUS) {
+            error!("Unable to write status: {}", e);
+        }
+        if base.main_ref.lock().unwrap().base().reboot {
+            PowerAction::Reboot
+        } else {
+            PowerAction::Poweroff
+        }
     }
 
-    fn main_start_rx(&self) -> Receiver<()> {
-        self.base_ref
-            .lock()
-            .unwrap()
-            .main_ref
-            .lock()
-            .unwrap()
-            .start_rx()
-            .clone()
+    // Write the status document whenever it changes, for the life of the system.
+    fn write_status(base_ref: Arc<Mutex<SupervisorBase>>) {
+        let mut last = None;
+        loop {
+            let status = base_ref.lock().unwrap().status();
+            if last.as_ref() != Some(&status) {
+                if let Err(e) = status.write(constants::FILE_STATUS) {
+                    error!("Unable to write status: {}", e);
+                }
+                last = Some(status);
+            }
+            sleep(STATUS_INTERVAL);
+        }
+    }
+
+    // Sample the CPU, memory, and open files of running processes from /proc for
+    // the life of the system, logging them and keeping them for the status document.
+    fn sample_resources(base_ref: Arc<Mutex<SupervisorBase>>) {
+        let mut previous: HashMap<u32, ProcessSample> = HashMap::new();
+        loop {
+            sleep(RESOURCES_INTERVAL);
+            let all_refs = base_ref.lock().unwrap().all_refs();
+            let mut samples = HashMap::new();
+            for service_ref in all_refs {
+                let mut service = service_ref.lock().unwrap();
+                let Some(pid) = service.pid() else {
+                    continue;
+                };
+                let sample = match ProcessSample::read(constants::DIR_PROC, pid) {
+                    Ok(sample) => sample,
+                    Err(e) => {
+                        debug!("Unable to sample resources of {}: {}", service.name(), e);
+                        continue;
+                    }
+                };
+                let usage = sample.usage(previous.get(&pid));
+                debug!("Resources of {}: {}", service.name(), usage);
+                service.base_mut().resources = Some(usage);
+                samples.insert(pid, sample);
+            }
+            previous = samples;
+        }
+    }
+
+    // Handle a command sent to the control socket.
+    fn control(
+        base_ref: &Arc<Mutex<SupervisorBase>>,
+        request: Request,
+        timeout_tx: &Sender<()>,
+    ) -> Response {
+        info!("Received control command {:?}", request);
+        let base = base_ref.lock().unwrap();
+        let name = match &request {
+            Request::List => {
+                return Response {
+                    services: base.all_refs().iter().map(service_status).collect(),
+                    ..Default::default()
+                };
+            }
+            Request::Reboot => {
+                let mut base = base;
+                // The instance reboots instead of powering off once processes stop.
+                base.main_ref.lock().unwrap().base_mut().reboot = true;
+                base.stop(timeout_tx.clone());
+                return Response::default();
+            }
+            Request::Shutdown => {
+                let mut base = base;
+                base.stop(timeout_tx.clone());
+                return Response::default();
+            }
+            Request::Restart(name)
+            | Request::Start(name)
+            | Request::Status(name)
+            | Request::Stop(name) => name,
+        };
+        let Some(service_ref) = base
+            .all_refs()
+            .into_iter()
+            .find(|service_ref| service_ref.lock().unwrap().name() == *name)
+        else {
+            return Response::error(format!("no service named {}", name));
+        };
+        let is_main = Arc::ptr_eq(&service_ref, &base.main_ref);
+        let shutdown = base.shutdown;
+        let shutdown_grace_period = Duration::from_secs(base.shutdown_grace_period);
+        drop(base);
+
+        let result = match request {
+            Request::Start(_) | Request::Stop(_) if is_main => Err(anyhow!(
+                "the main process cannot be started or stopped, use shutdown instead"
+            )),
+            Request::Start(_) | Request::Restart(_) if shutdown => {
+                Err(anyhow!("the system is shutting down"))
+            }
+            Request::Start(_) => start_stopped(&service_ref),
+            Request::Stop(_) => stop_running(&service_ref, shutdown_grace_period),
+            Request::Restart(_) => restart_running(&service_ref),
+            _ => Ok(()),
+        };
+        match result {
+            Ok(_) => Response {
+                services: vec![service_status(&service_ref)],
+                ..Default::default()
+            },
+            Err(e) => Response::error(e.to_string()),
+        }
+    }
+
+    // Watch for supervisor threads that have panicked or a supervisor that has
+    // held its lock for too long. Either means the supervisor can no longer make
+    // progress, so the instance would otherwise hang.
+    fn watchdog(
+        base_ref: Arc<Mutex<SupervisorBase>>,
+        mut handles: Vec<JoinHandle<()>>,
+        mount_points: Vec<String>,
+        done_rx: Receiver<()>,
+    ) {
+        let mut last_seen = Instant::now();
+        loop {
+            match done_rx.recv_timeout(WATCHDOG_INTERVAL) {
+                Err(RecvTimeoutError::Timeout) => (),
+                _ => {
+                    debug!("Supervisor watchdog exiting");
+                    return;
+                }
+            }
+
+            let (finished, running): (Vec<_>, Vec<_>) =
+                handles.into_iter().partition(|h| h.is_finished());
+            handles = running;
+            if finished.into_iter().any(|h| h.join().is_err()) {
+                return Self::recover(&mount_points, "a supervisor thread panicked");
+            }
+
+            match base_ref.try_lock() {
+                Ok(_) => last_seen = Instant::now(),
+                Err(TryLockError::Poisoned(_)) => {
+                    return Self::recover(&mount_points, "the supervisor lock is poisoned");
+                }
+                Err(TryLockError::WouldBlock) if last_seen.elapsed() > WATCHDOG_STALL_TIMEOUT => {
+                    return Self::recover(&mount_points, "the supervisor is stalled");
+                }
+                Err(TryLockError::WouldBlock) => (),
+            }
+        }
+    }
+
+    // Feed the watchdog device while the supervisor can be locked and the main process
+    // is not unhealthy. Otherwise the device reboots the instance after its timeout.
+    fn feed_watchdog(
+        base_ref: Arc<Mutex<SupervisorBase>>,
+        mut device: WatchdogDevice,
+        done_rx: Receiver<()>,
+    ) {
+        let interval = device.timeout() / 2;
+        loop {
+            match done_rx.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => (),
+                _ => {
+                    debug!("Disarming watchdog device");
+                    if let Err(e) = device.disarm() {
+                        error!("{}", e);
+                    }
+                    return;
+                }
+            }
+
+            let Ok(base) = base_ref.lock() else {
+                error!("Supervisor lock is poisoned, no longer feeding watchdog device");
+                return;
+            };
+            let unhealthy = base.main_ref.lock().unwrap().base().unhealthy;
+            drop(base);
+            if unhealthy {
+                error!("Main process is unhealthy, not feeding watchdog device");
+                continue;
+            }
+            if let Err(e) = device.feed() {
+                error!("{}", e);
+            }
+        }
+    }
+
+    // Last resort when the supervisor is stuck: leave a crash marker for the
+    // next boot, kill everything, unmount what we can, and reboot.
+    fn recover(mount_points: &[String], reason: &str) {
+        error!("Watchdog detected that {}, rebooting", reason);
+
+        // The root filesystem may have been remounted read-only.
+        let _ = remount(constants::DIR_ROOT, MountFlags::empty(), "");
+        if let Err(e) = state::write_crash_marker(reason) {
+            error!("Unable to write crash marker: {}", e);
+        }
+
+        for pid in SupervisorBase::pids().unwrap_or_default() {
+            if let Some(p) = Pid::from_raw(pid as i32) {
+                let _ = kill_process(p, Signal::Kill);
+            }
+        }
+        sync();
+        if let Err(e) = unmount_all(mount_points) {
+            error!("Unable to unmount filesystems: {}", e);
+        }
+
+        // Sleep to let console output catch up.
+        sleep(Duration::from_secs(1));
+        let _ = reboot(RebootCommand::Restart);
     }
 
     // Wait for a poweroff signal. If one is received, trigger a shutdown of all processes.
@@ -637,6 +2187,21 @@ impl Supervisor {
         signals.handle().close();
     }
 
+    // Wait for a power button to be pressed. If it is, trigger a shutdown of all processes.
+    fn wait_power_button(
+        base_ref: Arc<Mutex<SupervisorBase>>,
+        timeout_tx: Sender<()>,
+        device: PathBuf,
+    ) {
+        match wait_pressed(&device) {
+            Ok(_) => {
+                info!("Power button was pressed");
+                base_ref.lock().unwrap().stop(timeout_tx);
+            }
+            Err(e) => error!("Unable to wait for power button: {}", e),
+        }
+    }
+
     // Wait for the main process to exit. If it does, trigger a shutdown of all processes.
     fn wait_main(base_ref: Arc<Mutex<SupervisorBase>>, timeout_tx: Sender<()>) {
         let stop_rx = base_ref
@@ -657,121 +2222,636 @@ impl Supervisor {
         } else {
             info!("Main process exited");
         }
-        base_ref.lock().unwrap().stop(timeout_tx);
+        let mut base = base_ref.lock().unwrap();
+        if base.main_ref.lock().unwrap().base().stay_down {
+            info!("Leaving the main process stopped, other processes keep running");
+            return;
+        }
+        base.stop(timeout_tx);
+    }
+
+    // Start a thread to wait for each instance event. Spot events are watched only
+    // with the spot monitor, which is enabled on spot instances unless it is set.
+    fn watch_instance_events(
+        base_ref: Arc<Mutex<SupervisorBase>>,
+        timeout_tx: Sender<()>,
+        instance_events: Vec<(InstanceEvent, EventAction, Duration, Option<Signal>)>,
+        spot_monitor: Option<bool>,
+    ) {
+        let has_spot_events = instance_events.iter().any(|(event, ..)| event.is_spot());
+        let spot_monitor = has_spot_events
+            && spot_monitor.unwrap_or_else(|| {
+                spot::is_spot_instance(&CachedImds::default())
+                    .map_err(|e| error!("Unable to check for a spot instance: {}", e))
+                    .unwrap_or_default()
+            });
+        for (event, action, interval, signal) in instance_events {
+            if event.is_spot() && !spot_monitor {
+                continue;
+            }
+            let event_base_ref = base_ref.clone();
+            let event_timeout_tx = timeout_tx.clone();
+            thread::spawn(move || {
+                debug!("Starting thread to wait for instance event {}", event);
+                Self::wait_instance_event(
+                    event_base_ref,
+                    event_timeout_tx,
+                    event,
+                    action,
+                    interval,
+                    signal,
+                );
+            });
+        }
     }
 
-    // Reap child processes. If none are left, write a message to the done channel.
-    fn wait_children(main_start_rx: Receiver<()>, done_tx: Sender<()>) {
-        // Don't start reaping processes until the main process has started,
-        // otherwise the system may shut down before it starts, especially
-        // in cases where there are no services besides the main process.
-        let _ = main_start_rx.recv();
-        debug!("Finished waiting for the main process to start");
+    // Take the action for an instance event once it is announced.
+    fn wait_instance_event(
+        base_ref: Arc<Mutex<SupervisorBase>>,
+        timeout_tx: Sender<()>,
+        event: InstanceEvent,
+        action: EventAction,
+        interval: Duration,
+        signal: Option<Signal>,
+    ) {
+        let description = spot::wait_event(&CachedImds::default(), event, interval);
+        info!(
+            "Instance event {}: {}, taking action {:?}",
+            event, description, action
+        );
+        let mut base = base_ref.lock().unwrap();
+        match (action, signal) {
+            (EventAction::Drain, _) => {
+                let main_ref = base.main_ref.clone();
+                let command = base.drain_command.clone();
+                let timeout = base.drain_timeout;
+                drop(base);
+                if command.is_empty() {
+                    info!("No drain command to run for instance event {}", event);
+                }
+                run_drain(&main_ref, &command, timeout);
+            }
+            (EventAction::Shutdown, _) => base.stop(timeout_tx),
+            (EventAction::Signal, Some(signal)) => {
+                let pid = base.main_ref.lock().unwrap().pid();
+                if let Some(pid) = pid.and_then(|pid| Pid::from_raw(pid as i32)) {
+                    if let Err(e) = kill_process(pid, signal) {
+                        error!("Unable to signal main process: {}", e);
+                    }
+                }
+            }
+            (EventAction::Ignore, _) | (EventAction::Signal, None) => (),
+        }
+    }
+
+    // Power off if the main process is not ready by the startup deadline, logging
+    // the state of each process to show what it was waiting for.
+    fn wait_startup(
+        base_ref: Arc<Mutex<SupervisorBase>>,
+        timeout_tx: Sender<()>,
+        deadline: Instant,
+    ) {
+        let main_ref = base_ref.lock().unwrap().main_ref.clone();
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if wait_ready(&main_ref, timeout) {
+            return;
+        }
+        let mut base = base_ref.lock().unwrap();
+        if base.shutdown {
+            return;
+        }
+        error!("Main process is not ready after the startup timeout, powering off");
+        for status in base.all_refs().iter().map(service_status) {
+            error!(
+                "{}: state {:?}, pid {:?}, ready {}, restarts {}, failures {}",
+                status.name,
+                status.state,
+                status.pid,
+                status.ready,
+                status.restarts,
+                status.failures
+            );
+        }
+        for error in base.status().errors {
+            error!("Error at {}: {}", error.time, error.message);
+        }
+        status::record_error("main process not ready before startup timeout".into());
+        base.stop(timeout_tx);
+    }
 
+    // Reap child processes for the life of the supervisor, including orphans that
+    // were reparented to it. Once shutting down and none are left, or once stopped
+    // after processes are killed, write a message to the done channel.
+    fn wait_children(base_ref: Arc<Mutex<SupervisorBase>>, done_tx: Sender<()>) {
+        let mut orphans = 0;
         loop {
-            let wait_status = wait(WaitOptions::empty());
-            debug!("Reaped process: {:?}", &wait_status);
-            if let Err(Errno::CHILD) = wait_status {
-                break;
+            // Poll while shutting down, so the reaper can be stopped even if a
+            // killed process has not exited yet.
+            let shutdown = base_ref.lock().unwrap().shutdown;
+            let options = if shutdown {
+                WaitOptions::NOHANG
+            } else {
+                WaitOptions::empty()
+            };
+            let wait_status = wait(options);
+            if !matches!(wait_status, Ok(None)) {
+                debug!("Reaped process: {:?}", &wait_status);
+            }
+            match wait_status {
+                Ok(Some((pid, status))) => {
+                    let pid = pid.as_raw_nonzero().get() as u32;
+                    let mut watched = WATCHED_PIDS.lock().unwrap();
+                    match watched.iter_mut().find(|(p, _)| *p == pid) {
+                        Some(entry) => entry.1 = Some(status),
+                        None => {
+                            // Not started by the supervisor, such as a daemon
+                            // whose parent exited after forking it.
+                            debug!("Reaped orphaned process {}: {:?}", pid, status);
+                            orphans += 1;
+                        }
+                    }
+                }
+                Ok(None) if STOP_REAPING.load(Ordering::SeqCst) => break,
+                Ok(None) => sleep(WATCH_INTERVAL),
+                // There may be no processes while one is waiting to be restarted,
+                // so only finish once the supervisor is shutting down.
+                Err(Errno::CHILD) if shutdown => break,
+                Err(Errno::CHILD) => sleep(WATCH_INTERVAL),
+                _ => (),
             }
         }
+        if orphans > 0 {
+            info!("Reaped {} orphaned processes", orphans);
+        }
         let _ = done_tx.send(());
     }
 }
 
-fn start_main(service_ref: Arc<Mutex<dyn Service>>) -> Result<()> {
-    {
-        let service = service_ref.lock().unwrap();
-        info!("Starting main process {:?}", service.base().args);
+// Spawn a command, watching its PID so the reaper records its exit status.
+fn spawn_watched(cmd: &mut Command) -> io::Result<Child> {
+    // Hold the lock while spawning so the reaper cannot miss the exit status.
+    let mut watched = WATCHED_PIDS.lock().unwrap();
+    let child = cmd.spawn()?;
+    watched.push((child.id(), None));
+    Ok(child)
+}
+
+// Wait for a watched process to exit, giving up at the deadline if there is one.
+fn wait_watched(pid: u32, deadline: Option<Instant>) -> Option<ExitStatus> {
+    loop {
+        {
+            let mut watched = WATCHED_PIDS.lock().unwrap();
+            if let Some(i) = watched
+                .iter()
+                .position(|(p, status)| *p == pid && status.is_some())
+            {
+                let (_, status) = watched.swap_remove(i);
+                return status.map(|status| ExitStatus::from_raw(status.as_raw() as i32));
+            }
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return None;
+        }
+        sleep(WATCH_INTERVAL);
     }
+}
 
-    let thread_service_ref = service_ref.clone();
+fn unwatch(pid: u32) {
+    WATCHED_PIDS.lock().unwrap().retain(|(p, _)| *p != pid);
+}
 
-    thread::spawn(move || {
-        let mut cmd = thread_service_ref.lock().unwrap().command();
-        let result = cmd.spawn();
-        let _ = thread_service_ref.lock().unwrap().start_tx().send(());
-        match result {
-            Err(e) => {
-                let _ = thread_service_ref.lock().unwrap().stop_tx().send(Err(e));
-            }
-            Ok(mut child) => {
-                thread_service_ref.lock().unwrap().base_mut().pid = Some(child.id());
-                let wait_result = child.wait();
-                let _ = thread_service_ref
-                    .lock()
-                    .unwrap()
-                    .stop_tx()
-                    .send(wait_result.map_err(Into::into));
+// Run a check command as the user of a process, returning whether it succeeded. A
+// command that does not finish before the timeout is killed and fails.
+fn run_check(service_ref: &Arc<Mutex<dyn Service>>, command: &[String], timeout: Duration) -> bool {
+    let mut cmd = service_ref
+        .lock()
+        .unwrap()
+        .base()
+        .command_with_args(command);
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    let pid = match spawn_watched(&mut cmd) {
+        Ok(child) => child.id(),
+        Err(e) => {
+            error!("Unable to run check {:?}: {}", command, e);
+            return false;
+        }
+    };
+    if let Some(status) = wait_watched(pid, Some(Instant::now() + timeout)) {
+        return status.success();
+    }
+
+    info!("Check {:?} timed out after {:?}", command, timeout);
+    if let Some(p) = Pid::from_raw(pid as i32) {
+        let _ = kill_process(p, Signal::Kill);
+    }
+    if wait_watched(pid, Some(Instant::now() + Duration::from_secs(5))).is_none() {
+        unwatch(pid);
+    }
+    false
+}
+
+// Run the drain command, if there is one, as the user of the main process.
+fn run_drain(main_ref: &Arc<Mutex<dyn Service>>, command: &[String], timeout: Duration) {
+    if command.is_empty() {
+        return;
+    }
+    info!("Running drain command {:?}", command);
+    if !run_check(main_ref, command, timeout) {
+        error!("Drain command {:?} failed", command);
+    }
+}
+
+// Check whether a process is ready with its readiness probe.
+fn run_probe(service_ref: &Arc<Mutex<dyn Service>>, probe: &ReadinessProbe) -> bool {
+    let timeout = probe.timeout();
+    if let Some(command) = &probe.exec {
+        return run_check(service_ref, command, timeout);
+    }
+    if let Some(http_get) = &probe.http_get {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(timeout)
+            .redirects(0)
+            .build();
+        // Responses with a status of 400 or more are errors.
+        return agent.get(&http_get.url()).call().is_ok();
+    }
+    if let Some(tcp_socket) = &probe.tcp_socket {
+        return match tcp_socket.address().to_socket_addrs() {
+            Ok(mut addrs) => addrs.any(|addr| TcpStream::connect_timeout(&addr, timeout).is_ok()),
+            Err(_) => false,
+        };
+    }
+    true
+}
+
+// Run the readiness probe of a process until it succeeds, marking the process ready,
+// or until the process exits.
+fn wait_probe(service_ref: Arc<Mutex<dyn Service>>, pid: u32, probe: ReadinessProbe) {
+    loop {
+        if service_ref.lock().unwrap().pid() != Some(pid) {
+            return;
+        }
+        if run_probe(&service_ref, &probe) {
+            let mut service = service_ref.lock().unwrap();
+            if service.pid() == Some(pid) {
+                info!("{} is ready", service.name());
+                service.base_mut().ready = true;
             }
+            return;
         }
-    });
+        sleep(probe.interval());
+    }
+}
 
+fn start_main(service_ref: Arc<Mutex<dyn Service>>) -> Result<()> {
+    {
+        let mut service = service_ref.lock().unwrap();
+        info!("Starting main process {:?}", service.base().args);
+        service.base_mut().active = true;
+    }
+
+    thread::spawn(move || supervise(service_ref));
     Ok(())
 }
 
-fn start_service(service_ref: Arc<Mutex<dyn Service>>) -> Result<()> {
+fn init_service(service_ref: Arc<Mutex<dyn Service>>) -> Result<()> {
     let result = match service_ref.lock().unwrap().init_fn() {
         Some(init_fn) => init_fn(),
         None => Ok(()),
     };
     let _ = service_ref.lock().unwrap().init_tx().send(());
-    result?;
+    result
+}
 
-    let thread_service_ref = service_ref.clone();
+fn start_service(service_ref: Arc<Mutex<dyn Service>>) {
+    service_ref.lock().unwrap().base_mut().active = true;
+    thread::spawn(move || supervise(service_ref));
+}
 
-    thread::spawn(move || {
-        let oncer = Once::new();
+// Order processes so each starts after those it depends on, otherwise keeping the
+// order they are given in. Dependencies on names that are not given are ignored.
+fn start_order(names: &[String], dependencies: &[Vec<String>]) -> Result<Vec<usize>> {
+    let mut order = Vec::with_capacity(names.len());
+    let mut started = vec![false; names.len()];
+    while order.len() < names.len() {
+        let next = (0..names.len()).find(|&i| {
+            !started[i]
+                && dependencies[i].iter().all(|dependency| {
+                    names
+                        .iter()
+                        .zip(&started)
+                        .all(|(name, started)| name != dependency || *started)
+                })
+        });
+        let Some(i) = next else {
+            let remaining = names
+                .iter()
+                .zip(&started)
+                .filter(|(_, started)| !**started)
+                .map(|(name, _)| name.as_str())
+                .collect::<Vec<_>>();
+            return Err(anyhow!(
+                "dependency cycle between services: {}",
+                remaining.join(", ")
+            ));
+        };
+        started[i] = true;
+        order.push(i);
+    }
+    Ok(order)
+}
 
-        loop {
-            let mut cmd = thread_service_ref.lock().unwrap().command();
-            debug!(
-                "Starting service: {:?} {:?}",
-                cmd.get_program(),
-                cmd.get_args()
-            );
-            let result = match cmd.spawn() {
-                Err(e) => {
-                    if thread_service_ref.lock().unwrap().is_shutdown() {
-                        let _ = thread_service_ref.lock().unwrap().stop_tx().send(Err(e));
-                        return;
-                    }
-                    Err(e)
-                }
-                Ok(mut child) => {
-                    thread_service_ref.lock().unwrap().base_mut().pid = Some(child.id());
-                    let oncer_service_ref = thread_service_ref.clone();
-                    oncer.call_once(move || {
-                        let _ = oncer_service_ref.lock().unwrap().start_tx().send(());
-                    });
-                    let wait_result = child.wait();
-                    if thread_service_ref.lock().unwrap().is_shutdown() {
-                        let _ = thread_service_ref
-                            .lock()
-                            .unwrap()
-                            .stop_tx()
-                            .send(wait_result.map_err(Into::into));
-                        return;
-                    }
-                    wait_result
-                }
-            };
+// Send TERM to a group of processes together, then wait for each to exit, killing any
+// still running after its stop timeout. Processes without their own stop timeout use
+// the shutdown grace period.
+fn stop_group(group: &[Arc<Mutex<dyn Service>>], default_timeout: Duration) {
+    let now = Instant::now();
+    let mut deadlines = Vec::with_capacity(group.len());
+    for service_ref in group {
+        let mut service = service_ref.lock().unwrap();
+        service.stop();
+        let Some(pid) = service.pid().and_then(|pid| Pid::from_raw(pid as i32)) else {
+            continue;
+        };
+        debug!("Stopping {}", service.name());
+        match kill_process(pid, Signal::Term) {
+            Ok(_) => (),
+            Err(Errno::SRCH) => continue,
+            Err(e) => error!("Error sending TERM signal to {}: {}", service.name(), e),
+        }
+        let timeout = service.base().stop_timeout.unwrap_or(default_timeout);
+        deadlines.push((service_ref, now + timeout));
+    }
+
+    for (service_ref, deadline) in deadlines {
+        while service_ref.lock().unwrap().pid().is_some() && Instant::now() < deadline {
+            sleep(WATCH_INTERVAL);
+        }
+        let service = service_ref.lock().unwrap();
+        if let Some(pid) = service.pid().and_then(|pid| Pid::from_raw(pid as i32)) {
             info!(
-                "Service {} exited, will restart. Exit status: {:?}",
-                thread_service_ref.lock().unwrap().name(),
-                result
+                "{} did not stop before its timeout, killing it",
+                service.name()
             );
-            sleep(Duration::from_secs(5));
+            let _ = kill_process(pid, Signal::Kill);
+        }
+    }
+}
+
+// Start a process that was stopped, with a new restart history.
+fn start_stopped(service_ref: &Arc<Mutex<dyn Service>>) -> Result<()> {
+    let mut service = service_ref.lock().unwrap();
+    if service.base().active {
+        return Err(anyhow!("{} is already running", service.name()));
+    }
+    info!("Starting {}", service.name());
+    let base = service.base_mut();
+    base.ready = false;
+    base.restart = false;
+    base.restart_history = RestartHistory::default();
+    base.shutdown = false;
+    base.stay_down = false;
+    drop(service);
+    start_service(service_ref.clone());
+    Ok(())
+}
+
+// Stop a process so it is not restarted, killing it if it is still running after
+// its stop timeout.
+fn stop_running(service_ref: &Arc<Mutex<dyn Service>>, default_timeout: Duration) -> Result<()> {
+    {
+        let service = service_ref.lock().unwrap();
+        if !service.base().active {
+            return Err(anyhow!("{} is not running", service.name()));
         }
-    });
+        info!("Stopping {}", service.name());
+    }
+    stop_group(slice::from_ref(service_ref), default_timeout);
     Ok(())
 }
 
+// Restart a running process without counting it toward its restart policy, or
+// start it if it was stopped.
+fn restart_running(service_ref: &Arc<Mutex<dyn Service>>) -> Result<()> {
+    let mut service = service_ref.lock().unwrap();
+    if !service.base().active {
+        drop(service);
+        return start_stopped(service_ref);
+    }
+    let Some(pid) = service.pid().and_then(|pid| Pid::from_raw(pid as i32)) else {
+        return Err(anyhow!("{} is already waiting to restart", service.name()));
+    };
+    info!("Restarting {}", service.name());
+    service.base_mut().restart = true;
+    kill_process(pid, Signal::Term)
+        .map_err(|e| anyhow!("unable to signal {}: {}", service.name(), e))
+}
+
+// Describe the state of a process for the control socket.
+fn service_status(service_ref: &Arc<Mutex<dyn Service>>) -> ServiceStatus {
+    let service = service_ref.lock().unwrap();
+    let base = service.base();
+    let state = match (base.active, base.pid) {
+        (_, Some(_)) => ServiceState::Running,
+        (true, None) => ServiceState::Starting,
+        (false, None) => ServiceState::Stopped,
+    };
+    ServiceStatus {
+        crash_loops: base.restart_history.crash_loops,
+        failures: base.restart_history.failures,
+        name: service.name(),
+        pid: base.pid,
+        ready: base.ready && base.pid.is_some(),
+        resources: base.pid.and(base.resources.clone()),
+        restarts: base.restart_history.total_restarts,
+        state,
+    }
+}
+
+// Wait for a process to be started, returning false if it is not before the timeout.
+fn wait_ready(service_ref: &Arc<Mutex<dyn Service>>, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while !service_ref.lock().unwrap().base().ready {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        sleep(WATCH_INTERVAL);
+    }
+    true
+}
+
+// Write the output of a process to its log file, a line at a time, until it exits.
+fn capture_output(name: &str, child: &mut Child, log: Arc<Mutex<RotatingFile>>) {
+    let stdout = child
+        .stdout
+        .take()
+        .map(|s| Box::new(s) as Box<dyn Read + Send>);
+    let stderr = child
+        .stderr
+        .take()
+        .map(|s| Box::new(s) as Box<dyn Read + Send>);
+    for stream in [stdout, stderr].into_iter().flatten() {
+        let log = log.clone();
+        let name = name.to_string();
+        thread::spawn(move || {
+            let mut reader = BufReader::new(stream);
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                match reader.read_until(b'\n', &mut line) {
+                    Ok(0) | Err(_) => return,
+                    Ok(_) => {
+                        if let Err(e) = log.lock().unwrap().write_line(&line) {
+                            error!("Unable to write log of {}: {}", name, e);
+                        }
+                    }
+                }
+            }
+        });
+    }
+}
+
+// Mark a process as no longer supervised, sending the result of its last run. A
+// process started again by the control socket may finish more than once, and only
+// the result of the main process is received, so an unread result is not replaced.
+fn finish_supervising(service: &mut dyn Service, result: io::Result<ExitStatus>) {
+    service.base_mut().active = false;
+    let _ = service.stop_tx().try_send(result);
+}
+
+// Run a process, starting it again when it exits for as long as its restart policy
+// allows. The final result is sent to the stop channel once it will not be restarted.
+fn supervise(service_ref: Arc<Mutex<dyn Service>>) {
+    let name = service_ref.lock().unwrap().name();
+
+    loop {
+        let mut cmd = service_ref.lock().unwrap().command();
+        let log = service_ref.lock().unwrap().base().log.clone();
+        if log.is_some() {
+            cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        debug!(
+            "Starting {}: {:?} {:?}",
+            name,
+            cmd.get_program(),
+            cmd.get_args()
+        );
+        let started = Instant::now();
+        let spawned = spawn_watched(&mut cmd);
+        let result = spawned.map(|mut child| {
+            let pid = child.id();
+            let mut service = service_ref.lock().unwrap();
+            service.base_mut().pid = Some(pid);
+            let probe = service.base().readiness_probe.clone();
+            service.base_mut().ready = probe.is_none();
+            drop(service);
+            if let Some(probe) = probe {
+                let service_ref = service_ref.clone();
+                thread::spawn(move || wait_probe(service_ref, pid, probe));
+            }
+            if let Some(log) = log {
+                capture_output(&name, &mut child, log);
+            }
+            let status = wait_watched(pid, None).unwrap();
+            service_ref.lock().unwrap().base_mut().pid = None;
+            status
+        });
+
+        let mut service = service_ref.lock().unwrap();
+        if service.is_shutdown() {
+            finish_supervising(&mut *service, result);
+            return;
+        }
+        // A restart requested by the health check does not count toward the policy.
+        if service.base().restart {
+            service.base_mut().restart = false;
+            info!("Restarting {}, exit status: {:?}", name, result);
+            continue;
+        }
+
+        let exit_action = result.as_ref().ok().and_then(|status| {
+            let code = status.code()?;
+            let exit_actions = &service.base().exit_actions;
+            exit_actions
+                .iter()
+                .find(|(codes, _)| codes.contains(&code))
+                .map(|(_, action)| *action)
+        });
+        let success = matches!(&result, Ok(status) if status.success());
+        match &result {
+            Ok(status) if !success => {
+                status::record_error(format!("{} exited with {}", name, status))
+            }
+            Err(e) => status::record_error(format!("unable to start {}: {}", name, e)),
+            _ => (),
+        }
+        let mut policy = service.base().restart_policy.clone();
+        match exit_action {
+            // The backoff and maximum restarts of the policy still apply.
+            Some(ExitAction::Restart) => policy.condition = Some(RestartCondition::Always),
+            Some(action) => {
+                info!(
+                    "Exit of {} requests {:?}, exit status: {:?}",
+                    name, action, result
+                );
+                service.base_mut().reboot = action == ExitAction::Reboot;
+                finish_supervising(&mut *service, result);
+                return;
+            }
+            None => (),
+        }
+        let history = &mut service.base_mut().restart_history;
+        let crash_loops = history.crash_loops;
+        let delay = history.next(&policy, success, started.elapsed());
+        if history.crash_loops > crash_loops {
+            info!("{} is in a crash loop", name);
+            status::record_error(format!("{} is in a crash loop", name));
+        }
+        let Some(delay) = delay else {
+            match history.crash_loop_action(&policy) {
+                Some(CrashLoopAction::Poweroff) => {
+                    info!("Not restarting {} after a crash loop, powering off", name)
+                }
+                Some(CrashLoopAction::StayDown) => {
+                    info!(
+                        "Not restarting {} after a crash loop, leaving it stopped",
+                        name
+                    );
+                    service.base_mut().stay_down = true;
+                }
+                None => info!("Not restarting {}, exit status: {:?}", name, result),
+            }
+            finish_supervising(&mut *service, result);
+            return;
+        };
+        info!(
+            "Restarting {} in {:?}, exit status: {:?}",
+            name, delay, result
+        );
+        drop(service);
+
+        sleep(delay);
+        let mut service = service_ref.lock().unwrap();
+        if service.is_shutdown() {
+            finish_supervising(&mut *service, result);
+            return;
+        }
+    }
+}
+
 fn find_enabled_services(
     path: &Path,
     disabled_services: &[String],
+    service_overrides: &HashMap<String, ServiceOverride>,
+    ssh: &vmspec::Ssh,
+    time: &vmspec::Time,
+    readonly_root_fs: bool,
 ) -> Result<Vec<Arc<Mutex<dyn Service>>>> {
     let mut services: Vec<Arc<Mutex<dyn Service>>> = Vec::new();
+    let default_override = ServiceOverride::default();
     let fd = File::open(path)?;
     for entry_res in Dir::read_from(fd)? {
         let entry = entry_res?;
@@ -781,13 +2861,412 @@ fn find_enabled_services(
         } else if disabled_services.contains(&entry_name) {
             info!("Disabling service {}", entry_name);
             continue;
-        } else if entry_name == "chrony" {
-            services.push(Arc::new(Mutex::new(Chrony::new())));
+        }
+        let service_override = service_overrides
+            .get(&entry_name)
+            .unwrap_or(&default_override);
+        if entry_name == "chrony" {
+            services.push(Arc::new(Mutex::new(Chrony::new(
+                service_override,
+                time,
+                readonly_root_fs,
+            ))));
         } else if entry_name == "ssh" {
-            services.push(Arc::new(Mutex::new(Ssh::new())));
+            services.push(Arc::new(Mutex::new(Ssh::new(service_override, ssh))));
         } else {
             info!("Unknown service {}", entry_name);
         }
     }
+
+    if !disabled_services.iter().any(|name| name == "ssm-agent") {
+        if let Some(path) = SsmAgent::find_executable() {
+            let service_override = service_overrides
+                .get("ssm-agent")
+                .unwrap_or(&default_override);
+            services.push(Arc::new(Mutex::new(SsmAgent::new(&path, service_override))));
+        }
+    }
     Ok(services)
 }
+
+// Get the indices from the public-keys metadata listing, whose lines are each an
+// index and key pair name such as 0=my-key.
+fn parse_public_key_indices(index: &str) -> Vec<&str> {
+    index
+        .lines()
+        .filter_map(|line| line.split_once('=').map(|(i, _)| i.trim()))
+        .filter(|i| !i.is_empty())
+        .collect()
+}
+
+// Parse a signal name such as HUP or SIGHUP.
+fn parse_signal(name: &str) -> Result<Signal> {
+    let upper = name.to_uppercase();
+    let short = upper.strip_prefix("SIG").unwrap_or(&upper);
+    match short {
+        "HUP" => Ok(Signal::Hup),
+        "INT" => Ok(Signal::Int),
+        "QUIT" => Ok(Signal::Quit),
+        "TERM" => Ok(Signal::Term),
+        "USR1" => Ok(Signal::Usr1),
+        "USR2" => Ok(Signal::Usr2),
+        "WINCH" => Ok(Signal::Winch),
+        _ => Err(anyhow!("unsupported signal {}", name)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_chrony_config() {
+        struct Case {
+            phc: Option<&'static str>,
+            ntp_servers: Vec<String>,
+            expected: &'static str,
+        }
+        let cases = [
+            Case {
+                phc: None,
+                ntp_servers: Vec::new(),
+                expected: "user cb-chrony
+pidfile /.easyto/run/chrony/chronyd.pid
+driftfile /.easyto/var/chrony/drift
+makestep 1.0 3
+rtcsync
+server 169.254.169.123 prefer iburst minpoll 4 maxpoll 4
+",
+            },
+            Case {
+                phc: Some("/dev/ptp0"),
+                ntp_servers: Vec::new(),
+                expected: "user cb-chrony
+pidfile /.easyto/run/chrony/chronyd.pid
+driftfile /.easyto/var/chrony/drift
+makestep 1.0 3
+rtcsync
+refclock PHC /dev/ptp0 poll 0 delay 0.000010 prefer
+server 169.254.169.123 iburst minpoll 4 maxpoll 4
+",
+            },
+            Case {
+                phc: None,
+                ntp_servers: vec!["ntp1.example.com".into(), "10.0.0.123".into()],
+                expected: "user cb-chrony
+pidfile /.easyto/run/chrony/chronyd.pid
+driftfile /.easyto/var/chrony/drift
+makestep 1.0 3
+rtcsync
+server ntp1.example.com iburst
+server 10.0.0.123 iburst
+",
+            },
+        ];
+        for case in cases {
+            assert_eq!(
+                chrony_config(
+                    case.phc.map(Path::new),
+                    &case.ntp_servers,
+                    Path::new("/.easyto/var/chrony/drift")
+                ),
+                case.expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_chrony_drift_path() {
+        struct Case {
+            readonly_root_fs: bool,
+            expected: &'static str,
+        }
+        let cases = [
+            Case {
+                readonly_root_fs: false,
+                expected: "/.easyto/var/chrony/drift",
+            },
+            Case {
+                readonly_root_fs: true,
+                expected: "/.easyto/run/chrony/drift",
+            },
+        ];
+        for case in cases {
+            let chrony = Chrony::new(
+                &ServiceOverride::default(),
+                &vmspec::Time::default(),
+                case.readonly_root_fs,
+            );
+            assert_eq!(chrony.drift_path, Path::new(case.expected));
+            let config = chrony.config.unwrap();
+            assert!(
+                config.contains(&format!("driftfile {}\n", case.expected)),
+                "{}",
+                config
+            );
+        }
+    }
+
+    #[test]
+    fn test_find_ena_phc() {
+        let dir = std::env::temp_dir().join(format!("ptp-{}", std::process::id()));
+        assert_eq!(find_ena_phc(&dir), None);
+
+        for (name, clock_name) in [("ptp0", "KVM virtual PTP\n"), ("ptp1", "ena-ptp-0\n")] {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+            std::fs::write(dir.join(name).join("clock_name"), clock_name).unwrap();
+        }
+        assert_eq!(find_ena_phc(&dir), Some(PathBuf::from("/dev/ptp1")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_authorized_keys() {
+        let document = [
+            "# break-glass",
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG4f admin@example.com",
+            "",
+            "  ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQC7 deploy  ",
+            "",
+        ]
+        .join("\n");
+        assert_eq!(
+            parse_authorized_keys(&document).collect::<Vec<_>>(),
+            vec![
+                "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG4f admin@example.com",
+                "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQC7 deploy",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_public_key_indices() {
+        struct Case {
+            index: &'static str,
+            expected: Vec<&'static str>,
+        }
+        let cases = [
+            Case {
+                index: "0=my-key",
+                expected: vec!["0"],
+            },
+            Case {
+                index: "0=my-key\n1=imported-key\n",
+                expected: vec!["0", "1"],
+            },
+            Case {
+                index: "",
+                expected: vec![],
+            },
+        ];
+        for case in cases {
+            assert_eq!(parse_public_key_indices(case.index), case.expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        struct Case {
+            name: &'static str,
+            expected: Option<Signal>,
+        }
+        let cases = [
+            Case {
+                name: "HUP",
+                expected: Some(Signal::Hup),
+            },
+            Case {
+                name: "SIGUSR1",
+                expected: Some(Signal::Usr1),
+            },
+            Case {
+                name: "sigterm",
+                expected: Some(Signal::Term),
+            },
+            Case {
+                name: "KILL",
+                expected: None,
+            },
+        ];
+        for case in cases {
+            assert_eq!(parse_signal(case.name).ok(), case.expected);
+        }
+    }
+
+    #[test]
+    fn test_override_args() {
+        struct Case {
+            service_override: ServiceOverride,
+            expected: Vec<&'static str>,
+        }
+        let cases = [
+            Case {
+                service_override: ServiceOverride::default(),
+                expected: vec!["/sbin/sshd", "-D", "-e", "-f", "/etc/sshd_config"],
+            },
+            Case {
+                service_override: ServiceOverride {
+                    args: Some(vec!["-o".into(), "LogLevel=DEBUG".into()]),
+                    ..Default::default()
+                },
+                expected: vec![
+                    "/sbin/sshd",
+                    "-D",
+                    "-e",
+                    "-f",
+                    "/etc/sshd_config",
+                    "-o",
+                    "LogLevel=DEBUG",
+                ],
+            },
+            Case {
+                service_override: ServiceOverride {
+                    args: Some(vec!["-D".into()]),
+                    replace_args: Some(true),
+                    ..Default::default()
+                },
+                expected: vec!["/sbin/sshd", "-f", "/etc/sshd_config", "-D"],
+            },
+        ];
+        for case in cases {
+            let args = ServiceBase::override_args(
+                Path::new("/sbin/sshd"),
+                &["-D", "-e"],
+                vec!["-f".into(), "/etc/sshd_config".into()],
+                &case.service_override,
+            );
+            assert_eq!(args, case.expected);
+        }
+    }
+
+    #[test]
+    fn test_ssh_host_certificate_args() {
+        let config = vmspec::Ssh {
+            host_certificate: Some(HostCertificate::default()),
+            ..Default::default()
+        };
+        let ssh = Ssh::new(&ServiceOverride::default(), &config);
+        assert_eq!(
+            ssh.base.args[3..],
+            [
+                "-f",
+                "/.easyto/etc/ssh/sshd_config",
+                "-o",
+                "HostKey=/.easyto/etc/ssh/ssh_host_cert_key",
+                "-o",
+                "HostCertificate=/.easyto/etc/ssh/ssh_host_cert_key-cert.pub",
+            ]
+        );
+
+        let ssh = Ssh::new(&ServiceOverride::default(), &vmspec::Ssh::default());
+        assert_eq!(ssh.base.args.len(), 5);
+    }
+
+    #[test]
+    fn test_start_order() {
+        struct Case {
+            names: Vec<&'static str>,
+            dependencies: Vec<Vec<&'static str>>,
+            expected: Option<Vec<usize>>,
+        }
+        let cases = [
+            Case {
+                names: vec!["chrony", "ssh", "main"],
+                dependencies: vec![vec![], vec![], vec![]],
+                expected: Some(vec![0, 1, 2]),
+            },
+            Case {
+                names: vec!["exporter", "chrony", "main"],
+                dependencies: vec![vec!["main"], vec![], vec!["chrony"]],
+                expected: Some(vec![1, 2, 0]),
+            },
+            Case {
+                names: vec!["chrony", "main"],
+                dependencies: vec![vec!["ssh"], vec![]],
+                expected: Some(vec![0, 1]),
+            },
+            Case {
+                names: vec!["a", "b", "main"],
+                dependencies: vec![vec!["b"], vec!["a"], vec![]],
+                expected: None,
+            },
+        ];
+        for case in cases {
+            let names = case
+                .names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>();
+            let dependencies = case
+                .dependencies
+                .iter()
+                .map(|deps| deps.iter().map(|dep| dep.to_string()).collect())
+                .collect::<Vec<Vec<String>>>();
+            assert_eq!(start_order(&names, &dependencies).ok(), case.expected);
+        }
+    }
+
+    #[test]
+    fn test_restart_history_next() {
+        let secs = Duration::from_secs;
+        let policy = RestartPolicy {
+            backoff: Some(2),
+            condition: Some(RestartCondition::OnFailure),
+            max_backoff: Some(10),
+            max_restarts: Some(4),
+            ..Default::default()
+        };
+        let mut history = RestartHistory::default();
+        assert_eq!(history.next(&policy, true, secs(1)), None);
+        assert_eq!(history.next(&policy, false, secs(1)), Some(secs(2)));
+        assert_eq!(history.next(&policy, false, secs(1)), Some(secs(4)));
+        assert_eq!(history.next(&policy, false, secs(1)), Some(secs(8)));
+        assert_eq!(history.next(&policy, false, secs(1)), Some(secs(10)));
+        assert_eq!(history.next(&policy, false, secs(1)), None);
+        // Running for at least max-backoff resets the history.
+        assert_eq!(history.next(&policy, false, secs(10)), Some(secs(2)));
+        assert_eq!(history.total_restarts, 5);
+
+        let never = RestartPolicy::default();
+        assert_eq!(RestartHistory::default().next(&never, false, secs(1)), None);
+    }
+
+    #[test]
+    fn test_restart_history_crash_loop() {
+        let secs = Duration::from_secs;
+        let policy = RestartPolicy {
+            backoff: Some(2),
+            condition: Some(RestartCondition::Always),
+            crash_loop: Some(CrashLoop {
+                action: Some(CrashLoopAction::Poweroff),
+                failures: Some(3),
+                max_restarts: Some(2),
+                period: Some(60),
+            }),
+            max_backoff: Some(10),
+            ..Default::default()
+        };
+        let mut history = RestartHistory::default();
+        // The backoff is fixed until a crash loop is detected.
+        assert_eq!(history.next(&policy, false, secs(1)), Some(secs(2)));
+        assert_eq!(history.next(&policy, false, secs(1)), Some(secs(2)));
+        assert_eq!(history.crash_loops, 0);
+        assert_eq!(history.next(&policy, false, secs(1)), Some(secs(2)));
+        assert_eq!(history.crash_loops, 1);
+        assert_eq!(history.next(&policy, false, secs(1)), Some(secs(4)));
+        assert_eq!(history.next(&policy, false, secs(1)), None);
+        assert_eq!(
+            history.crash_loop_action(&policy),
+            Some(CrashLoopAction::Poweroff)
+        );
+        assert_eq!(history.failures, 5);
+
+        // Running for at least max-backoff ends the crash loop.
+        assert_eq!(history.next(&policy, false, secs(10)), Some(secs(2)));
+        assert_eq!(history.crash_loop_action(&policy), None);
+        assert_eq!(history.total_restarts, 5);
+    }
+}
@@ -0,0 +1,202 @@
+use std::{fmt, path::Path, thread::sleep, time::Duration};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+use crate::aws::imds::CachedImds;
+
+// Metadata that is "spot" on spot instances.
+const PATH_INSTANCE_LIFE_CYCLE: &str = "instance-life-cycle";
+
+// Metadata listing scheduled maintenance, which is an empty list if there is none.
+const PATH_MAINTENANCE_SCHEDULED: &str = "events/maintenance/scheduled";
+
+// Metadata that is not found until EC2 recommends replacing a spot instance because
+// it is at elevated risk of interruption, which may come well before a notice.
+const PATH_REBALANCE_RECOMMENDATION: &str = "events/recommendations/rebalance";
+
+// Metadata that is not found until a spot instance is scheduled to be interrupted,
+// about two minutes before it happens.
+const PATH_SPOT_INSTANCE_ACTION: &str = "spot/instance-action";
+
+// An event that EC2 announces in instance metadata.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InstanceEvent {
+    Maintenance,
+    Rebalance,
+    SpotInterruption,
+}
+
+impl InstanceEvent {
+    pub fn is_spot(&self) -> bool {
+        matches!(self, Self::Rebalance | Self::SpotInterruption)
+    }
+
+    fn path(&self) -> &'static str {
+        match self {
+            Self::Maintenance => PATH_MAINTENANCE_SCHEDULED,
+            Self::Rebalance => PATH_REBALANCE_RECOMMENDATION,
+            Self::SpotInterruption => PATH_SPOT_INSTANCE_ACTION,
+        }
+    }
+
+    // Describe the event from its metadata, or return None if it is not announced.
+    fn describe(&self, body: &str) -> Option<String> {
+        match self {
+            Self::Maintenance => serde_json::from_str::<Vec<MaintenanceEvent>>(body)
+                .ok()?
+                .into_iter()
+                .find(|event| event.state.as_deref().unwrap_or("active") == "active")
+                .map(|event| {
+                    format!(
+                        "{} maintenance is scheduled after {}: {}",
+                        event.code, event.not_before, event.description
+                    )
+                }),
+            Self::Rebalance => serde_json::from_str::<RebalanceRecommendation>(body)
+                .ok()
+                .map(|rebalance| format!("rebalance was recommended at {}", rebalance.notice_time)),
+            Self::SpotInterruption => serde_json::from_str::<InstanceAction>(body)
+                .ok()
+                .map(|action| format!("spot instance will {} at {}", action.action, action.time)),
+        }
+    }
+}
+
+impl fmt::Display for InstanceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Maintenance => write!(f, "maintenance"),
+            Self::Rebalance => write!(f, "rebalance"),
+            Self::SpotInterruption => write!(f, "spot-interruption"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct InstanceAction {
+    // One of hibernate, stop, or terminate.
+    pub action: String,
+    pub time: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RebalanceRecommendation {
+    #[serde(rename = "noticeTime")]
+    pub notice_time: String,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+pub struct MaintenanceEvent {
+    // Such as system-reboot or instance-stop.
+    pub code: String,
+    pub description: String,
+    pub not_before: String,
+    // One of active, completed, or canceled.
+    pub state: Option<String>,
+}
+
+pub fn is_spot_instance(imds: &CachedImds) -> Result<bool> {
+    let life_cycle = imds
+        .get_metadata(Path::new(PATH_INSTANCE_LIFE_CYCLE))
+        .map_err(|e| anyhow!("unable to get instance life cycle: {}", e))?;
+    Ok(life_cycle.trim() == "spot")
+}
+
+// Poll IMDS until the event is announced, returning its description.
+pub fn wait_event(imds: &CachedImds, event: InstanceEvent, interval: Duration) -> String {
+    loop {
+        let description = imds
+            .get_metadata(Path::new(event.path()))
+            .ok()
+            .and_then(|body| event.describe(&body));
+        if let Some(description) = description {
+            return description;
+        }
+        sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_instance_action_deserialize() {
+        let action: InstanceAction =
+            serde_json::from_str(r#"{"action": "terminate", "time": "2017-09-18T08:22:00Z"}"#)
+                .unwrap();
+        assert_eq!(
+            action,
+            InstanceAction {
+                action: "terminate".into(),
+                time: "2017-09-18T08:22:00Z".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_describe() {
+        struct Case {
+            event: InstanceEvent,
+            body: &'static str,
+            expected: Option<&'static str>,
+        }
+        let cases = [
+            Case {
+                event: InstanceEvent::SpotInterruption,
+                body: r#"{"action": "stop", "time": "2017-09-18T08:22:00Z"}"#,
+                expected: Some("spot instance will stop at 2017-09-18T08:22:00Z"),
+            },
+            Case {
+                event: InstanceEvent::Rebalance,
+                body: r#"{"noticeTime": "2020-10-27T08:22:00Z"}"#,
+                expected: Some("rebalance was recommended at 2020-10-27T08:22:00Z"),
+            },
+            Case {
+                event: InstanceEvent::Maintenance,
+                body: "[]",
+                expected: None,
+            },
+            Case {
+                event: InstanceEvent::Maintenance,
+                body: r#"[{"NotBefore": "21 Jan 2019 09:00:43 GMT", "Code": "system-reboot",
+                    "Description": "scheduled reboot", "EventId": "instance-event-0d59937288b749b32",
+                    "NotAfter": "21 Jan 2019 09:17:23 GMT", "State": "active"}]"#,
+                expected: Some(
+                    "system-reboot maintenance is scheduled after 21 Jan 2019 09:00:43 GMT: scheduled reboot",
+                ),
+            },
+            Case {
+                event: InstanceEvent::Maintenance,
+                body: r#"[{"NotBefore": "21 Jan 2019 09:00:43 GMT", "Code": "system-reboot",
+                    "Description": "scheduled reboot", "State": "canceled"}]"#,
+                expected: None,
+            },
+            Case {
+                // IMDS returns an HTML error page for a path that is not found.
+                event: InstanceEvent::SpotInterruption,
+                body: "<html>404 - Not Found</html>",
+                expected: None,
+            },
+        ];
+        for case in cases {
+            assert_eq!(
+                case.event.describe(case.body).as_deref(),
+                case.expected,
+                "{}",
+                case.body
+            );
+        }
+    }
+
+    #[test]
+    fn test_rebalance_recommendation_deserialize() {
+        let recommendation: RebalanceRecommendation =
+            serde_json::from_str(r#"{"noticeTime": "2020-10-27T08:22:00Z"}"#).unwrap();
+        assert_eq!(recommendation.notice_time, "2020-10-27T08:22:00Z");
+    }
+}
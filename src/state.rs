@@ -0,0 +1,70 @@
+use std::fs::{read_to_string, remove_file, write};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, Result};
+use chrono::DateTime;
+use rustix::fs::Mode;
+
+use crate::constants;
+use crate::fs::mkdir_p;
+
+// State in this module is kept on the root volume so it survives a reboot.
+
+fn crash_marker_path() -> PathBuf {
+    Path::new(constants::DIR_ET_VAR).join(constants::FILE_CRASH_MARKER)
+}
+
+// Record that the current boot is ending abnormally, so the next boot can report it.
+pub fn write_crash_marker(reason: &str) -> Result<()> {
+    mkdir_p(constants::DIR_ET_VAR, Mode::from(0o755))?;
+    let path = crash_marker_path();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    let timestamp = DateTime::from_timestamp(now, 0)
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_default();
+    write(&path, format!("{} {}\n", timestamp, reason))
+        .map_err(|e| anyhow!("unable to write {:?}: {}", path, e))
+}
+
+// Return the contents of the crash marker if one was left by the previous boot,
+// removing it so it is only reported once.
+pub fn take_crash_marker() -> Result<Option<String>> {
+    let path = crash_marker_path();
+    let marker = match read_to_string(&path) {
+        Ok(marker) => marker,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(anyhow!("unable to read {:?}: {}", path, e)),
+    };
+    remove_file(&path).map_err(|e| anyhow!("unable to remove {:?}: {}", path, e))?;
+    Ok(Some(marker.trim_end().into()))
+}
+
+fn failed_boots_path() -> PathBuf {
+    Path::new(constants::DIR_ET_VAR).join(constants::FILE_FAILED_BOOTS)
+}
+
+// Increment the failed boot counter and return the number of consecutive failed
+// boots before this one. The counter stays incremented unless this boot calls
+// reset_failed_boots(), so a boot that never gets that far counts as failed.
+pub fn start_boot() -> Result<u64> {
+    let path = failed_boots_path();
+    let failed_boots = match read_to_string(&path) {
+        Ok(contents) => contents.trim().parse::<u64>().unwrap_or_default(),
+        Err(e) if e.kind() == ErrorKind::NotFound => 0,
+        Err(e) => return Err(anyhow!("unable to read {:?}: {}", path, e)),
+    };
+    mkdir_p(constants::DIR_ET_VAR, Mode::from(0o755))?;
+    write(&path, format!("{}\n", failed_boots + 1))
+        .map_err(|e| anyhow!("unable to write {:?}: {}", path, e))?;
+    Ok(failed_boots)
+}
+
+pub fn reset_failed_boots() -> Result<()> {
+    let path = failed_boots_path();
+    write(&path, "0\n").map_err(|e| anyhow!("unable to write {:?}: {}", path, e))
+}
@@ -0,0 +1,161 @@
+use std::{
+    collections::VecDeque,
+    fs::{rename, write},
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::DateTime;
+use serde::Serialize;
+
+use crate::control::ServiceStatus;
+
+// The number of recent errors kept in the status document.
+const MAX_ERRORS: usize = 20;
+
+// Recent errors, oldest first, for the status document.
+static ERRORS: Mutex<VecDeque<StatusError>> = Mutex::new(VecDeque::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    // Init is preparing the instance and has not started any processes.
+    Initializing,
+    // Processes are supervised.
+    Running,
+    // Processes are being stopped before the instance powers off or reboots.
+    ShuttingDown,
+    // Initialization failed and the instance is powering off.
+    Failed,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StatusError {
+    pub message: String,
+    pub time: String,
+}
+
+// A document describing init and the processes it supervises, which is written to
+// a file so tools on the instance can check its state without parsing logs.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Status {
+    pub errors: Vec<StatusError>,
+    pub main: Option<ServiceStatus>,
+    pub phase: Phase,
+    pub services: Vec<ServiceStatus>,
+}
+
+impl Status {
+    pub fn new(phase: Phase) -> Self {
+        Self {
+            errors: ERRORS.lock().unwrap().iter().cloned().collect(),
+            main: None,
+            phase,
+            services: Vec::new(),
+        }
+    }
+
+    // Write the document with the time it was updated, replacing the file at once
+    // so readers never see a partial document.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        #[derive(Serialize)]
+        struct Document<'a> {
+            #[serde(flatten)]
+            status: &'a Status,
+            updated: String,
+        }
+        let path = path.as_ref();
+        let document = Document {
+            status: self,
+            updated: timestamp(),
+        };
+        let mut buf = serde_json::to_vec_pretty(&document)?;
+        buf.push(b'\n');
+        let mut tmp_path = path.to_path_buf().into_os_string();
+        tmp_path.push(".tmp");
+        write(&tmp_path, buf).map_err(|e| anyhow!("unable to write {:?}: {}", tmp_path, e))?;
+        rename(&tmp_path, path).map_err(|e| anyhow!("unable to rename {:?}: {}", tmp_path, e))
+    }
+}
+
+// Record an error to report in the status document.
+pub fn record_error(message: String) {
+    let mut errors = ERRORS.lock().unwrap();
+    if errors.len() == MAX_ERRORS {
+        errors.pop_front();
+    }
+    errors.push_back(StatusError {
+        message,
+        time: timestamp(),
+    });
+}
+
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    DateTime::from_timestamp(now, 0)
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::control::ServiceState;
+
+    #[test]
+    fn test_status_write() {
+        let dir = std::env::temp_dir().join(format!("status-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("status.json");
+
+        let status = Status {
+            errors: Vec::new(),
+            main: Some(ServiceStatus {
+                crash_loops: 0,
+                failures: 1,
+                name: "main".into(),
+                pid: Some(42),
+                ready: true,
+                resources: None,
+                restarts: 1,
+                state: ServiceState::Running,
+            }),
+            phase: Phase::Running,
+            services: Vec::new(),
+        };
+        status.write(&path).unwrap();
+
+        let mut written: Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert!(written["updated"].as_str().is_some_and(|t| !t.is_empty()));
+        written.as_object_mut().unwrap().remove("updated");
+        assert_eq!(
+            written,
+            serde_json::json!({
+                "errors": [],
+                "main": {
+                    "crash-loops": 0,
+                    "failures": 1,
+                    "name": "main",
+                    "pid": 42,
+                    "ready": true,
+                    "restarts": 1,
+                    "state": "running",
+                },
+                "phase": "running",
+                "services": [],
+            })
+        );
+        assert!(!dir.join("status.json.tmp").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
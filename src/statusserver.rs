@@ -0,0 +1,295 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use log::{error, warn};
+
+use crate::status::{Phase, Status};
+
+// The most connections handled at once, each on its own thread. Connections
+// beyond this are closed without a response.
+const MAX_CONNECTIONS: usize = 16;
+
+// The most header lines read from a request, which are otherwise ignored.
+const MAX_HEADERS: usize = 100;
+
+// The most bytes read from a request, so a client cannot send an endless line.
+const MAX_REQUEST_BYTES: u64 = 16 * 1024;
+
+// How long a client may take to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+// An HTTP listener answering GET /healthz and GET /status with the state of the
+// supervisor, for load balancer health checks and probes on the instance.
+pub struct StatusListener {
+    listener: TcpListener,
+}
+
+#[derive(Debug, PartialEq)]
+struct HttpResponse {
+    code: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl HttpResponse {
+    fn text(code: u16, body: &str) -> Self {
+        Self {
+            code,
+            content_type: "text/plain",
+            body: format!("{}\n", body),
+        }
+    }
+
+    fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let reason = match self.code {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Service Unavailable",
+        };
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.code,
+            reason,
+            self.content_type,
+            self.body.len()
+        );
+        writer.write_all(head.as_bytes())?;
+        writer.write_all(self.body.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl StatusListener {
+    pub fn bind(address: &str) -> Result<Self> {
+        let listener =
+            TcpListener::bind(address).map_err(|e| anyhow!("unable to bind {}: {}", address, e))?;
+        Ok(Self { listener })
+    }
+
+    // Accept connections for the life of the system, answering a single request on
+    // each with the status returned by the handler.
+    pub fn serve<F>(&self, handler: F)
+    where
+        F: Fn() -> Status + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        let connections = Arc::new(AtomicUsize::new(0));
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Unable to accept status connection: {}", e);
+                    continue;
+                }
+            };
+            if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                connections.fetch_sub(1, Ordering::SeqCst);
+                warn!(
+                    "Closing status connection, {} are already open",
+                    MAX_CONNECTIONS
+                );
+                continue;
+            }
+            let handler = handler.clone();
+            let connections = connections.clone();
+            thread::spawn(move || {
+                if let Err(e) = handle_connection(stream, handler.as_ref()) {
+                    error!("Unable to handle status request: {}", e);
+                }
+                connections.fetch_sub(1, Ordering::SeqCst);
+            });
+        }
+    }
+}
+
+fn handle_connection<F>(mut stream: TcpStream, handler: &F) -> Result<()>
+where
+    F: Fn() -> Status,
+{
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let request_line = read_request(&stream)?;
+    let response = respond(&request_line, handler);
+    response.write(&mut stream)
+}
+
+// Read the head of a request, returning its request line. No more than
+// MAX_REQUEST_BYTES are read, and the headers are skipped.
+fn read_request<R: Read>(reader: R) -> Result<String> {
+    let mut reader = BufReader::new(reader.take(MAX_REQUEST_BYTES));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    for _ in 0..MAX_HEADERS {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+    Ok(request_line)
+}
+
+// Answer a request given its request line, such as GET /healthz HTTP/1.1. The
+// instance is healthy while processes are supervised and the main process is ready,
+// and the status is returned whether or not it is.
+fn respond<F>(request_line: &str, handler: &F) -> HttpResponse
+where
+    F: Fn() -> Status,
+{
+    let mut fields = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (fields.next(), fields.next()) else {
+        return HttpResponse::text(400, "bad request");
+    };
+    let path = target.split('?').next().unwrap_or_default();
+    if !matches!(path, "/healthz" | "/status") {
+        return HttpResponse::text(404, "not found");
+    }
+    if method != "GET" {
+        return HttpResponse::text(405, "method not allowed");
+    }
+    let status = handler();
+    let healthy =
+        status.phase == Phase::Running && status.main.as_ref().is_some_and(|main| main.ready);
+    match path {
+        "/healthz" if healthy => HttpResponse::text(200, "ok"),
+        "/healthz" => HttpResponse::text(503, "unhealthy"),
+        _ => match serde_json::to_string_pretty(&status) {
+            Ok(body) => HttpResponse {
+                code: 200,
+                content_type: "application/json",
+                body: body + "\n",
+            },
+            Err(e) => HttpResponse::text(503, &format!("unable to encode status: {}", e)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::control::{ServiceState, ServiceStatus};
+
+    fn main_status(ready: bool) -> ServiceStatus {
+        ServiceStatus {
+            crash_loops: 0,
+            failures: 0,
+            name: "main".into(),
+            pid: Some(100),
+            ready,
+            resources: None,
+            restarts: 0,
+            state: ServiceState::Running,
+        }
+    }
+
+    #[test]
+    fn test_respond() {
+        struct Case {
+            request_line: &'static str,
+            phase: Phase,
+            ready: bool,
+            expected_code: u16,
+            expected_body: Option<&'static str>,
+        }
+        let cases = [
+            Case {
+                request_line: "GET /healthz HTTP/1.1\r\n",
+                phase: Phase::Running,
+                ready: true,
+                expected_code: 200,
+                expected_body: Some("ok\n"),
+            },
+            Case {
+                request_line: "GET /healthz?verbose=1 HTTP/1.0\r\n",
+                phase: Phase::Running,
+                ready: false,
+                expected_code: 503,
+                expected_body: Some("unhealthy\n"),
+            },
+            Case {
+                request_line: "GET /healthz HTTP/1.1\r\n",
+                phase: Phase::ShuttingDown,
+                ready: true,
+                expected_code: 503,
+                expected_body: Some("unhealthy\n"),
+            },
+            Case {
+                request_line: "GET /status HTTP/1.1\r\n",
+                phase: Phase::Running,
+                ready: true,
+                expected_code: 200,
+                expected_body: None,
+            },
+            Case {
+                request_line: "POST /status HTTP/1.1\r\n",
+                phase: Phase::Running,
+                ready: true,
+                expected_code: 405,
+                expected_body: Some("method not allowed\n"),
+            },
+            Case {
+                request_line: "GET /metrics HTTP/1.1\r\n",
+                phase: Phase::Running,
+                ready: true,
+                expected_code: 404,
+                expected_body: Some("not found\n"),
+            },
+            Case {
+                request_line: "\r\n",
+                phase: Phase::Running,
+                ready: true,
+                expected_code: 400,
+                expected_body: Some("bad request\n"),
+            },
+        ];
+        for case in cases {
+            let handler = || Status {
+                main: Some(main_status(case.ready)),
+                ..Status::new(case.phase)
+            };
+            let response = respond(case.request_line, &handler);
+            assert_eq!(response.code, case.expected_code, "{}", case.request_line);
+            if let Some(body) = case.expected_body {
+                assert_eq!(response.body, body, "{}", case.request_line);
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_request() {
+        let request = "GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        assert_eq!(
+            read_request(request.as_bytes()).unwrap(),
+            "GET /healthz HTTP/1.1\r\n"
+        );
+
+        // A request line without an end is cut off rather than read into memory.
+        let endless = std::io::repeat(b'A');
+        let request_line = read_request(endless).unwrap();
+        assert_eq!(request_line.len() as u64, MAX_REQUEST_BYTES);
+    }
+
+    #[test]
+    fn test_respond_status() {
+        let handler = || Status {
+            main: Some(main_status(true)),
+            ..Status::new(Phase::Running)
+        };
+        let response = respond("GET /status HTTP/1.1", &handler);
+        assert_eq!(response.content_type, "application/json");
+        let document: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(document["phase"], "running");
+        assert_eq!(document["main"]["name"], "main");
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    fs::remove_file,
+    io::ErrorKind,
+    os::unix::net::UnixDatagram,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Result};
+use chrono::DateTime;
+use log::{error, log, Level};
+use rustix::fs::{chmod, Mode};
+
+use crate::logrotate::RotatingFile;
+
+// Messages longer than this are truncated, which is more than syslog(3) sends.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+// A listener for messages sent with syslog(3), which are logged by init and
+// optionally written to a file.
+pub struct SyslogSink {
+    file: Option<Arc<Mutex<RotatingFile>>>,
+    socket: UnixDatagram,
+}
+
+impl SyslogSink {
+    pub fn bind<P: AsRef<Path>>(path: P, file: Option<RotatingFile>) -> Result<Self> {
+        let path = path.as_ref();
+        match remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(anyhow!("unable to remove {:?}: {}", path, e));
+            }
+            _ => (),
+        }
+        let socket =
+            UnixDatagram::bind(path).map_err(|e| anyhow!("unable to bind {:?}: {}", path, e))?;
+        // Processes running as any user must be able to log.
+        chmod(path, Mode::from(0o666))
+            .map_err(|e| anyhow!("unable to change permissions on {:?}: {}", path, e))?;
+        Ok(Self {
+            file: file.map(|file| Arc::new(Mutex::new(file))),
+            socket,
+        })
+    }
+
+    // Receive messages for the life of the system.
+    pub fn serve(&self) {
+        let mut buf = vec![0; MAX_MESSAGE_SIZE];
+        loop {
+            let n = match self.socket.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Unable to receive syslog message: {}", e);
+                    continue;
+                }
+            };
+            let (level, message) = parse_message(&buf[..n]);
+            log!(target: "syslog", level, "{}", message);
+            if let Some(file) = &self.file {
+                let line = format!("{} {} {}\n", timestamp(), level, message);
+                if let Err(e) = file.lock().unwrap().write_line(line.as_bytes()) {
+                    error!("Unable to write syslog message to file: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// Parse a message in the format sent by syslog(3), such as
+// "<30>Oct 16 12:00:00 app[42]: started", into its level and the text after
+// the timestamp. Messages without a priority are logged at info level.
+fn parse_message(buf: &[u8]) -> (Level, String) {
+    let text = String::from_utf8_lossy(buf);
+    let text = text.trim_end_matches(['\n', '\0']);
+    let Some((priority, rest)) = text
+        .strip_prefix('<')
+        .and_then(|text| text.split_once('>'))
+        .and_then(|(priority, rest)| Some((priority.parse::<u32>().ok()?, rest)))
+    else {
+        return (Level::Info, text.to_string());
+    };
+    let level = match priority & 0x7 {
+        0..=3 => Level::Error,
+        4 => Level::Warn,
+        5 | 6 => Level::Info,
+        _ => Level::Debug,
+    };
+    (level, strip_timestamp(rest).to_string())
+}
+
+// Remove a timestamp such as "Oct 16 12:00:00 " from the start of a message.
+fn strip_timestamp(text: &str) -> &str {
+    const TIMESTAMP_LEN: usize = "Mmm dd hh:mm:ss ".len();
+    match text.get(..TIMESTAMP_LEN) {
+        Some(prefix)
+            if prefix.as_bytes()[3] == b' '
+                && prefix.as_bytes()[9] == b':'
+                && prefix.as_bytes()[12] == b':' =>
+        {
+            &text[TIMESTAMP_LEN..]
+        }
+        _ => text,
+    }
+}
+
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    DateTime::from_timestamp(now, 0)
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_message() {
+        struct Case {
+            message: &'static str,
+            expected: (Level, &'static str),
+        }
+        let cases = [
+            Case {
+                message: "<30>Oct 16 12:00:00 app[42]: started\n",
+                expected: (Level::Info, "app[42]: started"),
+            },
+            Case {
+                message: "<11>Oct  6 01:02:03 app: failed",
+                expected: (Level::Error, "app: failed"),
+            },
+            Case {
+                message: "<12>app: low on disk",
+                expected: (Level::Warn, "app: low on disk"),
+            },
+            Case {
+                message: "<15>Oct 16 12:00:00 app: details\0",
+                expected: (Level::Debug, "app: details"),
+            },
+            Case {
+                message: "no priority",
+                expected: (Level::Info, "no priority"),
+            },
+        ];
+        for case in cases {
+            let (level, message) = parse_message(case.message.as_bytes());
+            assert_eq!((level, message.as_str()), case.expected);
+        }
+    }
+}
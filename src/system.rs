@@ -1,7 +1,11 @@
-use std::fs::{write, File};
+use std::fmt::Display;
+use std::fs::{read_to_string, write, File};
 use std::io::{ErrorKind, Read};
+use std::os::unix::fs::FileExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, Result};
 use blkpg::resize_partition as kernel_reread_partition;
@@ -10,13 +14,32 @@ use gpt::GptConfig;
 use log::{debug, info};
 use nvme_amz::Nvme;
 use rustix::cstr;
-use rustix::fs::{stat, symlink, Dir, FileType};
+use rustix::fs::{open, stat, statfs, symlink, Dir, FileType, Mode, OFlags};
+use rustix::io::{self, Errno};
 
 use crate::constants;
+use crate::fs::{mkdir_p, JoinRelative};
 use crate::rdev::find_block_device;
 
 const SYS_BLOCK_PATH: &str = "/sys/block";
 
+// Range of OOM score adjustments, where the minimum disables OOM killing of a process.
+pub const OOM_SCORE_ADJ_MIN: i32 = -1000;
+pub const OOM_SCORE_ADJ_MAX: i32 = 1000;
+
+// Filesystem magic numbers, from include/uapi/linux/magic.h in kernel source.
+const BTRFS_SUPER_MAGIC: i64 = 0x9123683E;
+const XFS_SUPER_MAGIC: i64 = 0x58465342;
+
+// Layout of the master boot record in the first sector of a disk.
+const MBR_PARTITION_TABLE_OFFSET: usize = 446;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_SIZE: usize = 512;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+// The model of NVMe instance store devices, as opposed to "Amazon Elastic Block Store".
+const INSTANCE_STORE_MODEL: &str = "Amazon EC2 NVMe Instance Storage";
+
 pub fn find_executable_in_path(executable: &str, path_var: &str) -> Option<PathBuf> {
     for dir in path_var.split(":") {
         let try_path = PathBuf::from_iter([constants::DIR_ROOT, dir, executable]);
@@ -38,6 +61,42 @@ pub fn sysctl<P: AsRef<Path>>(base_dir: P, key: &str, value: &str) -> Result<()>
     Ok(())
 }
 
+// Reserve count hugepages of the given size, e.g. 2M or 1G, and verify that the kernel
+// was able to allocate all of them, as it allocates as many as it can without error.
+pub fn reserve_hugepages<P: AsRef<Path>>(base_dir: P, size: &str, count: u64) -> Result<()> {
+    let size_kb = hugepage_size_kb(size)?;
+    let nr_path = base_dir
+        .as_ref()
+        .join_relative(constants::DIR_SYS_KERNEL_MM_HUGEPAGES)
+        .join(format!("hugepages-{}kB", size_kb))
+        .join("nr_hugepages");
+    write(&nr_path, count.to_string())
+        .map_err(|e| anyhow!("unable to write {} to {:?}: {}", count, nr_path, e))?;
+    let allocated = int_from_file(&nr_path)?;
+    if allocated < count as i64 {
+        return Err(anyhow!(
+            "only {} of {} hugepages of size {} could be allocated",
+            allocated,
+            count,
+            size
+        ));
+    }
+    Ok(())
+}
+
+fn hugepage_size_kb(size: &str) -> Result<u64> {
+    let (number, multiplier) = match size.trim_end_matches(['B', 'b']) {
+        s if s.ends_with(['K', 'k']) => (&s[..s.len() - 1], 1),
+        s if s.ends_with(['M', 'm']) => (&s[..s.len() - 1], 1024),
+        s if s.ends_with(['G', 'g']) => (&s[..s.len() - 1], 1024 * 1024),
+        _ => return Err(anyhow!("invalid hugepage size {}", size)),
+    };
+    let number = number
+        .parse::<u64>()
+        .map_err(|e| anyhow!("invalid hugepage size {}: {}", size, e))?;
+    Ok(number * multiplier)
+}
+
 // Convert e.g. "net.ipv4.tcp_syncookies" to "/proc/sys/net/ipv4/tcp_syncookies".
 fn proc_path_from_dotted(key: &str) -> PathBuf {
     let mut fields = vec![constants::DIR_PROC, "sys"];
@@ -45,6 +104,77 @@ fn proc_path_from_dotted(key: &str) -> PathBuf {
     PathBuf::from_iter(fields)
 }
 
+// Write /etc/machine-id, generating it on first boot and keeping it on the root volume
+// so it is stable across reboots. It is derived from the instance ID when one is given.
+pub fn write_machine_id<P: AsRef<Path>>(base_dir: P, instance_id: Option<&str>) -> Result<()> {
+    let base_dir = base_dir.as_ref();
+    let persisted_path = base_dir
+        .join_relative(constants::DIR_ET_ETC)
+        .join(constants::FILE_MACHINE_ID);
+    let machine_id = match read_to_string(&persisted_path) {
+        Ok(machine_id) => machine_id.trim().to_string(),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let machine_id = match instance_id.and_then(machine_id_from_instance_id) {
+                Some(machine_id) => machine_id,
+                None => random_machine_id(base_dir)?,
+            };
+            mkdir_p(
+                base_dir.join_relative(constants::DIR_ET_ETC),
+                Mode::from(0o755),
+            )?;
+            write(&persisted_path, format!("{}\n", machine_id))
+                .map_err(|e| anyhow!("unable to write {:?}: {}", persisted_path, e))?;
+            machine_id
+        }
+        Err(e) => return Err(anyhow!("unable to read {:?}: {}", persisted_path, e)),
+    };
+    let etc_path = base_dir.join_relative(constants::FILE_ETC_MACHINE_ID);
+    write(&etc_path, format!("{}\n", machine_id))
+        .map_err(|e| anyhow!("unable to write {:?}: {}", etc_path, e))
+}
+
+// A machine ID is 32 lowercase hexadecimal characters. An instance ID such as
+// i-0123456789abcdef0 is padded with leading zeros to that length.
+fn machine_id_from_instance_id(instance_id: &str) -> Option<String> {
+    let hex = instance_id.strip_prefix("i-")?;
+    if hex.is_empty() || hex.len() > 32 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(format!("{:0>32}", hex.to_ascii_lowercase()))
+}
+
+fn random_machine_id(base_dir: &Path) -> Result<String> {
+    let uuid_path = base_dir.join_relative(constants::FILE_PROC_RANDOM_UUID);
+    let uuid =
+        read_to_string(&uuid_path).map_err(|e| anyhow!("unable to read {:?}: {}", uuid_path, e))?;
+    Ok(uuid.trim().replace('-', ""))
+}
+
+pub fn check_oom_score_adj(value: i32) -> Result<()> {
+    if !(OOM_SCORE_ADJ_MIN..=OOM_SCORE_ADJ_MAX).contains(&value) {
+        return Err(anyhow!(
+            "oom-score-adj {} is not between {} and {}",
+            value,
+            OOM_SCORE_ADJ_MIN,
+            OOM_SCORE_ADJ_MAX
+        ));
+    }
+    Ok(())
+}
+
+// Set the OOM score adjustment of the current process, which its children inherit.
+// The value is preformatted and only system calls are made, so it can be called
+// between fork and exec. Lowering the value requires CAP_SYS_RESOURCE.
+pub fn set_oom_score_adj(value: &[u8]) -> io::Result<()> {
+    let fd = open(
+        constants::FILE_PROC_SELF_OOM_SCORE_ADJ,
+        OFlags::WRONLY | OFlags::CLOEXEC,
+        Mode::empty(),
+    )?;
+    io::write(&fd, value)?;
+    Ok(())
+}
+
 pub fn device_has_fs(path: &Path) -> Result<bool> {
     let blkid_path = Path::new(constants::DIR_ET_SBIN).join("blkid");
     let blkid_result = Command::new(&blkid_path)
@@ -98,11 +228,7 @@ pub fn link_nvme_devices() -> Result<()> {
             let partitions = disk_partitions(&device_name)
                 .map_err(|e| anyhow!("unable to get partitions of {:?}: {}", &device_name, e))?;
             for partition in partitions {
-                let partition_name = if has_digit_suffix(ec2_device_name) {
-                    format!("{}p{}", &ec2_device_name, &partition.partition)
-                } else {
-                    format!("{}{}", &ec2_device_name, &partition.partition)
-                };
+                let partition_name = partition_device(ec2_device_name, &partition.partition);
                 let partition_link_path = Path::new("/dev").join(&partition_name);
                 debug!(
                     "linking {} to {:?}",
@@ -122,6 +248,204 @@ pub fn link_nvme_devices() -> Result<()> {
     Ok(())
 }
 
+// Find NVMe instance store devices, ordered as the kernel numbered them.
+pub fn find_instance_store_devices() -> Result<Vec<String>> {
+    let dir_fd = File::open(SYS_BLOCK_PATH)
+        .map_err(|e| anyhow!("unable to open {}: {}", SYS_BLOCK_PATH, e))?;
+    let dir = Dir::read_from(dir_fd)
+        .map_err(|e| anyhow!("unable to read from directory {}: {}", SYS_BLOCK_PATH, e))?;
+    let mut devices = Vec::new();
+    for entry_res in dir {
+        let entry = entry_res.map_err(|e| {
+            anyhow!(
+                "unable to read directory entry in {}: {}",
+                SYS_BLOCK_PATH,
+                e
+            )
+        })?;
+        let device_name = entry.file_name().to_string_lossy().to_string();
+        if !device_name.starts_with("nvme") {
+            continue;
+        }
+        let model_path = Path::new(SYS_BLOCK_PATH)
+            .join(&device_name)
+            .join("device/model");
+        match std::fs::read_to_string(&model_path) {
+            Ok(model) if model.trim() == INSTANCE_STORE_MODEL => devices.push(device_name),
+            Ok(_) => (),
+            Err(e) => return Err(anyhow!("unable to read {:?}: {}", model_path, e)),
+        }
+    }
+    // Sort by length first so that nvme10n1 comes after nvme2n1.
+    devices.sort_by(|a, b| a.len().cmp(&b.len()).then(a.cmp(b)));
+    Ok(devices
+        .into_iter()
+        .map(|device| format!("/dev/{}", device))
+        .collect())
+}
+
+// Assemble devices into a RAID0 array, reusing an existing array from a previous
+// boot if the devices already belong to one, since instance store data survives
+// a reboot.
+pub fn assemble_raid0(array_device: &str, devices: &[String]) -> Result<()> {
+    let mdadm_path = Path::new(constants::DIR_ET_SBIN).join("mdadm");
+    let assembled = Command::new(&mdadm_path)
+        .arg("--assemble")
+        .arg(array_device)
+        .args(devices)
+        .output()
+        .map_err(|e| anyhow!("unable to run {:?}: {}", mdadm_path, e))?;
+    if assembled.status.success() {
+        info!("Assembled existing RAID0 array {}", array_device);
+        return Ok(());
+    }
+    let created = Command::new(&mdadm_path)
+        .args(["--create", array_device, "--level=0", "--run"])
+        .arg(format!("--raid-devices={}", devices.len()))
+        .args(devices)
+        .output()
+        .map_err(|e| anyhow!("unable to run {:?}: {}", mdadm_path, e))?;
+    if !created.status.success() {
+        return Err(anyhow!(
+            "unable to create RAID0 array {}: {}",
+            array_device,
+            String::from_utf8_lossy(&created.stderr).trim()
+        ));
+    }
+    info!("Created RAID0 array {} from {:?}", array_device, devices);
+    Ok(())
+}
+
+fn lvm(args: &[&str]) -> Result<String> {
+    let lvm_path = Path::new(constants::DIR_ET_SBIN).join("lvm");
+    let output = Command::new(&lvm_path)
+        .args(args)
+        .output()
+        .map_err(|e| anyhow!("unable to run {:?}: {}", lvm_path, e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "lvm {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// Create or extend a volume group so that it includes all of the devices, then
+// activate it. Devices that are already physical volumes of another volume group
+// are an error rather than being taken over.
+pub fn activate_volume_group(volume_group: &str, devices: &[String]) -> Result<()> {
+    let exists = lvm(&["vgs", volume_group]).is_ok();
+    let mut new_devices = Vec::new();
+    for device in devices {
+        let device_vg = lvm(&["pvs", "--noheadings", "-o", "vg_name", device]).ok();
+        match device_vg.as_deref() {
+            Some(vg) if vg == volume_group => (),
+            Some(vg) if !vg.is_empty() => {
+                return Err(anyhow!("device {} belongs to volume group {}", device, vg));
+            }
+            _ => new_devices.push(device.as_str()),
+        }
+    }
+    if !new_devices.is_empty() {
+        lvm(&[&["pvcreate", "--yes"], new_devices.as_slice()].concat())?;
+        let command = if exists { "vgextend" } else { "vgcreate" };
+        lvm(&[&[command, volume_group], new_devices.as_slice()].concat())?;
+        info!(
+            "Added {:?} to volume group {} with {}",
+            new_devices, volume_group, command
+        );
+    }
+    lvm(&["vgchange", "--activate", "y", volume_group])?;
+    lvm(&["vgmknodes", volume_group])?;
+    Ok(())
+}
+
+// Create a logical volume if it does not exist, returning the path to its device.
+pub fn ensure_logical_volume(
+    volume_group: &str,
+    name: &str,
+    size: Option<&str>,
+) -> Result<PathBuf> {
+    let device = Path::new("/dev").join(volume_group).join(name);
+    if stat(&device).is_ok() {
+        return Ok(device);
+    }
+    match size {
+        Some(size) => lvm(&["lvcreate", "--yes", "-n", name, "-L", size, volume_group])?,
+        None => lvm(&[
+            "lvcreate",
+            "--yes",
+            "-n",
+            name,
+            "-l",
+            "100%FREE",
+            volume_group,
+        ])?,
+    };
+    lvm(&["vgmknodes", volume_group])?;
+    info!("Created logical volume {}/{}", volume_group, name);
+    Ok(device)
+}
+
+// Find the NVMe device of an EBS volume, whose controller serial number is the
+// volume ID without the hyphen, e.g. vol0123456789abcdef0.
+pub fn find_device_by_volume_id(volume_id: &str) -> Result<Option<String>> {
+    let serial = volume_id.replace('-', "");
+    let dir_fd = File::open(SYS_BLOCK_PATH)
+        .map_err(|e| anyhow!("unable to open {}: {}", SYS_BLOCK_PATH, e))?;
+    let dir = Dir::read_from(dir_fd)
+        .map_err(|e| anyhow!("unable to read from directory {}: {}", SYS_BLOCK_PATH, e))?;
+    for entry_res in dir {
+        let entry = entry_res.map_err(|e| {
+            anyhow!(
+                "unable to read directory entry in {}: {}",
+                SYS_BLOCK_PATH,
+                e
+            )
+        })?;
+        let device_name = entry.file_name().to_string_lossy().to_string();
+        if !device_name.starts_with("nvme") {
+            continue;
+        }
+        let serial_path = Path::new(SYS_BLOCK_PATH)
+            .join(&device_name)
+            .join("device/serial");
+        if let Ok(device_serial) = std::fs::read_to_string(&serial_path) {
+            if device_serial.trim() == serial {
+                return Ok(Some(format!("/dev/{}", device_name)));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Wait for the device of an EBS volume to appear, as it may still be attaching.
+pub fn wait_for_volume_id(volume_id: &str, timeout: Duration) -> Result<String> {
+    let start = Instant::now();
+    loop {
+        if let Some(device) = find_device_by_volume_id(volume_id)? {
+            return Ok(device);
+        }
+        if start.elapsed() >= timeout {
+            return Err(anyhow!("device of volume {} not found", volume_id));
+        }
+        sleep(Duration::from_millis(500));
+    }
+}
+
+// Find the device with a filesystem of the given label.
+pub fn find_device_by_label(label: &str) -> Option<String> {
+    let blkid_path = Path::new(constants::DIR_ET_SBIN).join("blkid");
+    let output = Command::new(blkid_path).args(["-L", label]).output().ok()?;
+    let device = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || device.is_empty() {
+        return None;
+    }
+    Some(device)
+}
+
 pub fn resize_root_volume() -> Result<()> {
     let (root_partition_device_name, root_disk_device_name) = find_root_devices()?;
     let root_disk_device_path = Path::new("/dev").join(&root_disk_device_name);
@@ -141,6 +465,114 @@ pub fn resize_root_volume() -> Result<()> {
 
     let logical_block_size = logical_block_size(&root_disk_device_name)
         .map_err(|e| anyhow!("unable to get sector size of root disk: {}", e))?;
+
+    let disk_sectors = disk_sectors(&root_disk_device_name)
+        .map_err(|e| anyhow!("unable to get sectors of root disk: {}", e))?;
+
+    let mut mbr = [0u8; MBR_SIZE];
+    root_disk_device
+        .read_exact_at(&mut mbr, 0)
+        .map_err(|e| anyhow!("unable to read partition table: {}", e))?;
+
+    let resized = if is_protective_mbr(&mbr) {
+        resize_gpt_root_partition(&root_disk_device, logical_block_size, disk_sectors)?
+    } else {
+        let root_part_num = disk_partitions(&root_disk_device_name)
+            .map_err(|e| anyhow!("unable to get partitions of root disk: {}", e))?
+            .into_iter()
+            .find(|p| p.device == root_partition_device_name)
+            .ok_or_else(|| anyhow!("root partition not found"))?
+            .partition
+            .parse::<u32>()
+            .map_err(|e| anyhow!("unable to parse root partition number: {}", e))?;
+        let resized = resize_mbr_partition(&mut mbr, root_part_num, disk_sectors as u64)?;
+        if resized.is_some() {
+            root_disk_device
+                .write_all_at(&mbr, 0)
+                .map_err(|e| anyhow!("unable to write partition table: {}", e))?;
+        }
+        resized.map(|(first_lba, last_lba)| (root_part_num, first_lba, last_lba))
+    };
+
+    if let Some((root_part_num, first_lba, last_lba)) = resized {
+        kernel_reread_partition(
+            &root_disk_device,
+            root_part_num as i32,
+            first_lba as i64,
+            last_lba as i64,
+            logical_block_size,
+        )
+        .map_err(|e| anyhow!("unable to reread partition table: {}", e))?;
+        debug!("growing root filesystem");
+        grow_filesystem(
+            &Path::new("/dev").join(root_partition_device_name),
+            Path::new(constants::DIR_ROOT),
+        )
+        .map_err(|e| anyhow!("unable to grow root filesystem: {}", e))?;
+    }
+    Ok(())
+}
+
+// An MBR whose first partition has the GPT type is a protective MBR for a GPT disk.
+fn is_protective_mbr(mbr: &[u8; MBR_SIZE]) -> bool {
+    mbr[MBR_PARTITION_TABLE_OFFSET + 4] == MBR_TYPE_GPT_PROTECTIVE
+}
+
+// Extend a primary MBR partition to the end of the disk, if it is the last partition
+// on the disk and is not already within the threshold of it. Returns the first and
+// last sectors of the partition if it was resized.
+fn resize_mbr_partition(
+    mbr: &mut [u8; MBR_SIZE],
+    part_num: u32,
+    disk_sectors: u64,
+) -> Result<Option<(u64, u64)>> {
+    if mbr[MBR_SIZE - 2..] != MBR_SIGNATURE {
+        return Err(anyhow!("partition table is neither GPT nor MBR"));
+    }
+    if !(1..=4).contains(&part_num) {
+        return Err(anyhow!("partition {} is not a primary partition", part_num));
+    }
+
+    let entry_offset = |n: u32| MBR_PARTITION_TABLE_OFFSET + (n as usize - 1) * 16;
+    let read_u32 = |mbr: &[u8; MBR_SIZE], offset: usize| {
+        u32::from_le_bytes(mbr[offset..offset + 4].try_into().unwrap()) as u64
+    };
+
+    let offset = entry_offset(part_num);
+    let first_lba = read_u32(mbr, offset + 8);
+    let num_sectors = read_u32(mbr, offset + 12);
+    for other in (1..=4).filter(|n| *n != part_num) {
+        let other_offset = entry_offset(other);
+        if read_u32(mbr, other_offset + 12) > 0 && read_u32(mbr, other_offset + 8) > first_lba {
+            return Err(anyhow!("partition {} is not the last partition", part_num));
+        }
+    }
+
+    // MBR cannot address more than 2^32 sectors.
+    let last_sector = disk_sectors.min(u32::MAX as u64) - 1;
+    let new_num_sectors = last_sector - first_lba + 1;
+    let fudge = 1024 * 1024; // A la growpart; don't resize if within this threshold.
+    if new_num_sectors < num_sectors + fudge {
+        return Ok(None);
+    }
+    info!(
+        "resizing partition from sector {} to sector {}",
+        first_lba + num_sectors - 1,
+        last_sector
+    );
+    // Set the ending CHS address to the maximum, as for any partition beyond 8GiB.
+    mbr[offset + 5..offset + 8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+    mbr[offset + 12..offset + 16].copy_from_slice(&(new_num_sectors as u32).to_le_bytes());
+    Ok(Some((first_lba, last_sector)))
+}
+
+// Extend the partition named root to the end of the disk. Returns the partition
+// number and its first and last sectors if it was resized.
+fn resize_gpt_root_partition(
+    root_disk_device: &File,
+    logical_block_size: i64,
+    disk_sectors: i64,
+) -> Result<Option<(u32, u64, u64)>> {
     let logical_block_size_cfg = match logical_block_size {
         512 => LogicalBlockSize::Lb512,
         4096 => LogicalBlockSize::Lb4096,
@@ -150,10 +582,7 @@ pub fn resize_root_volume() -> Result<()> {
     let mut root_disk = GptConfig::new()
         .logical_block_size(logical_block_size_cfg)
         .writable(true)
-        .open_from_device(&root_disk_device)?;
-
-    let disk_sectors = disk_sectors(&root_disk_device_name)
-        .map_err(|e| anyhow!("unable to get sectors of root disk: {}", e))?;
+        .open_from_device(root_disk_device)?;
 
     let align = root_disk.calculate_alignment() as i64;
 
@@ -195,27 +624,17 @@ pub fn resize_root_volume() -> Result<()> {
         }
     }
 
-    if resized {
-        debug!("partitions after resizing: {:?}", partitions);
-        root_disk
-            .update_partitions(partitions)
-            .map_err(|e| anyhow!("unable to update partitions: {}", e))?;
-        root_disk
-            .write()
-            .map_err(|e| anyhow!("unable to write disk: {}", e))?;
-        kernel_reread_partition(
-            &root_disk_device,
-            root_part_num as i32,
-            first_lba as i64,
-            last_usable_sector as i64,
-            logical_block_size,
-        )
-        .map_err(|e| anyhow!("unable to reread partition table: {}", e))?;
-        debug!("growing root filesystem");
-        grow_filesystem(&Path::new("/dev").join(root_partition_device_name))
-            .map_err(|e| anyhow!("unable to grow root filesystem: {}", e))?;
+    if !resized {
+        return Ok(None);
     }
-    Ok(())
+    debug!("partitions after resizing: {:?}", partitions);
+    root_disk
+        .update_partitions(partitions)
+        .map_err(|e| anyhow!("unable to update partitions: {}", e))?;
+    root_disk
+        .write()
+        .map_err(|e| anyhow!("unable to write disk: {}", e))?;
+    Ok(Some((root_part_num, first_lba, last_usable_sector)))
 }
 
 fn last_usable_sector(disk_sectors: i64, first_usable_sector: i64, align: i64) -> u64 {
@@ -285,12 +704,30 @@ fn find_root_devices() -> Result<(String, String)> {
     Err(anyhow!("unable to find parent device of root partition"))
 }
 
-fn grow_filesystem(path: &PathBuf) -> Result<()> {
-    let resize2fs_path = Path::new(constants::DIR_ET_SBIN).join("resize2fs");
-    Command::new(resize2fs_path)
-        .arg(path)
-        .spawn()?
-        .wait_with_output()?;
+// Grow the filesystem mounted at mount_point on device, using the tool for its type.
+// Filesystems other than btrfs and XFS are assumed to be ext4.
+fn grow_filesystem(device: &Path, mount_point: &Path) -> Result<()> {
+    let fs_type = statfs(mount_point)
+        .map_err(|e| anyhow!("unable to get filesystem type of {:?}: {}", mount_point, e))?
+        .f_type;
+    let mut cmd = match fs_type {
+        BTRFS_SUPER_MAGIC => {
+            let mut cmd = Command::new(Path::new(constants::DIR_ET_SBIN).join("btrfs"));
+            cmd.args(["filesystem", "resize", "max"]).arg(mount_point);
+            cmd
+        }
+        XFS_SUPER_MAGIC => {
+            let mut cmd = Command::new(Path::new(constants::DIR_ET_SBIN).join("xfs_growfs"));
+            cmd.arg(mount_point);
+            cmd
+        }
+        _ => {
+            let mut cmd = Command::new(Path::new(constants::DIR_ET_SBIN).join("resize2fs"));
+            cmd.arg(device);
+            cmd
+        }
+    };
+    cmd.spawn()?.wait_with_output()?;
     Ok(())
 }
 
@@ -330,6 +767,29 @@ fn disk_partitions(device: &str) -> Result<Vec<PartitionInfo>> {
     Ok(partitions)
 }
 
+// Name the partition of a device, e.g. sdf2 for partition 2 of sdf, or nvme1n1p2
+// when the device name ends in a digit.
+pub fn partition_device<T: Display>(device: &str, partition: T) -> String {
+    if has_digit_suffix(device) {
+        format!("{}p{}", device, partition)
+    } else {
+        format!("{}{}", device, partition)
+    }
+}
+
+// Wait for a device to appear, as partitions may not be visible as soon as their
+// disk is.
+pub fn wait_for_device(device: &str, timeout: Duration) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        match stat(device) {
+            Ok(_) => return Ok(()),
+            Err(Errno::NOENT) if start.elapsed() < timeout => sleep(Duration::from_millis(100)),
+            Err(e) => return Err(anyhow!("unable to find device {}: {}", device, e)),
+        }
+    }
+}
+
 fn has_digit_suffix(string: &str) -> bool {
     string.chars().last().map_or(false, |c| c.is_ascii_digit())
 }
@@ -340,6 +800,122 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_machine_id_from_instance_id() {
+        struct Case {
+            instance_id: &'static str,
+            expected: Option<&'static str>,
+        }
+        let cases = [
+            Case {
+                instance_id: "i-0123456789abcdef0",
+                expected: Some("0000000000000000123456789abcdef0"),
+            },
+            Case {
+                instance_id: "i-0A1B2C3D",
+                expected: Some("0000000000000000000000000a1b2c3d"),
+            },
+            Case {
+                instance_id: "i-",
+                expected: None,
+            },
+            Case {
+                instance_id: "i-not-hex",
+                expected: None,
+            },
+            Case {
+                instance_id: "0123456789abcdef0",
+                expected: None,
+            },
+        ];
+        for case in cases {
+            assert_eq!(
+                machine_id_from_instance_id(case.instance_id).as_deref(),
+                case.expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_write_machine_id() {
+        let base_dir = std::env::temp_dir().join(format!("machine-id-{}", std::process::id()));
+        std::fs::create_dir_all(base_dir.join_relative(constants::DIR_ET_ETC)).unwrap();
+        std::fs::create_dir_all(base_dir.join("etc")).unwrap();
+
+        write_machine_id(&base_dir, Some("i-0123456789abcdef0")).unwrap();
+        let etc_path = base_dir.join_relative(constants::FILE_ETC_MACHINE_ID);
+        assert_eq!(
+            read_to_string(&etc_path).unwrap(),
+            "0000000000000000123456789abcdef0\n"
+        );
+
+        // The persisted machine ID is kept even if the instance ID changes.
+        write_machine_id(&base_dir, Some("i-fedcba9876543210f")).unwrap();
+        assert_eq!(
+            read_to_string(&etc_path).unwrap(),
+            "0000000000000000123456789abcdef0\n"
+        );
+
+        std::fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[test]
+    fn test_resize_mbr_partition() {
+        fn mbr_with(entries: &[(u32, u32)]) -> [u8; MBR_SIZE] {
+            let mut mbr = [0u8; MBR_SIZE];
+            for (i, (first_lba, num_sectors)) in entries.iter().enumerate() {
+                let offset = MBR_PARTITION_TABLE_OFFSET + i * 16;
+                mbr[offset + 4] = 0x83;
+                mbr[offset + 8..offset + 12].copy_from_slice(&first_lba.to_le_bytes());
+                mbr[offset + 12..offset + 16].copy_from_slice(&num_sectors.to_le_bytes());
+            }
+            mbr[MBR_SIZE - 2..].copy_from_slice(&MBR_SIGNATURE);
+            mbr
+        }
+        let disk_sectors = 16 * 1024 * 1024;
+
+        let mut mbr = mbr_with(&[(2048, 4 * 1024 * 1024)]);
+        assert_eq!(is_protective_mbr(&mbr), false);
+        assert_eq!(
+            resize_mbr_partition(&mut mbr, 1, disk_sectors).unwrap(),
+            Some((2048, disk_sectors - 1))
+        );
+        assert_eq!(
+            mbr[MBR_PARTITION_TABLE_OFFSET + 12..MBR_PARTITION_TABLE_OFFSET + 16],
+            ((disk_sectors - 2048) as u32).to_le_bytes()
+        );
+
+        // Already within the threshold of the end of the disk.
+        let mut mbr = mbr_with(&[(2048, disk_sectors as u32 - 4096)]);
+        assert_eq!(
+            resize_mbr_partition(&mut mbr, 1, disk_sectors).unwrap(),
+            None
+        );
+
+        // Not the last partition.
+        let mut mbr = mbr_with(&[(2048, 1024 * 1024), (2 * 1024 * 1024, 1024 * 1024)]);
+        assert!(resize_mbr_partition(&mut mbr, 1, disk_sectors).is_err());
+
+        let mut mbr = mbr_with(&[(1, 1)]);
+        mbr[MBR_PARTITION_TABLE_OFFSET + 4] = MBR_TYPE_GPT_PROTECTIVE;
+        assert_eq!(is_protective_mbr(&mbr), true);
+    }
+
+    #[test]
+    fn test_hugepage_size_kb() {
+        assert_eq!(hugepage_size_kb("2M").unwrap(), 2048);
+        assert_eq!(hugepage_size_kb("2048kB").unwrap(), 2048);
+        assert_eq!(hugepage_size_kb("1G").unwrap(), 1024 * 1024);
+        assert!(hugepage_size_kb("1T").is_err());
+        assert!(hugepage_size_kb("M").is_err());
+    }
+
+    #[test]
+    fn test_partition_device() {
+        assert_eq!(partition_device("/dev/sdf", 2), "/dev/sdf2");
+        assert_eq!(partition_device("/dev/nvme1n1", 2), "/dev/nvme1n1p2");
+    }
+
     #[test]
     fn test_has_digit_suffix() {
         assert_eq!(has_digit_suffix(""), false);
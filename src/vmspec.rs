@@ -1,26 +1,45 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fs::{self, File};
-use std::io::BufReader;
+use std::io::{BufReader, Read, Write};
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::time::Duration;
 
 use anyhow::{anyhow, Error, Result};
+use base64::prelude::*;
+use flate2::read::GzDecoder;
 use k8s_expand::{expand, mapping_func_for};
-use log::{debug, info};
-use minaws::imds::Imds;
-use rustix::fs::{chmod, Mode};
+use log::{debug, info, warn, LevelFilter};
+use rustix::fs::{chmod, chown, Gid, Mode, OpenOptionsExt, Uid};
+use rustix::process::{Resource, Rlimit};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
+use crate::aws::asm::AsmClient;
+use crate::aws::imds::{self, CachedImds};
+use crate::aws::s3::S3Client;
+use crate::aws::ssm::SsmClient;
+use crate::aws::LazyCredentials;
+use crate::capabilities::CapabilityPlan;
+use crate::cloudconfig::{is_cloud_config, CloudConfig};
 use crate::constants;
-use crate::container::ConfigFile;
-use crate::login::user_group_id;
-use crate::system::{find_executable_in_path, sysctl};
+use crate::container::{ConfigFile, HealthConfig};
+use crate::fs::{mkdir_p, parse_mode, JoinRelative};
+use crate::kmod::ModuleLoader;
+use crate::login::{self, user_group_id, Find, GroupEntry, PasswdEntry, ShadowEntry};
+use crate::mime::{is_multipart, parse_multipart};
+use crate::spot::InstanceEvent;
+use crate::system::{find_executable_in_path, reserve_hugepages, sysctl};
+use crate::writable::Writable;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
 
 #[derive(Debug, PartialEq)]
-struct UserGroupNames {
-    user: String,
-    group: Option<String>,
+pub(crate) struct UserGroupNames {
+    pub(crate) user: String,
+    pub(crate) group: Option<String>,
 }
 
 impl TryFrom<String> for UserGroupNames {
@@ -43,83 +62,519 @@ impl TryFrom<String> for UserGroupNames {
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct UserData {
     pub args: Option<Vec<String>>,
+    #[serde(rename = "boot-log")]
+    pub boot_log: Option<BootLog>,
     pub command: Option<Vec<String>>,
     pub debug: Option<bool>,
+    #[serde(rename = "degraded-boot")]
+    pub degraded_boot: Option<bool>,
     #[serde(rename = "disable-services")]
     pub disable_services: Option<Vec<String>>,
+    #[serde(rename = "drain-command")]
+    pub drain_command: Option<Vec<String>>,
+    #[serde(rename = "drain-timeout")]
+    pub drain_timeout: Option<u64>,
     pub env: Option<NameValues>,
     #[serde(rename = "env-from")]
     pub env_from: Option<EnvFromSources>,
+    #[serde(rename = "failed-boot-threshold")]
+    pub failed_boot_threshold: Option<u64>,
+    #[serde(rename = "failure-report")]
+    pub failure_report: Option<FailureReport>,
+    pub groups: Option<Groups>,
+    #[serde(rename = "health-check")]
+    pub health_check: Option<HealthCheck>,
+    pub hugepages: Option<HugePagesList>,
+    pub include: Option<String>,
     #[serde(rename = "init-scripts")]
-    pub init_scripts: Option<Vec<String>>,
+    pub init_scripts: Option<InitScripts>,
+    #[serde(rename = "instance-events")]
+    pub instance_events: Option<InstanceEvents>,
+    #[serde(rename = "kernel-modules")]
+    pub kernel_modules: Option<KernelModules>,
+    pub kexec: Option<Kexec>,
+    pub limits: Option<Limits>,
+    #[serde(rename = "log-format")]
+    pub log_format: Option<LogFormat>,
+    #[serde(rename = "log-levels")]
+    pub log_levels: Option<HashMap<String, LogLevel>>,
+    #[serde(rename = "login-password")]
+    pub login_password: Option<LoginPassword>,
+    #[serde(rename = "on-exit")]
+    pub on_exit: Option<OnExit>,
+    #[serde(rename = "oom-score-adj")]
+    pub oom_score_adj: Option<i32>,
+    #[serde(rename = "overlay-from")]
+    pub overlay_from: Option<OverlayFrom>,
+    #[serde(rename = "privilege-escalation")]
+    pub privilege_escalation: Option<PrivilegeEscalation>,
+    #[serde(rename = "readiness-probe")]
+    pub readiness_probe: Option<ReadinessProbe>,
     #[serde(rename = "replace-init")]
     pub replace_init: Option<bool>,
+    #[serde(rename = "restart-policy")]
+    pub restart_policy: Option<RestartPolicy>,
     pub security: Option<Security>,
+    #[serde(rename = "service-dependencies")]
+    pub service_dependencies: Option<HashMap<String, ServiceDependencies>>,
+    #[serde(rename = "service-logs")]
+    pub service_logs: Option<ServiceLogs>,
+    #[serde(rename = "service-overrides")]
+    pub service_overrides: Option<HashMap<String, ServiceOverride>>,
+    #[serde(rename = "service-readiness-probes")]
+    pub service_readiness_probes: Option<HashMap<String, ReadinessProbe>>,
+    #[serde(rename = "service-restart-policies")]
+    pub service_restart_policies: Option<HashMap<String, RestartPolicy>>,
+    pub services: Option<SidecarServices>,
     #[serde(rename = "shutdown-grace-period")]
     pub shutdown_grace_period: Option<u64>,
+    #[serde(rename = "shutdown-scripts")]
+    pub shutdown_scripts: Option<InitScripts>,
+    #[serde(rename = "spot-monitor")]
+    pub spot_monitor: Option<bool>,
+    pub ssh: Option<Ssh>,
+    #[serde(rename = "startup-timeout")]
+    pub startup_timeout: Option<u64>,
+    #[serde(rename = "status-server")]
+    pub status_server: Option<StatusServer>,
+    pub strict: Option<bool>,
+    pub syslog: Option<Syslog>,
     pub sysctls: Option<NameValues>,
+    pub time: Option<Time>,
+    pub users: Option<Users>,
     pub volumes: Option<Volumes>,
+    pub watchdog: Option<Watchdog>,
     pub working_dir: Option<String>,
+    #[serde(rename = "write-files")]
+    pub write_files: Option<WriteFiles>,
 }
 
 impl UserData {
-    pub fn from_imds(imds_client: &Imds) -> Result<Self> {
-        imds_client
-            .get_user_data()
-            .map_err(|e| anyhow!("unable to get user data: {}", e))
-            .and_then(|user_data| {
-                serde_yml::from_str::<UserData>(&user_data)
-                    .map_err(|e| anyhow!("unable to parse user data: {}", e))
-            })
+    pub fn from_imds() -> Result<Self> {
+        imds::get_user_data().and_then(|user_data| Self::from_bytes(&user_data))
+    }
+
+    pub fn from_string(user_data: &str) -> Result<Self> {
+        Self::from_bytes(user_data.as_bytes())
+    }
+
+    pub fn from_bytes(user_data: &[u8]) -> Result<Self> {
+        let decoded = decode_user_data(user_data)?;
+        let user_data = std::str::from_utf8(&decoded)
+            .map_err(|e| anyhow!("unable to read user data as UTF-8: {}", e))?;
+        if is_multipart(user_data) {
+            return Self::from_multipart(user_data);
+        }
+        if is_cloud_config(user_data) {
+            return CloudConfig::from_string(user_data)?.to_user_data();
+        }
+        Self::from_yaml(user_data)
+    }
+
+    // Parse easyto user data. Unknown keys are ignored unless strict is set,
+    // in which case they are reported as an error to catch typos.
+    fn from_yaml(user_data: &str) -> Result<Self> {
+        let mut unknown_keys = Vec::new();
+        let deserializer = serde_yml::Deserializer::from_str(user_data);
+        let parsed: UserData = serde_ignored::deserialize(deserializer, |path| {
+            unknown_keys.push(path.to_string());
+        })
+        .map_err(|e| anyhow!("unable to parse user data: {}", e))?;
+        if parsed.strict.unwrap_or_default() && !unknown_keys.is_empty() {
+            return Err(anyhow!(
+                "unrecognized keys in user data: {}",
+                unknown_keys.join(", ")
+            ));
+        }
+        Ok(parsed)
+    }
+
+    // Build user data from a multipart document. At most one part may be easyto
+    // or cloud-config user data. Shell script parts become init scripts, and other
+    // parts with an absolute filename are written to that path.
+    fn from_multipart(user_data: &str) -> Result<Self> {
+        let mut config: Option<UserData> = None;
+        let mut init_scripts = Vec::new();
+        let mut write_files = Vec::new();
+        for part in parse_multipart(user_data)? {
+            match part.content_type.as_str() {
+                "application/x-yaml" | "application/yaml" | "text/cloud-config" | "text/x-yaml"
+                | "text/yaml" => {
+                    if config.is_some() {
+                        return Err(anyhow!("multiple user data parts found"));
+                    }
+                    config = Some(Self::from_bytes(&part.body)?);
+                }
+                "text/x-shellscript" => {
+                    let script = String::from_utf8(part.body)
+                        .map_err(|e| anyhow!("unable to read shell script part: {}", e))?;
+                    init_scripts.push(InitScript::from(script));
+                }
+                content_type => match part.filename {
+                    Some(filename) if filename.starts_with(constants::DIR_ROOT) => {
+                        write_files.push(WriteFile {
+                            base64_encoded: Some(true),
+                            content: BASE64_STANDARD.encode(&part.body),
+                            path: filename,
+                            ..Default::default()
+                        });
+                    }
+                    _ => {
+                        return Err(anyhow!(
+                            "unsupported user data part with content type {}",
+                            content_type
+                        ))
+                    }
+                },
+            }
+        }
+
+        let mut user_data = config.unwrap_or_default();
+        if !init_scripts.is_empty() {
+            user_data
+                .init_scripts
+                .get_or_insert_with(Vec::new)
+                .extend(init_scripts);
+        }
+        if !write_files.is_empty() {
+            user_data
+                .write_files
+                .get_or_insert_with(Vec::new)
+                .extend(write_files);
+        }
+        Ok(user_data)
+    }
+}
+
+// Decode user data that is gzip compressed, base64 encoded, or both, as provisioning
+// tools often compress user data to stay under the size limit. Anything else is
+// returned as is.
+fn decode_user_data(user_data: &[u8]) -> Result<Vec<u8>> {
+    if user_data.starts_with(&GZIP_MAGIC) {
+        let mut buf = Vec::new();
+        GzDecoder::new(user_data)
+            .read_to_end(&mut buf)
+            .map_err(|e| anyhow!("unable to decompress user data: {}", e))?;
+        return Ok(buf);
+    }
+    match decode_base64(user_data) {
+        Some(decoded) if decoded.starts_with(&GZIP_MAGIC) => decode_user_data(&decoded),
+        Some(decoded) => Ok(decoded),
+        None => Ok(user_data.to_vec()),
+    }
+}
+
+// Decode user data only if all of it is base64, optionally wrapped in lines of
+// equal length, and it decodes to gzip or to printable text. This keeps plain user
+// data that happens to be made of base64 characters from being decoded.
+fn decode_base64(user_data: &[u8]) -> Option<Vec<u8>> {
+    let text = std::str::from_utf8(user_data).ok()?.trim();
+    let lines = text
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .collect::<Vec<_>>();
+    let (last, full) = lines.split_last()?;
+    let width = lines[0].len();
+    if last.len() > width
+        || full
+            .iter()
+            .any(|line| line.len() != width || width % 4 != 0)
+    {
+        return None;
     }
+    let decoded = BASE64_STANDARD.decode(lines.concat()).ok()?;
+    if decoded.starts_with(&GZIP_MAGIC) {
+        return Some(decoded);
+    }
+    let printable = std::str::from_utf8(&decoded)
+        .ok()?
+        .chars()
+        .all(|c| !c.is_control() || c.is_ascii_whitespace());
+    printable.then_some(decoded)
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct OverlayFrom {
+    pub optional: Option<bool>,
+    #[serde(rename = "ssm-path")]
+    pub ssm_path: String,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct VmSpec {
     pub args: Vec<String>,
+    #[serde(rename = "boot-log")]
+    pub boot_log: BootLog,
     pub command: Vec<String>,
     pub debug: bool,
+    #[serde(rename = "degraded-boot")]
+    pub degraded_boot: bool,
     #[serde(rename = "disable-services")]
     pub disable_services: Vec<String>,
+    // A command run as the main process's user when a shutdown starts, before any
+    // process is stopped, and killed after drain-timeout seconds.
+    #[serde(rename = "drain-command")]
+    pub drain_command: Vec<String>,
+    #[serde(rename = "drain-timeout")]
+    pub drain_timeout: u64,
     pub env: NameValues,
     #[serde(rename = "env-from")]
     pub env_from: EnvFromSources,
+    #[serde(rename = "failed-boot-threshold")]
+    pub failed_boot_threshold: u64,
+    #[serde(rename = "failure-report")]
+    pub failure_report: Option<FailureReport>,
+    pub groups: Groups,
+    #[serde(rename = "health-check")]
+    pub health_check: Option<HealthCheck>,
+    pub hugepages: HugePagesList,
     #[serde(rename = "init-scripts")]
-    pub init_scripts: Vec<String>,
+    pub init_scripts: InitScripts,
+    #[serde(rename = "instance-events")]
+    pub instance_events: InstanceEvents,
+    #[serde(rename = "kernel-modules")]
+    pub kernel_modules: KernelModules,
+    pub kexec: Kexec,
+    pub limits: Limits,
+    // The format of the messages init writes to the console. It can also be set
+    // with easyto.log-format on the kernel command line.
+    #[serde(rename = "log-format")]
+    pub log_format: LogFormat,
+    // Levels of messages of particular modules of init, such as service or syslog,
+    // overriding the level set by debug.
+    #[serde(rename = "log-levels")]
+    pub log_levels: HashMap<String, LogLevel>,
+    #[serde(rename = "login-password")]
+    pub login_password: Option<LoginPassword>,
+    #[serde(rename = "on-exit")]
+    pub on_exit: OnExit,
+    #[serde(rename = "oom-score-adj")]
+    pub oom_score_adj: i32,
+    #[serde(rename = "privilege-escalation")]
+    pub privilege_escalation: Option<PrivilegeEscalation>,
+    #[serde(rename = "readiness-probe")]
+    pub readiness_probe: Option<ReadinessProbe>,
     #[serde(rename = "replace-init")]
     pub replace_init: bool,
+    #[serde(rename = "restart-policy")]
+    pub restart_policy: RestartPolicy,
     pub security: Security,
+    #[serde(rename = "service-dependencies")]
+    pub service_dependencies: HashMap<String, ServiceDependencies>,
+    #[serde(rename = "service-logs")]
+    pub service_logs: ServiceLogs,
+    #[serde(rename = "service-overrides")]
+    pub service_overrides: HashMap<String, ServiceOverride>,
+    #[serde(rename = "service-readiness-probes")]
+    pub service_readiness_probes: HashMap<String, ReadinessProbe>,
+    #[serde(rename = "service-restart-policies")]
+    pub service_restart_policies: HashMap<String, RestartPolicy>,
+    pub services: SidecarServices,
     #[serde(rename = "shutdown-grace-period")]
     pub shutdown_grace_period: u64,
+    #[serde(rename = "shutdown-scripts")]
+    pub shutdown_scripts: InitScripts,
+    // Whether to watch for spot interruptions and rebalance recommendations. If not
+    // set, they are watched on spot instances.
+    #[serde(rename = "spot-monitor")]
+    pub spot_monitor: Option<bool>,
+    pub ssh: Ssh,
+    // Seconds to wait for the main process to start, and to become ready if it has a
+    // readiness probe, before powering off.
+    #[serde(rename = "startup-timeout")]
+    pub startup_timeout: Option<u64>,
+    #[serde(rename = "status-server")]
+    pub status_server: StatusServer,
+    pub syslog: Syslog,
     pub sysctls: NameValues,
+    pub time: Time,
+    pub users: Users,
     pub volumes: Volumes,
+    pub watchdog: Watchdog,
     pub working_dir: String,
+    #[serde(rename = "write-files")]
+    pub write_files: WriteFiles,
 }
 
 impl Default for VmSpec {
     fn default() -> Self {
         VmSpec {
             args: Vec::new(),
+            boot_log: BootLog::default(),
             command: Vec::new(),
             debug: false,
+            degraded_boot: false,
             disable_services: Vec::new(),
+            drain_command: Vec::new(),
+            drain_timeout: 30,
             env: Vec::new(),
             env_from: Vec::new(),
+            failed_boot_threshold: 3,
+            failure_report: None,
+            groups: Vec::new(),
+            health_check: None,
+            hugepages: Vec::new(),
             init_scripts: Vec::new(),
+            instance_events: InstanceEvents::default(),
+            kernel_modules: Vec::new(),
+            kexec: Kexec::default(),
+            limits: Limits::default(),
+            log_format: LogFormat::default(),
+            log_levels: HashMap::new(),
+            login_password: None,
+            on_exit: HashMap::new(),
+            oom_score_adj: 0,
+            privilege_escalation: None,
+            readiness_probe: None,
             replace_init: false,
+            restart_policy: RestartPolicy::default(),
             security: Security::default(),
+            service_dependencies: HashMap::new(),
+            service_logs: ServiceLogs::default(),
+            service_overrides: HashMap::new(),
+            service_readiness_probes: HashMap::new(),
+            service_restart_policies: HashMap::new(),
+            services: Vec::new(),
             shutdown_grace_period: 10,
+            shutdown_scripts: Vec::new(),
+            spot_monitor: None,
+            ssh: Ssh::default(),
+            startup_timeout: None,
+            status_server: StatusServer::default(),
+            syslog: Syslog::default(),
             sysctls: Vec::new(),
+            time: Time::default(),
+            users: Vec::new(),
             volumes: Vec::new(),
+            watchdog: Watchdog::default(),
             working_dir: "/".into(),
+            write_files: Vec::new(),
         }
     }
 }
 
 impl VmSpec {
+    // Drop optional volumes and environment sources, so a degraded boot depends
+    // on as few external resources as possible.
+    pub fn degrade(&mut self) {
+        self.volumes.retain(|volume| !volume.is_optional());
+        self.env_from.retain(|source| !source.is_optional());
+        if let Some(sources) = &mut self.ssh.authorized_keys_from {
+            sources.retain(|source| !source.is_optional());
+        }
+        if let Some(true) = self.ssh.host_certificate.as_ref().map(|c| c.is_optional()) {
+            self.ssh.host_certificate = None;
+        }
+        if let Some(true) = self.login_password.as_ref().map(|p| p.from.is_optional()) {
+            self.login_password = None;
+        }
+    }
+
+    // Return the names of the top level fields that differ from those of another
+    // VmSpec, as they appear in user data.
+    pub fn changed_fields(&self, other: &VmSpec) -> Vec<String> {
+        let (Ok(Value::Object(fields)), Ok(Value::Object(mut other_fields))) =
+            (serde_json::to_value(self), serde_json::to_value(other))
+        else {
+            return Vec::new();
+        };
+        let mut changed = fields
+            .into_iter()
+            .filter(|(name, value)| other_fields.remove(name).as_ref() != Some(value))
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        changed.sort();
+        changed
+    }
+
+    // The configuration as JSON, with values that may be secret redacted, for
+    // including in a failure report.
+    pub fn redacted(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        redact(&mut value);
+        value
+    }
+
+    pub fn overlays(&self) -> &[Overlay] {
+        match self.security.readonly_root_fs {
+            Some(true) => self
+                .security
+                .readonly_root_overlays
+                .as_deref()
+                .unwrap_or_default(),
+            _ => &[],
+        }
+    }
+
+    // Mount points to unmount at shutdown, with overlays first as they may be
+    // backed by EBS volumes.
+    pub fn mount_points(&self) -> Vec<String> {
+        self.overlays()
+            .iter()
+            .map(|overlay| overlay.path.clone())
+            .chain(self.volumes.iter().flat_map(Volume::block_mount_points))
+            .collect()
+    }
+
+    // The actions for exit codes of the main process, with the narrowest ranges of
+    // codes first so they take precedence over wider ones that overlap them.
+    pub fn exit_actions(&self) -> Result<Vec<(RangeInclusive<i32>, ExitAction)>> {
+        let mut actions = self
+            .on_exit
+            .iter()
+            .map(|(codes, action)| Ok((parse_exit_codes(codes)?, *action)))
+            .collect::<Result<Vec<_>>>()?;
+        actions.sort_by_key(|(codes, _)| (codes.end() - codes.start(), *codes.start()));
+        Ok(actions)
+    }
+
+    // Mount points of EBS volumes to trim periodically, with their intervals.
+    pub fn trim_intervals(&self) -> Vec<(String, Duration)> {
+        self.volumes
+            .iter()
+            .filter_map(|v| v.ebs.as_ref())
+            .filter(|ebs| ebs.trim.unwrap_or_default())
+            .filter_map(|ebs| {
+                ebs.trim_interval
+                    .map(|secs| (ebs.mount.destination.clone(), Duration::from_secs(secs)))
+            })
+            .collect()
+    }
+
+    // Secrets Manager and SSM volumes to fetch again periodically, into their
+    // destinations under base_dir.
+    pub fn volume_refreshes(&self, base_dir: &Path, region: &str) -> Vec<VolumeRefresh> {
+        let mut refreshes = Vec::new();
+        for volume in &self.volumes {
+            if let Some(asm) = &volume.secrets_manager {
+                if let Some(secs) = asm.refresh_interval {
+                    refreshes.push(VolumeRefresh {
+                        destination: base_dir.join(&asm.mount.destination),
+                        interval: Duration::from_secs(secs),
+                        region: region.into(),
+                        signal: asm.refresh_signal.clone(),
+                        source: RefreshSource::SecretsManager(asm.clone()),
+                    });
+                }
+            }
+            if let Some(ssm) = &volume.ssm {
+                if let Some(secs) = ssm.refresh_interval {
+                    refreshes.push(VolumeRefresh {
+                        destination: base_dir.join(&ssm.mount.destination),
+                        interval: Duration::from_secs(secs),
+                        region: region.into(),
+                        signal: ssm.refresh_signal.clone(),
+                        source: RefreshSource::Ssm(ssm.clone()),
+                    });
+                }
+            }
+        }
+        refreshes
+    }
+
     pub fn full_command(&self, env: &NameValues) -> Result<Vec<String>> {
         let cap = self.command.len() + self.args.len();
         if cap == 0 {
@@ -130,46 +585,7 @@ impl VmSpec {
         exe.extend(self.command.clone());
         exe.extend(self.args.clone());
 
-        let path_var = env
-            .find("PATH")
-            .unwrap_or_else(|| unreachable!("PATH should have been defined"));
-
-        if !exe[0].starts_with(constants::DIR_ROOT) {
-            let exe_path = find_executable_in_path(&exe[0], &path_var.value)
-                .ok_or_else(|| anyhow!("unable to find executable in PATH: {}", exe[0]))?
-                .to_str()
-                .ok_or_else(|| anyhow!("unable to convert path to string: {}", exe[0]))?
-                .into();
-            exe[0] = exe_path;
-        }
-
-        let env_refs = HashMap::from_iter(env.to_map_rc());
-        let maps = vec![&env_refs];
-        let mapping = mapping_func_for(&maps);
-        let mut expanded_exe = Vec::with_capacity(exe.len());
-        for arg in exe.iter() {
-            expanded_exe.push(expand(arg, &mapping));
-        }
-
-        Ok(expanded_exe)
-    }
-
-    fn run_init_script<P: AsRef<Path>>(
-        &self,
-        path: P,
-        contents: &[u8],
-        env: &NameValues,
-    ) -> Result<()> {
-        fs::write(&path, contents)
-            .map_err(|e| anyhow!("unable to write init script to {:?}: {}", path.as_ref(), e))?;
-        chmod(path.as_ref(), Mode::from(0o755))
-            .map_err(|e| anyhow!("unable to set init script as executable: {}", e))?;
-        Command::new(path.as_ref())
-            .stdout(Stdio::inherit())
-            .envs(env.to_map())
-            .output()
-            .map_err(|e| anyhow!("unable to run init script: {}", e))?;
-        fs::remove_file(&path).map_err(|e| anyhow!("failed to remove init script: {}", e))
+        resolve_command(exe, env)
     }
 
     fn update_defaults(&mut self) {
@@ -185,6 +601,50 @@ impl VmSpec {
                     ebs.mount.mode = Some("0755".into());
                 }
             }
+            if let Some(instance_store) = &mut volume.instance_store {
+                if instance_store.device_index.is_none() {
+                    instance_store.device_index = Some(0);
+                }
+                if instance_store.fs_type.is_none() {
+                    instance_store.fs_type = Some("ext4".into());
+                }
+                if instance_store.mount.destination.is_empty() {
+                    instance_store.mount.destination = if instance_store.raid0.unwrap_or_default() {
+                        constants::DIR_INSTANCE_STORE.into()
+                    } else {
+                        format!(
+                            "{}/{}",
+                            constants::DIR_INSTANCE_STORE,
+                            instance_store.device_index.unwrap()
+                        )
+                    };
+                }
+                if instance_store.mount.group_id.is_none() {
+                    instance_store.mount.group_id = self.security.run_as_group_id;
+                }
+                if instance_store.mount.user_id.is_none() {
+                    instance_store.mount.user_id = self.security.run_as_user_id;
+                }
+                if instance_store.mount.mode.is_none() {
+                    instance_store.mount.mode = Some("0755".into());
+                }
+            }
+            if let Some(lvm) = &mut volume.lvm {
+                for lv in &mut lvm.logical_volumes {
+                    if lv.fs_type.is_none() {
+                        lv.fs_type = Some("ext4".into());
+                    }
+                    if lv.mount.group_id.is_none() {
+                        lv.mount.group_id = self.security.run_as_group_id;
+                    }
+                    if lv.mount.user_id.is_none() {
+                        lv.mount.user_id = self.security.run_as_user_id;
+                    }
+                    if lv.mount.mode.is_none() {
+                        lv.mount.mode = Some("0755".into());
+                    }
+                }
+            }
             if let Some(s3) = &mut volume.s3 {
                 if s3.mount.group_id.is_none() {
                     s3.mount.group_id = self.security.run_as_group_id;
@@ -210,6 +670,17 @@ impl VmSpec {
                 }
             }
         }
+        for write_file in &mut self.write_files {
+            if write_file.group_id.is_none() {
+                write_file.group_id = self.security.run_as_group_id;
+            }
+            if write_file.user_id.is_none() {
+                write_file.user_id = self.security.run_as_user_id;
+            }
+            if write_file.mode.is_none() {
+                write_file.mode = Some("0644".into());
+            }
+        }
     }
 
     pub fn from_config_file(config_file: &ConfigFile) -> Result<Self> {
@@ -229,6 +700,9 @@ impl VmSpec {
         if let Some(working_dir) = config.working_dir {
             vmspec.working_dir = working_dir;
         }
+        if let Some(healthcheck) = config.healthcheck {
+            vmspec.health_check = HealthCheck::from_health_config(healthcheck);
+        }
         if let Some(user) = config.user {
             let user_group_names: UserGroupNames = user.try_into()?;
             let fp = File::open(constants::FILE_ETC_PASSWD)?;
@@ -247,6 +721,14 @@ impl VmSpec {
         if let Some(args) = &other.args {
             self.args = args.clone();
         }
+        if let Some(boot_log) = other.boot_log {
+            if boot_log.disable.is_some() {
+                self.boot_log.disable = boot_log.disable;
+            }
+            if boot_log.path.is_some() {
+                self.boot_log.path = boot_log.path;
+            }
+        }
         if let Some(command) = other.command {
             self.command = command;
             // If args is not set in other, set it to empty here to
@@ -258,52 +740,167 @@ impl VmSpec {
         if other.debug.is_some() {
             self.debug = other.debug.unwrap();
         }
+        if let Some(degraded_boot) = other.degraded_boot {
+            self.degraded_boot = degraded_boot;
+        }
         if let Some(disable_services) = other.disable_services {
             if !disable_services.is_empty() {
                 self.disable_services = disable_services;
             }
         }
+        if let Some(drain_command) = other.drain_command {
+            self.drain_command = drain_command;
+        }
+        if let Some(drain_timeout) = other.drain_timeout {
+            self.drain_timeout = drain_timeout;
+        }
         if let Some(env) = other.env {
             self.env = (&self.env).merge(&env);
         }
         if let Some(env_from) = other.env_from {
             self.env_from = env_from;
         }
+        if let Some(failed_boot_threshold) = other.failed_boot_threshold {
+            self.failed_boot_threshold = failed_boot_threshold;
+        }
+        if other.failure_report.is_some() {
+            self.failure_report = other.failure_report;
+        }
+        if let Some(groups) = other.groups {
+            self.groups = groups;
+        }
+        if let Some(health_check) = other.health_check {
+            match &mut self.health_check {
+                Some(existing) => existing.merge(health_check),
+                None => self.health_check = Some(health_check),
+            }
+        }
+        if let Some(hugepages) = other.hugepages {
+            self.hugepages = hugepages;
+        }
         if let Some(init_scripts) = other.init_scripts {
             self.init_scripts = init_scripts;
         }
+        if let Some(instance_events) = other.instance_events {
+            self.instance_events.merge(instance_events);
+        }
+        if let Some(kernel_modules) = other.kernel_modules {
+            self.kernel_modules = kernel_modules;
+        }
+        if let Some(kexec) = other.kexec {
+            self.kexec.merge(kexec);
+        }
+        if let Some(limits) = other.limits {
+            self.limits = limits;
+        }
+        if let Some(log_format) = other.log_format {
+            self.log_format = log_format;
+        }
+        if let Some(log_levels) = other.log_levels {
+            self.log_levels.extend(log_levels);
+        }
+        if other.login_password.is_some() {
+            self.login_password = other.login_password;
+        }
+        if let Some(on_exit) = other.on_exit {
+            self.on_exit.extend(on_exit);
+        }
+        if let Some(oom_score_adj) = other.oom_score_adj {
+            self.oom_score_adj = oom_score_adj;
+        }
+        if other.privilege_escalation.is_some() {
+            self.privilege_escalation = other.privilege_escalation;
+        }
+        if other.readiness_probe.is_some() {
+            self.readiness_probe = other.readiness_probe;
+        }
         if other.replace_init.is_some() {
             self.replace_init = other.replace_init.unwrap();
         }
+        if let Some(restart_policy) = other.restart_policy {
+            self.restart_policy.merge(restart_policy);
+        }
         if let Some(security) = other.security {
             self.security.merge(security);
         }
+        if let Some(service_dependencies) = other.service_dependencies {
+            self.service_dependencies.extend(service_dependencies);
+        }
+        if let Some(service_logs) = other.service_logs {
+            self.service_logs.merge(service_logs);
+        }
+        if let Some(service_overrides) = other.service_overrides {
+            self.service_overrides.extend(service_overrides);
+        }
+        if let Some(service_readiness_probes) = other.service_readiness_probes {
+            self.service_readiness_probes
+                .extend(service_readiness_probes);
+        }
+        if let Some(service_restart_policies) = other.service_restart_policies {
+            for (name, policy) in service_restart_policies {
+                self.service_restart_policies
+                    .entry(name)
+                    .or_default()
+                    .merge(policy);
+            }
+        }
+        if let Some(services) = other.services {
+            self.services = services;
+        }
         if other.shutdown_grace_period.is_some() {
             self.shutdown_grace_period = other.shutdown_grace_period.unwrap();
         }
+        if let Some(shutdown_scripts) = other.shutdown_scripts {
+            self.shutdown_scripts = shutdown_scripts;
+        }
+        if other.spot_monitor.is_some() {
+            self.spot_monitor = other.spot_monitor;
+        }
+        if let Some(ssh) = other.ssh {
+            self.ssh.merge(ssh);
+        }
+        if other.startup_timeout.is_some() {
+            self.startup_timeout = other.startup_timeout;
+        }
+        if let Some(status_server) = other.status_server {
+            self.status_server.merge(status_server);
+        }
+        if let Some(syslog) = other.syslog {
+            if syslog.enable.is_some() {
+                self.syslog.enable = syslog.enable;
+            }
+            if syslog.write_file.is_some() {
+                self.syslog.write_file = syslog.write_file;
+            }
+        }
         if let Some(sysctls) = other.sysctls {
             self.sysctls = (&self.sysctls).merge(&sysctls);
         }
+        if let Some(time) = other.time {
+            if time.ntp_servers.is_some() {
+                self.time.ntp_servers = time.ntp_servers;
+            }
+        }
+        if let Some(users) = other.users {
+            self.users = users;
+        }
         if let Some(volumes) = other.volumes {
             self.volumes = volumes;
         }
+        if let Some(watchdog) = other.watchdog {
+            self.watchdog.merge(watchdog);
+        }
         if other.working_dir.is_some() {
             self.working_dir = other.working_dir.unwrap();
         }
+        if let Some(write_files) = other.write_files {
+            self.write_files = write_files;
+        }
         self.update_defaults();
     }
 
     pub fn run_init_scripts<P: AsRef<Path>>(&self, base_dir: P, env: &NameValues) -> Result<()> {
-        for (i, script) in self.init_scripts.iter().enumerate() {
-            let path = PathBuf::from_iter(&[
-                base_dir.as_ref(),
-                constants::DIR_ET_RUN.as_ref(),
-                format!("init-{}", i).as_ref(),
-            ]);
-            info!("Running init script {:?}", &path);
-            self.run_init_script(&path, script.as_bytes(), env)?;
-        }
-        Ok(())
+        run_scripts(&self.init_scripts, "init", base_dir, env)
     }
 
     pub fn set_sysctls<P: AsRef<Path>>(&self, base_dir: P) -> Result<()> {
@@ -313,151 +910,1856 @@ impl VmSpec {
         }
         Ok(())
     }
-}
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
-pub struct NameValue {
-    pub name: String,
-    pub value: String,
-}
+    pub fn load_kernel_modules(&self) -> Result<()> {
+        if self.kernel_modules.is_empty() {
+            return Ok(());
+        }
+        let loader = ModuleLoader::new()?;
+        for module in &self.kernel_modules {
+            loader
+                .load(
+                    &module.name,
+                    module.parameters.as_deref().unwrap_or_default(),
+                )
+                .map_err(|e| anyhow!("unable to load kernel module {}: {}", &module.name, e))?;
+        }
+        Ok(())
+    }
 
-pub type NameValues = Vec<NameValue>;
+    pub fn reserve_hugepages<P: AsRef<Path>>(&self, base_dir: P) -> Result<()> {
+        for hugepages in &self.hugepages {
+            info!(
+                "Reserving {} hugepages of size {}",
+                hugepages.count, &hugepages.size
+            );
+            reserve_hugepages(&base_dir, &hugepages.size, hugepages.count)?;
+        }
+        Ok(())
+    }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
-pub struct EnvFromSource {
-    pub imds: Option<ImdsEnvSource>,
-    pub s3: Option<S3EnvSource>,
-    #[serde(rename = "secrets-manager")]
-    pub secrets_manager: Option<SecretsManagerEnvSource>,
-    pub ssm: Option<SsmEnvSource>,
+    // Add groups and users that do not already exist, so that this is safe to
+    // run again on a root filesystem that persists across boots.
+    pub fn create_users_groups<P: AsRef<Path>>(&self, base_dir: P) -> Result<()> {
+        if self.groups.is_empty() && self.users.is_empty() {
+            return Ok(());
+        }
+        // Names and fields are checked before anything is written, so an invalid
+        // one cannot add entries to the files.
+        for group in &self.groups {
+            login::check_user_name(&group.name)
+                .map_err(|e| anyhow!("invalid group {:?}: {}", &group.name, e))?;
+        }
+        for user in &self.users {
+            user.check()
+                .map_err(|e| anyhow!("invalid user {:?}: {}", &user.name, e))?;
+        }
+
+        let base_dir = base_dir.as_ref();
+        let group_path = base_dir.join_relative(constants::FILE_ETC_GROUP);
+        let passwd_path = base_dir.join_relative(constants::FILE_ETC_PASSWD);
+        let shadow_path = base_dir.join_relative(constants::FILE_ETC_SHADOW);
+
+        let mut group_entries = login::parse_group_lines(
+            File::open(&group_path)
+                .map_err(|e| anyhow!("unable to open {:?}: {}", &group_path, e))?,
+        )?;
+        for group in &self.groups {
+            if group_entries.find(&group.name).is_some() {
+                debug!("Group {} already exists", &group.name);
+                continue;
+            }
+            info!("Creating group {}", &group.name);
+            group_entries.push(GroupEntry {
+                group_name: group.name.clone(),
+                password: "x".into(),
+                gid: group.group_id,
+                members: Vec::new(),
+            });
+        }
+
+        let passwd_entries = login::parse_passwd_lines(
+            File::open(&passwd_path)
+                .map_err(|e| anyhow!("unable to open {:?}: {}", &passwd_path, e))?,
+        )?;
+        let mut new_passwd_entries = Vec::new();
+        let mut new_shadow_entries = Vec::new();
+        for user in &self.users {
+            for group_name in user.groups.iter().flatten() {
+                let group_entry = group_entries
+                    .iter_mut()
+                    .find(|entry| entry.group_name == *group_name)
+                    .ok_or_else(|| {
+                        anyhow!("group {} of user {} not found", group_name, &user.name)
+                    })?;
+                if !group_entry.members.contains(&user.name) {
+                    group_entry.members.push(user.name.clone());
+                }
+            }
+            let entry = match passwd_entries.find(&user.name) {
+                Some(entry) => {
+                    debug!("User {} already exists", &user.name);
+                    entry
+                }
+                None => {
+                    info!("Creating user {}", &user.name);
+                    let entry = user.passwd_entry();
+                    let home_dir = base_dir.join_relative(&entry.home_dir);
+                    login::create_home_dir(&home_dir, entry.uid, entry.gid).map_err(|e| {
+                        anyhow!("unable to create home directory for {}: {}", &user.name, e)
+                    })?;
+                    new_shadow_entries.push(ShadowEntry {
+                        user_name: user.name.clone(),
+                        password: "!".into(),
+                    });
+                    new_passwd_entries.push(entry.clone());
+                    entry
+                }
+            };
+            // Keys are rewritten on every boot, so those removed from user data no
+            // longer have access.
+            if let Some(keys) = &user.ssh_authorized_keys {
+                let ssh_dir = base_dir.join_relative(&entry.home_dir).join(".ssh");
+                login::write_authorized_keys(&ssh_dir, keys, entry.uid, entry.gid).map_err(
+                    |e| anyhow!("unable to write authorized keys for {}: {}", &user.name, e),
+                )?;
+            }
+        }
+
+        login::write_entries(&group_path, &group_entries)
+            .map_err(|e| anyhow!("unable to write {:?}: {}", &group_path, e))?;
+        login::append_entries(&passwd_path, &new_passwd_entries)
+            .map_err(|e| anyhow!("unable to write {:?}: {}", &passwd_path, e))?;
+        login::append_entries(&shadow_path, &new_shadow_entries)
+            .map_err(|e| anyhow!("unable to write {:?}: {}", &shadow_path, e))?;
+        Ok(())
+    }
+
+    pub fn write_files<P: AsRef<Path>>(&self, base_dir: P) -> Result<()> {
+        for write_file in &self.write_files {
+            info!("Writing file {}", &write_file.path);
+            write_file
+                .write(base_dir.as_ref())
+                .map_err(|e| anyhow!("unable to write file {}: {}", &write_file.path, e))?;
+        }
+        Ok(())
+    }
 }
 
-pub type EnvFromSources = Vec<EnvFromSource>;
+// Replace the values of environment variables and the contents of files and
+// scripts, which may be secret.
+fn redact(value: &mut Value) {
+    const REDACTED_FIELDS: [&str; 3] = ["content", "script", "value"];
+    match value {
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                if REDACTED_FIELDS.contains(&name.as_str()) && field.is_string() {
+                    *field = Value::String("<redacted>".into());
+                } else {
+                    redact(field);
+                }
+            }
+        }
+        _ => (),
+    }
+}
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
-pub struct ImdsEnvSource {
-    pub name: String,
-    pub optional: Option<bool>,
-    pub path: String,
+// Run scripts sorted by their order, or in the order they were declared if they
+// have the same order. The kind is used to name the scripts, e.g. init or shutdown.
+pub fn run_scripts<P: AsRef<Path>>(
+    scripts: &InitScripts,
+    kind: &str,
+    base_dir: P,
+    env: &NameValues,
+) -> Result<()> {
+    let mut sorted: Vec<(usize, &InitScript)> = scripts.iter().enumerate().collect();
+    sorted.sort_by_key(|(_, script)| script.order.unwrap_or_default());
+    for (i, script) in sorted {
+        let path = PathBuf::from_iter(&[
+            base_dir.as_ref(),
+            constants::DIR_ET_RUN.as_ref(),
+            format!("{}-{}", kind, i).as_ref(),
+        ]);
+        let name = script
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("{}-{}", kind, i));
+        info!("Running {} script {} at {:?}", kind, name, &path);
+        if let Err(e) = run_script(&path, script.script.as_bytes(), env) {
+            match script.on_failure.unwrap_or_default() {
+                OnFailure::Fail => return Err(anyhow!("{} script {} failed: {}", kind, name, e)),
+                OnFailure::Warn => warn!("The {} script {} failed: {}", kind, name, e),
+                OnFailure::Ignore => {
+                    debug!("Ignoring failure of {} script {}: {}", kind, name, e)
+                }
+            }
+        }
+    }
+    Ok(())
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
-pub struct S3EnvSource {
-    #[serde(rename = "base64-encode")]
-    pub base64_encode: Option<bool>,
-    pub bucket: String,
-    pub key: String,
-    pub name: Option<String>,
-    pub optional: Option<bool>,
+fn run_script<P: AsRef<Path>>(path: P, contents: &[u8], env: &NameValues) -> Result<()> {
+    fs::write(&path, contents)
+        .map_err(|e| anyhow!("unable to write script to {:?}: {}", path.as_ref(), e))?;
+    chmod(path.as_ref(), Mode::from(0o755))
+        .map_err(|e| anyhow!("unable to set script as executable: {}", e))?;
+    let status = Command::new(path.as_ref())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .envs(env.to_map())
+        .status();
+    fs::remove_file(&path).map_err(|e| anyhow!("failed to remove script: {}", e))?;
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(anyhow!("script exited with {}", status)),
+        Err(e) => Err(anyhow!("unable to run script: {}", e)),
+    }
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
-pub struct SecretsManagerEnvSource {
-    #[serde(rename = "base64-encode")]
-    pub base64_encode: Option<bool>,
-    pub name: Option<String>,
-    pub optional: Option<bool>,
-    #[serde(rename = "secret-id")]
-    pub secret_id: String,
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnFailure {
+    #[default]
+    Fail,
+    Warn,
+    Ignore,
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
-pub struct SsmEnvSource {
-    #[serde(rename = "base64-encode")]
-    pub base64_encode: Option<bool>,
+// An init script may be given as just the script, or with additional settings.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(from = "InitScriptDef")]
+pub struct InitScript {
     pub name: Option<String>,
-    pub path: String,
-    pub optional: Option<bool>,
+    #[serde(rename = "on-failure")]
+    pub on_failure: Option<OnFailure>,
+    pub order: Option<i64>,
+    pub script: String,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct Security {
-    #[serde(rename = "readonly-root-fs")]
-    pub readonly_root_fs: Option<bool>,
-    #[serde(rename = "run-as-group-id")]
-    pub run_as_group_id: Option<u32>,
-    #[serde(rename = "run-as-user-id")]
-    pub run_as_user_id: Option<u32>,
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum InitScriptDef {
+    Script(String),
+    Full {
+        name: Option<String>,
+        #[serde(rename = "on-failure")]
+        on_failure: Option<OnFailure>,
+        order: Option<i64>,
+        script: String,
+    },
 }
 
-impl Default for Security {
-    fn default() -> Self {
-        Security {
-            readonly_root_fs: Some(false),
-            run_as_group_id: Some(0),
-            run_as_user_id: Some(0),
+impl From<InitScriptDef> for InitScript {
+    fn from(def: InitScriptDef) -> Self {
+        match def {
+            InitScriptDef::Script(script) => Self::from(script),
+            InitScriptDef::Full {
+                name,
+                on_failure,
+                order,
+                script,
+            } => Self {
+                name,
+                on_failure,
+                order,
+                script,
+            },
         }
     }
 }
 
-impl Security {
-    fn merge(&mut self, other: Self) {
-        if other.readonly_root_fs.is_some() {
-            self.readonly_root_fs = other.readonly_root_fs;
-        }
-        if other.run_as_group_id.is_some() {
-            self.run_as_group_id = other.run_as_group_id;
-        }
-        if other.run_as_user_id.is_some() {
-            self.run_as_user_id = other.run_as_user_id;
+impl From<String> for InitScript {
+    fn from(script: String) -> Self {
+        Self {
+            script,
+            ..Default::default()
         }
     }
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
-pub struct Volume {
-    pub ebs: Option<EbsVolumeSource>,
-    pub s3: Option<S3VolumeSource>,
-    #[serde(rename = "secrets-manager")]
-    pub secrets_manager: Option<SecretsManagerVolumeSource>,
-    pub ssm: Option<SsmVolumeSource>,
-}
+pub type InitScripts = Vec<InitScript>;
 
-pub type Volumes = Vec<Volume>;
+// A kernel module may be given as just its name, or with parameters such as max_part=8.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(from = "KernelModuleDef")]
+pub struct KernelModule {
+    pub name: String,
+    pub parameters: Option<Vec<String>>,
+}
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
-pub struct EbsVolumeSource {
-    pub device: String,
-    #[serde(rename = "fs-type")]
-    pub fs_type: Option<String>,
-    #[serde(rename = "make-fs")]
-    pub make_fs: Option<bool>,
-    pub mount: Mount,
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KernelModuleDef {
+    Name(String),
+    Full {
+        name: String,
+        parameters: Option<Vec<String>>,
+    },
+}
+
+impl From<KernelModuleDef> for KernelModule {
+    fn from(def: KernelModuleDef) -> Self {
+        match def {
+            KernelModuleDef::Name(name) => Self {
+                name,
+                parameters: None,
+            },
+            KernelModuleDef::Full { name, parameters } => Self { name, parameters },
+        }
+    }
+}
+
+pub type KernelModules = Vec<KernelModule>;
+
+// A resource limit may be given as a single number to set both the soft and hard
+// limits, or with soft and hard set separately. An omitted soft or hard limit is
+// unlimited.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(from = "LimitDef")]
+pub struct Limit {
+    pub hard: Option<u64>,
+    pub soft: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LimitDef {
+    Both(u64),
+    Separate {
+        hard: Option<u64>,
+        soft: Option<u64>,
+    },
+}
+
+impl From<LimitDef> for Limit {
+    fn from(def: LimitDef) -> Self {
+        match def {
+            LimitDef::Both(value) => Self {
+                hard: Some(value),
+                soft: Some(value),
+            },
+            LimitDef::Separate { hard, soft } => Self { hard, soft },
+        }
+    }
+}
+
+impl From<&Limit> for Rlimit {
+    fn from(limit: &Limit) -> Self {
+        Self {
+            current: limit.soft,
+            maximum: limit.hard,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Limits {
+    #[serde(rename = "as")]
+    pub address_space: Option<Limit>,
+    pub core: Option<Limit>,
+    pub cpu: Option<Limit>,
+    pub data: Option<Limit>,
+    pub fsize: Option<Limit>,
+    pub locks: Option<Limit>,
+    pub memlock: Option<Limit>,
+    pub msgqueue: Option<Limit>,
+    pub nice: Option<Limit>,
+    pub nofile: Option<Limit>,
+    pub nproc: Option<Limit>,
+    pub rss: Option<Limit>,
+    pub rtprio: Option<Limit>,
+    pub rttime: Option<Limit>,
+    pub sigpending: Option<Limit>,
+    pub stack: Option<Limit>,
+}
+
+impl Limits {
+    pub fn to_rlimits(&self) -> Vec<(Resource, Rlimit)> {
+        [
+            (Resource::As, &self.address_space),
+            (Resource::Core, &self.core),
+            (Resource::Cpu, &self.cpu),
+            (Resource::Data, &self.data),
+            (Resource::Fsize, &self.fsize),
+            (Resource::Locks, &self.locks),
+            (Resource::Memlock, &self.memlock),
+            (Resource::Msgqueue, &self.msgqueue),
+            (Resource::Nice, &self.nice),
+            (Resource::Nofile, &self.nofile),
+            (Resource::Nproc, &self.nproc),
+            (Resource::Rss, &self.rss),
+            (Resource::Rtprio, &self.rtprio),
+            (Resource::Rttime, &self.rttime),
+            (Resource::Sigpending, &self.sigpending),
+            (Resource::Stack, &self.stack),
+        ]
+        .into_iter()
+        .filter_map(|(resource, limit)| limit.as_ref().map(|l| (resource, Rlimit::from(l))))
+        .collect()
+    }
+}
+
+// A check of whether a process is ready, which it is once the check first succeeds
+// after it starts. One of exec, http-get, or tcp-socket is used. An exec command
+// runs as the user of the process. Durations are in seconds.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ReadinessProbe {
+    pub exec: Option<Vec<String>>,
+    #[serde(rename = "http-get")]
+    pub http_get: Option<HttpGetProbe>,
+    pub interval: Option<u64>,
+    #[serde(rename = "tcp-socket")]
+    pub tcp_socket: Option<TcpSocketProbe>,
+    pub timeout: Option<u64>,
+}
+
+impl ReadinessProbe {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.unwrap_or(1))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(1))
+    }
+}
+
+// A probe that succeeds when a GET request returns a 2xx or 3xx status.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct HttpGetProbe {
+    pub host: Option<String>,
+    pub path: Option<String>,
+    pub port: u16,
+}
+
+impl HttpGetProbe {
+    pub fn url(&self) -> String {
+        let host = self.host.as_deref().unwrap_or("127.0.0.1");
+        let path = self.path.as_deref().unwrap_or("/");
+        let slash = if path.starts_with('/') { "" } else { "/" };
+        format!("http://{}:{}{}{}", host, self.port, slash, path)
+    }
+}
+
+// A probe that succeeds when a TCP connection is accepted.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct TcpSocketProbe {
+    pub host: Option<String>,
+    pub port: u16,
+}
+
+impl TcpSocketProbe {
+    pub fn address(&self) -> (&str, u16) {
+        (self.host.as_deref().unwrap_or("127.0.0.1"), self.port)
+    }
+}
+
+// A command run periodically as the main process's user to check its health. After
+// retries consecutive failures the action is taken, either restarting the main process
+// or powering off. Durations are in seconds.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct HealthCheck {
+    pub action: Option<UnhealthyAction>,
+    pub command: Option<Vec<String>>,
+    pub interval: Option<u64>,
+    pub retries: Option<u32>,
+    #[serde(rename = "start-period")]
+    pub start_period: Option<u64>,
+    pub timeout: Option<u64>,
+}
+
+impl HealthCheck {
+    fn from_health_config(config: HealthConfig) -> Option<Self> {
+        let test = config.test.unwrap_or_default();
+        let command = match test.split_first() {
+            Some((kind, args)) if kind == "CMD" && !args.is_empty() => args.to_vec(),
+            Some((kind, args)) if kind == "CMD-SHELL" && !args.is_empty() => {
+                vec!["/bin/sh".into(), "-c".into(), args.join(" ")]
+            }
+            _ => return None,
+        };
+        let secs = |ns: Option<u64>| {
+            ns.filter(|ns| *ns > 0)
+                .map(|ns| Duration::from_nanos(ns).as_secs().max(1))
+        };
+        Some(Self {
+            action: None,
+            command: Some(command),
+            interval: secs(config.interval),
+            retries: config.retries.filter(|retries| *retries > 0),
+            start_period: secs(config.start_period),
+            timeout: secs(config.timeout),
+        })
+    }
+
+    fn merge(&mut self, other: HealthCheck) {
+        if other.action.is_some() {
+            self.action = other.action;
+        }
+        if other.command.is_some() {
+            self.command = other.command;
+        }
+        if other.interval.is_some() {
+            self.interval = other.interval;
+        }
+        if other.retries.is_some() {
+            self.retries = other.retries;
+        }
+        if other.start_period.is_some() {
+            self.start_period = other.start_period;
+        }
+        if other.timeout.is_some() {
+            self.timeout = other.timeout;
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.unwrap_or(30))
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(3)
+    }
+
+    pub fn start_period(&self) -> Duration {
+        Duration::from_secs(self.start_period.unwrap_or_default())
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(30))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnhealthyAction {
+    #[default]
+    Restart,
+    Poweroff,
+}
+
+// Find the executable of a command in PATH if it is not an absolute path, and expand
+// variable references in its arguments.
+fn resolve_command(mut exe: Vec<String>, env: &NameValues) -> Result<Vec<String>> {
+    let path_var = env
+        .find("PATH")
+        .unwrap_or_else(|| unreachable!("PATH should have been defined"));
+
+    if !exe[0].starts_with(constants::DIR_ROOT) {
+        let exe_path = find_executable_in_path(&exe[0], &path_var.value)
+            .ok_or_else(|| anyhow!("unable to find executable in PATH: {}", exe[0]))?
+            .to_str()
+            .ok_or_else(|| anyhow!("unable to convert path to string: {}", exe[0]))?
+            .into();
+        exe[0] = exe_path;
+    }
+
+    let env_refs = HashMap::from_iter(env.to_map_rc());
+    let maps = vec![&env_refs];
+    let mapping = mapping_func_for(&maps);
+    let mut expanded_exe = Vec::with_capacity(exe.len());
+    for arg in exe.iter() {
+        expanded_exe.push(expand(arg, &mapping));
+    }
+
+    Ok(expanded_exe)
+}
+
+// An extra long-running process run alongside the main process, such as a metrics
+// exporter. It gets the environment of the main process with its own env merged in,
+// and runs as the main process's user unless another is given.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SidecarService {
+    pub command: Vec<String>,
+    pub env: Option<NameValues>,
+    pub name: String,
+    #[serde(rename = "oom-score-adj")]
+    pub oom_score_adj: Option<i32>,
+    #[serde(rename = "restart-policy")]
+    pub restart_policy: Option<RestartPolicy>,
+    #[serde(rename = "run-as-group-id")]
+    pub run_as_group_id: Option<u32>,
+    #[serde(rename = "run-as-user-id")]
+    pub run_as_user_id: Option<u32>,
+    #[serde(rename = "stop-timeout")]
+    pub stop_timeout: Option<u64>,
+    #[serde(rename = "working-dir")]
+    pub working_dir: Option<String>,
+}
+
+impl SidecarService {
+    pub fn full_command(&self, env: &NameValues) -> Result<Vec<String>> {
+        if self.command.is_empty() {
+            return Err(anyhow!("service {} has no command", self.name));
+        }
+        resolve_command(self.command.clone(), env)
+    }
+}
+
+pub type SidecarServices = Vec<SidecarService>;
+
+// Changes to a built-in service such as chrony or ssh. Args are added after the
+// default arguments, or replace the default flags if replace-args is set, and config
+// is the path of the service's configuration file.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ServiceOverride {
+    pub args: Option<Vec<String>>,
+    pub config: Option<String>,
+    pub env: Option<NameValues>,
+    #[serde(rename = "oom-score-adj")]
+    pub oom_score_adj: Option<i32>,
+    #[serde(rename = "replace-args")]
+    pub replace_args: Option<bool>,
+    #[serde(rename = "run-as-group-id")]
+    pub run_as_group_id: Option<u32>,
+    #[serde(rename = "run-as-user-id")]
+    pub run_as_user_id: Option<u32>,
+    #[serde(rename = "stop-timeout")]
+    pub stop_timeout: Option<u64>,
+}
+
+// Where a report is saved if initialization fails, so instances that are terminated
+// right away can still be debugged. The report is a tarball of the last log-lines
+// messages of init, the configuration, the kernel log, mounts, and the state of the
+// network interfaces. It is written to a directory on the root volume, and uploaded
+// to S3 under key-prefix, followed by the instance ID and the time of the failure.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct FailureReport {
+    #[serde(rename = "log-lines")]
+    pub log_lines: Option<usize>,
+    pub path: Option<String>,
+    pub s3: Option<S3FailureReport>,
+}
+
+impl FailureReport {
+    pub fn log_lines(&self) -> usize {
+        self.log_lines.unwrap_or(1000)
+    }
+
+    pub fn path(&self) -> PathBuf {
+        match &self.path {
+            Some(path) => PathBuf::from(path),
+            None => Path::new(constants::DIR_ET_VAR).join("failure-reports"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct S3FailureReport {
+    pub bucket: String,
+    #[serde(rename = "key-prefix")]
+    pub key_prefix: Option<String>,
+}
+
+// A file where the messages of init and the supervisor are written, so they can be
+// read after the console scrollback is gone. It is rotated with the max-size and
+// max-files of service-logs. The path defaults to init.log in the service log
+// directory, and must not be on the root volume if the root filesystem is read-only.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct BootLog {
+    pub disable: Option<bool>,
+    pub path: Option<String>,
+}
+
+impl BootLog {
+    pub fn path(&self, service_logs: &ServiceLogs, readonly_root_fs: bool) -> PathBuf {
+        match &self.path {
+            Some(path) => PathBuf::from(path),
+            None => service_logs.directory(readonly_root_fs).join("init.log"),
+        }
+    }
+}
+
+// Where the output of services is written, to a file named for each service. A file
+// is rotated when it would grow past max-size bytes, keeping max-files rotated files.
+// The directory defaults to one on the root volume, or on a tmpfs if the root
+// filesystem is read-only.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ServiceLogs {
+    pub directory: Option<String>,
+    #[serde(rename = "max-files")]
+    pub max_files: Option<u32>,
+    #[serde(rename = "max-size")]
+    pub max_size: Option<u64>,
+}
+
+impl ServiceLogs {
+    fn merge(&mut self, other: ServiceLogs) {
+        if other.directory.is_some() {
+            self.directory = other.directory;
+        }
+        if other.max_files.is_some() {
+            self.max_files = other.max_files;
+        }
+        if other.max_size.is_some() {
+            self.max_size = other.max_size;
+        }
+    }
+
+    pub fn directory(&self, readonly_root_fs: bool) -> PathBuf {
+        match &self.directory {
+            Some(directory) => PathBuf::from(directory),
+            None if readonly_root_fs => Path::new(constants::DIR_ET_RUN).join("log"),
+            None => Path::new(constants::DIR_ET_VAR).join("log"),
+        }
+    }
+
+    pub fn max_files(&self) -> u32 {
+        self.max_files.unwrap_or(5)
+    }
+
+    pub fn max_size(&self) -> u64 {
+        self.max_size.unwrap_or(10 * 1024 * 1024)
+    }
+}
+
+// Users allowed to run commands as root with sudo or doas, which is configured for
+// each of them that is found on the image. The users default to the login user.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct PrivilegeEscalation {
+    // Whether users must enter their own password, which requires one to be set,
+    // such as with login-password.
+    #[serde(rename = "require-password")]
+    pub require_password: Option<bool>,
+    pub users: Option<Vec<String>>,
+}
+
+// The password of the login user, for logging in on the serial console when ssh is
+// unavailable. Unless hashed is set, the secret is the password itself, which is
+// hashed with SHA-512 before it is written to /etc/shadow.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct LoginPassword {
+    pub from: SecretSource,
+    pub hashed: Option<bool>,
+}
+
+// Settings of the built-in ssh service. Keys from each of authorized-keys-from are
+// written to authorized_keys along with those of the instance's key pairs, so keys
+// can be managed centrally rather than only by the key pair given at launch.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Ssh {
+    #[serde(rename = "authorized-keys-from")]
+    pub authorized_keys_from: Option<SecretSources>,
+    #[serde(rename = "host-certificate")]
+    pub host_certificate: Option<HostCertificate>,
+}
+
+impl Ssh {
+    fn merge(&mut self, other: Ssh) {
+        if other.authorized_keys_from.is_some() {
+            self.authorized_keys_from = other.authorized_keys_from;
+        }
+        if other.host_certificate.is_some() {
+            self.host_certificate = other.host_certificate;
+        }
+    }
+
+    pub fn authorized_keys_from(&self) -> &[SecretSource] {
+        self.authorized_keys_from.as_deref().unwrap_or_default()
+    }
+}
+
+// A host key and its certificate signed by an SSH certificate authority, which sshd
+// presents in addition to its generated host keys, so clients that trust the
+// authority can verify the instance without a known_hosts entry.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct HostCertificate {
+    // The certificate, as written by ssh-keygen -s.
+    pub certificate: SecretSource,
+    // The private key that was signed.
+    pub key: SecretSource,
+}
+
+impl HostCertificate {
+    pub fn is_optional(&self) -> bool {
+        self.certificate.is_optional() || self.key.is_optional()
+    }
+}
+
+// A secret such as a password, a private key, or a document of public keys.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SecretSource {
+    pub s3: Option<S3SecretSource>,
+    #[serde(rename = "secrets-manager")]
+    pub secrets_manager: Option<SecretsManagerSecretSource>,
+    pub ssm: Option<SsmSecretSource>,
+}
+
+impl SecretSource {
+    pub fn is_optional(&self) -> bool {
+        [
+            self.s3.as_ref().and_then(|s| s.optional),
+            self.secrets_manager.as_ref().and_then(|s| s.optional),
+            self.ssm.as_ref().and_then(|s| s.optional),
+        ]
+        .iter()
+        .any(|optional| optional.unwrap_or_default())
+    }
+
+    // Get the secret from its source. Each part ends with a newline, as OpenSSH
+    // cannot read a private key without one.
+    pub fn fetch(&self, imds: &CachedImds, credentials: &LazyCredentials) -> Result<Vec<u8>> {
+        let region = imds
+            .get_region()
+            .map_err(|e| anyhow!("unable to get AWS region from IMDS: {}", e))?;
+        let mut contents = Vec::new();
+        let mut append = |bytes: Vec<u8>| {
+            contents.extend(bytes);
+            if contents.last() != Some(&b'\n') {
+                contents.push(b'\n');
+            }
+        };
+        if let Some(s3_source) = &self.s3 {
+            let s3_url = format!("s3://{}/{}", s3_source.bucket, s3_source.key);
+            let credentials = credentials.get(&format!("S3 secret source {}", s3_url))?;
+            let bytes = S3Client::new(credentials, &region)?
+                .get_object_bytes(&s3_source.bucket, &s3_source.key)
+                .map_err(|e| anyhow!("unable to get {}: {}", s3_url, e))?;
+            append(bytes);
+        }
+        if let Some(asm_source) = &self.secrets_manager {
+            let credentials = credentials.get(&format!(
+                "Secrets Manager secret source {}",
+                asm_source.secret_id
+            ))?;
+            let bytes = AsmClient::new(credentials, &region)?
+                .get_secret_value(&asm_source.secret_id)
+                .map_err(|e| anyhow!("unable to get secret {}: {}", asm_source.secret_id, e))?;
+            append(bytes);
+        }
+        if let Some(ssm_source) = &self.ssm {
+            let credentials = credentials.get(&format!("SSM secret source {}", ssm_source.path))?;
+            let bytes = SsmClient::new(credentials, &region)?
+                .get_parameter_value(&ssm_source.selector())
+                .map_err(|e| anyhow!("unable to get parameter {}: {}", ssm_source.path, e))?;
+            append(bytes);
+        }
+        Ok(contents)
+    }
+}
+
+pub type SecretSources = Vec<SecretSource>;
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct S3SecretSource {
+    pub bucket: String,
+    pub key: String,
+    pub optional: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SecretsManagerSecretSource {
+    pub optional: Option<bool>,
+    #[serde(rename = "secret-id")]
+    pub secret_id: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SsmSecretSource {
+    pub optional: Option<bool>,
+    pub path: String,
+    pub version: Option<u64>,
+}
+
+impl SsmSecretSource {
+    pub fn selector(&self) -> String {
+        version_selector(&self.path, self.version)
+    }
+}
+
+// An HTTP listener in the supervisor answering /healthz and /status, for load
+// balancer health checks and probes on the instance. It listens on localhost unless
+// another address is configured.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct StatusServer {
+    pub address: Option<String>,
+    pub enable: Option<bool>,
+}
+
+impl StatusServer {
+    fn merge(&mut self, other: StatusServer) {
+        if other.address.is_some() {
+            self.address = other.address;
+        }
+        if other.enable.is_some() {
+            self.enable = other.enable;
+        }
+    }
+
+    pub fn address(&self) -> &str {
+        self.address.as_deref().unwrap_or("127.0.0.1:8099")
+    }
+}
+
+// The listener on /dev/log for messages sent with syslog(3). It is off unless
+// enabled, so images that run their own syslog daemon keep their socket, and can
+// also write messages to syslog.log in the service log directory.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Syslog {
+    pub enable: Option<bool>,
+    #[serde(rename = "write-file")]
+    pub write_file: Option<bool>,
+}
+
+// Time synchronization by the built-in chrony service. If ntp-servers is not set,
+// the Amazon Time Sync Service is used, which may be blocked in some VPCs.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Time {
+    #[serde(rename = "ntp-servers")]
+    pub ntp_servers: Option<Vec<String>>,
+}
+
+impl Time {
+    pub fn ntp_servers(&self) -> &[String] {
+        self.ntp_servers.as_deref().unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // One JSON object per line, for log pipelines that scrape the console.
+    Json,
+    #[default]
+    Text,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+// A watchdog device fed by the supervisor while it is making progress and the main
+// process is healthy. If feeding stops, the device reboots the instance once its
+// timeout passes.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Watchdog {
+    pub device: Option<String>,
+    pub enable: Option<bool>,
+    pub timeout: Option<u64>,
+}
+
+impl Watchdog {
+    fn merge(&mut self, other: Watchdog) {
+        if other.device.is_some() {
+            self.device = other.device;
+        }
+        if other.enable.is_some() {
+            self.enable = other.enable;
+        }
+        if other.timeout.is_some() {
+            self.timeout = other.timeout;
+        }
+    }
+
+    pub fn device(&self) -> &str {
+        self.device
+            .as_deref()
+            .unwrap_or(constants::FILE_DEV_WATCHDOG)
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(60))
+    }
+}
+
+// Actions for events that EC2 announces in instance metadata, each polled at its
+// own interval in seconds. Spot interruptions and rebalance recommendations are
+// only watched while the spot monitor runs. By default a spot interruption shuts
+// down the instance and other events are ignored.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct InstanceEvents {
+    pub maintenance: Option<EventHandler>,
+    pub rebalance: Option<EventHandler>,
+    #[serde(rename = "spot-interruption")]
+    pub spot_interruption: Option<EventHandler>,
+}
+
+impl InstanceEvents {
+    fn merge(&mut self, other: InstanceEvents) {
+        if other.maintenance.is_some() {
+            self.maintenance = other.maintenance;
+        }
+        if other.rebalance.is_some() {
+            self.rebalance = other.rebalance;
+        }
+        if other.spot_interruption.is_some() {
+            self.spot_interruption = other.spot_interruption;
+        }
+    }
+
+    // The handler of each event, with the defaults for any that are not set.
+    pub fn handlers(&self) -> Vec<(InstanceEvent, EventHandler)> {
+        let with_defaults = |handler: &Option<EventHandler>, action, interval| {
+            let handler = handler.clone().unwrap_or_default();
+            EventHandler {
+                action: handler.action.or(Some(action)),
+                interval: handler.interval.or(Some(interval)),
+                signal: handler.signal,
+            }
+        };
+        vec![
+            (
+                InstanceEvent::Maintenance,
+                with_defaults(&self.maintenance, EventAction::Ignore, 60),
+            ),
+            (
+                InstanceEvent::Rebalance,
+                with_defaults(&self.rebalance, EventAction::Ignore, 5),
+            ),
+            (
+                InstanceEvent::SpotInterruption,
+                with_defaults(&self.spot_interruption, EventAction::Shutdown, 5),
+            ),
+        ]
+    }
+}
+
+// What to do when an instance event is announced. The signal is sent to the main
+// process for the signal action.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct EventHandler {
+    pub action: Option<EventAction>,
+    pub interval: Option<u64>,
+    pub signal: Option<String>,
+}
+
+impl EventHandler {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.unwrap_or(5).max(1))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventAction {
+    // Run the drain command, leaving processes running.
+    Drain,
+    // Do not watch for the event.
+    Ignore,
+    // Stop all processes and power off.
+    Shutdown,
+    // Send a signal to the main process.
+    Signal,
+}
+
+// Reboots that start a kernel directly with kexec, skipping the firmware and
+// bootloader. The command line of the running kernel is used if none is given.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Kexec {
+    #[serde(rename = "command-line")]
+    pub command_line: Option<String>,
+    pub enable: Option<bool>,
+    pub initramfs: Option<String>,
+    pub kernel: Option<String>,
+}
+
+impl Kexec {
+    fn merge(&mut self, other: Kexec) {
+        if other.command_line.is_some() {
+            self.command_line = other.command_line;
+        }
+        if other.enable.is_some() {
+            self.enable = other.enable;
+        }
+        if other.initramfs.is_some() {
+            self.initramfs = other.initramfs;
+        }
+        if other.kernel.is_some() {
+            self.kernel = other.kernel;
+        }
+    }
+
+    pub fn kernel(&self) -> &str {
+        self.kernel
+            .as_deref()
+            .unwrap_or(constants::FILE_BOOT_KERNEL)
+    }
+}
+
+// Processes, named main or by service name, that must be running before a process is
+// started. A process waits for those it comes after if they are running, but is not
+// started at all if one it requires is not running.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ServiceDependencies {
+    pub after: Option<Vec<String>>,
+    pub requires: Option<Vec<String>>,
+}
+
+// Actions for exit codes of the main process, keyed by a code such as "75" or an
+// inclusive range such as "64-78". Exits that match none of them are handled by
+// the restart policy, then power off.
+pub type OnExit = HashMap<String, ExitAction>;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExitAction {
+    Poweroff,
+    Reboot,
+    Restart,
+}
+
+fn parse_exit_codes(codes: &str) -> Result<RangeInclusive<i32>> {
+    let parse = |code: &str| {
+        code.trim()
+            .parse::<i32>()
+            .map_err(|e| anyhow!("invalid exit code {}: {}", code, e))
+    };
+    let range = match codes.split_once('-') {
+        Some((start, end)) => parse(start)?..=parse(end)?,
+        None => parse(codes)?..=parse(codes)?,
+    };
+    if range.is_empty() {
+        return Err(anyhow!("invalid range of exit codes {}", codes));
+    }
+    Ok(range)
+}
+
+// When a process is started again after it exits. The delay before the first restart
+// is backoff seconds, doubling with each consecutive restart up to max-backoff. A
+// process that runs for at least max-backoff seconds is considered to have recovered,
+// which resets the backoff and the count of restarts toward max-restarts.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct RestartPolicy {
+    pub backoff: Option<u64>,
+    pub condition: Option<RestartCondition>,
+    #[serde(rename = "crash-loop")]
+    pub crash_loop: Option<CrashLoop>,
+    #[serde(rename = "max-backoff")]
+    pub max_backoff: Option<u64>,
+    #[serde(rename = "max-restarts")]
+    pub max_restarts: Option<u32>,
+}
+
+impl RestartPolicy {
+    fn merge(&mut self, other: RestartPolicy) {
+        if other.backoff.is_some() {
+            self.backoff = other.backoff;
+        }
+        if other.condition.is_some() {
+            self.condition = other.condition;
+        }
+        if other.crash_loop.is_some() {
+            self.crash_loop = other.crash_loop;
+        }
+        if other.max_backoff.is_some() {
+            self.max_backoff = other.max_backoff;
+        }
+        if other.max_restarts.is_some() {
+            self.max_restarts = other.max_restarts;
+        }
+    }
+
+    pub fn backoff(&self) -> Duration {
+        Duration::from_secs(self.backoff.unwrap_or(5))
+    }
+
+    pub fn max_backoff(&self) -> Duration {
+        Duration::from_secs(self.max_backoff.unwrap_or(300)).max(self.backoff())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartCondition {
+    Always,
+    Never,
+    OnFailure,
+}
+
+// Detection of a process that keeps failing. Until failures exits fail within period
+// seconds, a process is restarted after the fixed backoff of its restart policy. In
+// a crash loop the backoff doubles with each restart up to max-backoff, and after
+// max-restarts more restarts without recovering, the action is taken.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct CrashLoop {
+    pub action: Option<CrashLoopAction>,
+    pub failures: Option<u32>,
+    #[serde(rename = "max-restarts")]
+    pub max_restarts: Option<u32>,
+    pub period: Option<u64>,
+}
+
+impl CrashLoop {
+    pub fn action(&self) -> CrashLoopAction {
+        self.action.unwrap_or(CrashLoopAction::StayDown)
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures.unwrap_or(5).max(1)
+    }
+
+    pub fn max_restarts(&self) -> u32 {
+        self.max_restarts.unwrap_or(5)
+    }
+
+    pub fn period(&self) -> Duration {
+        Duration::from_secs(self.period.unwrap_or(60))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CrashLoopAction {
+    // Power off the instance, as when the main process exits.
+    Poweroff,
+    // Leave the process stopped, keeping the instance and other processes running.
+    StayDown,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct NameValue {
+    pub name: String,
+    pub value: String,
+}
+
+pub type NameValues = Vec<NameValue>;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct EnvFromSource {
+    pub file: Option<FileEnvSource>,
+    pub http: Option<HttpEnvSource>,
+    pub imds: Option<ImdsEnvSource>,
+    #[serde(rename = "instance-identity")]
+    pub instance_identity: Option<InstanceIdentityEnvSource>,
+    #[serde(rename = "instance-tags")]
+    pub instance_tags: Option<InstanceTagsEnvSource>,
+    pub s3: Option<S3EnvSource>,
+    #[serde(rename = "secrets-manager")]
+    pub secrets_manager: Option<SecretsManagerEnvSource>,
+    pub ssm: Option<SsmEnvSource>,
+}
+
+impl EnvFromSource {
+    fn is_optional(&self) -> bool {
+        [
+            self.file.as_ref().and_then(|s| s.optional),
+            self.http.as_ref().and_then(|s| s.optional),
+            self.imds.as_ref().and_then(|s| s.optional),
+            self.instance_identity.as_ref().and_then(|s| s.optional),
+            self.instance_tags.as_ref().and_then(|s| s.optional),
+            self.s3.as_ref().and_then(|s| s.optional),
+            self.secrets_manager.as_ref().and_then(|s| s.optional),
+            self.ssm.as_ref().and_then(|s| s.optional),
+        ]
+        .iter()
+        .any(|optional| optional.unwrap_or_default())
+    }
+}
+
+pub type EnvFromSources = Vec<EnvFromSource>;
+
+// The identity of the instance from IMDS, as the variables AWS_ACCOUNT_ID,
+// AWS_AVAILABILITY_ZONE, AWS_REGION, EC2_INSTANCE_ID, and EC2_INSTANCE_TYPE.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InstanceIdentityEnvSource {
+    pub optional: Option<bool>,
+}
+
+// Instance tags from IMDS, which requires tags in instance metadata to be enabled.
+// Each tag becomes a variable named by the prefix and the tag key.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InstanceTagsEnvSource {
+    pub optional: Option<bool>,
+    pub prefix: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ImdsEnvSource {
+    pub name: String,
+    pub optional: Option<bool>,
+    pub path: String,
+}
+
+// A file on the instance, such as one written by a volume. Without a name, the file
+// contains variables in dotenv or JSON format, which is inferred from the extension
+// if not given.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct FileEnvSource {
+    #[serde(rename = "base64-encode")]
+    pub base64_encode: Option<bool>,
+    pub format: Option<EnvFileFormat>,
+    pub name: Option<String>,
+    pub optional: Option<bool>,
+    pub path: String,
+}
+
+impl FileEnvSource {
+    pub fn format(&self) -> EnvFileFormat {
+        self.format.unwrap_or(if self.path.ends_with(".json") {
+            EnvFileFormat::Json
+        } else {
+            EnvFileFormat::Dotenv
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvFileFormat {
+    Dotenv,
+    Json,
+}
+
+// A document fetched from an HTTPS URL. Without a name, the document must be a JSON
+// object whose keys and values become the variables.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct HttpEnvSource {
+    #[serde(rename = "base64-encode")]
+    pub base64_encode: Option<bool>,
+    pub headers: Option<HashMap<String, String>>,
+    pub name: Option<String>,
+    pub optional: Option<bool>,
+    pub url: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct S3EnvSource {
+    #[serde(rename = "base64-encode")]
+    pub base64_encode: Option<bool>,
+    pub bucket: String,
+    // For a map, only keys in include-keys if set and not in exclude-keys are used,
+    // and their names are given the prefix.
+    #[serde(rename = "exclude-keys")]
+    pub exclude_keys: Option<Vec<String>>,
+    #[serde(rename = "include-keys")]
+    pub include_keys: Option<Vec<String>>,
+    pub key: String,
+    pub name: Option<String>,
+    pub optional: Option<bool>,
+    pub prefix: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SecretsManagerEnvSource {
+    #[serde(rename = "base64-encode")]
+    pub base64_encode: Option<bool>,
+    #[serde(rename = "exclude-keys")]
+    pub exclude_keys: Option<Vec<String>>,
+    #[serde(rename = "include-keys")]
+    pub include_keys: Option<Vec<String>>,
+    pub name: Option<String>,
+    pub optional: Option<bool>,
+    pub prefix: Option<String>,
+    #[serde(rename = "secret-id")]
+    pub secret_id: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SsmEnvSource {
+    #[serde(rename = "base64-encode")]
+    pub base64_encode: Option<bool>,
+    #[serde(rename = "exclude-keys")]
+    pub exclude_keys: Option<Vec<String>>,
+    #[serde(rename = "include-keys")]
+    pub include_keys: Option<Vec<String>>,
+    pub name: Option<String>,
+    pub path: String,
+    pub optional: Option<bool>,
+    pub prefix: Option<String>,
+    pub version: Option<u64>,
+}
+
+impl SsmEnvSource {
+    // The path with the version appended as a selector if one is set, which pins a
+    // single parameter to that version.
+    pub fn selector(&self) -> String {
+        version_selector(&self.path, self.version)
+    }
+}
+
+fn version_selector(path: &str, version: Option<u64>) -> String {
+    match version {
+        Some(version) => format!("{}:{}", path, version),
+        None => path.to_string(),
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Capabilities {
+    pub add: Option<Vec<String>>,
+    pub drop: Option<Vec<String>>,
+}
+
+// A writable overlay on a directory of a readonly root filesystem. Changes are
+// kept in a tmpfs unless upper-dir is set, for example to a directory on an EBS
+// volume so they persist across boots.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Overlay {
+    pub path: String,
+    pub size: Option<String>,
+    #[serde(rename = "upper-dir")]
+    pub upper_dir: Option<String>,
+}
+
+pub type Overlays = Vec<Overlay>;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Security {
+    pub capabilities: Option<Capabilities>,
+    #[serde(rename = "no-new-privileges")]
+    pub no_new_privileges: Option<bool>,
+    #[serde(rename = "readonly-root-overlays")]
+    pub readonly_root_overlays: Option<Overlays>,
+    #[serde(rename = "readonly-root-fs")]
+    pub readonly_root_fs: Option<bool>,
+    #[serde(rename = "run-as-group-id")]
+    pub run_as_group_id: Option<u32>,
+    #[serde(rename = "run-as-user-id")]
+    pub run_as_user_id: Option<u32>,
+    #[serde(rename = "supplementary-group-ids")]
+    pub supplementary_group_ids: Option<Vec<u32>>,
+}
+
+impl Default for Security {
+    fn default() -> Self {
+        Security {
+            capabilities: None,
+            no_new_privileges: Some(false),
+            readonly_root_overlays: None,
+            readonly_root_fs: Some(false),
+            run_as_group_id: Some(0),
+            run_as_user_id: Some(0),
+            supplementary_group_ids: None,
+        }
+    }
+}
+
+impl Security {
+    pub fn capability_plan(&self) -> Result<Option<CapabilityPlan>> {
+        self.capabilities
+            .as_ref()
+            .map(|caps| {
+                CapabilityPlan::new(
+                    caps.add.as_deref().unwrap_or_default(),
+                    caps.drop.as_deref().unwrap_or_default(),
+                    self.run_as_user_id.unwrap_or_default(),
+                )
+            })
+            .transpose()
+    }
+
+    fn merge(&mut self, other: Self) {
+        if other.capabilities.is_some() {
+            self.capabilities = other.capabilities;
+        }
+        if other.no_new_privileges.is_some() {
+            self.no_new_privileges = other.no_new_privileges;
+        }
+        if other.readonly_root_fs.is_some() {
+            self.readonly_root_fs = other.readonly_root_fs;
+        }
+        if other.readonly_root_overlays.is_some() {
+            self.readonly_root_overlays = other.readonly_root_overlays;
+        }
+        if other.run_as_group_id.is_some() {
+            self.run_as_group_id = other.run_as_group_id;
+        }
+        if other.run_as_user_id.is_some() {
+            self.run_as_user_id = other.run_as_user_id;
+        }
+        if other.supplementary_group_ids.is_some() {
+            self.supplementary_group_ids = other.supplementary_group_ids;
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Volume {
+    pub ebs: Option<EbsVolumeSource>,
+    #[serde(rename = "instance-store")]
+    pub instance_store: Option<InstanceStoreVolumeSource>,
+    pub lvm: Option<LvmVolumeSource>,
+    pub s3: Option<S3VolumeSource>,
+    #[serde(rename = "secrets-manager")]
+    pub secrets_manager: Option<SecretsManagerVolumeSource>,
+    pub ssm: Option<SsmVolumeSource>,
+}
+
+impl Volume {
+    // Destinations of volumes backed by block devices, which must be unmounted at shutdown.
+    fn block_mount_points(&self) -> Vec<String> {
+        let mut mount_points = Vec::new();
+        if let Some(ebs) = &self.ebs {
+            mount_points.push(ebs.mount.destination.clone());
+        }
+        if let Some(instance_store) = &self.instance_store {
+            mount_points.push(instance_store.mount.destination.clone());
+        }
+        if let Some(lvm) = &self.lvm {
+            for lv in &lvm.logical_volumes {
+                mount_points.push(lv.mount.destination.clone());
+            }
+        }
+        mount_points
+    }
+
+    fn is_optional(&self) -> bool {
+        [
+            self.instance_store.as_ref().and_then(|s| s.optional),
+            self.s3.as_ref().and_then(|s| s.optional),
+            self.secrets_manager.as_ref().and_then(|s| s.optional),
+            self.ssm.as_ref().and_then(|s| s.optional),
+        ]
+        .iter()
+        .any(|optional| optional.unwrap_or_default())
+    }
+}
+
+pub type Volumes = Vec<Volume>;
+
+// Whether to check a filesystem before mounting it, given as true, false, or force
+// to check it even if it appears clean.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "FsckDef")]
+pub enum Fsck {
+    #[default]
+    Disabled,
+    Enabled,
+    Force,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FsckDef {
+    Bool(bool),
+    String(String),
+}
+
+impl TryFrom<FsckDef> for Fsck {
+    type Error = Error;
+
+    fn try_from(def: FsckDef) -> Result<Self> {
+        match def {
+            FsckDef::Bool(false) => Ok(Self::Disabled),
+            FsckDef::Bool(true) => Ok(Self::Enabled),
+            FsckDef::String(s) if s == "force" => Ok(Self::Force),
+            FsckDef::String(s) => Err(anyhow!("invalid fsck value {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct EbsVolumeSource {
+    pub device: String,
+    #[serde(rename = "fs-type")]
+    pub fs_type: Option<String>,
+    pub fsck: Option<Fsck>,
+    #[serde(rename = "fsck-on-failure")]
+    pub fsck_on_failure: Option<OnFailure>,
+    #[serde(rename = "make-fs")]
+    pub make_fs: Option<bool>,
+    pub mount: Mount,
+    pub partition: Option<u32>,
+    // Discard unused blocks after mounting, and every trim-interval seconds if set.
+    pub trim: Option<bool>,
+    #[serde(rename = "trim-interval")]
+    pub trim_interval: Option<u64>,
+    // The ID of an attached volume, to find its device by NVMe serial number rather
+    // than relying on the device name, which may refer to a different volume.
+    #[serde(rename = "volume-id")]
+    pub volume_id: Option<String>,
+}
+
+// An NVMe instance store device, which is formatted if it has no filesystem. The
+// device index selects among the instance store devices in the order they are found,
+// unless raid0 is set, in which case all of them are striped into one array.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InstanceStoreVolumeSource {
+    #[serde(rename = "device-index")]
+    pub device_index: Option<usize>,
+    #[serde(rename = "fs-type")]
+    pub fs_type: Option<String>,
+    #[serde(default)]
+    pub mount: Mount,
+    pub optional: Option<bool>,
+    pub raid0: Option<bool>,
+}
+
+// A volume group spanning one or more devices, with logical volumes that are each
+// formatted and mounted. Devices added to the list later are added to the volume
+// group, so it can grow without being recreated.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct LvmVolumeSource {
+    pub devices: Vec<String>,
+    #[serde(rename = "logical-volumes")]
+    pub logical_volumes: Vec<LogicalVolume>,
+    #[serde(rename = "volume-group")]
+    pub volume_group: String,
+}
+
+// A logical volume of the given size, in units accepted by lvcreate such as 10G,
+// or using all remaining free space in the volume group if size is not set.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct LogicalVolume {
+    #[serde(rename = "fs-type")]
+    pub fs_type: Option<String>,
+    pub mount: Mount,
+    pub name: String,
+    pub size: Option<String>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct S3VolumeSource {
     pub bucket: String,
+    // Unpack tar, gzipped tar, and zip archives into the destination instead of
+    // writing the archives themselves. Archives are detected from their contents, and
+    // other objects are written as is.
+    pub extract: Option<bool>,
     #[serde(rename = "key-prefix")]
     pub key_prefix: String,
     pub optional: Option<bool>,
     pub mount: Mount,
 }
 
+#[derive(Clone, Debug)]
+pub enum RefreshSource {
+    SecretsManager(SecretsManagerVolumeSource),
+    Ssm(SsmVolumeSource),
+}
+
+// A volume fetched again every refresh-interval seconds, rewriting files that
+// changed and then sending refresh-signal to the main process if it is set.
+#[derive(Clone, Debug)]
+pub struct VolumeRefresh {
+    pub destination: PathBuf,
+    pub interval: Duration,
+    pub region: String,
+    pub signal: Option<String>,
+    pub source: RefreshSource,
+}
+
+impl VolumeRefresh {
+    // Fetch the volume and rewrite any files that changed, returning whether any did.
+    // Credentials are fetched each time, as those from IMDS expire.
+    pub fn refresh(&self, imds: &CachedImds) -> Result<bool> {
+        let mut changed = false;
+        let dest = self.destination.as_path();
+        match &self.source {
+            RefreshSource::SecretsManager(volume) => {
+                let client = AsmClient::from_imds(imds, &self.region)?;
+                let split_json = volume.split_json.unwrap_or_default();
+                for mut secret in client.get_secret_list(&volume.secret_id, split_json)? {
+                    changed |= secret.refresh(
+                        dest,
+                        volume.mount.user_id.unwrap(),
+                        volume.mount.group_id.unwrap(),
+                    )?;
+                }
+            }
+            RefreshSource::Ssm(volume) => {
+                let client = SsmClient::from_imds(imds, &self.region)?;
+                for mut parameter in client.get_parameter_list(&volume.selector())? {
+                    changed |= parameter.refresh(
+                        dest,
+                        volume.mount.user_id.unwrap(),
+                        volume.mount.group_id.unwrap(),
+                    )?;
+                }
+            }
+        }
+        Ok(changed)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SecretsManagerVolumeSource {
+    #[serde(rename = "secret-id")]
+    pub secret_id: String,
+    pub mount: Mount,
+    pub optional: Option<bool>,
+    #[serde(rename = "refresh-interval")]
+    pub refresh_interval: Option<u64>,
+    #[serde(rename = "refresh-signal")]
+    pub refresh_signal: Option<String>,
+    // Write one file per key of a secret that is a JSON object, named by the key.
+    #[serde(rename = "split-json")]
+    pub split_json: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SsmVolumeSource {
+    pub path: String,
+    pub mount: Mount,
+    pub optional: Option<bool>,
+    pub version: Option<u64>,
+    #[serde(rename = "refresh-interval")]
+    pub refresh_interval: Option<u64>,
+    #[serde(rename = "refresh-signal")]
+    pub refresh_signal: Option<String>,
+}
+
+impl SsmVolumeSource {
+    // The path with the version appended as a selector if one is set, which pins a
+    // single parameter to that version.
+    pub fn selector(&self) -> String {
+        version_selector(&self.path, self.version)
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Mount {
+    pub destination: String,
+    #[serde(rename = "group-id")]
+    pub group_id: Option<u32>,
+    // A filesystem label for block volumes, set when the filesystem is created. On
+    // later boots, a device with this label is used in place of the configured one.
+    pub label: Option<String>,
+    #[serde(rename = "mkfs-options")]
+    pub mkfs_options: Option<Vec<String>>,
+    pub mode: Option<String>,
+    pub options: Option<Vec<String>>,
+    #[serde(rename = "user-id")]
+    pub user_id: Option<u32>,
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
-pub struct SecretsManagerVolumeSource {
-    #[serde(rename = "secret-id")]
-    pub secret_id: String,
-    pub mount: Mount,
-    pub optional: Option<bool>,
+pub struct WriteFile {
+    #[serde(rename = "base64-encoded")]
+    pub base64_encoded: Option<bool>,
+    pub content: String,
+    #[serde(rename = "group-id")]
+    pub group_id: Option<u32>,
+    pub mode: Option<String>,
+    pub path: String,
+    #[serde(rename = "user-id")]
+    pub user_id: Option<u32>,
 }
 
+pub type WriteFiles = Vec<WriteFile>;
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
-pub struct SsmVolumeSource {
-    pub path: String,
-    pub mount: Mount,
-    pub optional: Option<bool>,
+pub struct Group {
+    #[serde(rename = "group-id")]
+    pub group_id: u32,
+    pub name: String,
 }
 
+pub type Groups = Vec<Group>;
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
-pub struct Mount {
-    pub destination: String,
+pub struct HugePages {
+    pub count: u64,
+    pub size: String,
+}
+
+pub type HugePagesList = Vec<HugePages>;
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct User {
+    pub comment: Option<String>,
     #[serde(rename = "group-id")]
     pub group_id: Option<u32>,
-    pub mode: Option<String>,
-    pub options: Option<Vec<String>>,
+    pub groups: Option<Vec<String>>,
+    #[serde(rename = "home-dir")]
+    pub home_dir: Option<String>,
+    pub name: String,
+    pub shell: Option<String>,
+    // Public keys written to the user's authorized_keys, so the user can log in
+    // with ssh when the ssh service is enabled.
+    #[serde(rename = "ssh-authorized-keys")]
+    pub ssh_authorized_keys: Option<Vec<String>>,
     #[serde(rename = "user-id")]
-    pub user_id: Option<u32>,
+    pub user_id: u32,
+}
+
+impl User {
+    // Check the name and the fields of the user that are written to /etc/passwd.
+    fn check(&self) -> Result<()> {
+        login::check_user_name(&self.name)?;
+        for group_name in self.groups.iter().flatten() {
+            login::check_user_name(group_name)?;
+        }
+        for field in [&self.comment, &self.home_dir, &self.shell]
+            .into_iter()
+            .flatten()
+        {
+            login::check_entry_field(field)?;
+        }
+        Ok(())
+    }
+
+    // The primary group defaults to the user ID, and the home directory to /home/<name>.
+    fn passwd_entry(&self) -> PasswdEntry {
+        PasswdEntry {
+            user_name: self.name.clone(),
+            password: "x".into(),
+            uid: self.user_id,
+            gid: self.group_id.unwrap_or(self.user_id),
+            comment: self.comment.clone().unwrap_or_default(),
+            home_dir: self
+                .home_dir
+                .clone()
+                .unwrap_or_else(|| format!("/home/{}", &self.name)),
+            shell: self
+                .shell
+                .clone()
+                .unwrap_or_else(|| format!("{}/sh", constants::DIR_ET_BIN)),
+        }
+    }
+}
+
+pub type Users = Vec<User>;
+
+impl WriteFile {
+    fn contents(&self) -> Result<Vec<u8>> {
+        if self.base64_encoded.unwrap_or_default() {
+            BASE64_STANDARD
+                .decode(&self.content)
+                .map_err(|e| anyhow!("unable to decode base64 content: {}", e))
+        } else {
+            Ok(self.content.clone().into_bytes())
+        }
+    }
+
+    fn write(&self, base_dir: &Path) -> Result<()> {
+        if self.path.is_empty() {
+            return Err(anyhow!("file must have a path"));
+        }
+        let contents = self.contents()?;
+        let mode = parse_mode(self.mode.as_deref().unwrap_or("0644"))?;
+        let dest = base_dir.join_relative(&self.path);
+        let dest_dir = dest.parent().ok_or(anyhow!("no parent directory"))?;
+        mkdir_p(dest_dir, Mode::from(0o755))?;
+
+        let mut f = File::options()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(mode.as_raw_mode())
+            .open(&dest)?;
+        f.write_all(&contents)?;
+
+        // Set the mode explicitly as the umask may have masked it on creation.
+        chmod(&dest, mode)?;
+        let (uid, gid) = unsafe {
+            (
+                self.user_id.map(|u| Uid::from_raw(u)),
+                self.group_id.map(|g| Gid::from_raw(g)),
+            )
+        };
+        chown(&dest, uid, gid)?;
+        Ok(())
+    }
 }
 
 pub trait NameValuesExt<T> {
@@ -534,6 +2836,8 @@ impl StringSliceExt for Vec<String> {
 
 #[cfg(test)]
 mod test {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
     use pretty_assertions::assert_eq;
 
     use super::*;
@@ -573,4 +2877,835 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn test_write_file_contents() {
+        struct Case<'a> {
+            base64_encoded: Option<bool>,
+            content: &'a str,
+            expected: Option<&'a [u8]>,
+        }
+        let cases = [
+            Case {
+                base64_encoded: None,
+                content: "abc",
+                expected: Some(b"abc"),
+            },
+            Case {
+                base64_encoded: Some(false),
+                content: "YWJj",
+                expected: Some(b"YWJj"),
+            },
+            Case {
+                base64_encoded: Some(true),
+                content: "YWJj",
+                expected: Some(b"abc"),
+            },
+            Case {
+                base64_encoded: Some(true),
+                content: "not base64!",
+                expected: None,
+            },
+        ];
+        for case in cases {
+            let write_file = WriteFile {
+                base64_encoded: case.base64_encoded,
+                content: case.content.into(),
+                ..Default::default()
+            };
+            let result = write_file.contents();
+            match case.expected {
+                Some(expected) => assert_eq!(expected, result.unwrap().as_slice()),
+                None => assert!(result.is_err()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_user_check() {
+        struct Case {
+            user: User,
+            valid: bool,
+        }
+        let cases = [
+            Case {
+                user: User {
+                    comment: Some("Cloud Boss".into()),
+                    groups: Some(vec!["wheel".into()]),
+                    home_dir: Some("/var/lib/cloudboss".into()),
+                    name: "cloud.boss-1_a".into(),
+                    shell: Some("/bin/bash".into()),
+                    ..Default::default()
+                },
+                valid: true,
+            },
+            Case {
+                user: User {
+                    name: "evil:x:0:0::/root:/bin/sh".into(),
+                    ..Default::default()
+                },
+                valid: false,
+            },
+            Case {
+                user: User {
+                    name: "cloudboss\nevil".into(),
+                    ..Default::default()
+                },
+                valid: false,
+            },
+            Case {
+                user: User {
+                    name: "".into(),
+                    ..Default::default()
+                },
+                valid: false,
+            },
+            Case {
+                user: User {
+                    groups: Some(vec!["wheel\nevil:x:0:".into()]),
+                    name: "cloudboss".into(),
+                    ..Default::default()
+                },
+                valid: false,
+            },
+            Case {
+                user: User {
+                    comment: Some("Cloud Boss\nevil::0:0::/root:/bin/sh".into()),
+                    name: "cloudboss".into(),
+                    ..Default::default()
+                },
+                valid: false,
+            },
+            Case {
+                user: User {
+                    name: "cloudboss".into(),
+                    shell: Some("/bin/sh:0".into()),
+                    ..Default::default()
+                },
+                valid: false,
+            },
+        ];
+        for case in cases {
+            assert_eq!(case.user.check().is_ok(), case.valid, "{:?}", case.user);
+        }
+    }
+
+    #[test]
+    fn test_user_passwd_entry() {
+        struct Case {
+            user: User,
+            expected: PasswdEntry,
+        }
+        let cases = [
+            Case {
+                user: User {
+                    name: "cloudboss".into(),
+                    user_id: 1234,
+                    ..Default::default()
+                },
+                expected: PasswdEntry {
+                    user_name: "cloudboss".into(),
+                    password: "x".into(),
+                    uid: 1234,
+                    gid: 1234,
+                    comment: "".into(),
+                    home_dir: "/home/cloudboss".into(),
+                    shell: "/.easyto/bin/sh".into(),
+                },
+            },
+            Case {
+                user: User {
+                    comment: Some("Cloud Boss".into()),
+                    group_id: Some(100),
+                    home_dir: Some("/var/lib/cloudboss".into()),
+                    name: "cloudboss".into(),
+                    shell: Some("/bin/bash".into()),
+                    user_id: 1234,
+                    ..Default::default()
+                },
+                expected: PasswdEntry {
+                    user_name: "cloudboss".into(),
+                    password: "x".into(),
+                    uid: 1234,
+                    gid: 100,
+                    comment: "Cloud Boss".into(),
+                    home_dir: "/var/lib/cloudboss".into(),
+                    shell: "/bin/bash".into(),
+                },
+            },
+        ];
+        for case in cases {
+            assert_eq!(case.user.passwd_entry(), case.expected);
+        }
+    }
+
+    #[test]
+    fn test_limits_to_rlimits() {
+        let user_data = UserData::from_string(
+            r#"
+limits:
+  nofile: 65536
+  core:
+    soft: 0
+  memlock:
+    soft: 1024
+    hard: 2048
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            user_data.limits.unwrap().to_rlimits(),
+            vec![
+                (
+                    Resource::Core,
+                    Rlimit {
+                        current: Some(0),
+                        maximum: None,
+                    }
+                ),
+                (
+                    Resource::Memlock,
+                    Rlimit {
+                        current: Some(1024),
+                        maximum: Some(2048),
+                    }
+                ),
+                (
+                    Resource::Nofile,
+                    Rlimit {
+                        current: Some(65536),
+                        maximum: Some(65536),
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mount_points() {
+        let mut vmspec = VmSpec {
+            security: Security {
+                readonly_root_overlays: Some(vec![Overlay {
+                    path: "/var".into(),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            },
+            volumes: vec![
+                Volume {
+                    ebs: Some(EbsVolumeSource {
+                        mount: Mount {
+                            destination: "/data".into(),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                Volume {
+                    ssm: Some(SsmVolumeSource::default()),
+                    ..Default::default()
+                },
+                Volume {
+                    lvm: Some(LvmVolumeSource {
+                        logical_volumes: vec![LogicalVolume {
+                            mount: Mount {
+                                destination: "/lv".into(),
+                                ..Default::default()
+                            },
+                            ..Default::default()
+                        }],
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        // Overlays are only used with a readonly root filesystem.
+        assert_eq!(
+            vmspec.mount_points(),
+            vec!["/data".to_string(), "/lv".to_string()]
+        );
+
+        vmspec.security.readonly_root_fs = Some(true);
+        assert_eq!(
+            vmspec.mount_points(),
+            vec!["/var".to_string(), "/data".to_string(), "/lv".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_instance_store_defaults() {
+        let mut vmspec = VmSpec::default();
+        vmspec.merge_user_data(
+            UserData::from_string(
+                r#"
+volumes:
+  - instance-store: {}
+  - instance-store:
+      device-index: 1
+      fs-type: xfs
+      mount:
+        destination: /scratch
+  - instance-store:
+      raid0: true
+"#,
+            )
+            .unwrap(),
+        );
+        let sources: Vec<(Option<usize>, Option<String>, String)> = vmspec
+            .volumes
+            .iter()
+            .map(|v| v.instance_store.clone().unwrap())
+            .map(|s| (s.device_index, s.fs_type, s.mount.destination))
+            .collect();
+        assert_eq!(
+            sources,
+            vec![
+                (Some(0), Some("ext4".into()), "/mnt/instance-store/0".into()),
+                (Some(1), Some("xfs".into()), "/scratch".into()),
+                (Some(0), Some("ext4".into()), "/mnt/instance-store".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fsck_deserialize() {
+        struct Case {
+            input: &'static str,
+            expected: Option<Fsck>,
+        }
+        let cases = [
+            Case {
+                input: "true",
+                expected: Some(Fsck::Enabled),
+            },
+            Case {
+                input: "false",
+                expected: Some(Fsck::Disabled),
+            },
+            Case {
+                input: "force",
+                expected: Some(Fsck::Force),
+            },
+            Case {
+                input: "sometimes",
+                expected: None,
+            },
+        ];
+        for case in cases {
+            let fsck: Option<Fsck> = serde_yml::from_str(case.input).ok();
+            assert_eq!(fsck, case.expected);
+        }
+    }
+
+    #[test]
+    fn test_health_check_from_health_config() {
+        struct Case {
+            config: HealthConfig,
+            expected: Option<HealthCheck>,
+        }
+        let cases = [
+            Case {
+                config: HealthConfig {
+                    test: Some(vec![
+                        "CMD-SHELL".into(),
+                        "curl -f http://localhost/ || exit 1".into(),
+                    ]),
+                    interval: Some(10_000_000_000),
+                    timeout: Some(500_000_000),
+                    start_period: Some(0),
+                    retries: Some(5),
+                },
+                expected: Some(HealthCheck {
+                    command: Some(vec![
+                        "/bin/sh".into(),
+                        "-c".into(),
+                        "curl -f http://localhost/ || exit 1".into(),
+                    ]),
+                    interval: Some(10),
+                    retries: Some(5),
+                    timeout: Some(1),
+                    ..Default::default()
+                }),
+            },
+            Case {
+                config: HealthConfig {
+                    test: Some(vec!["CMD".into(), "/healthcheck".into(), "-q".into()]),
+                    ..Default::default()
+                },
+                expected: Some(HealthCheck {
+                    command: Some(vec!["/healthcheck".into(), "-q".into()]),
+                    ..Default::default()
+                }),
+            },
+            Case {
+                config: HealthConfig {
+                    test: Some(vec!["NONE".into()]),
+                    ..Default::default()
+                },
+                expected: None,
+            },
+        ];
+        for case in cases {
+            assert_eq!(HealthCheck::from_health_config(case.config), case.expected);
+        }
+    }
+
+    #[test]
+    fn test_exit_actions() {
+        let vmspec = VmSpec {
+            on_exit: HashMap::from([
+                ("1-255".into(), ExitAction::Restart),
+                ("75".into(), ExitAction::Reboot),
+                ("64-78".into(), ExitAction::Poweroff),
+            ]),
+            ..Default::default()
+        };
+        assert_eq!(
+            vmspec.exit_actions().unwrap(),
+            vec![
+                (75..=75, ExitAction::Reboot),
+                (64..=78, ExitAction::Poweroff),
+                (1..=255, ExitAction::Restart),
+            ]
+        );
+
+        for codes in ["", "x", "9-3", "1-"] {
+            let vmspec = VmSpec {
+                on_exit: HashMap::from([(codes.into(), ExitAction::Reboot)]),
+                ..Default::default()
+            };
+            assert!(vmspec.exit_actions().is_err(), "{}", codes);
+        }
+    }
+
+    #[test]
+    fn test_degrade() {
+        let mut vmspec = VmSpec {
+            env_from: vec![
+                EnvFromSource {
+                    ssm: Some(SsmEnvSource {
+                        path: "/required".into(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                EnvFromSource {
+                    imds: Some(ImdsEnvSource {
+                        optional: Some(true),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            ],
+            volumes: vec![
+                Volume {
+                    s3: Some(S3VolumeSource {
+                        optional: Some(true),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                Volume {
+                    ssm: Some(SsmVolumeSource {
+                        optional: Some(false),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            ],
+            ssh: Ssh {
+                authorized_keys_from: Some(vec![
+                    SecretSource {
+                        s3: Some(S3SecretSource {
+                            optional: Some(true),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                    SecretSource {
+                        secrets_manager: Some(SecretsManagerSecretSource {
+                            secret_id: "team-keys".into(),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                ]),
+                host_certificate: Some(HostCertificate {
+                    certificate: SecretSource {
+                        ssm: Some(SsmSecretSource {
+                            optional: Some(true),
+                            path: "/ssh/host-cert".into(),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                }),
+            },
+            ..Default::default()
+        };
+        vmspec.degrade();
+        assert_eq!(1, vmspec.env_from.len());
+        assert!(vmspec.env_from[0].ssm.is_some());
+        assert_eq!(1, vmspec.volumes.len());
+        assert!(vmspec.volumes[0].ssm.is_some());
+        assert_eq!(1, vmspec.ssh.authorized_keys_from().len());
+        assert!(vmspec.ssh.authorized_keys_from()[0]
+            .secrets_manager
+            .is_some());
+        assert!(vmspec.ssh.host_certificate.is_none());
+    }
+
+    #[test]
+    fn test_user_data_from_multipart() {
+        let document = [
+            "Content-Type: multipart/mixed; boundary=\"XYZ\"",
+            "MIME-Version: 1.0",
+            "",
+            "--XYZ",
+            "Content-Type: text/x-shellscript",
+            "",
+            "#!/bin/sh",
+            "--XYZ",
+            "Content-Type: text/yaml",
+            "",
+            "debug: true",
+            "init-scripts:",
+            "  - \"#!/bin/true\"",
+            "--XYZ",
+            "Content-Type: text/plain",
+            "Content-Disposition: attachment; filename=\"/etc/app.conf\"",
+            "",
+            "a=b",
+            "--XYZ--",
+        ]
+        .join("\n");
+        let user_data = UserData::from_string(&document).unwrap();
+        assert_eq!(Some(true), user_data.debug);
+        assert_eq!(
+            Some(vec![
+                InitScript::from("#!/bin/true".to_string()),
+                InitScript::from("#!/bin/sh".to_string()),
+            ]),
+            user_data.init_scripts
+        );
+        let write_files = user_data.write_files.unwrap();
+        assert_eq!(1, write_files.len());
+        assert_eq!("/etc/app.conf", write_files[0].path);
+        assert_eq!(b"a=b".to_vec(), write_files[0].contents().unwrap());
+    }
+
+    #[test]
+    fn test_decode_user_data() {
+        let plain = b"debug: true\n".to_vec();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&plain).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        struct Case {
+            input: Vec<u8>,
+            expected: Vec<u8>,
+        }
+        let cases = [
+            Case {
+                input: plain.clone(),
+                expected: plain.clone(),
+            },
+            Case {
+                input: gzipped.clone(),
+                expected: plain.clone(),
+            },
+            Case {
+                input: BASE64_STANDARD.encode(&plain).into_bytes(),
+                expected: plain.clone(),
+            },
+            Case {
+                input: BASE64_STANDARD.encode(&gzipped).into_bytes(),
+                expected: plain.clone(),
+            },
+            Case {
+                input: b"abcd".to_vec(),
+                expected: b"abcd".to_vec(),
+            },
+            Case {
+                // Base64 characters separated by spaces are not base64.
+                input: b"ZGVi dWc6 IHRy dWUK".to_vec(),
+                expected: b"ZGVi dWc6 IHRy dWUK".to_vec(),
+            },
+            Case {
+                input: b"ZGVidWc6\nIHRydWUK\n".to_vec(),
+                expected: plain.clone(),
+            },
+            Case {
+                // Lines of different lengths are not wrapped base64.
+                input: b"ZGVidWc6IHRy\ndWUK\nZGVi\n".to_vec(),
+                expected: b"ZGVidWc6IHRy\ndWUK\nZGVi\n".to_vec(),
+            },
+        ];
+        for case in cases {
+            assert_eq!(case.expected, decode_user_data(&case.input).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_user_data_from_gzip_bytes() {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(b"command: [/app]\ndebug: true\n")
+            .unwrap();
+        let gzipped = encoder.finish().unwrap();
+        // Gzip data is not valid UTF-8, so it must be decoded from bytes.
+        assert!(std::str::from_utf8(&gzipped).is_err());
+
+        let user_data = UserData::from_bytes(&gzipped).unwrap();
+        assert_eq!(user_data.command, Some(vec!["/app".to_string()]));
+        assert_eq!(user_data.debug, Some(true));
+    }
+
+    #[test]
+    fn test_user_data_strict() {
+        struct Case<'a> {
+            user_data: &'a str,
+            err: bool,
+        }
+        let cases = [
+            Case {
+                user_data: "init-script: []\n",
+                err: false,
+            },
+            Case {
+                user_data: "strict: true\ninit-scripts: []\n",
+                err: false,
+            },
+            Case {
+                user_data: "strict: true\ninit-script: []\n",
+                err: true,
+            },
+            Case {
+                user_data: "strict: true\nvolumes:\n  - ebs:\n      device: /dev/sdb\n      fs-typ: ext4\n      mount:\n        destination: /data\n",
+                err: true,
+            },
+            Case {
+                user_data: "strict: true\nvolumes:\n  - ebs:\n      device: /dev/sdb\n      fs-type: ext4\n      mount:\n        destination: /data\n",
+                err: false,
+            },
+        ];
+        for case in cases {
+            let result = UserData::from_string(case.user_data);
+            assert_eq!(case.err, result.is_err());
+        }
+    }
+
+    #[test]
+    fn test_init_scripts_deserialize() {
+        let user_data = UserData::from_string(
+            r##"
+init-scripts:
+  - "#!/bin/sh"
+  - name: setup
+    on-failure: warn
+    order: -1
+    script: "#!/bin/true"
+"##,
+        )
+        .unwrap();
+        assert_eq!(
+            Some(vec![
+                InitScript::from("#!/bin/sh".to_string()),
+                InitScript {
+                    name: Some("setup".into()),
+                    on_failure: Some(OnFailure::Warn),
+                    order: Some(-1),
+                    script: "#!/bin/true".into(),
+                },
+            ]),
+            user_data.init_scripts
+        );
+    }
+
+    #[test]
+    fn test_services_deserialize() {
+        let user_data = UserData::from_string(
+            r#"
+services:
+  - name: exporter
+    command: [/usr/bin/exporter, --listen=:$(PORT)]
+    env:
+      - name: PORT
+        value: "9100"
+    restart-policy:
+      condition: on-failure
+      max-restarts: 3
+    run-as-user-id: 65534
+"#,
+        )
+        .unwrap();
+        let services = user_data.services.unwrap();
+        assert_eq!(
+            services,
+            vec![SidecarService {
+                command: vec!["/usr/bin/exporter".into(), "--listen=:$(PORT)".into()],
+                env: Some(vec![NameValue {
+                    name: "PORT".into(),
+                    value: "9100".into(),
+                }]),
+                name: "exporter".into(),
+                restart_policy: Some(RestartPolicy {
+                    condition: Some(RestartCondition::OnFailure),
+                    max_restarts: Some(3),
+                    ..Default::default()
+                }),
+                run_as_user_id: Some(65534),
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_changed_fields() {
+        let vmspec = VmSpec::default();
+        let mut other = vmspec.clone();
+        assert_eq!(vmspec.changed_fields(&other), Vec::<String>::new());
+
+        other.debug = true;
+        other.disable_services = vec!["ssh".into()];
+        other.service_dependencies.insert(
+            "main".into(),
+            ServiceDependencies {
+                after: Some(vec!["chrony".into()]),
+                requires: None,
+            },
+        );
+        assert_eq!(
+            vmspec.changed_fields(&other),
+            vec!["debug", "disable-services", "service-dependencies"]
+        );
+    }
+
+    #[test]
+    fn test_redacted() {
+        let mut vmspec = VmSpec::default();
+        vmspec.merge_user_data(
+            UserData::from_string(
+                r#"
+env:
+  - name: DB_PASSWORD
+    value: hunter2
+init-scripts:
+  - "echo hunter2 > /etc/secret"
+write-files:
+  - path: /etc/app.conf
+    content: password=hunter2
+"#,
+            )
+            .unwrap(),
+        );
+        let redacted = vmspec.redacted();
+        assert!(!redacted.to_string().contains("hunter2"));
+        assert_eq!(redacted["env"][0]["name"], "DB_PASSWORD");
+        assert_eq!(redacted["env"][0]["value"], "<redacted>");
+        assert_eq!(redacted["write-files"][0]["path"], "/etc/app.conf");
+    }
+
+    #[test]
+    fn test_instance_events_handlers() {
+        let mut vmspec = VmSpec::default();
+        vmspec.merge_user_data(
+            UserData::from_string(
+                "instance-events:\n  rebalance:\n    action: signal\n    signal: SIGUSR1\n",
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            vmspec.instance_events.handlers(),
+            vec![
+                (
+                    InstanceEvent::Maintenance,
+                    EventHandler {
+                        action: Some(EventAction::Ignore),
+                        interval: Some(60),
+                        signal: None,
+                    },
+                ),
+                (
+                    InstanceEvent::Rebalance,
+                    EventHandler {
+                        action: Some(EventAction::Signal),
+                        interval: Some(5),
+                        signal: Some("SIGUSR1".into()),
+                    },
+                ),
+                (
+                    InstanceEvent::SpotInterruption,
+                    EventHandler {
+                        action: Some(EventAction::Shutdown),
+                        interval: Some(5),
+                        signal: None,
+                    },
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_watchdog_merge() {
+        let mut vmspec = VmSpec::default();
+        assert_eq!(vmspec.watchdog.device(), "/dev/watchdog");
+        assert_eq!(vmspec.watchdog.timeout(), Duration::from_secs(60));
+
+        vmspec.merge_user_data(
+            UserData::from_string("watchdog:\n  enable: true\n  timeout: 120\n").unwrap(),
+        );
+        vmspec.merge_user_data(
+            UserData::from_string("watchdog:\n  device: /dev/watchdog1\n").unwrap(),
+        );
+        assert_eq!(
+            vmspec.watchdog,
+            Watchdog {
+                device: Some("/dev/watchdog1".into()),
+                enable: Some(true),
+                timeout: Some(120),
+            }
+        );
+        assert_eq!(vmspec.watchdog.timeout(), Duration::from_secs(120));
+    }
+
+    #[test]
+    fn test_http_get_probe_url() {
+        struct Case {
+            probe: HttpGetProbe,
+            expected: &'static str,
+        }
+        let cases = [
+            Case {
+                probe: HttpGetProbe {
+                    port: 8080,
+                    ..Default::default()
+                },
+                expected: "http://127.0.0.1:8080/",
+            },
+            Case {
+                probe: HttpGetProbe {
+                    host: Some("localhost".into()),
+                    path: Some("healthz".into()),
+                    port: 80,
+                },
+                expected: "http://localhost:80/healthz",
+            },
+            Case {
+                probe: HttpGetProbe {
+                    path: Some("/ready?full=1".into()),
+                    port: 9000,
+                    ..Default::default()
+                },
+                expected: "http://127.0.0.1:9000/ready?full=1",
+            },
+        ];
+        for case in cases {
+            assert_eq!(case.probe.url(), case.expected);
+        }
+    }
 }
@@ -0,0 +1,61 @@
+use std::{
+    ffi::c_int,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use rustix::ioctl::{ioctl, ReadWriteOpcode, Updater};
+
+// Set the timeout of a watchdog, from include/uapi/linux/watchdog.h in kernel source.
+// The driver may round the timeout, and writes back the one it uses.
+type SetTimeoutOpcode = ReadWriteOpcode<b'W', 6, c_int>;
+
+// An open watchdog device, which reboots the system if it is not fed before its
+// timeout passes.
+pub struct WatchdogDevice {
+    file: File,
+    path: PathBuf,
+    timeout: Duration,
+}
+
+impl WatchdogDevice {
+    // Open the device, which starts its timer.
+    pub fn open<P: AsRef<Path>>(path: P, timeout: Duration) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::options()
+            .write(true)
+            .open(path)
+            .map_err(|e| anyhow!("unable to open {:?}: {}", path, e))?;
+        let mut secs = timeout.as_secs() as c_int;
+        unsafe { ioctl(&file, Updater::<SetTimeoutOpcode, c_int>::new(&mut secs)) }
+            .map_err(|e| anyhow!("unable to set timeout of {:?}: {}", path, e))?;
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+            timeout: Duration::from_secs(secs as u64),
+        })
+    }
+
+    // Reset the timer of the device.
+    pub fn feed(&mut self) -> Result<()> {
+        self.file
+            .write_all(b"\0")
+            .map_err(|e| anyhow!("unable to feed {:?}: {}", self.path, e))
+    }
+
+    // Stop the timer by writing the magic character before closing the device.
+    // Drivers built with nowayout keep running, so the system must not hang after.
+    pub fn disarm(mut self) -> Result<()> {
+        self.file
+            .write_all(b"V")
+            .map_err(|e| anyhow!("unable to disarm {:?}: {}", self.path, e))
+    }
+
+    // The timeout the driver is using.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+}
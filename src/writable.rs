@@ -1,14 +1,34 @@
 use std::{
-    fs::File,
-    io::{self, Read},
-    path::Path,
+    fs::{self, File},
+    io::{self, Cursor, ErrorKind, Read, Write},
+    os::unix::fs::symlink,
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
 };
 
 use anyhow::{anyhow, Result};
-use rustix::fs::{chown, Gid, Mode, OpenOptionsExt, Uid};
+use flate2::read::GzDecoder;
+use log::{debug, info};
+use rustix::fs::{chown, chownat, fchmod, AtFlags, Gid, Mode, OpenOptionsExt, Uid, CWD};
+use tar::Archive;
+use zip::ZipArchive;
 
 use crate::fs::{mkdir_p_own, JoinRelative};
 
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+// The number of bytes read from the start of data to detect an archive format. The
+// magic of a tar archive is at offset 257, which for a gzipped tar archive must be
+// found after decompressing some of it.
+const DETECT_BUFFER_SIZE: usize = 1024;
+
+// Temporary files that zip archives are copied to are numbered, as several may be
+// unpacked into the same directory at once.
+static ZIP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Progress of a write is logged each time this many more bytes have been written.
+const PROGRESS_INTERVAL: u64 = 256 * 1024 * 1024;
+
 pub trait Writable
 where
     Self: Read,
@@ -16,15 +36,24 @@ where
     fn name(&self) -> &str;
     fn is_secret(&self) -> bool;
 
-    fn write(&mut self, dest: &Path, user_id: u32, group_id: u32) -> Result<()> {
-        let mode_dir = Mode::from(if self.is_secret() { 0o700 } else { 0o755 });
-        let mode_file = Mode::from(if self.is_secret() { 0o600 } else { 0o644 });
+    // The size in bytes if known before reading, used for progress logs.
+    fn size(&self) -> Option<u64> {
+        None
+    }
+
+    fn destination(&self, dest: &Path) -> PathBuf {
         let name = self.name();
-        let final_dest = if name.is_empty() {
+        if name.is_empty() {
             dest.to_path_buf()
         } else {
             dest.join_relative(name)
-        };
+        }
+    }
+
+    fn write(&mut self, dest: &Path, user_id: u32, group_id: u32) -> Result<()> {
+        let mode_dir = Mode::from(if self.is_secret() { 0o700 } else { 0o755 });
+        let mode_file = Mode::from(if self.is_secret() { 0o600 } else { 0o644 });
+        let final_dest = self.destination(dest);
         let dest_dir = final_dest.parent().ok_or(anyhow!("no parent directory"))?;
 
         let (uid, gid) = unsafe { (Uid::from_raw(user_id), Gid::from_raw(group_id)) };
@@ -37,10 +66,567 @@ where
             .mode(mode_file.as_raw_mode())
             .open(&final_dest)?;
 
-        io::copy(self, &mut f)?;
+        let size = self.size();
+        copy_with_progress(self, &mut f, &final_dest, size)?;
 
         chown(final_dest, Some(uid), Some(gid))?;
 
         Ok(())
     }
+
+    // Write the file again only if its contents have changed, replacing it with a
+    // rename so readers never see it partially written. Returns whether it changed.
+    fn refresh(&mut self, dest: &Path, user_id: u32, group_id: u32) -> Result<bool> {
+        let mode_dir = Mode::from(if self.is_secret() { 0o700 } else { 0o755 });
+        let mode_file = Mode::from(if self.is_secret() { 0o600 } else { 0o644 });
+        let final_dest = self.destination(dest);
+        let dest_dir = final_dest.parent().ok_or(anyhow!("no parent directory"))?;
+        let file_name = final_dest
+            .file_name()
+            .ok_or(anyhow!("no file name in {:?}", final_dest))?;
+
+        let mut contents = Vec::new();
+        self.read_to_end(&mut contents)?;
+        match fs::read(&final_dest) {
+            Ok(existing) if existing == contents => return Ok(false),
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(anyhow!("unable to read {:?}: {}", final_dest, e)),
+        }
+
+        let (uid, gid) = unsafe { (Uid::from_raw(user_id), Gid::from_raw(group_id)) };
+        mkdir_p_own(dest_dir, mode_dir, Some(uid), Some(gid))?;
+
+        let mut tmp_name = file_name.to_os_string();
+        tmp_name.push(".tmp");
+        let tmp_dest = dest_dir.join(tmp_name);
+        let mut f = File::options()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .mode(mode_file.as_raw_mode())
+            .open(&tmp_dest)?;
+        f.write_all(&contents)?;
+        chown(&tmp_dest, Some(uid), Some(gid))?;
+        fs::rename(&tmp_dest, &final_dest)
+            .map_err(|e| anyhow!("unable to rename {:?}: {}", tmp_dest, e))?;
+
+        Ok(true)
+    }
+
+    // Unpack an archive into the directory it would otherwise be written to, keeping
+    // the modes of its files but giving them to the user and group of the mount.
+    fn extract(
+        &mut self,
+        format: ArchiveFormat,
+        dest: &Path,
+        user_id: u32,
+        group_id: u32,
+    ) -> Result<()> {
+        let mode_dir = Mode::from(if self.is_secret() { 0o700 } else { 0o755 });
+        let name = self.name();
+        let dest_dir = match Path::new(name).parent() {
+            Some(parent) => dest.join_relative(parent),
+            None => dest.to_path_buf(),
+        };
+
+        let (uid, gid) = unsafe { (Uid::from_raw(user_id), Gid::from_raw(group_id)) };
+        mkdir_p_own(&dest_dir, mode_dir, Some(uid), Some(gid))?;
+
+        match format {
+            ArchiveFormat::Tar => unpack_tar(self, &dest_dir, mode_dir, uid, gid),
+            ArchiveFormat::TarGz => unpack_tar(GzDecoder::new(self), &dest_dir, mode_dir, uid, gid),
+            ArchiveFormat::Zip => unpack_zip(self, &dest_dir, mode_dir, uid, gid),
+        }
+    }
+
+    // Unpack the data if its first bytes show that it is an archive, and otherwise
+    // write it as is.
+    fn extract_or_write(&mut self, dest: &Path, user_id: u32, group_id: u32) -> Result<()> {
+        let mut header = Vec::with_capacity(DETECT_BUFFER_SIZE);
+        Read::take(&mut *self, DETECT_BUFFER_SIZE as u64)
+            .read_to_end(&mut header)
+            .map_err(|e| anyhow!("unable to read {}: {}", self.name(), e))?;
+        let format = ArchiveFormat::detect(&header);
+        let mut peeked = Peeked {
+            header: Cursor::new(header),
+            inner: self,
+        };
+        match format {
+            Some(format) => peeked.extract(format, dest, user_id, group_id),
+            None => peeked.write(dest, user_id, group_id),
+        }
+    }
+}
+
+// Data whose first bytes have already been read, which are read again before the
+// rest of it.
+struct Peeked<'a, W: ?Sized> {
+    header: Cursor<Vec<u8>>,
+    inner: &'a mut W,
+}
+
+impl<W: Writable + ?Sized> Read for Peeked<'_, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.header.read(buf)? {
+            0 => self.inner.read(buf),
+            n => Ok(n),
+        }
+    }
+}
+
+impl<W: Writable + ?Sized> Writable for Peeked<'_, W> {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn is_secret(&self) -> bool {
+        self.inner.is_secret()
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.inner.size()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl ArchiveFormat {
+    // Detect the format of an archive from its first bytes. Gzipped data is only a
+    // tar archive if it has a tar header once decompressed.
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+        const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+        if header.starts_with(ZIP_MAGIC) {
+            return Some(Self::Zip);
+        }
+        if is_tar_header(header) {
+            return Some(Self::Tar);
+        }
+        if header.starts_with(GZIP_MAGIC) {
+            let mut decompressed = Vec::with_capacity(DETECT_BUFFER_SIZE);
+            // The header is only part of the gzipped data, so this ends in an error
+            // once it runs out, after decompressing what it can.
+            let _ = GzDecoder::new(header)
+                .take(DETECT_BUFFER_SIZE as u64)
+                .read_to_end(&mut decompressed);
+            if is_tar_header(&decompressed) {
+                return Some(Self::TarGz);
+            }
+        }
+        None
+    }
+}
+
+// Whether data starts with a POSIX or GNU tar header, which has "ustar" at offset 257.
+fn is_tar_header(header: &[u8]) -> bool {
+    header.get(257..262) == Some(b"ustar".as_slice())
+}
+
+// The path an archive entry is unpacked to, or None if it would be outside of
+// dest_dir.
+fn entry_path(dest_dir: &Path, path: &Path) -> Option<PathBuf> {
+    if path.components().any(|c| c == Component::ParentDir) {
+        return None;
+    }
+    Some(dest_dir.join_relative(path))
+}
+
+// Whether path is inside canonical_dest_dir, resolving symbolic links in the part of
+// it that exists, which earlier entries of an archive may have created. Checked
+// before creating anything, so directories are never created through a link.
+fn resolves_inside(canonical_dest_dir: &Path, path: &Path) -> bool {
+    let Some(existing) = path.ancestors().find(|p| fs::symlink_metadata(p).is_ok()) else {
+        return false;
+    };
+    fs::canonicalize(existing)
+        .map(|existing| existing.starts_with(canonical_dest_dir))
+        .unwrap_or_default()
+}
+
+// Remove a file or link that an archive entry replaces, so it is not written through.
+fn remove_existing(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.is_dir() => fs::remove_file(path)
+            .map_err(|e| anyhow!("unable to remove existing {:?}: {}", path, e)),
+        _ => Ok(()),
+    }
+}
+
+fn unpack_tar<R: Read>(
+    reader: R,
+    dest_dir: &Path,
+    mode_dir: Mode,
+    uid: Uid,
+    gid: Gid,
+) -> Result<()> {
+    let mut archive = Archive::new(reader);
+    archive.set_preserve_permissions(true);
+    let canonical_dest_dir = fs::canonicalize(dest_dir)
+        .map_err(|e| anyhow!("unable to resolve {:?}: {}", dest_dir, e))?;
+    let entries = archive
+        .entries()
+        .map_err(|e| anyhow!("unable to read archive: {}", e))?;
+    for entry in entries {
+        let mut entry = entry.map_err(|e| anyhow!("unable to read archive entry: {}", e))?;
+        let path = entry
+            .path()
+            .map_err(|e| anyhow!("invalid path in archive: {}", e))?
+            .into_owned();
+        // Entries with paths outside of the destination are skipped.
+        let Some(full_path) = entry_path(dest_dir, &path) else {
+            debug!("Skipped archive entry {:?} outside of {:?}", path, dest_dir);
+            continue;
+        };
+        // Parent directories that are not entries in the archive are created here
+        // rather than by unpack_in, so they also belong to the user and group.
+        if let Some(parent) = full_path.parent() {
+            if !resolves_inside(&canonical_dest_dir, parent) {
+                debug!("Skipped archive entry {:?} outside of {:?}", path, dest_dir);
+                continue;
+            }
+            mkdir_p_own(parent, mode_dir, Some(uid), Some(gid))?;
+        }
+        if !entry
+            .unpack_in(dest_dir)
+            .map_err(|e| anyhow!("unable to unpack {:?}: {}", path, e))?
+        {
+            debug!("Skipped archive entry {:?} outside of {:?}", path, dest_dir);
+            continue;
+        }
+        chownat(
+            CWD,
+            &full_path,
+            Some(uid),
+            Some(gid),
+            AtFlags::SYMLINK_NOFOLLOW,
+        )
+        .map_err(|e| anyhow!("unable to change ownership of {:?}: {}", full_path, e))?;
+    }
+    Ok(())
+}
+
+fn unpack_zip<R: Read>(
+    mut reader: R,
+    dest_dir: &Path,
+    mode_dir: Mode,
+    uid: Uid,
+    gid: Gid,
+) -> Result<()> {
+    // A zip archive is read from its end, so it is copied to a file first. The file
+    // is removed right away, and is gone once it is closed.
+    let tmp_path = dest_dir.join(format!(
+        ".unpack-{}.zip",
+        ZIP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut tmp_file = File::options()
+        .create_new(true)
+        .read(true)
+        .write(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .map_err(|e| anyhow!("unable to create {:?}: {}", tmp_path, e))?;
+    fs::remove_file(&tmp_path).map_err(|e| anyhow!("unable to remove {:?}: {}", tmp_path, e))?;
+    io::copy(&mut reader, &mut tmp_file)
+        .map_err(|e| anyhow!("unable to copy archive to {:?}: {}", tmp_path, e))?;
+
+    let mut archive =
+        ZipArchive::new(tmp_file).map_err(|e| anyhow!("unable to read archive: {}", e))?;
+    let canonical_dest_dir = fs::canonicalize(dest_dir)
+        .map_err(|e| anyhow!("unable to resolve {:?}: {}", dest_dir, e))?;
+    for i in 0..archive.len() {
+        let mut file = archive
+            .by_index(i)
+            .map_err(|e| anyhow!("unable to read archive entry: {}", e))?;
+        // Entries with paths outside of the destination are skipped.
+        let Some(full_path) = file
+            .enclosed_name()
+            .and_then(|path| entry_path(dest_dir, &path))
+        else {
+            debug!(
+                "Skipped archive entry {} outside of {:?}",
+                file.name(),
+                dest_dir
+            );
+            continue;
+        };
+        let Some(parent) = full_path.parent() else {
+            continue;
+        };
+        // A symbolic link unpacked earlier must not lead outside of the destination.
+        let checked = if file.is_dir() { &full_path } else { parent };
+        if !resolves_inside(&canonical_dest_dir, checked) {
+            debug!(
+                "Skipped archive entry {} outside of {:?}",
+                file.name(),
+                dest_dir
+            );
+            continue;
+        }
+        if file.is_dir() {
+            mkdir_p_own(&full_path, mode_dir, Some(uid), Some(gid))?;
+            continue;
+        }
+        mkdir_p_own(parent, mode_dir, Some(uid), Some(gid))?;
+        remove_existing(&full_path)?;
+
+        let mode = file.unix_mode().unwrap_or(0o644);
+        if mode & libc::S_IFMT == libc::S_IFLNK {
+            let mut target = String::new();
+            file.read_to_string(&mut target)
+                .map_err(|e| anyhow!("unable to read link {}: {}", file.name(), e))?;
+            symlink(&target, &full_path)
+                .map_err(|e| anyhow!("unable to create link {:?}: {}", full_path, e))?;
+        } else {
+            let mut f = File::options()
+                .create_new(true)
+                .write(true)
+                .custom_flags(libc::O_NOFOLLOW)
+                .mode(0o600)
+                .open(&full_path)
+                .map_err(|e| anyhow!("unable to create {:?}: {}", full_path, e))?;
+            io::copy(&mut file, &mut f)
+                .map_err(|e| anyhow!("unable to unpack {:?}: {}", full_path, e))?;
+            fchmod(&f, Mode::from(mode & 0o7777))
+                .map_err(|e| anyhow!("unable to change mode of {:?}: {}", full_path, e))?;
+        }
+        chownat(
+            CWD,
+            &full_path,
+            Some(uid),
+            Some(gid),
+            AtFlags::SYMLINK_NOFOLLOW,
+        )
+        .map_err(|e| anyhow!("unable to change ownership of {:?}: {}", full_path, e))?;
+    }
+    Ok(())
+}
+
+// Copy from reader to writer in chunks, logging progress for large copies so that
+// long downloads are visible during boot.
+fn copy_with_progress<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    dest: &Path,
+    size: Option<u64>,
+) -> Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write,
+{
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+    let mut written = 0;
+    let mut next_progress = PROGRESS_INTERVAL;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(anyhow!("unable to read data for {:?}: {}", dest, e)),
+        };
+        writer
+            .write_all(&buf[..n])
+            .map_err(|e| anyhow!("unable to write to {:?}: {}", dest, e))?;
+        written += n as u64;
+        if written >= next_progress {
+            match size {
+                Some(size) => info!("Wrote {} of {} bytes to {:?}", written, size, dest),
+                None => info!("Wrote {} bytes to {:?}", written, dest),
+            }
+            next_progress += PROGRESS_INTERVAL;
+        }
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::os::unix::fs::PermissionsExt;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn tar_archive(files: &[(&str, u32, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, mode, contents) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(*mode);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .or_else(|_| {
+                    // The builder refuses paths with "..", so write it directly.
+                    header.as_gnu_mut().unwrap().name[..path.len()]
+                        .copy_from_slice(path.as_bytes());
+                    header.set_cksum();
+                    builder.append(&header, contents.as_bytes())
+                })
+                .unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_archive_format_detect() {
+        let tar = tar_archive(&[("app.conf", 0o644, "key=value\n")]);
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(&tar).unwrap();
+        let tar_gz = encoder.finish().unwrap();
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(b"{\"not\": \"a tar archive\"}").unwrap();
+        let json_gz = encoder.finish().unwrap();
+
+        struct Case {
+            header: Vec<u8>,
+            expected: Option<ArchiveFormat>,
+        }
+        let cases = [
+            Case {
+                header: tar[..DETECT_BUFFER_SIZE].to_vec(),
+                expected: Some(ArchiveFormat::Tar),
+            },
+            Case {
+                header: tar_gz,
+                expected: Some(ArchiveFormat::TarGz),
+            },
+            Case {
+                header: json_gz,
+                expected: None,
+            },
+            Case {
+                header: b"PK\x03\x04\x14\x00".to_vec(),
+                expected: Some(ArchiveFormat::Zip),
+            },
+            Case {
+                header: b"key: value\n".to_vec(),
+                expected: None,
+            },
+        ];
+        for case in cases {
+            assert_eq!(ArchiveFormat::detect(&case.header), case.expected);
+        }
+    }
+
+    #[test]
+    fn test_unpack_tar() {
+        let archive = tar_archive(&[
+            ("bin/app", 0o755, "#!/bin/sh\n"),
+            ("etc/app.conf", 0o640, "key=value\n"),
+            ("../escape", 0o644, "outside\n"),
+        ]);
+
+        let base_dir = std::env::temp_dir().join(format!("unpack-tar-{}", std::process::id()));
+        let dest_dir = base_dir.join("dest");
+        std::fs::create_dir_all(&dest_dir).unwrap();
+        let (uid, gid) = (rustix::process::getuid(), rustix::process::getgid());
+        unpack_tar(Cursor::new(archive), &dest_dir, Mode::from(0o750), uid, gid).unwrap();
+
+        let app = std::fs::metadata(dest_dir.join("bin/app")).unwrap();
+        assert_eq!(app.permissions().mode() & 0o777, 0o755);
+        // The directory is not an entry in the archive, so it is created with mode_dir.
+        let bin = std::fs::metadata(dest_dir.join("bin")).unwrap();
+        assert_eq!(bin.permissions().mode() & 0o777, 0o750);
+        let conf = std::fs::read_to_string(dest_dir.join("etc/app.conf")).unwrap();
+        assert_eq!(conf, "key=value\n");
+        assert!(!base_dir.join("escape").exists());
+
+        std::fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[test]
+    fn test_unpack_zip() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        writer
+            .start_file("bin/app", options.unix_permissions(0o755))
+            .unwrap();
+        writer.write_all(b"#!/bin/sh\n").unwrap();
+        writer
+            .start_file("etc/app.conf", options.unix_permissions(0o640))
+            .unwrap();
+        writer.write_all(b"key=value\n").unwrap();
+        writer.start_file("../escape", options).unwrap();
+        writer.write_all(b"outside\n").unwrap();
+        let archive = writer.finish().unwrap().into_inner();
+
+        let base_dir = std::env::temp_dir().join(format!("unpack-zip-{}", std::process::id()));
+        let dest_dir = base_dir.join("dest");
+        std::fs::create_dir_all(&dest_dir).unwrap();
+        let (uid, gid) = (rustix::process::getuid(), rustix::process::getgid());
+        unpack_zip(Cursor::new(archive), &dest_dir, Mode::from(0o750), uid, gid).unwrap();
+
+        let app = std::fs::metadata(dest_dir.join("bin/app")).unwrap();
+        assert_eq!(app.permissions().mode() & 0o777, 0o755);
+        let bin = std::fs::metadata(dest_dir.join("bin")).unwrap();
+        assert_eq!(bin.permissions().mode() & 0o777, 0o750);
+        let conf = std::fs::read_to_string(dest_dir.join("etc/app.conf")).unwrap();
+        assert_eq!(conf, "key=value\n");
+        assert!(!base_dir.join("escape").exists());
+        // Only the unpacked files are left in the destination.
+        let mut names = std::fs::read_dir(&dest_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["bin", "etc"]);
+
+        std::fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[test]
+    fn test_unpack_zip_through_links() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored);
+        // A file entry after a link of the same name replaces the link.
+        writer.add_symlink("link", "../outside", options).unwrap();
+        writer.start_file("./link", options).unwrap();
+        writer.write_all(b"replaced\n").unwrap();
+        // Entries under a link to outside of the destination are skipped.
+        writer.add_symlink("parent", "..", options).unwrap();
+        writer.add_directory("parent/created", options).unwrap();
+        writer.start_file("parent/outside", options).unwrap();
+        writer.write_all(b"replaced\n").unwrap();
+        let archive = writer.finish().unwrap().into_inner();
+
+        let base_dir =
+            std::env::temp_dir().join(format!("unpack-zip-links-{}", std::process::id()));
+        let dest_dir = base_dir.join("dest");
+        std::fs::create_dir_all(&dest_dir).unwrap();
+        std::fs::write(base_dir.join("outside"), "original\n").unwrap();
+        let (uid, gid) = (rustix::process::getuid(), rustix::process::getgid());
+        unpack_zip(Cursor::new(archive), &dest_dir, Mode::from(0o750), uid, gid).unwrap();
+
+        let outside = std::fs::read_to_string(base_dir.join("outside")).unwrap();
+        assert_eq!(outside, "original\n");
+        let link = std::fs::symlink_metadata(dest_dir.join("link")).unwrap();
+        assert!(link.is_file());
+        let replaced = std::fs::read_to_string(dest_dir.join("link")).unwrap();
+        assert_eq!(replaced, "replaced\n");
+        assert!(!base_dir.join("created").exists());
+
+        std::fs::remove_dir_all(&base_dir).unwrap();
+    }
+
+    #[test]
+    fn test_copy_with_progress() {
+        let data = (0..COPY_BUFFER_SIZE * 3 + 17)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<u8>>();
+        let mut reader = Cursor::new(data.clone());
+        let mut writer = Vec::new();
+        let written =
+            copy_with_progress(&mut reader, &mut writer, Path::new("/dest"), None).unwrap();
+        assert_eq!(written, data.len() as u64);
+        assert_eq!(writer, data);
+    }
 }